@@ -0,0 +1,105 @@
+use glob::glob;
+use failure::bail;
+use std::fs::write;
+use std::path::{PathBuf};
+
+// Shared with the framework, which exports it for anyone compiling
+// shaders at runtime
+#[path = "src/preprocess.rs"]
+#[allow(dead_code)]
+mod preprocess;
+#[path = "src/shader_defines.rs"]
+#[allow(dead_code)]
+mod shader_defines;
+
+// The framework doesn't have any resources of its own, just the
+// shaders for the passes it provides.
+fn main() {
+    compile_shaders();
+}
+
+fn compile_shaders() {
+    // This tells cargo to rerun this script if something in /src/ changes.
+    println!("cargo:rerun-if-changed=src/*");
+    
+    // Collect all shaders recursively within /src/
+    let mut shader_paths = [
+        glob("./src/**/*.vert").unwrap(),
+        glob("./src/**/*.frag").unwrap(),
+        glob("./src/**/*.comp").unwrap(),
+    ];
+    
+    let mut preprocessor = preprocess::ShaderPreprocessor::new();
+    for root in &["./src/shaders"] {
+        preprocessor.add_include_root(*root);
+    }
+    for (name, value) in shader_defines::SHADER_DEFINES {
+        preprocessor.define(*name, value);
+    }
+
+    // This could be parallelized
+    let shaders = shader_paths.iter_mut()
+        .flatten()
+        .map(|glob_result| {
+            ShaderData::load(glob_result.unwrap(), &preprocessor).unwrap()
+        })
+        .collect::<Vec<ShaderData>>();
+
+    let mut compiler = shaderc::Compiler::new().unwrap();
+
+    // This can't be parallelized. The [shaderc::Compiler] is not
+    // thread safe. Also, it creates a lot of resources. You could
+    // spawn multiple processes to handle this, but it would probably
+    // be better just to only compile shaders that have been changed
+    // recently.
+    for shader in shaders {
+        // Editing an included file has to rebuild everything using it
+        for dependency in &shader.preprocessed.dependencies {
+            println!("cargo:rerun-if-changed={}", dependency.display());
+        }
+
+        let name = shader.src_path.to_str().unwrap();
+        let compiled = match compiler.compile_into_spirv(
+            &shader.preprocessed.source,
+            shader.kind,
+            name,
+            "main",
+            None
+        ) {
+            Ok(compiled) => compiled,
+            // Point the messages at the files the code came from rather
+            // than the preprocessed source
+            Err(shaderc::Error::CompilationError(_, messages)) => {
+                panic!("{}", shader.preprocessed.map_errors(name, &messages))
+            }
+            Err(e) => panic!("{}: {}", name, e),
+        };
+        write(shader.spv_path, compiled.as_binary_u8()).unwrap();
+    }
+
+    // panic!("Debugging...");
+}
+
+struct ShaderData {
+    preprocessed: preprocess::PreprocessedShader,
+    src_path: PathBuf,
+    spv_path: PathBuf,
+    kind: shaderc::ShaderKind,
+}
+
+impl ShaderData {
+    pub fn load(src_path: PathBuf, preprocessor: &preprocess::ShaderPreprocessor) -> Result<Self, failure::Error> {
+        let extension = src_path.extension().unwrap().to_str().unwrap();
+        let kind = match extension {
+            "vert" => shaderc::ShaderKind::Vertex,
+            "frag" => shaderc::ShaderKind::Fragment,
+            "comp" => shaderc::ShaderKind::Compute,
+            _ => bail!("Unsupported shader: {}", src_path.display()),
+        };
+
+        let preprocessed = preprocessor.process(&src_path)?;
+        let spv_path = src_path.with_extension(format!("{}.spv", extension));
+
+        Ok(Self { preprocessed, src_path, spv_path, kind })
+    }
+}
\ No newline at end of file
@@ -0,0 +1,88 @@
+use cgmath::*;
+use std::collections::HashMap;
+
+/**
+ * Which triangles touch which. Model files split vertices wherever the
+ * UVs or normals change, so vertices are welded by position first,
+ * otherwise every UV seam would look like a hole in the mesh.
+ *
+ * This is shared by the cleanup pass and anything else that needs to
+ * walk the surface, such as normal recomputation.
+ */
+#[derive(Debug, Clone)]
+pub struct Adjacency {
+    /// The welded id of each original vertex
+    pub vertex_ids: Vec<u32>,
+    /// The corners of each triangle as welded ids
+    pub triangles: Vec<[u32; 3]>,
+    /// Every triangle using each (undirected) edge
+    edges: HashMap<(u32, u32), Vec<u32>>,
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+impl Adjacency {
+    pub fn new(positions: &[Vector3<f32>], indices: &[u32]) -> Self {
+        // Weld on the exact bit pattern. Vertices that are only nearly
+        // in the same place are left alone.
+        let mut welded = HashMap::new();
+        let vertex_ids = positions.iter()
+            .map(|p| {
+                let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+                let next = welded.len() as u32;
+                *welded.entry(key).or_insert(next)
+            })
+            .collect::<Vec<_>>();
+
+        let triangles = indices.chunks_exact(3)
+            .map(|c| [
+                vertex_ids[c[0] as usize],
+                vertex_ids[c[1] as usize],
+                vertex_ids[c[2] as usize],
+            ])
+            .collect::<Vec<_>>();
+
+        let mut edges: HashMap<_, Vec<u32>> = HashMap::new();
+        for (t, tri) in triangles.iter().enumerate() {
+            for k in 0..3 {
+                let (a, b) = (tri[k], tri[(k + 1) % 3]);
+                if a != b {
+                    edges.entry(edge_key(a, b)).or_default().push(t as u32);
+                }
+            }
+        }
+
+        Self { vertex_ids, triangles, edges }
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.triangles.len()
+    }
+
+    /// Triangles using the edge between welded vertices `a` and `b`
+    pub fn edge_triangles(&self, a: u32, b: u32) -> &[u32] {
+        self.edges.get(&edge_key(a, b)).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// Every triangle sharing an edge with `triangle`, along with the
+    /// edge (as the index of its first corner in `triangle`)
+    pub fn neighbors(&self, triangle: u32) -> impl Iterator<Item = (usize, u32)> + '_ {
+        let tri = self.triangles[triangle as usize];
+        (0..3).flat_map(move |k| {
+            self.edge_triangles(tri[k], tri[(k + 1) % 3])
+                .iter()
+                .filter(move |&&other| other != triangle)
+                .map(move |&other| (k, other))
+        })
+    }
+
+    /// Whether `triangle` walks from `a` to `b` (as opposed to `b` to
+    /// `a`). Two neighbors have matching winding when they walk their
+    /// shared edge in opposite directions.
+    pub fn has_directed_edge(&self, triangle: u32, a: u32, b: u32) -> bool {
+        let tri = self.triangles[triangle as usize];
+        (0..3).any(|k| tri[k] == a && tri[(k + 1) % 3] == b)
+    }
+}
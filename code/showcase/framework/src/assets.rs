@@ -0,0 +1,342 @@
+use anyhow::*;
+use std::path::{Component, Path, PathBuf};
+use crate::manifest::EmbeddedAssets;
+use crate::material_layout::MaterialLayout;
+use crate::model::{Model, ModelData, ModelLoadOptions};
+use crate::resources::{ResourceTracker, Unloaded};
+use crate::texture::TextureCache;
+
+/**
+ * Turns the paths that model files use to refer to other files into
+ * paths that actually exist. Files exported on Windows like to use
+ * backslashes, the wrong case, and absolute paths from the artist's
+ * machine, none of which work anywhere else.
+ *
+ * Strategies are tried in order, stopping at the first hit:
+ *
+ * 1. The path as written, if it's absolute and exists
+ * 2. Relative to the folder of the file doing the referring
+ * 3. Relative to each search path, in the order they were added
+ * 4. All of the above again, ignoring case
+ *
+ * Absolute paths that don't exist get their root stripped and are
+ * then tried with fewer and fewer leading folders, so
+ * `C:\Users\me\project\textures\brick.png` can still find
+ * `textures/brick.png` or just `brick.png`.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PathResolver {
+    search_paths: Vec<PathBuf>,
+}
+
+/// How [PathResolver::resolve] found a file. This is mostly for the log.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ResolveStrategy {
+    AsWritten,
+    ContainingFolder,
+    SearchPath(PathBuf),
+    CaseInsensitive(PathBuf),
+    /// Packed into the binary, see [crate::EmbeddedAssets]
+    Embedded,
+}
+
+impl PathResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.search_paths.push(path.into());
+    }
+
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
+    }
+
+    /// Finds the file that `reference` (as written in a file inside
+    /// `containing_folder`) means
+    pub fn resolve(&self, reference: &str, containing_folder: &Path) -> Result<PathBuf> {
+        Ok(self.resolve_logged(reference, containing_folder)?.0)
+    }
+
+    /// Same as [PathResolver::resolve], but also says how the file was
+    /// found
+    pub fn resolve_logged(&self, reference: &str, containing_folder: &Path) -> Result<(PathBuf, ResolveStrategy)> {
+        let (path, strategy) = self.resolve_with_strategy(reference, containing_folder)
+            .with_context(|| format!(
+                "Unable to find {:?} near {} or in any search path",
+                reference,
+                containing_folder.display(),
+            ))?;
+        match strategy {
+            ResolveStrategy::AsWritten | ResolveStrategy::ContainingFolder => {
+                log::debug!("{:?} -> {}", reference, path.display());
+            }
+            _ => log::info!("{:?} -> {} ({:?})", reference, path.display(), strategy),
+        }
+        Ok((path, strategy))
+    }
+
+    pub fn resolve_with_strategy(
+        &self,
+        reference: &str,
+        containing_folder: &Path,
+    ) -> Option<(PathBuf, ResolveStrategy)> {
+        let normalized = reference.trim().replace('\\', "/");
+        let written = Path::new(&normalized);
+        if written.is_absolute() && written.is_file() {
+            return Some((written.to_path_buf(), ResolveStrategy::AsWritten));
+        }
+
+        let parts = relative_parts(&normalized);
+        if parts.is_empty() {
+            return None;
+        }
+        // Longest first, so `textures/brick.png` wins over `brick.png`
+        let suffixes = (0..parts.len())
+            .map(|start| parts[start..].iter().collect::<PathBuf>())
+            .collect::<Vec<_>>();
+
+        let roots = std::iter::once((containing_folder.to_path_buf(), ResolveStrategy::ContainingFolder))
+            .chain(self.search_paths.iter().map(|p| (p.clone(), ResolveStrategy::SearchPath(p.clone()))));
+        let roots = roots.collect::<Vec<_>>();
+
+        for (root, strategy) in &roots {
+            for suffix in &suffixes {
+                let candidate = root.join(suffix);
+                if candidate.is_file() {
+                    return Some((candidate, strategy.clone()));
+                }
+            }
+        }
+
+        for (root, _) in &roots {
+            for start in 0..parts.len() {
+                if let Some(found) = find_ignoring_case(root, &parts[start..]) {
+                    return Some((found, ResolveStrategy::CaseInsensitive(root.clone())));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// The folders and file name of `path` with any drive letter, root or
+/// `.` taken out. `..` is kept, since it means something.
+fn relative_parts(path: &str) -> Vec<String> {
+    // Path doesn't know about drive letters on anything but Windows
+    let bytes = path.as_bytes();
+    let path = if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic() {
+        &path[2..]
+    } else {
+        path
+    };
+    Path::new(path)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            Component::ParentDir => Some("..".to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Walks `parts` down from `root`, matching each one against the
+/// directory listing without caring about case
+fn find_ignoring_case(root: &Path, parts: &[String]) -> Option<PathBuf> {
+    let mut current = root.to_path_buf();
+    for (i, part) in parts.iter().enumerate() {
+        let is_last = i + 1 == parts.len();
+        if part == ".." {
+            current.push("..");
+            continue;
+        }
+        let exact = current.join(part);
+        if (is_last && exact.is_file()) || (!is_last && exact.is_dir()) {
+            current = exact;
+            continue;
+        }
+        let lower = part.to_lowercase();
+        let entry = std::fs::read_dir(&current).ok()?
+            .filter_map(|e| e.ok())
+            .find(|e| {
+                e.file_name().to_string_lossy().to_lowercase() == lower
+                    && e.file_type().map(|t| if is_last { t.is_file() } else { t.is_dir() }).unwrap_or(false)
+            })?;
+        current = entry.path();
+    }
+    Some(current)
+}
+
+/**
+ * Where demos go to load things. For now this just makes sure every
+ * loader uses the same [PathResolver], and reads models out of
+ * [AssetManager::embedded] instead when they're in there.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct AssetManager {
+    resolver: PathResolver,
+    pub embedded: Option<EmbeddedAssets>,
+}
+
+impl AssetManager {
+    /// Sets up the usual search paths under `res_dir`
+    pub fn new<P: AsRef<Path>>(res_dir: P) -> Self {
+        let res_dir = res_dir.as_ref();
+        let mut resolver = PathResolver::new();
+        resolver.add_search_path(res_dir);
+        resolver.add_search_path(res_dir.join("textures"));
+        Self { resolver, embedded: None }
+    }
+
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.resolver.add_search_path(path);
+    }
+
+    pub fn resolver(&self) -> &PathResolver {
+        &self.resolver
+    }
+
+    /// Finds `path` the same way textures referenced by a model are
+    /// found, using the working directory as the containing folder
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        self.resolver.resolve(path, Path::new("."))
+    }
+
+    pub fn load_model<'a>(
+        &self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        path: &str,
+        options: &ModelLoadOptions,
+    ) -> Result<(Model<'a>, Vec<wgpu::CommandBuffer>)> {
+        if let Some(embedded) = self.embedded_with(path) {
+            return Model::load_from_memory(device, layout, path, &embedded, options);
+        }
+        let path = self.resolve(path)?;
+        Model::load_with_resolver(device, layout, path, options, &self.resolver)
+    }
+
+    /// Loads without touching the GPU, for when the same file is going
+    /// to be uploaded more than once with [Model::from_data]
+    pub fn load_model_data(&self, path: &str, options: &ModelLoadOptions) -> Result<ModelData> {
+        if let Some(embedded) = self.embedded_with(path) {
+            return ModelData::load_from_memory(path, &embedded, options);
+        }
+        let path = self.resolve(path)?;
+        ModelData::load_with_resolver(path, options, &self.resolver)
+    }
+
+    /**
+     * Drops `model`, which was loaded from `path`, and evicts every
+     * texture nothing else uses any more. `tracker` should have the
+     * model under its path, the way [AssetManager::load_model] was
+     * given it.
+     */
+    pub fn unload<'a>(
+        &self,
+        path: &str,
+        model: Model<'a>,
+        tracker: &mut ResourceTracker,
+        textures: &mut TextureCache<'a>,
+    ) -> Unloaded {
+        tracker.unload_model(path, model, textures)
+    }
+
+    fn embedded_with(&self, path: &str) -> Option<EmbeddedAssets> {
+        self.embedded.filter(|embedded| embedded.get(path).is_some())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A folder in the system temp dir that gets deleted on drop. The
+    /// loader's tests use it too.
+    pub(crate) struct Fixture {
+        pub root: PathBuf,
+    }
+
+    impl Fixture {
+        pub fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let root = std::env::temp_dir().join(format!(
+                "learn-wgpu-assets-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst),
+            ));
+            std::fs::create_dir_all(&root).unwrap();
+            Self { root }
+        }
+
+        /// An empty file
+        pub fn file(&self, path: &str) -> PathBuf {
+            self.write(path, "")
+        }
+
+        pub fn write(&self, path: &str, contents: &str) -> PathBuf {
+            let path = self.root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn backslashes_in_containing_folder() {
+        let fixture = Fixture::new();
+        let expected = fixture.file("models/textures/brick.png");
+        let (path, strategy) = PathResolver::new()
+            .resolve_with_strategy("textures\\brick.png", &fixture.root.join("models"))
+            .unwrap();
+        assert_eq!(path, expected);
+        assert_eq!(strategy, ResolveStrategy::ContainingFolder);
+    }
+
+    #[test]
+    fn windows_absolute_path_falls_back_to_search_path() {
+        let fixture = Fixture::new();
+        let expected = fixture.file("res/textures/brick.png");
+        let mut resolver = PathResolver::new();
+        resolver.add_search_path(fixture.root.join("res"));
+        let (path, strategy) = resolver
+            .resolve_with_strategy("C:\\Users\\artist\\project\\textures\\brick.png", &fixture.root.join("models"))
+            .unwrap();
+        assert_eq!(path, expected);
+        assert_eq!(strategy, ResolveStrategy::SearchPath(fixture.root.join("res")));
+    }
+
+    #[test]
+    fn wrong_case_is_found_last() {
+        let fixture = Fixture::new();
+        let expected = fixture.file("models/Textures/Brick.PNG");
+        if fixture.root.join("models/textures/brick.png").is_file() {
+            // The file system ignores case already (hello macOS), so
+            // this strategy never gets a chance
+            return;
+        }
+        let (path, strategy) = PathResolver::new()
+            .resolve_with_strategy("textures/brick.png", &fixture.root.join("models"))
+            .unwrap();
+        assert_eq!(path, expected);
+        assert_eq!(strategy, ResolveStrategy::CaseInsensitive(fixture.root.join("models")));
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let fixture = Fixture::new();
+        fixture.file("models/other.png");
+        let resolver = PathResolver::new();
+        assert!(resolver.resolve("brick.png", &fixture.root.join("models")).is_err());
+        assert!(resolver.resolve("", &fixture.root.join("models")).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::profiler::ChromeTrace;
+use crate::settings::{RenderSettings, WindowGeometry};
+use crate::{FramePacing, MotionBlurMode};
+
+/**
+ * How long stages are allowed to take in a [BenchReport]. Anything not
+ * listed can take as long as it likes.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BenchThresholds {
+    /// Milliseconds, by profiler scope name. Frame stages are held to
+    /// their 95th percentile so one hitch doesn't fail a run, and load
+    /// stages to their total. `"first_frame"` is the time from the
+    /// first load stage to the end of the first frame. A render pass's
+    /// name on its own is how long it took to encode, and `"gpu/main"`
+    /// how long the main pass took on the GPU.
+    pub stages_ms: BTreeMap<String, f64>,
+    /// Peak tracked GPU memory
+    pub gpu_memory_mb: Option<f64>,
+}
+
+/**
+ * What a startup benchmark runs, usually from a RON file so the suite
+ * can change without a rebuild. Everything has a default, so the file
+ * only needs what's different.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BenchConfig {
+    /// The scene file, for demos that load one
+    pub scene: String,
+    pub frames: usize,
+    /// Seconds each frame simulates, whatever it actually took, so
+    /// every run sees the same frames
+    pub dt: f32,
+    /// An input recording to replay for the camera, see
+    /// [crate::InputSession]. What the camera does without one is up
+    /// to the demo.
+    pub camera_path: Option<PathBuf>,
+    /// Same size every run, as the timings depend on it
+    pub window: WindowGeometry,
+    pub settings: RenderSettings,
+    pub thresholds: BenchThresholds,
+    /// Where the JSON version of the report goes
+    pub report: PathBuf,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        let mut settings = RenderSettings {
+            // Everything on that we have, and nothing waiting on vsync
+            // or focus
+            occlusion_culling: true,
+            pacing: FramePacing {
+                vsync: false,
+                sleep_when_unfocused: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        settings.contact_shadows.enabled = true;
+        settings.motion_blur.mode = MotionBlurMode::PerObject;
+        Self {
+            scene: "scene.ron".to_string(),
+            frames: 300,
+            dt: 1.0 / 60.0,
+            camera_path: None,
+            window: WindowGeometry { width: 1280, height: 720, position: None },
+            settings,
+            thresholds: Default::default(),
+            report: PathBuf::from("bench-report.json"),
+        }
+    }
+}
+
+impl BenchConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let src = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Unable to read bench config {}", path.as_ref().display()))?;
+        Self::parse(&src)
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        Ok(ron::de::from_str(src)?)
+    }
+}
+
+/// How long one profiler scope took over a run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageTiming {
+    pub name: String,
+    pub count: usize,
+    pub total_ms: f64,
+    pub mean_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+impl StageTiming {
+    /// Every `"X"` event of category `cat` in `trace`, by name, slowest
+    /// total first
+    pub fn from_trace(trace: &ChromeTrace, cat: &str) -> Vec<Self> {
+        let mut by_name = BTreeMap::<&str, Vec<f64>>::new();
+        for event in &trace.trace_events {
+            match event.dur {
+                Some(dur) if event.ph == "X" && event.cat == cat => {
+                    by_name.entry(&event.name).or_default().push(dur / 1000.0);
+                }
+                _ => {}
+            }
+        }
+        let mut stages = by_name.into_iter()
+            .map(|(name, mut times)| {
+                times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let total_ms = times.iter().sum::<f64>();
+                let p95 = ((times.len() as f64 * 0.95).ceil() as usize).max(1) - 1;
+                Self {
+                    name: name.to_string(),
+                    count: times.len(),
+                    total_ms,
+                    mean_ms: total_ms / times.len() as f64,
+                    p95_ms: times[p95],
+                    max_ms: times[times.len() - 1],
+                }
+            })
+            .collect::<Vec<_>>();
+        stages.sort_by(|a, b| b.total_ms.partial_cmp(&a.total_ms).unwrap());
+        stages
+    }
+}
+
+/**
+ * What a startup benchmark found, from the profiler's trace of the
+ * whole run. Load stages are the trace's [crate::Profiler::load_scope]s,
+ * and frame stages everything else timed on the CPU or GPU. The GPU
+ * times come from the render graph, see
+ * [crate::RenderGraph::with_profiler].
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub scene: String,
+    pub frames: usize,
+    /// From the first load stage to the end of the first frame
+    pub first_frame_ms: Option<f64>,
+    pub load_stages: Vec<StageTiming>,
+    /// CPU scopes, where a render pass's is how long it took to encode
+    pub cpu_stages: Vec<StageTiming>,
+    /// Render passes, by how long the GPU took to do them
+    pub gpu_stages: Vec<StageTiming>,
+    pub peak_gpu_bytes: u64,
+    /// Every threshold that got crossed, empty when the run passed
+    pub failures: Vec<String>,
+}
+
+impl BenchReport {
+    pub fn new(config: &BenchConfig, trace: &ChromeTrace, peak_gpu_bytes: u64) -> Self {
+        let first_load = trace.trace_events.iter()
+            .filter(|e| e.cat == "load")
+            .map(|e| e.ts)
+            .fold(None, |first: Option<f64>, ts| Some(first.map_or(ts, |f| f.min(ts))));
+        let first_frame = trace.trace_events.iter().find(|e| e.cat == "frame").map(|e| e.ts);
+        let first_frame_ms = match (first_load, first_frame) {
+            (Some(load), Some(frame)) => Some((frame - load) / 1000.0),
+            _ => None,
+        };
+
+        let mut report = Self {
+            scene: config.scene.clone(),
+            frames: trace.trace_events.iter().filter(|e| e.cat == "frame").count(),
+            first_frame_ms,
+            load_stages: StageTiming::from_trace(trace, "load"),
+            cpu_stages: StageTiming::from_trace(trace, "cpu"),
+            gpu_stages: StageTiming::from_trace(trace, "gpu"),
+            peak_gpu_bytes,
+            failures: Vec::new(),
+        };
+        report.failures = report.check(&config.thresholds);
+        report
+    }
+
+    fn check(&self, thresholds: &BenchThresholds) -> Vec<String> {
+        let mut failures = Vec::new();
+        for (name, &limit) in &thresholds.stages_ms {
+            let (what, took) = if name == "first_frame" {
+                ("took", self.first_frame_ms)
+            } else if let Some(stage) = self.load_stages.iter().find(|s| s.name == *name) {
+                ("took", Some(stage.total_ms))
+            } else {
+                let frame = match name.strip_prefix("gpu/") {
+                    Some(pass) => self.gpu_stages.iter().find(|s| s.name == pass),
+                    None => self.cpu_stages.iter().find(|s| s.name == *name),
+                };
+                ("has a p95 of", frame.map(|s| s.p95_ms))
+            };
+            match took {
+                Some(ms) if ms > limit => {
+                    failures.push(format!("{} {} {:.2} ms, over the {:.2} ms allowed", name, what, ms, limit));
+                }
+                Some(_) => {}
+                None => failures.push(format!("{} has a threshold but never ran", name)),
+            }
+        }
+        if let Some(limit) = thresholds.gpu_memory_mb {
+            let mb = self.peak_gpu_bytes as f64 / (1 << 20) as f64;
+            if mb > limit {
+                failures.push(format!("GPU memory peaked at {:.1} MB, over the {:.1} MB allowed", mb, limit));
+            }
+        }
+        failures
+    }
+
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path.as_ref(), self.to_json()?)
+            .with_context(|| format!("Unable to write bench report to {}", path.as_ref().display()))
+    }
+}
+
+/// The summary for people, the JSON is for scripts
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}, {} frames", self.scene, self.frames)?;
+        if let Some(ms) = self.first_frame_ms {
+            writeln!(f, "First frame after {:.1} ms", ms)?;
+        }
+        writeln!(f, "Loading:")?;
+        for stage in &self.load_stages {
+            writeln!(f, "  {:<24} {:>9.2} ms", stage.name, stage.total_ms)?;
+        }
+        let sections = [
+            ("CPU per frame, passes by encode time:", &self.cpu_stages),
+            ("GPU per frame, by pass:", &self.gpu_stages),
+        ];
+        for (title, stages) in sections.iter() {
+            if stages.is_empty() {
+                continue;
+            }
+            writeln!(f, "{:<39}{:>6}{:>9}{:>9}", title, "mean", "p95", "max")?;
+            for stage in stages.iter() {
+                writeln!(
+                    f,
+                    "  {:<24} {:>6.2} {:>8.2} {:>8.2} ms",
+                    stage.name, stage.mean_ms, stage.p95_ms, stage.max_ms,
+                )?;
+            }
+        }
+        writeln!(f, "Peak tracked GPU memory: {:.1} MB", self.peak_gpu_bytes as f64 / (1 << 20) as f64)?;
+        if self.passed() {
+            write!(f, "Passed")
+        } else {
+            write!(f, "FAILED:")?;
+            for failure in &self.failures {
+                write!(f, "\n  {}", failure)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::profiler::TraceEvent;
+
+    fn event(name: &str, cat: &'static str, ph: &'static str, ts: f64, dur_ms: f64) -> TraceEvent {
+        TraceEvent {
+            name: name.to_string(),
+            cat,
+            ph,
+            ts,
+            dur: if ph == "X" { Some(dur_ms * 1000.0) } else { None },
+            pid: 1,
+            tid: 1,
+            s: None,
+            args: None,
+        }
+    }
+
+    #[test]
+    fn reports_check_their_thresholds() {
+        let mut events = vec![
+            event("models", "load", "X", 0.0, 40.0),
+            event("scene", "load", "X", 40_000.0, 5.0),
+            event("frame", "frame", "i", 100_000.0, 0.0),
+        ];
+        // One bad frame in twenty doesn't move the p95
+        for i in 0..20 {
+            events.push(event("encode", "cpu", "X", 100_000.0 + i as f64, if i == 7 { 30.0 } else { 2.0 }));
+            // Cheap to encode, slow to draw
+            events.push(event("main", "cpu", "X", 100_000.0 + i as f64, 0.5));
+            events.push(event("main", "gpu", "X", 100_000.0 + i as f64, 8.0));
+        }
+        let trace = ChromeTrace { trace_events: events };
+
+        let config = BenchConfig::parse(r#"(
+            frames: 20,
+            thresholds: (
+                stages_ms: {
+                    "encode": 3.0,
+                    "models": 50.0,
+                    "first_frame": 90.0,
+                    "ssao": 1.0,
+                    "main": 1.0,
+                    "gpu/main": 4.0,
+                },
+                gpu_memory_mb: Some(1.0),
+            ),
+        )"#).unwrap();
+        assert_eq!(config.scene, "scene.ron");
+        assert!(config.settings.occlusion_culling);
+
+        let report = BenchReport::new(&config, &trace, 2 << 20);
+        assert_eq!(report.first_frame_ms, Some(100.0));
+        assert_eq!(report.load_stages[0].name, "models");
+        let encode = &report.cpu_stages[0];
+        assert_eq!((encode.count, encode.p95_ms, encode.max_ms), (20, 2.0, 30.0));
+        assert_eq!(report.gpu_stages[0].name, "main");
+        assert_eq!(report.failures.len(), 4, "{:?}", report.failures);
+        assert!(report.failures[0].starts_with("first_frame took 100.00 ms"));
+        assert!(report.failures[1].starts_with("gpu/main has a p95 of 8.00 ms"));
+        assert!(report.failures[2].starts_with("ssao has a threshold but never ran"));
+        assert!(report.failures[3].starts_with("GPU memory peaked at 2.0 MB"));
+        assert!(!report.passed());
+        let summary = report.to_string();
+        assert!(summary.contains("GPU per frame, by pass:"));
+        assert!(summary.contains("FAILED"));
+        assert!(report.to_json().unwrap().contains("\"p95_ms\": 2.0"));
+    }
+}
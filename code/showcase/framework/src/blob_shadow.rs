@@ -0,0 +1,166 @@
+use anyhow::*;
+use crate::bounds::Aabb;
+use crate::model::Vertex;
+use crate::pipeline::RenderPipelineBuilder;
+
+/// How dark a blob is when the object is sitting on the ground
+const MAX_ALPHA: f32 = 0.6;
+/// A blob is completely faded out once the object is this many times
+/// its own footprint radius above the ground
+const FADE_HEIGHT: f32 = 4.0;
+/// How far above the ground to draw the blobs so they don't z-fight
+/// with a ground plane
+const GROUND_OFFSET: f32 = 0.01;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct BlobInstance {
+    /// xyz is the center on the ground, w is the radius
+    center_radius: [f32; 4],
+    alpha: f32,
+}
+
+unsafe impl bytemuck::Pod for BlobInstance {}
+unsafe impl bytemuck::Zeroable for BlobInstance {}
+
+impl Vertex for BlobInstance {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        use std::mem;
+        wgpu::VertexBufferDescriptor {
+            stride: mem::size_of::<BlobInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float,
+                },
+            ],
+        }
+    }
+}
+
+impl BlobInstance {
+    /// Sizes the blob from the footprint of `aabb` and fades it out
+    /// the higher the box is above `ground_height`. Returns `None` if
+    /// the blob would be invisible.
+    pub fn from_aabb(aabb: &Aabb, ground_height: f32) -> Option<Self> {
+        if aabb.is_empty() {
+            return None;
+        }
+        let size = aabb.size();
+        let radius = size.x.max(size.z) * 0.5;
+        let height = (aabb.min.y - ground_height).max(0.0);
+        let fade = 1.0 - height / (radius * FADE_HEIGHT);
+        if fade <= 0.0 || radius <= 0.0 {
+            return None;
+        }
+
+        let center = aabb.center();
+        // Shadows from higher objects are softer and spread out more
+        let radius = radius * (1.0 + 0.5 * (1.0 - fade));
+        Some(Self {
+            center_radius: [center.x, ground_height + GROUND_OFFSET, center.z, radius],
+            alpha: MAX_ALPHA * fade,
+        })
+    }
+}
+
+/**
+ * Cheap fake shadows: a dark, soft circle on the ground under each
+ * object. This is what we use when real shadow mapping is off.
+ */
+pub struct BlobShadows {
+    pipeline: wgpu::RenderPipeline,
+    blobs: Vec<BlobInstance>,
+    buffer: Option<wgpu::Buffer>,
+    buffer_len: usize,
+}
+
+impl BlobShadows {
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[uniform_layout],
+            }
+        );
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&layout)
+            .color_state(wgpu::ColorStateDescriptor {
+                format: color_format,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            // Test against the scene, but don't write so overlapping
+            // blobs blend together
+            .depth_no_stencil(depth_format, false, wgpu::CompareFunction::LessEqual)
+            .vertex_buffer::<BlobInstance>()
+            .vertex_shader(include_bytes!("shaders/blob_shadow.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/blob_shadow.frag.spv"))
+            .build(device)?;
+
+        Ok(Self {
+            pipeline,
+            blobs: Vec::new(),
+            buffer: None,
+            buffer_len: 0,
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.blobs.clear();
+    }
+
+    /// Adds a blob under an object whose bounds in world space are `aabb`
+    pub fn add(&mut self, aabb: &Aabb, ground_height: f32) {
+        self.blobs.extend(BlobInstance::from_aabb(aabb, ground_height));
+    }
+
+    pub fn upload(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        if self.blobs.is_empty() {
+            return;
+        }
+        let data = bytemuck::cast_slice(&self.blobs);
+        match &self.buffer {
+            Some(buffer) if self.buffer_len >= self.blobs.len() => {
+                let staging_buffer = device.create_buffer_with_data(data, wgpu::BufferUsage::COPY_SRC);
+                encoder.copy_buffer_to_buffer(&staging_buffer, 0, buffer, 0, data.len() as _);
+            }
+            _ => {
+                self.buffer = Some(device.create_buffer_with_data(
+                    data,
+                    wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+                ));
+                self.buffer_len = self.blobs.len();
+            }
+        }
+    }
+
+    /// Draw this after the opaque geometry
+    pub fn render<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>, uniforms: &'a wgpu::BindGroup) {
+        if let Some(buffer) = &self.buffer {
+            if self.blobs.is_empty() {
+                return;
+            }
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, uniforms, &[]);
+            pass.set_vertex_buffer(0, buffer, 0, 0);
+            pass.draw(0..6, 0..self.blobs.len() as u32);
+        }
+    }
+}
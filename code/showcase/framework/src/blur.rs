@@ -0,0 +1,399 @@
+use anyhow::*;
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/// The widest kernel the shader's uniform has room for, on each side of
+/// the center pixel
+pub const MAX_BLUR_RADIUS: usize = 16;
+
+/**
+ * A normalized Gaussian kernel, `2 * radius + 1` weights long with the
+ * center in the middle. `sigma` of `None` uses half the radius, which
+ * puts the ends of the kernel at two standard deviations. The radius
+ * gets clamped to [MAX_BLUR_RADIUS].
+ */
+pub fn gaussian_weights(radius: usize, sigma: Option<f32>) -> Vec<f32> {
+    let radius = radius.min(MAX_BLUR_RADIUS);
+    let sigma = sigma.unwrap_or(radius as f32 * 0.5).max(1e-3);
+    let r = radius as isize;
+    let weights = (-r..=r)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let sum: f32 = weights.iter().sum();
+    weights.into_iter().map(|w| w / sum).collect()
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct BlurUniform {
+    /// xy is the step between taps, z is the radius
+    direction: [i32; 4],
+    /// Only x is used. Arrays in uniforms have a 16 byte stride anyway.
+    weights: [[f32; 4]; MAX_BLUR_RADIUS + 1],
+}
+
+unsafe impl bytemuck::Pod for BlurUniform {}
+unsafe impl bytemuck::Zeroable for BlurUniform {}
+
+impl BlurUniform {
+    fn new(direction: [i32; 2], weights: &[f32]) -> Self {
+        // The kernel is symmetric, so the shader only needs the center
+        // and one side
+        let radius = weights.len() / 2;
+        let mut uniform = Self {
+            direction: [direction[0], direction[1], radius as i32, 0],
+            weights: [[0.0; 4]; MAX_BLUR_RADIUS + 1],
+        };
+        for (i, w) in weights[radius..].iter().enumerate() {
+            uniform.weights[i][0] = *w;
+        }
+        uniform
+    }
+}
+
+/**
+ * A separable Gaussian blur from one texture to another, done as a
+ * horizontal pass into a texture the pass owns and then a vertical pass
+ * into the destination. The in between texture gets made the first
+ * time it's needed and remade whenever the source's size changes.
+ *
+ * Each pass is made for one format, which its source, destination and
+ * in between texture all share. Anything with float channels works,
+ * like [Texture::HDR_FORMAT] or `R32Float`. The destination has to be
+ * the same size as the source.
+ */
+pub struct BlurPass<'a> {
+    /// `None` uses half the radius, see [gaussian_weights]
+    pub sigma: Option<f32>,
+    format: wgpu::TextureFormat,
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    horizontal: wgpu::Buffer,
+    vertical: wgpu::Buffer,
+    intermediate: Option<Texture<'a>>,
+}
+
+impl<'a> BlurPass<'a> {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Result<Self> {
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                ],
+                label: Some("BlurPass::layout"),
+            }
+        );
+        // Only used with texelFetch, so the filtering doesn't matter,
+        // but it can't be a comparison sampler like the texture's own
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let create_uniform_buffer = |direction| device.create_buffer_with_data(
+            bytemuck::cast_slice(&[BlurUniform::new(direction, &[1.0])]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        let horizontal = create_uniform_buffer([1, 0]);
+        let vertical = create_uniform_buffer([0, 1]);
+
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&layout],
+            }
+        );
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .color_solid(format)
+            // Same full screen triangle
+            .vertex_shader(include_bytes!("shaders/tonemap.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/blur.frag.spv"))
+            .build(device)?;
+
+        Ok(Self {
+            sigma: None,
+            format,
+            pipeline,
+            layout,
+            sampler,
+            horizontal,
+            vertical,
+            intermediate: None,
+        })
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    /**
+     * Blurs `src` into `dst` with a kernel `radius` pixels either side.
+     * This takes the source texture rather than just its view, as wgpu
+     * views don't know how big they are. `src` and `dst` can't be the
+     * same texture.
+     */
+    pub fn run(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        src: &Texture,
+        dst: &wgpu::TextureView,
+        radius: usize,
+    ) {
+        let weights = gaussian_weights(radius, self.sigma);
+        for (buffer, direction) in [(&self.horizontal, [1, 0]), (&self.vertical, [0, 1])].iter() {
+            let staging = device.create_buffer_with_data(
+                bytemuck::cast_slice(&[BlurUniform::new(*direction, &weights)]),
+                wgpu::BufferUsage::COPY_SRC,
+            );
+            encoder.copy_buffer_to_buffer(
+                &staging,
+                0,
+                buffer,
+                0,
+                std::mem::size_of::<BlurUniform>() as wgpu::BufferAddress,
+            );
+        }
+
+        let size = src.desc.size;
+        let stale = match &self.intermediate {
+            Some(t) => t.desc.size.width != size.width || t.desc.size.height != size.height,
+            None => true,
+        };
+        if stale {
+            self.intermediate = Some(Texture::from_descriptor(device, wgpu::TextureDescriptor {
+                label: Some("BlurPass::intermediate"),
+                size: wgpu::Extent3d { width: size.width, height: size.height, depth: 1 },
+                array_layer_count: 1,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.format,
+                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+            }));
+        }
+        let intermediate = &self.intermediate.as_ref().unwrap().view;
+
+        let horizontal = self.create_bind_group(device, &src.view, &self.horizontal);
+        self.draw(encoder, intermediate, &horizontal);
+        let vertical = self.create_bind_group(device, intermediate, &self.vertical);
+        self.draw(encoder, dst, &vertical);
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        src: &wgpu::TextureView,
+        uniform_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(src),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buffer,
+                        range: 0..std::mem::size_of::<BlurUniform>() as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("BlurPass::bind_group"),
+        })
+    }
+
+    fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        bind_group: &wgpu::BindGroup,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    // Every pixel gets written
+                    load_op: wgpu::LoadOp::Load,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::capture::read_pixels;
+
+    /// What the blur shader does to a single channel image. Pixels past
+    /// the edge are clamped, the same as the shader.
+    fn blur_cpu(pixels: &[f32], width: usize, height: usize, radius: usize, sigma: Option<f32>) -> Vec<f32> {
+        let weights = gaussian_weights(radius, sigma);
+        let r = (weights.len() / 2) as isize;
+        let pass = |src: &[f32], step: (isize, isize)| -> Vec<f32> {
+            let mut dst = vec![0.0; src.len()];
+            for y in 0..height as isize {
+                for x in 0..width as isize {
+                    let mut sum = 0.0;
+                    for (i, w) in (-r..=r).zip(&weights) {
+                        let sx = (x + i * step.0).max(0).min(width as isize - 1);
+                        let sy = (y + i * step.1).max(0).min(height as isize - 1);
+                        sum += w * src[(sy * width as isize + sx) as usize];
+                    }
+                    dst[(y * width as isize + x) as usize] = sum;
+                }
+            }
+            dst
+        };
+        let horizontal = pass(pixels, (1, 0));
+        pass(&horizontal, (0, 1))
+    }
+
+    /// 8x8 with 2x2 squares, white in the top right
+    const CHECKERBOARD_SIZE: usize = 8;
+
+    fn checkerboard() -> Vec<f32> {
+        let size = CHECKERBOARD_SIZE;
+        (0..size * size)
+            .map(|i| (((i % size) / 2 + (i / size) / 2) % 2) as f32)
+            .collect()
+    }
+
+    /// The first two rows of [checkerboard] after a blur of radius 2
+    /// and sigma 1
+    fn assert_blurred_checkerboard(blurred: &[f32], tolerance: f32) {
+        let expected = [
+            [0.1030, 0.3206, 0.6308, 0.6308, 0.3692, 0.3692, 0.6794, 0.8970],
+            [0.3206, 0.4189, 0.5591, 0.5591, 0.4409, 0.4409, 0.5811, 0.6794],
+        ];
+        for (y, row) in expected.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                let got = blurred[y * CHECKERBOARD_SIZE + x];
+                assert!((got - value).abs() < tolerance, "({}, {}) was {}, not {}", x, y, got, value);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_are_normalized_and_symmetric() {
+        for &(radius, sigma) in &[(1, None), (4, None), (4, Some(3.0)), (MAX_BLUR_RADIUS, Some(0.5))] {
+            let weights = gaussian_weights(radius, sigma);
+            assert_eq!(weights.len(), radius * 2 + 1);
+            let sum: f32 = weights.iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5, "{} for {:?}", sum, (radius, sigma));
+            for (a, b) in weights.iter().zip(weights.iter().rev()) {
+                assert_eq!(a, b);
+            }
+            // Falls off away from the center
+            assert!(weights[radius..].windows(2).all(|w| w[0] >= w[1]));
+        }
+        assert_eq!(gaussian_weights(0, None), vec![1.0]);
+        assert_eq!(gaussian_weights(100, None).len(), MAX_BLUR_RADIUS * 2 + 1);
+    }
+
+    #[test]
+    fn checkerboard_blurs_to_known_values() {
+        let size = CHECKERBOARD_SIZE;
+        assert_blurred_checkerboard(&blur_cpu(&checkerboard(), size, size, 2, Some(1.0)), 1e-4);
+    }
+
+    #[test]
+    fn checkerboard_blurs_the_same_on_the_gpu() {
+        let (device, queue) = match crate::thumbnail::test_device() {
+            Some(device) => device,
+            None => return,
+        };
+        let size = CHECKERBOARD_SIZE as u32;
+        let texture = |label, usage| Texture::from_descriptor(&device, wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d { width: size, height: size, depth: 1 },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage,
+        });
+        let src = texture("checkerboard", wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST);
+        let dst = texture("blurred", wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC);
+
+        // Rows of a buffer to texture copy have to be 256 bytes apart
+        let row_size = 256;
+        let mut upload = vec![0u8; row_size * CHECKERBOARD_SIZE];
+        for (y, row) in checkerboard().chunks_exact(CHECKERBOARD_SIZE).enumerate() {
+            upload[y * row_size..][..row.len() * 4].copy_from_slice(bytemuck::cast_slice(row));
+        }
+        let staging = device.create_buffer_with_data(&upload, wgpu::BufferUsage::COPY_SRC);
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("checkerboard_blurs_the_same_on_the_gpu::encoder") }
+        );
+        encoder.copy_buffer_to_texture(
+            wgpu::BufferCopyView {
+                buffer: &staging,
+                offset: 0,
+                bytes_per_row: row_size as u32,
+                rows_per_image: size,
+            },
+            wgpu::TextureCopyView {
+                texture: &src.texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            src.desc.size,
+        );
+        let mut blur = BlurPass::new(&device, wgpu::TextureFormat::R32Float).unwrap();
+        blur.sigma = Some(1.0);
+        blur.run(&device, &mut encoder, &src, &dst.view, 2);
+        queue.submit(&[encoder.finish()]);
+
+        let pixels = read_pixels(&device, &queue, &dst.texture, size, size, 4).unwrap();
+        let blurred = pixels.chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        assert_blurred_checkerboard(&blurred, 1e-3);
+        let on_cpu = blur_cpu(&checkerboard(), CHECKERBOARD_SIZE, CHECKERBOARD_SIZE, 2, Some(1.0));
+        for (i, (gpu, cpu)) in blurred.iter().zip(&on_cpu).enumerate() {
+            assert!((gpu - cpu).abs() < 1e-3, "pixel {} was {} on the GPU, but {} on the CPU", i, gpu, cpu);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use cgmath::*;
+
+/**
+ * Axis aligned bounding box. An empty box has `min` > `max` so that
+ * growing it by any point gives a box around just that point.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Self {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points<I, P>(points: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Point3<f32>>,
+    {
+        let mut aabb = Self::empty();
+        for p in points {
+            aabb.grow(p.into());
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, p: Point3<f32>) {
+        self.min = Point3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Point3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut result = *self;
+        if !other.is_empty() {
+            result.grow(other.min);
+            result.grow(other.max);
+        }
+        result
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn size(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    /// Radius of the sphere that contains the box
+    pub fn radius(&self) -> f32 {
+        self.size().magnitude() * 0.5
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Point3::new(a.x, a.y, a.z),
+            Point3::new(b.x, a.y, a.z),
+            Point3::new(a.x, b.y, a.z),
+            Point3::new(b.x, b.y, a.z),
+            Point3::new(a.x, a.y, b.z),
+            Point3::new(b.x, a.y, b.z),
+            Point3::new(a.x, b.y, b.z),
+            Point3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    /// The box around this box after it's been transformed. This is
+    /// usually a bit bigger than the transformed geometry.
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        Self::from_points(self.corners().iter().map(|c| matrix.transform_point(*c)))
+    }
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
@@ -1,8 +1,16 @@
 use std::mem;
+use std::ops::Range;
 
 pub trait ToRaw {
     type Output;
     fn to_raw(&self) -> Self::Output;
+
+    /// Same as `to_raw`, but replacing `previous` in a buffer. Raw
+    /// types that carry something over between updates, like
+    /// [crate::InstanceRaw]'s previous transform, keep it here.
+    fn to_raw_over(&self, _previous: &Self::Output) -> Self::Output {
+        self.to_raw()
+    }
 }
 
 pub struct RawBuffer<R> 
@@ -75,4 +83,39 @@ impl<U: ToRaw<Output=R>, R: Copy + bytemuck::Pod + bytemuck::Zeroable> Buffer<U,
     pub fn from_parts(data: Vec<U>, raw_buffer: RawBuffer<R>, usage: wgpu::BufferUsage) -> Self {
         Self { data, raw_buffer, usage }
     }
-}
\ No newline at end of file
+
+    /// Re-uploads `data[range]` after it's been changed on the CPU.
+    /// The buffer needs `COPY_DST`.
+    pub fn update_range(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+        for i in range.clone() {
+            self.raw_buffer.data[i] = self.data[i].to_raw_over(&self.raw_buffer.data[i]);
+        }
+        let raw = &self.raw_buffer.data[range.clone()];
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(raw),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.raw_buffer.buffer,
+            (range.start * mem::size_of::<R>()) as wgpu::BufferAddress,
+            (raw.len() * mem::size_of::<R>()) as wgpu::BufferAddress,
+        );
+    }
+
+    /// Makes a new GPU buffer from `data`. Use this after adding or
+    /// removing items, since buffers can't be resized.
+    pub fn recreate(&mut self, device: &wgpu::Device) {
+        // wgpu doesn't like empty buffers, and there's nothing to draw
+        // anyway, so we hang on to the old one
+        if self.data.is_empty() {
+            self.raw_buffer.data.clear();
+            return;
+        }
+        self.raw_buffer = RawBuffer::from_slice(device, &self.data, self.usage);
+    }
+}
@@ -67,11 +67,21 @@ impl Camera {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ProjectionMode {
+    Perspective,
+    Orthographic,
+}
+
 pub struct Projection {
     aspect: f32,
     fovy: Rad<f32>,
     znear: f32,
     zfar: f32,
+    pub mode: ProjectionMode,
+    /// How many world units fit vertically on screen in
+    /// [ProjectionMode::Orthographic].
+    pub ortho_height: f32,
 }
 
 impl Projection {
@@ -87,6 +97,8 @@ impl Projection {
             fovy: fovy.into(),
             znear,
             zfar,
+            mode: ProjectionMode::Perspective,
+            ortho_height: 10.0,
         }
     }
 
@@ -94,8 +106,214 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
+    pub fn fovy(&self) -> Rad<f32> {
+        self.fovy
+    }
+
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub fn zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            ProjectionMode::Perspective => ProjectionMode::Orthographic,
+            ProjectionMode::Orthographic => ProjectionMode::Perspective,
+        };
+    }
+
+    /// Sets [Projection::ortho_height] so that something `distance`
+    /// away from the camera is the same size in both modes.
+    pub fn match_ortho_to_distance(&mut self, distance: f32) {
+        self.ortho_height = 2.0 * distance * (self.fovy / 2.0).tan();
+    }
+
+    pub fn calc_matrix(&self) -> Matrix4<f32> {
+        match self.mode {
+            ProjectionMode::Perspective => {
+                OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
+            }
+            ProjectionMode::Orthographic => {
+                let half_height = self.ortho_height * 0.5;
+                let half_width = half_height * self.aspect;
+                OPENGL_TO_WGPU_MATRIX * ortho(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    self.znear,
+                    self.zfar,
+                )
+            }
+        }
+    }
+}
+
+/// Keeps the orbit camera from flipping over the top
+const MAX_ORBIT_PITCH: f32 = FRAC_PI_2 - 0.001;
+
+/**
+ * A camera that circles around `target`. Yaw and pitch work the same
+ * as [Camera], but they describe where the eye is relative to the
+ * target instead of where the camera is looking.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OrbitCamera {
+    pub target: Point3<f32>,
+    pub distance: f32,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+}
+
+impl OrbitCamera {
+    pub fn new<
+        V: Into<Point3<f32>>,
+        Y: Into<Rad<f32>>,
+        P: Into<Rad<f32>>,
+    >(
+        target: V,
+        distance: f32,
+        yaw: Y,
+        pitch: P,
+    ) -> Self {
+        let mut camera = Self {
+            target: target.into(),
+            distance,
+            yaw: yaw.into(),
+            pitch: pitch.into(),
+        };
+        camera.clamp_pitch();
+        camera
+    }
+
+    /// Places the camera so that all of `aabb` fits on screen
+    pub fn framing<Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(
+        aabb: &crate::Aabb,
+        fovy: Rad<f32>,
+        yaw: Y,
+        pitch: P,
+    ) -> Self {
+        let radius = aabb.radius().max(0.01);
+        let distance = radius / (fovy / 2.0).sin();
+        Self::new(aabb.center(), distance, yaw, pitch)
+    }
+
+    /// Unit vector pointing from the target to the eye
+    pub fn direction(&self) -> Vector3<f32> {
+        let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
+        let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
+        Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin)
+    }
+
+    pub fn eye(&self) -> Point3<f32> {
+        self.target + self.direction() * self.distance
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
+        Matrix4::look_at(self.eye(), self.target, Vector3::unit_y())
+    }
+
+    pub fn rotate<Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(&mut self, yaw: Y, pitch: P) {
+        self.yaw += yaw.into();
+        self.pitch += pitch.into();
+        self.clamp_pitch();
+    }
+
+    pub fn zoom(&mut self, amount: f32) {
+        self.distance = (self.distance - amount).max(0.01);
+    }
+
+    /**
+     * Moves the eye and the target `fraction` of the way towards
+     * `point`, which keeps `point` at the same place on screen.
+     * Negative fractions back away from it.
+     *
+     * The eye stops `min_distance` short of `point`, so zooming can't
+     * go through it. Pass something bigger than the near plane.
+     */
+    pub fn zoom_towards(&mut self, point: Point3<f32>, fraction: f32, min_distance: f32) {
+        let to_point = (point - self.eye()).magnitude();
+        if !to_point.is_finite() || to_point <= 0.0 {
+            return;
+        }
+        let max_fraction = (1.0 - min_distance / to_point).max(0.0);
+        let fraction = fraction.min(max_fraction);
+        // Both points move towards `point` by the same fraction, so the
+        // direction between them stays the same and only the distance
+        // shrinks
+        let distance = self.distance * (1.0 - fraction);
+        if !(distance >= 0.01) {
+            return;
+        }
+        self.target += (point - self.target) * fraction;
+        self.distance = distance;
+    }
+
+    /// Interpolates towards `other`, taking the short way around
+    pub fn lerp(&self, other: &OrbitCamera, t: f32) -> OrbitCamera {
+        let yaw_delta = (other.yaw - self.yaw).normalize_signed();
+        OrbitCamera {
+            target: self.target + (other.target - self.target) * t,
+            distance: self.distance + (other.distance - self.distance) * t,
+            yaw: self.yaw + yaw_delta * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+        }
+    }
+
+    fn clamp_pitch(&mut self) {
+        self.pitch = Rad(self.pitch.0.max(-MAX_ORBIT_PITCH).min(MAX_ORBIT_PITCH));
+    }
+}
+
+/**
+ * Smoothly moves an [OrbitCamera] from one pose to another instead of
+ * teleporting it.
+ */
+#[derive(Debug, Clone)]
+pub struct OrbitTransition {
+    from: OrbitCamera,
+    to: OrbitCamera,
+    elapsed: Duration,
+    duration: Duration,
+}
+
+impl OrbitTransition {
+    pub const DEFAULT_DURATION: Duration = Duration::from_millis(200);
+
+    pub fn new(from: OrbitCamera, to: OrbitCamera, duration: Duration) -> Self {
+        Self {
+            from,
+            to,
+            elapsed: Duration::from_secs(0),
+            duration,
+        }
+    }
+
+    pub fn target(&self) -> &OrbitCamera {
+        &self.to
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    /// Advances the transition and returns where the camera should be
+    pub fn update(&mut self, dt: Duration) -> OrbitCamera {
+        self.elapsed += dt;
+        if self.is_finished() {
+            return self.to;
+        }
+        let t = self.elapsed.as_secs_f32() / self.duration.as_secs_f32();
+        // smoothstep so we ease in and out
+        let t = t * t * (3.0 - 2.0 * t);
+        self.from.lerp(&self.to, t)
     }
 }
 
@@ -217,4 +435,33 @@ impl CameraController {
             camera.pitch = Rad(FRAC_PI_2);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn screen_position(camera: &OrbitCamera, point: Point3<f32>) -> Vector2<f32> {
+        let projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        let clip = projection.calc_matrix() * camera.calc_matrix() * point.to_homogeneous();
+        Vector2::new(clip.x / clip.w, clip.y / clip.w)
+    }
+
+    #[test]
+    fn zooming_towards_a_point_keeps_it_still() {
+        let mut camera = OrbitCamera::new((0.0, 0.0, 0.0), 10.0, Deg(30.0), Deg(20.0));
+        let point = Point3::new(1.0, 0.5, -0.5);
+        let before = screen_position(&camera, point);
+
+        camera.zoom_towards(point, 0.5, 0.2);
+        assert!((screen_position(&camera, point) - before).magnitude() < 1e-4);
+        assert!((camera.distance - 5.0).abs() < 1e-4);
+
+        // Scrolling a lot stops short of the point instead of going
+        // through it
+        camera.zoom_towards(point, 10.0, 0.2);
+        assert!(((point - camera.eye()).magnitude() - 0.2).abs() < 1e-3);
+        assert!(camera.distance.is_finite() && camera.distance > 0.0);
+        assert!((screen_position(&camera, point) - before).magnitude() < 1e-3);
+    }
+}
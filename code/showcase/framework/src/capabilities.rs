@@ -0,0 +1,366 @@
+use anyhow::*;
+use serde::Serialize;
+use std::fmt;
+
+/// The smallest [DeviceCapabilities::max_buffer_size] of any backend,
+/// for when there's no device to ask
+pub const DEFAULT_MAX_BUFFER_SIZE: u64 = 128 << 20;
+
+/**
+ * Swap chain formats in the order [DeviceCapabilities::surface_format]
+ * tries them. sRGB first, as then the hardware does the encoding. BGRA
+ * before RGBA, as it's what most desktop compositors scan out without
+ * a copy. The last two need [crate::TonemapPass] to encode the output
+ * itself.
+ */
+pub const SURFACE_FORMAT_PREFERENCE: [wgpu::TextureFormat; 4] = [
+    wgpu::TextureFormat::Bgra8UnormSrgb,
+    wgpu::TextureFormat::Rgba8UnormSrgb,
+    wgpu::TextureFormat::Bgra8Unorm,
+    wgpu::TextureFormat::Rgba8Unorm,
+];
+
+/// Whether writes to `format` get sRGB encoded by the hardware
+pub fn is_srgb_format(format: wgpu::TextureFormat) -> bool {
+    match format {
+        wgpu::TextureFormat::Rgba8UnormSrgb | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+        _ => false,
+    }
+}
+
+/// Whether `format` keeps blue in its first byte, so readbacks have to
+/// swap it with red
+pub fn is_bgra_format(format: wgpu::TextureFormat) -> bool {
+    match format {
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+        _ => false,
+    }
+}
+
+/**
+ * What the device can do, worked out once when it's created. Anything
+ * that would fail, or quietly do nothing, without some feature asks
+ * in here first and gets a [Decision] back.
+ *
+ * wgpu 0.5 only reports the anisotropic filtering extension and the
+ * bind group limit. The rest are the minimums each backend's spec
+ * guarantees, which every device on that backend will have. Probing
+ * for more isn't possible, as wgpu 0.5 panics rather than returning an
+ * error when something's unsupported. That goes for the surface too,
+ * which can't be asked what formats it takes.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceCapabilities {
+    pub adapter: String,
+    pub backend: String,
+    pub device_type: String,
+    /// Whether the extension was turned on when the device was made
+    pub anisotropic_filtering: bool,
+    pub max_bind_groups: u32,
+    /// Largest MSAA sample count that works for every color and depth
+    /// format we use
+    pub max_sample_count: u32,
+    pub max_texture_array_layers: u32,
+    /// Storage buffers bound to the vertex stage. Compute can always
+    /// use them.
+    pub vertex_storage_buffers: bool,
+    /// Biggest single buffer in bytes. Meshes bigger than this get
+    /// split, see [crate::split_mesh].
+    pub max_buffer_size: u64,
+    /// Formats the backend's swap chains take, in no particular order.
+    /// See [DeviceCapabilities::surface_format].
+    #[serde(serialize_with = "serialize_formats")]
+    pub surface_formats: Vec<wgpu::TextureFormat>,
+}
+
+impl DeviceCapabilities {
+    pub fn new(adapter: &wgpu::Adapter, desc: &wgpu::DeviceDescriptor) -> Self {
+        let info = adapter.get_info();
+        Self::for_backend(
+            info.backend,
+            info.name,
+            format!("{:?}", info.device_type),
+            desc,
+        )
+    }
+
+    pub(crate) fn for_backend(
+        backend: wgpu::Backend,
+        adapter: String,
+        device_type: String,
+        desc: &wgpu::DeviceDescriptor,
+    ) -> Self {
+        let (max_sample_count, max_texture_array_layers, vertex_storage_buffers, max_buffer_size) = match backend {
+            // Vulkan only promises 256 layers, and 1GB allocations
+            wgpu::Backend::Vulkan => (4, 256, true, 1 << 30),
+            wgpu::Backend::Metal => (4, 2048, true, 256 << 20),
+            wgpu::Backend::Dx12 => (4, 2048, true, 128 << 20),
+            // Feature level 11 has no UAVs outside the pixel and
+            // compute stages
+            wgpu::Backend::Dx11 => (4, 2048, false, 128 << 20),
+            _ => (1, 256, false, DEFAULT_MAX_BUFFER_SIZE),
+        };
+        let surface_formats = match backend {
+            // Android drivers often only do RGBA
+            wgpu::Backend::Vulkan => vec![
+                wgpu::TextureFormat::Bgra8UnormSrgb,
+                wgpu::TextureFormat::Bgra8Unorm,
+                wgpu::TextureFormat::Rgba8UnormSrgb,
+                wgpu::TextureFormat::Rgba8Unorm,
+            ],
+            // CAMetalLayer has no RGBA8 at all
+            wgpu::Backend::Metal => vec![
+                wgpu::TextureFormat::Bgra8UnormSrgb,
+                wgpu::TextureFormat::Bgra8Unorm,
+            ],
+            wgpu::Backend::Dx12 | wgpu::Backend::Dx11 => SURFACE_FORMAT_PREFERENCE.to_vec(),
+            _ => vec![wgpu::TextureFormat::Bgra8UnormSrgb],
+        };
+        Self {
+            adapter,
+            backend: format!("{:?}", backend),
+            device_type,
+            anisotropic_filtering: desc.extensions.anisotropic_filtering,
+            max_bind_groups: desc.limits.max_bind_groups,
+            max_sample_count,
+            max_texture_array_layers,
+            vertex_storage_buffers,
+            max_buffer_size,
+            surface_formats,
+        }
+    }
+
+    /// An adapter without a window, for `--print-caps` and the like
+    pub async fn request_headless() -> Result<Self> {
+        let adapter = wgpu::Adapter::request(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::Default,
+                compatible_surface: None,
+            },
+            wgpu::BackendBit::PRIMARY,
+        ).await.context("Unable to find valid device!")?;
+        Ok(Self::new(&adapter, &Default::default()))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Drops to the largest power of two sample count that's supported.
+    /// 1 turns MSAA off.
+    pub fn msaa_samples(&self, wanted: u32) -> Decision<u32> {
+        if wanted <= self.max_sample_count {
+            return Decision::supported(wanted.max(1));
+        }
+        let mut samples = self.max_sample_count.max(1);
+        while samples > 1 && !samples.is_power_of_two() {
+            samples -= 1;
+        }
+        Decision::fallback(
+            samples,
+            format!("{}x MSAA was asked for, but {} only guarantees {}x", wanted, self.backend, self.max_sample_count),
+        )
+    }
+
+    /// Falls back to plain trilinear filtering (`None`) without the
+    /// extension
+    pub fn anisotropy(&self, wanted: u8) -> Decision<Option<u8>> {
+        if wanted <= 1 {
+            Decision::supported(None)
+        } else if self.anisotropic_filtering {
+            Decision::supported(Some(wanted.min(16)))
+        } else {
+            Decision::fallback(None, "the anisotropic filtering extension isn't enabled".to_string())
+        }
+    }
+
+    /// Clamps to the layer limit. Callers that need every layer should
+    /// split into more than one texture.
+    pub fn texture_array_layers(&self, wanted: u32) -> Decision<u32> {
+        if wanted <= self.max_texture_array_layers {
+            Decision::supported(wanted)
+        } else {
+            Decision::fallback(
+                self.max_texture_array_layers,
+                format!("{} layers were asked for, but {} only guarantees {}", wanted, self.backend, self.max_texture_array_layers),
+            )
+        }
+    }
+
+    /// Where per instance data goes: a storage buffer read in the
+    /// vertex shader, or a second vertex buffer
+    pub fn instance_storage(&self) -> Decision<InstanceStorage> {
+        if self.vertex_storage_buffers {
+            Decision::supported(InstanceStorage::StorageBuffer)
+        } else {
+            Decision::fallback(
+                InstanceStorage::VertexBuffer,
+                format!("{} can't bind storage buffers to the vertex stage", self.backend),
+            )
+        }
+    }
+
+    /**
+     * The first of [SURFACE_FORMAT_PREFERENCE] the swap chain takes.
+     * Anything other than the first is a fallback, so it gets logged.
+     * A backend with none of them (there isn't one yet) gets its first
+     * format and the tonemap pass's best guess.
+     */
+    pub fn surface_format(&self) -> Decision<wgpu::TextureFormat> {
+        let preferred = SURFACE_FORMAT_PREFERENCE[0];
+        let chosen = SURFACE_FORMAT_PREFERENCE.iter()
+            .copied()
+            .find(|format| self.surface_formats.contains(format))
+            .or_else(|| self.surface_formats.first().copied())
+            .unwrap_or(preferred);
+        if chosen == preferred {
+            Decision::supported(chosen)
+        } else {
+            Decision::fallback(
+                chosen,
+                format!("{} swap chains don't take {:?}, using {:?}", self.backend, preferred, chosen),
+            )
+        }
+    }
+
+    /**
+     * A guess at how much texture memory is safe to use. wgpu 0.5
+     * can't say how much memory there is, so this goes by the kind of
+     * device: three quarters of a 2 GB card for discrete ones, which
+     * is the smallest still around, and less for anything sharing
+     * memory with the CPU.
+     */
+    pub fn texture_budget(&self) -> u64 {
+        match self.device_type.as_str() {
+            "DiscreteGpu" => 1536 << 20,
+            "IntegratedGpu" => 512 << 20,
+            _ => 256 << 20,
+        }
+    }
+
+    /// Fails with the reason if a pipeline layout with `needed` groups
+    /// can't be made. There's no fallback, as shaders hard code their
+    /// set numbers.
+    pub fn require_bind_groups(&self, needed: u32) -> Result<()> {
+        ensure!(
+            needed <= self.max_bind_groups,
+            "{} bind groups are needed, but the device only allows {}",
+            needed,
+            self.max_bind_groups,
+        );
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} ({}, {})", self.adapter, self.backend, self.device_type)?;
+        writeln!(f, "  anisotropic filtering: {}", self.anisotropic_filtering)?;
+        writeln!(f, "  max bind groups: {}", self.max_bind_groups)?;
+        writeln!(f, "  max MSAA samples: {}", self.max_sample_count)?;
+        writeln!(f, "  max texture array layers: {}", self.max_texture_array_layers)?;
+        writeln!(f, "  vertex stage storage buffers: {}", self.vertex_storage_buffers)?;
+        writeln!(f, "  max buffer size: {} MB", self.max_buffer_size >> 20)?;
+        writeln!(f, "  surface formats: {:?}", self.surface_formats)?;
+        write!(f, "  texture budget: {} MB", self.texture_budget() >> 20)
+    }
+}
+
+/// wgpu 0.5's formats aren't [Serialize], so they go in as their names
+fn serialize_formats<S: serde::Serializer>(formats: &[wgpu::TextureFormat], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(formats.iter().map(|format| format!("{:?}", format)))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InstanceStorage {
+    StorageBuffer,
+    VertexBuffer,
+}
+
+/**
+ * What [DeviceCapabilities] said about a feature. `reason` is set when
+ * `value` isn't what was asked for. Use [Decision::get] to log it.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision<T> {
+    pub value: T,
+    pub reason: Option<String>,
+}
+
+impl<T> Decision<T> {
+    fn supported(value: T) -> Self {
+        Self { value, reason: None }
+    }
+
+    fn fallback(value: T, reason: String) -> Self {
+        Self { value, reason: Some(reason) }
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        self.reason.is_some()
+    }
+
+    /// Logs why `feature` fell back, if it did
+    pub fn get(self, feature: &str) -> T {
+        if let Some(reason) = &self.reason {
+            log::warn!("{}: falling back, {}", feature, reason);
+        }
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn caps(backend: wgpu::Backend) -> DeviceCapabilities {
+        DeviceCapabilities::for_backend(backend, "test".to_string(), "Other".to_string(), &Default::default())
+    }
+
+    #[test]
+    fn downlevel_profiles_pick_their_fallbacks() {
+        let vulkan = caps(wgpu::Backend::Vulkan);
+        let dx11 = caps(wgpu::Backend::Dx11);
+        let mut low_end = caps(wgpu::Backend::Vulkan);
+        low_end.max_sample_count = 2;
+        low_end.max_bind_groups = 3;
+        low_end.anisotropic_filtering = true;
+
+        // MSAA
+        assert_eq!(vulkan.msaa_samples(4), Decision::supported(4));
+        assert!(vulkan.msaa_samples(8).is_fallback());
+        assert_eq!(vulkan.msaa_samples(8).value, 4);
+        assert_eq!(low_end.msaa_samples(4).value, 2);
+        assert_eq!(vulkan.msaa_samples(0).value, 1);
+
+        // Anisotropy isn't requested by default
+        assert_eq!(vulkan.anisotropy(8), Decision::fallback(None, "the anisotropic filtering extension isn't enabled".to_string()));
+        assert_eq!(low_end.anisotropy(32).value, Some(16));
+        assert!(!vulkan.anisotropy(1).is_fallback());
+
+        // Array layers
+        assert!(vulkan.texture_array_layers(1024).is_fallback());
+        assert_eq!(vulkan.texture_array_layers(1024).value, 256);
+        assert!(!dx11.texture_array_layers(1024).is_fallback());
+
+        // Instancing
+        assert_eq!(vulkan.instance_storage().value, InstanceStorage::StorageBuffer);
+        assert_eq!(dx11.instance_storage().value, InstanceStorage::VertexBuffer);
+        assert!(dx11.instance_storage().reason.unwrap().contains("Dx11"));
+
+        // Bind groups
+        assert!(vulkan.require_bind_groups(4).is_ok());
+        assert!(low_end.require_bind_groups(4).is_err());
+
+        // Surface formats
+        assert_eq!(vulkan.surface_format(), Decision::supported(wgpu::TextureFormat::Bgra8UnormSrgb));
+        let mut android = caps(wgpu::Backend::Vulkan);
+        android.surface_formats = vec![wgpu::TextureFormat::Rgba8Unorm, wgpu::TextureFormat::Rgba8UnormSrgb];
+        assert_eq!(android.surface_format().value, wgpu::TextureFormat::Rgba8UnormSrgb);
+        assert!(android.surface_format().is_fallback());
+        android.surface_formats = vec![wgpu::TextureFormat::Rgba8Unorm, wgpu::TextureFormat::Bgra8Unorm];
+        assert_eq!(android.surface_format().value, wgpu::TextureFormat::Bgra8Unorm);
+        android.surface_formats = vec![wgpu::TextureFormat::Rgba16Float];
+        assert_eq!(android.surface_format().value, wgpu::TextureFormat::Rgba16Float);
+    }
+}
@@ -0,0 +1,292 @@
+use anyhow::*;
+use cgmath::*;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use crate::capabilities::is_bgra_format;
+use crate::probe::f16_to_f32;
+use crate::texture::Texture;
+use crate::tonemap;
+
+/// Buffer copies need each row to start on a multiple of this
+const ROW_ALIGNMENT: u32 = 256;
+
+/// How a captured texture gets turned into something an image viewer
+/// can open
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CaptureKind {
+    /// Saved as 16 bit grayscale, from black at `znear` to white at
+    /// `zfar`
+    Depth { znear: f32, zfar: f32, perspective: bool },
+    /// Tonemapped the same way [crate::TonemapPass] does it
+    Hdr,
+    /// Saved as is
+    Color,
+}
+
+struct CaptureSource<'a> {
+    name: String,
+    texture: &'a Texture<'a>,
+    kind: CaptureKind,
+}
+
+/**
+ * Saves a frame's textures to a folder as PNGs, with a `notes.txt`
+ * saying how each one was converted. Add whatever the frame drew
+ * with [FrameCapture::add] and then call [FrameCapture::save] after
+ * the frame has been submitted.
+ *
+ * Multisampled color textures get resolved first. Multisampled depth
+ * can't be resolved, so it gets skipped, as does any format there's no
+ * conversion for. Either way it ends up in the notes rather than
+ * failing the whole capture.
+ *
+ * This waits for the GPU, so it's for debugging only.
+ */
+pub struct FrameCapture<'a> {
+    sources: Vec<CaptureSource<'a>>,
+    notes: Vec<String>,
+}
+
+impl<'a> FrameCapture<'a> {
+    pub fn new() -> Self {
+        Self { sources: Vec::new(), notes: Vec::new() }
+    }
+
+    /// `name` becomes the file name, so keep it simple
+    pub fn add(&mut self, name: &str, texture: &'a Texture<'a>, kind: CaptureKind) -> &mut Self {
+        self.sources.push(CaptureSource { name: name.to_string(), texture, kind });
+        self
+    }
+
+    /// Makes a note of a pass that didn't run this frame
+    pub fn skip(&mut self, name: &str, reason: &str) -> &mut Self {
+        self.notes.push(format!("{}: skipped, {}", name, reason));
+        self
+    }
+
+    /// A new folder like `frame-1590000000` under `parent`
+    pub fn timestamped_dir<P: AsRef<Path>>(parent: P) -> PathBuf {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        parent.as_ref().join(format!("frame-{}", secs))
+    }
+
+    /// Returns the files that got written
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        dir: P,
+    ) -> Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create {}", dir.display()))?;
+
+        let mut notes = String::new();
+        let mut saved = Vec::new();
+        for source in &self.sources {
+            match self.save_source(device, queue, dir, source) {
+                Ok((path, note)) => {
+                    writeln!(notes, "{}: {}", source.name, note)?;
+                    saved.push(path);
+                }
+                Err(e) => writeln!(notes, "{}: skipped, {}", source.name, e)?,
+            }
+        }
+        for note in &self.notes {
+            writeln!(notes, "{}", note)?;
+        }
+        let notes_path = dir.join("notes.txt");
+        std::fs::write(&notes_path, notes)?;
+        saved.push(notes_path);
+        Ok(saved)
+    }
+
+    fn save_source(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        dir: &Path,
+        source: &CaptureSource,
+    ) -> Result<(PathBuf, String)> {
+        let desc = &source.texture.desc;
+        let (width, height) = (desc.size.width, desc.size.height);
+        let format = desc.format;
+        let path = dir.join(format!("{}.png", source.name));
+
+        let resolved;
+        let texture = if desc.sample_count > 1 {
+            if let CaptureKind::Depth { .. } = source.kind {
+                bail!("multisampled depth can't be resolved");
+            }
+            resolved = resolve(device, queue, source.texture);
+            &resolved.texture
+        } else {
+            &source.texture.texture
+        };
+
+        let note = match (source.kind, format) {
+            (CaptureKind::Depth { znear, zfar, perspective }, wgpu::TextureFormat::Depth32Float) => {
+                let pixels = read_pixels(device, queue, texture, width, height, 4)?;
+                let gray = pixels.chunks_exact(4)
+                    .map(|b| {
+                        let depth = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                        let linear = if perspective { linearize_depth(depth, znear, zfar) } else { depth };
+                        (linear.max(0.0).min(1.0) * 65535.0).round() as u16
+                    })
+                    .collect::<Vec<_>>();
+                image::ImageBuffer::<image::Luma<u16>, _>::from_raw(width, height, gray)
+                    .context("Depth image is the wrong size")?
+                    .save(&path)?;
+                format!("linear depth, black at {} and white at {}", znear, zfar)
+            }
+            (CaptureKind::Hdr, wgpu::TextureFormat::Rgba16Float) => {
+                let pixels = read_pixels(device, queue, texture, width, height, 8)?;
+                let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+                for p in pixels.chunks_exact(8) {
+                    let half = |i: usize| f16_to_f32(u16::from_le_bytes([p[i * 2], p[i * 2 + 1]]));
+                    let c = tonemap::tonemap(Vector3::new(half(0), half(1), half(2)));
+                    for &c in &[c.x, c.y, c.z] {
+                        rgb.push((tonemap::linear_to_srgb(c) * 255.0).round() as u8);
+                    }
+                }
+                image::RgbImage::from_raw(width, height, rgb)
+                    .context("HDR image is the wrong size")?
+                    .save(&path)?;
+                "HDR, tonemapped and sRGB encoded like the final frame (so values above 1 are squashed, not clipped)".to_string()
+            }
+            (CaptureKind::Color, wgpu::TextureFormat::Rgba8Unorm)
+            | (CaptureKind::Color, wgpu::TextureFormat::Rgba8UnormSrgb)
+            | (CaptureKind::Color, wgpu::TextureFormat::Bgra8Unorm)
+            | (CaptureKind::Color, wgpu::TextureFormat::Bgra8UnormSrgb) => {
+                let mut pixels = read_pixels(device, queue, texture, width, height, 4)?;
+                if is_bgra_format(format) {
+                    for p in pixels.chunks_exact_mut(4) {
+                        p.swap(0, 2);
+                    }
+                }
+                image::RgbaImage::from_raw(width, height, pixels)
+                    .context("Image is the wrong size")?
+                    .save(&path)?;
+                format!("{:?}, saved as is", format)
+            }
+            (kind, format) => bail!("there's no conversion from {:?} as {:?}", format, kind),
+        };
+        let note = if desc.sample_count > 1 {
+            format!("{} (resolved from {}x MSAA)", note, desc.sample_count)
+        } else {
+            note
+        };
+        Ok((path, note))
+    }
+}
+
+/// Turns a depth buffer value into 0 at `znear` and 1 at `zfar`,
+/// undoing the perspective divide. Done in f64, as `zfar` minus nearly
+/// all of itself loses most of an f32 close to the far plane.
+pub fn linearize_depth(depth: f32, znear: f32, zfar: f32) -> f32 {
+    let (depth, znear, zfar) = (depth as f64, znear as f64, zfar as f64);
+    let view_z = znear * zfar / (zfar - depth * (zfar - znear));
+    ((view_z - znear) / (zfar - znear)) as f32
+}
+
+fn resolve(device: &wgpu::Device, queue: &wgpu::Queue, texture: &Texture) -> Texture<'static> {
+    let desc = &texture.desc;
+    let resolved = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+        label: Some("FrameCapture::resolved"),
+        size: desc.size,
+        array_layer_count: 1,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: desc.format,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+    });
+    let mut encoder = device.create_command_encoder(
+        &wgpu::CommandEncoderDescriptor { label: Some("FrameCapture::resolve") }
+    );
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        color_attachments: &[
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &texture.view,
+                resolve_target: Some(&resolved.view),
+                load_op: wgpu::LoadOp::Load,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }
+        ],
+        depth_stencil_attachment: None,
+    });
+    queue.submit(&[encoder.finish()]);
+    resolved
+}
+
+/// Reads the first mip of `texture`, with the row padding taken out
+pub(crate) fn read_pixels(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    width: u32,
+    height: u32,
+    bytes_per_pixel: u32,
+) -> Result<Vec<u8>> {
+    let row_size = width * bytes_per_pixel;
+    let padded_row_size = (row_size + ROW_ALIGNMENT - 1) / ROW_ALIGNMENT * ROW_ALIGNMENT;
+    let buffer_size = (padded_row_size * height) as wgpu::BufferAddress;
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("FrameCapture::readback"),
+        size: buffer_size,
+        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+    });
+    let mut encoder = device.create_command_encoder(
+        &wgpu::CommandEncoderDescriptor { label: Some("FrameCapture::read_pixels") }
+    );
+    encoder.copy_texture_to_buffer(
+        wgpu::TextureCopyView {
+            texture,
+            mip_level: 0,
+            array_layer: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::BufferCopyView {
+            buffer: &buffer,
+            offset: 0,
+            bytes_per_row: padded_row_size,
+            rows_per_image: height,
+        },
+        wgpu::Extent3d { width, height, depth: 1 },
+    );
+    queue.submit(&[encoder.finish()]);
+
+    let request = buffer.map_read(0, buffer_size);
+    device.poll(wgpu::Maintain::Wait);
+    let mapping = futures::executor::block_on(request)
+        .map_err(|_| anyhow!("Unable to read back the texture"))?;
+    let mut pixels = Vec::with_capacity((row_size * height) as usize);
+    for row in mapping.as_slice().chunks_exact(padded_row_size as usize) {
+        pixels.extend_from_slice(&row[..row_size as usize]);
+    }
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn linearized_depth_spans_near_to_far() {
+        let (znear, zfar) = (0.1, 100.0);
+        assert!(linearize_depth(0.0, znear, zfar).abs() < 1e-6);
+        assert!((linearize_depth(1.0, znear, zfar) - 1.0).abs() < 1e-6);
+
+        // Something 10 units away. Most of the depth buffer's range is
+        // used up close to the camera.
+        let view_z: f32 = 10.0;
+        let depth = (zfar / (zfar - znear)) * (1.0 - znear / view_z);
+        let expected = (view_z - znear) / (zfar - znear);
+        assert!(depth > 0.99);
+        assert!((linearize_depth(depth, znear, zfar) - expected).abs() < 1e-3);
+    }
+}
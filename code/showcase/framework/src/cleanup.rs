@@ -0,0 +1,189 @@
+use cgmath::*;
+use std::collections::VecDeque;
+use crate::adjacency::Adjacency;
+
+/// Twice the area below which a triangle counts as degenerate
+const MIN_AREA: f32 = 1e-12;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub degenerate_removed: usize,
+    /// Triangles whose winding disagreed with the rest of their
+    /// connected piece of surface
+    pub inconsistent_winding: usize,
+    /// Whether `inconsistent_winding` triangles were actually flipped
+    pub winding_fixed: bool,
+}
+
+/**
+ * Removes triangles that can't be drawn: ones that use the same vertex
+ * twice, or whose corners are all on a line. Returns how many were
+ * removed.
+ */
+pub fn remove_degenerates(positions: &[Vector3<f32>], indices: &mut Vec<u32>) -> usize {
+    let before = indices.len() / 3;
+    let mut kept = Vec::with_capacity(indices.len());
+    for c in indices.chunks_exact(3) {
+        if c[0] == c[1] || c[1] == c[2] || c[0] == c[2] {
+            continue;
+        }
+        let p0 = positions[c[0] as usize];
+        let p1 = positions[c[1] as usize];
+        let p2 = positions[c[2] as usize];
+        if (p1 - p0).cross(p2 - p0).magnitude2() <= MIN_AREA * MIN_AREA {
+            continue;
+        }
+        kept.extend_from_slice(c);
+    }
+    *indices = kept;
+    before - indices.len() / 3
+}
+
+/**
+ * Finds triangles that face the opposite way to their neighbors. Each
+ * connected piece of the surface is flood filled from one triangle,
+ * and whichever winding most of the piece uses wins. Returns the
+ * triangles in the minority.
+ */
+pub fn find_inconsistent_winding(adjacency: &Adjacency) -> Vec<u32> {
+    let n = adjacency.num_triangles();
+    // Some(true) means "flipped relative to the triangle we started
+    // this piece from"
+    let mut flipped: Vec<Option<bool>> = vec![None; n];
+    let mut minority = Vec::new();
+    let mut queue = VecDeque::new();
+
+    for start in 0..n as u32 {
+        if flipped[start as usize].is_some() {
+            continue;
+        }
+        flipped[start as usize] = Some(false);
+        queue.push_back(start);
+        let mut component = Vec::new();
+
+        while let Some(t) = queue.pop_front() {
+            component.push(t);
+            let t_flipped = flipped[t as usize].unwrap();
+            let tri = adjacency.triangles[t as usize];
+            for (k, other) in adjacency.neighbors(t) {
+                if flipped[other as usize].is_some() {
+                    continue;
+                }
+                let (a, b) = (tri[k], tri[(k + 1) % 3]);
+                // Consistent neighbors walk the edge the other way
+                let same_direction = adjacency.has_directed_edge(other, a, b);
+                flipped[other as usize] = Some(t_flipped ^ same_direction);
+                queue.push_back(other);
+            }
+        }
+
+        let num_flipped = component.iter().filter(|&&t| flipped[t as usize] == Some(true)).count();
+        // Ties go to the starting triangle's winding
+        let minority_is_flipped = num_flipped * 2 <= component.len();
+        minority.extend(component.into_iter()
+            .filter(|&t| flipped[t as usize] == Some(minority_is_flipped)));
+    }
+
+    minority.sort_unstable();
+    minority
+}
+
+/// Reverses the winding of the given triangles
+pub fn flip_triangles(indices: &mut [u32], triangles: &[u32]) {
+    for &t in triangles {
+        indices.swap(t as usize * 3 + 1, t as usize * 3 + 2);
+    }
+}
+
+/// Everything above, in order. Logs what it did.
+pub fn cleanup(
+    name: &str,
+    positions: &[Vector3<f32>],
+    indices: &mut Vec<u32>,
+    fix_winding: bool,
+) -> CleanupReport {
+    let degenerate_removed = remove_degenerates(positions, indices);
+    let adjacency = Adjacency::new(positions, indices);
+    let minority = find_inconsistent_winding(&adjacency);
+    if fix_winding {
+        flip_triangles(indices, &minority);
+    }
+
+    let report = CleanupReport {
+        degenerate_removed,
+        inconsistent_winding: minority.len(),
+        winding_fixed: fix_winding,
+    };
+    if report.degenerate_removed > 0 || report.inconsistent_winding > 0 {
+        log::info!(
+            "{}: removed {} degenerate triangles, {} {} triangles with inconsistent winding",
+            name,
+            report.degenerate_removed,
+            if fix_winding { "flipped" } else { "found" },
+            report.inconsistent_winding,
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A 2x1 strip of quads in the xy plane:
+    ///
+    ///     3---4---5
+    ///     | \ | \ |
+    ///     0---1---2
+    fn strip() -> Vec<Vector3<f32>> {
+        vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(1.0, 1.0, 0.0),
+            Vector3::new(2.0, 1.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn removes_exactly_the_degenerates() {
+        let positions = strip();
+        let mut indices = vec![
+            0, 1, 4,
+            0, 0, 3, // repeated index
+            0, 1, 2, // zero area, all on y = 0
+            0, 4, 3,
+        ];
+        assert_eq!(remove_degenerates(&positions, &mut indices), 2);
+        assert_eq!(indices, vec![0, 1, 4, 0, 4, 3]);
+    }
+
+    #[test]
+    fn flips_the_odd_one_out() {
+        let positions = strip();
+        let mut indices = vec![
+            0, 1, 4,
+            0, 4, 3,
+            1, 5, 2, // backwards, should be 1, 2, 5
+            1, 5, 4,
+        ];
+        let report = cleanup("strip", &positions, &mut indices, true);
+        assert_eq!(report.degenerate_removed, 0);
+        assert_eq!(report.inconsistent_winding, 1);
+        assert_eq!(&indices[6..9], &[1, 2, 5]);
+        // Everything else is untouched
+        assert_eq!(&indices[..6], &[0, 1, 4, 0, 4, 3]);
+        assert_eq!(&indices[9..], &[1, 5, 4]);
+    }
+
+    #[test]
+    fn detect_only_leaves_indices_alone() {
+        let positions = strip();
+        let original = vec![0, 1, 4, 0, 3, 4];
+        let mut indices = original.clone();
+        let report = cleanup("strip", &positions, &mut indices, false);
+        assert_eq!(report.inconsistent_winding, 1);
+        assert_eq!(indices, original);
+    }
+}
@@ -0,0 +1,375 @@
+use anyhow::*;
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::instance::InstanceRaw;
+use crate::material_layout::MaterialLayout;
+use crate::model::{DrawModel, ModelPass, ModelVertex};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::shader_matrix::{to_shader_bytes, MatrixKind, ShaderMatrix};
+use crate::texture::Texture;
+use crate::Uniforms;
+
+/// More steps than this cost more than the contact line is worth
+pub const MAX_CONTACT_STEPS: u32 = 32;
+
+/**
+ * Knobs for [ContactShadows]. Distances are in world units, so scenes
+ * built at a very different scale than the demos will want their own.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContactShadowSettings {
+    pub enabled: bool,
+    /// Samples along each ray, up to [MAX_CONTACT_STEPS]
+    pub steps: u32,
+    /// How far towards the light the ray goes. Anything further away
+    /// is the shadow map's job.
+    pub max_distance: f32,
+    /// How far behind the depth buffer a sample can be and still count
+    /// as hidden. Too much and thin things cast shadows as if they were
+    /// solid all the way back.
+    pub thickness: f32,
+    /// Fraction of the screen over which the shadows fade out towards
+    /// the edges, where the depth buffer runs out
+    pub edge_fade: f32,
+}
+
+impl Default for ContactShadowSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            steps: 12,
+            max_distance: 0.3,
+            thickness: 0.1,
+            edge_fade: 0.05,
+        }
+    }
+}
+
+// Matches the ContactShadow block in the viewer's shader.frag
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ContactData {
+    view_proj: ShaderMatrix,
+    inv_view_proj: ShaderMatrix,
+    /// w is 1 when they're on
+    eye: [f32; 4],
+    /// Steps, max distance, thickness and edge fade
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for ContactData {}
+unsafe impl bytemuck::Zeroable for ContactData {}
+
+fn contact_data(
+    settings: &ContactShadowSettings,
+    view_proj: Matrix4<f32>,
+    inv_view_proj: Matrix4<f32>,
+    eye: Point3<f32>,
+) -> ContactData {
+    let steps = settings.steps.max(1).min(MAX_CONTACT_STEPS);
+    ContactData {
+        view_proj: to_shader_bytes(&view_proj, MatrixKind::Combined),
+        inv_view_proj: to_shader_bytes(&inv_view_proj, MatrixKind::Combined),
+        eye: [eye.x, eye.y, eye.z, if settings.enabled { 1.0 } else { 0.0 }],
+        params: [
+            steps as f32,
+            settings.max_distance.max(0.0),
+            settings.thickness.max(0.0),
+            // The shader's smoothstep wants a range that isn't empty
+            settings.edge_fade.max(1e-4),
+        ],
+    }
+}
+
+/**
+ * Short shadows where things touch, like the line under a cube sitting
+ * on the floor, which shadow maps are too coarse for. The scene gets
+ * drawn depth only into a prepass first. The main pass then marches a
+ * few steps from each fragment towards the light, projecting them into
+ * that depth, and darkens the direct light if one ends up just behind
+ * something.
+ *
+ * The prepass depth and [ContactShadows::update]'s camera go in the
+ * per-frame group, see [crate::FrameBinding::with_contact_shadows].
+ * Other views like the minimap still see the main camera's depth, so
+ * they get contact shadows wherever the main camera can see.
+ */
+pub struct ContactShadows {
+    depth: Texture<'static>,
+    sampler: wgpu::Sampler,
+    buffer: wgpu::Buffer,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl ContactShadows {
+    /**
+     * `view_layout` is the scene's view group, like for
+     * [crate::OverdrawCounter::new], so the usual [DrawModel] calls draw
+     * into the prepass.
+     */
+    pub fn new(
+        device: &wgpu::Device,
+        material_layout: &MaterialLayout,
+        view_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&material_layout.layout, view_layout],
+        });
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&layout)
+            .depth_format(Texture::DEPTH_FORMAT)
+            .vertex_buffer::<ModelVertex>()
+            .vertex_buffer::<InstanceRaw>()
+            .vertex_shader(include_bytes!("shaders/overdraw.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/depth_only.frag.spv"))
+            .build(device)?;
+
+        // The depth texture's own sampler is a comparison sampler, and
+        // the shader wants the depth itself
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let data = contact_data(&Default::default(), Matrix4::identity(), Matrix4::identity(), Point3::origin());
+        let buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[data]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+
+        Ok(Self {
+            depth: Self::create_depth(device, width, height),
+            sampler,
+            buffer,
+            pipeline,
+        })
+    }
+
+    fn create_depth(device: &wgpu::Device, width: u32, height: u32) -> Texture<'static> {
+        Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("ContactShadows::depth"),
+            size: wgpu::Extent3d { width: width.max(1), height: height.max(1), depth: 1 },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        })
+    }
+
+    /// The per-frame group points at the old depth texture afterwards,
+    /// so rebind it with [crate::FrameBinding::rebind]
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.depth = Self::create_depth(device, width, height);
+    }
+
+    /// Call this once the main camera's [Uniforms] are up to date.
+    /// `settings` usually comes from [crate::RenderSettings].
+    pub fn update(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        uniforms: &Uniforms,
+        settings: &ContactShadowSettings,
+    ) {
+        let data = contact_data(settings, uniforms.view_proj(), uniforms.inv_view_proj(), uniforms.view_position());
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[data]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<ContactData>() as _,
+        );
+    }
+
+    /**
+     * Clears the prepass depth and starts drawing into it. Draw the
+     * scene with the main camera's view group and the usual [DrawModel]
+     * calls. Skipping it when the settings have them off is fine, the
+     * shader doesn't look at the depth then.
+     */
+    pub fn begin_prepass<'b>(
+        &'b self,
+        encoder: &'b mut wgpu::CommandEncoder,
+        material_layout: &MaterialLayout,
+    ) -> ModelPass<'b> {
+        let mut pass = ModelPass::from(encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.depth.view,
+                depth_load_op: wgpu::LoadOp::Clear,
+                depth_store_op: wgpu::StoreOp::Store,
+                clear_depth: 1.0,
+                stencil_load_op: wgpu::LoadOp::Clear,
+                stencil_store_op: wgpu::StoreOp::Store,
+                clear_stencil: 0,
+            }),
+        }));
+        pass.set_model_pipeline(&self.pipeline, material_layout);
+        pass
+    }
+
+    pub(crate) fn depth_view(&self) -> &wgpu::TextureView {
+        &self.depth.view
+    }
+
+    pub(crate) fn sampler(&self) -> &wgpu::Sampler {
+        &self.sampler
+    }
+
+    pub(crate) fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub(crate) fn buffer_size() -> wgpu::BufferAddress {
+        std::mem::size_of::<ContactData>() as wgpu::BufferAddress
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::OPENGL_TO_WGPU_MATRIX;
+
+    fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+        let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// contact_shadow in common/contact_shadow.glsl without the
+    /// jitter, with `depth_at` for the prepass depth
+    fn contact_shadow(
+        data: &ContactData,
+        position: Point3<f32>,
+        light_position: Point3<f32>,
+        depth_at: impl Fn(Vector2<f32>) -> f32,
+    ) -> f32 {
+        if data.eye[3] == 0.0 {
+            return 1.0;
+        }
+        let view_proj = Matrix4::from(data.view_proj);
+        let inv_view_proj = Matrix4::from(data.inv_view_proj);
+        let eye = Point3::new(data.eye[0], data.eye[1], data.eye[2]);
+        let [steps, max_distance, thickness, edge_fade] = data.params;
+
+        let to_light = light_position - position;
+        let light_distance = to_light.magnitude();
+        let dir = to_light / light_distance.max(1e-4);
+        let ray_length = max_distance.min(light_distance);
+        let step_length = ray_length / steps;
+        for i in 0..steps as u32 {
+            let t = i as f32 * step_length;
+            let sample = position + dir * t;
+            let clip = view_proj * sample.to_homogeneous();
+            if clip.w <= 0.0 {
+                break;
+            }
+            let ndc = clip.truncate() / clip.w;
+            let uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+            if uv.x < 0.0 || uv.y < 0.0 || uv.x > 1.0 || uv.y > 1.0 || ndc.z > 1.0 {
+                break;
+            }
+            let depth = depth_at(uv);
+            if depth >= 1.0 {
+                continue;
+            }
+            let occluder = Point3::from_homogeneous(inv_view_proj * vec4(ndc.x, ndc.y, depth, 1.0));
+            let behind = eye.distance(sample) - eye.distance(occluder);
+            if behind > 1e-3 && behind < thickness {
+                let edge = uv.x.min(uv.y).min(1.0 - uv.x).min(1.0 - uv.y);
+                let fade = smoothstep(0.0, edge_fade, edge) * (1.0 - t / ray_length);
+                return 1.0 - fade;
+            }
+        }
+        1.0
+    }
+
+    #[test]
+    fn floor_next_to_a_cube_gets_darker() {
+        // Looking at the cube's +x side, with the light behind it
+        let eye = Point3::new(4.0, 2.0, 0.0);
+        let view_proj = OPENGL_TO_WGPU_MATRIX
+            * perspective(Deg(45.0), 1.0, 0.1, 100.0)
+            * Matrix4::look_at(eye, Point3::new(0.0, 0.5, 0.0), Vector3::unit_y());
+        let inv_view_proj = view_proj.invert().unwrap();
+        let light = Point3::new(-3.0, 3.0, 0.0);
+
+        // What the prepass wrote: the unit cube sitting on the floor,
+        // and nothing for the floor itself
+        let cube = (Point3::new(-0.5, 0.0, -0.5), Point3::new(0.5, 1.0, 0.5));
+        let depth_at = |uv: Vector2<f32>| {
+            let ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+            let near = Point3::from_homogeneous(inv_view_proj * vec4(ndc.x, ndc.y, 0.0, 1.0));
+            let far = Point3::from_homogeneous(inv_view_proj * vec4(ndc.x, ndc.y, 1.0, 1.0));
+            let dir = far - near;
+            let (mut enter, mut exit) = (0.0f32, 1.0f32);
+            for axis in 0..3 {
+                let a = (cube.0[axis] - near[axis]) / dir[axis];
+                let b = (cube.1[axis] - near[axis]) / dir[axis];
+                enter = enter.max(a.min(b));
+                exit = exit.min(a.max(b));
+            }
+            if enter > exit {
+                return 1.0;
+            }
+            let clip = view_proj * (near + dir * enter).to_homogeneous();
+            clip.z / clip.w
+        };
+
+        let data = contact_data(&ContactShadowSettings::default(), view_proj, inv_view_proj, eye);
+        // Just off the cube's side, the ray goes into it
+        let touching = contact_shadow(&data, Point3::new(0.6, 0.0, 0.0), light, depth_at);
+        assert!(touching < 0.9, "{}", touching);
+        // Further out it runs out of steps before reaching it
+        assert_eq!(contact_shadow(&data, Point3::new(2.0, 0.0, 0.0), light, depth_at), 1.0);
+        // The light's on the near side, so the ray goes away from it
+        assert_eq!(contact_shadow(&data, Point3::new(0.6, 0.0, 0.0), Point3::new(4.0, 3.0, 0.0), depth_at), 1.0);
+
+        // Too thin for anything to count as behind the cube
+        let thin = ContactShadowSettings { thickness: 0.001, ..Default::default() };
+        let data = contact_data(&thin, view_proj, inv_view_proj, eye);
+        assert_eq!(contact_shadow(&data, Point3::new(0.6, 0.0, 0.0), light, depth_at), 1.0);
+
+        let off = ContactShadowSettings { enabled: false, ..Default::default() };
+        let data = contact_data(&off, view_proj, inv_view_proj, eye);
+        assert_eq!(contact_shadow(&data, Point3::new(0.6, 0.0, 0.0), light, depth_at), 1.0);
+    }
+
+    #[test]
+    fn steps_and_distances_are_clamped() {
+        let eye = Point3::new(1.0, 2.0, 3.0);
+        let data = contact_data(&ContactShadowSettings::default(), Matrix4::identity(), Matrix4::identity(), eye);
+        assert_eq!(data.eye, [1.0, 2.0, 3.0, 1.0]);
+        assert_eq!(data.params[0], 12.0);
+
+        let silly = ContactShadowSettings {
+            enabled: false,
+            steps: 1000,
+            max_distance: -1.0,
+            thickness: -1.0,
+            edge_fade: 0.0,
+        };
+        let data = contact_data(&silly, Matrix4::identity(), Matrix4::identity(), eye);
+        assert_eq!(data.eye[3], 0.0);
+        assert_eq!(data.params[0], MAX_CONTACT_STEPS as f32);
+        assert_eq!(&data.params[1..3], &[0.0, 0.0]);
+        assert!(data.params[3] > 0.0);
+
+        let none = ContactShadowSettings { steps: 0, ..Default::default() };
+        assert_eq!(contact_data(&none, Matrix4::identity(), Matrix4::identity(), eye).params[0], 1.0);
+    }
+}
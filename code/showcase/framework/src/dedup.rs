@@ -0,0 +1,346 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use crate::buffer::ToRaw;
+use crate::instance::InstanceRaw;
+use crate::model::{MeshData, Model};
+
+/// How far a vertex can end up from where it should be, as a fraction
+/// of the mesh's radius, before a copy is kept as its own mesh
+pub const ALIGNMENT_TOLERANCE: f32 = 1e-3;
+
+/// UVs aren't transformed, so copies need the same ones
+const TEX_COORD_TOLERANCE: f32 = 1e-5;
+
+/// How finely the shape part of the fingerprint is rounded
+const SHAPE_QUANTUM: f32 = 1e-3;
+
+/// What [auto_instance] did, for the load log and [crate::ModelReport]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoInstanceReport {
+    pub meshes_before: usize,
+    pub meshes_after: usize,
+    /// Meshes that turned into a placement of another mesh
+    pub instanced: usize,
+    /// Meshes with the same fingerprint as another that didn't line up
+    /// with it closely enough, so they were kept separate
+    pub rejected: usize,
+}
+
+/**
+ * Everything about a mesh that doesn't change when it's moved, turned
+ * or scaled. Meshes are only lined up with each other when these
+ * match, which keeps the number of alignments down.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Fingerprint {
+    vertices: usize,
+    indices: usize,
+    material: usize,
+    has_tex_coords: bool,
+    /// Copies need the same triangles, not just the same number
+    index_hash: u64,
+    /// The furthest vertex from the centroid over the RMS distance.
+    /// Being a ratio it doesn't change with scale.
+    shape: i64,
+}
+
+impl Fingerprint {
+    fn new(mesh: &MeshData) -> Self {
+        let mut hasher = DefaultHasher::new();
+        mesh.indices.hash(&mut hasher);
+
+        let centroid = centroid(mesh);
+        let (mut max, mut sum) = (0.0f32, 0.0f32);
+        for v in &mesh.vertices {
+            let d2 = (v.position - centroid).magnitude2();
+            max = max.max(d2);
+            sum += d2;
+        }
+        let rms = (sum / mesh.vertices.len().max(1) as f32).sqrt();
+        let shape = if rms > 0.0 { max.sqrt() / rms } else { 0.0 };
+
+        Self {
+            vertices: mesh.vertices.len(),
+            indices: mesh.indices.len(),
+            material: mesh.material,
+            has_tex_coords: mesh.has_tex_coords,
+            index_hash: hasher.finish(),
+            shape: (shape / SHAPE_QUANTUM).round() as i64,
+        }
+    }
+}
+
+fn centroid(mesh: &MeshData) -> Vector3<f32> {
+    let sum = mesh.vertices.iter().fold(Vector3::zero(), |sum, v| sum + v.position);
+    sum / mesh.vertices.len().max(1) as f32
+}
+
+/**
+ * Works out the transform that takes `from` onto `to`, if there is one.
+ * Copies in an export keep their vertex order, so vertex `i` of one
+ * lines up with vertex `i` of the other and a least squares fit is
+ * enough. The fit allows any linear transform. It only gets accepted if
+ * every vertex lands within [ALIGNMENT_TOLERANCE], and it doesn't
+ * mirror the mesh, as that would flip the winding.
+ *
+ * Flat meshes don't have a unique fit, so they're always kept separate.
+ */
+pub fn align(from: &MeshData, to: &MeshData) -> Option<Matrix4<f32>> {
+    if from.vertices.len() != to.vertices.len() || from.vertices.is_empty() {
+        return None;
+    }
+    let tex_coords_match = from.vertices.iter().zip(&to.vertices).all(|(a, b)| {
+        (a.tex_coords - b.tex_coords).magnitude2() <= TEX_COORD_TOLERANCE * TEX_COORD_TOLERANCE
+    });
+    if !tex_coords_match {
+        return None;
+    }
+
+    // Solve linear * p = q for the centered points
+    let from_centroid = centroid(from);
+    let to_centroid = centroid(to);
+    let mut pq = Matrix3::zero();
+    let mut pp = Matrix3::zero();
+    let mut radius = 0.0f32;
+    for (a, b) in from.vertices.iter().zip(&to.vertices) {
+        let p = a.position - from_centroid;
+        let q = b.position - to_centroid;
+        pq += outer(q, p);
+        pp += outer(p, p);
+        radius = radius.max(p.magnitude());
+    }
+    if radius <= 0.0 {
+        return None;
+    }
+    // Relative to the mesh's size and vertex count, so small or dense
+    // meshes aren't called flat
+    let flatness = (pp / from.vertices.len() as f32).determinant() / radius.powi(6);
+    if flatness.abs() < 1e-9 {
+        return None;
+    }
+    let linear = pq * pp.invert()?;
+    if linear.determinant() <= 0.0 {
+        return None;
+    }
+    let translation = to_centroid - linear * from_centroid;
+
+    // The copy's radius, as the residual is measured where it ended up
+    let tolerance = ALIGNMENT_TOLERANCE * radius * linear.determinant().cbrt();
+    let fits = from.vertices.iter().zip(&to.vertices).all(|(a, b)| {
+        (linear * a.position + translation - b.position).magnitude() <= tolerance
+    });
+    if !fits {
+        return None;
+    }
+
+    let mut matrix = Matrix4::from(linear);
+    matrix.w = translation.extend(1.0);
+    Some(matrix)
+}
+
+fn outer(a: Vector3<f32>, b: Vector3<f32>) -> Matrix3<f32> {
+    // Column major, so column i is a * b[i]
+    Matrix3::from_cols(a * b.x, a * b.y, a * b.z)
+}
+
+/**
+ * Finds meshes that are copies of an earlier one with a transform
+ * baked into their vertices, and replaces each with a placement of the
+ * earlier mesh. The mesh that's kept stays where it was, so its first
+ * placement is the identity. Meshes that don't have any copies keep
+ * an empty [MeshData::placements].
+ */
+pub fn auto_instance(name: &str, meshes: Vec<MeshData>) -> (Vec<MeshData>, AutoInstanceReport) {
+    let mut report = AutoInstanceReport {
+        meshes_before: meshes.len(),
+        ..Default::default()
+    };
+
+    let mut kept: Vec<MeshData> = Vec::new();
+    let mut by_fingerprint: HashMap<Fingerprint, Vec<usize>> = HashMap::new();
+    for mesh in meshes {
+        let fingerprint = Fingerprint::new(&mesh);
+        let candidates = by_fingerprint.entry(fingerprint).or_default();
+        let found = candidates.iter()
+            .find_map(|&i| align(&kept[i], &mesh).map(|matrix| (i, matrix)));
+        match found {
+            Some((i, matrix)) => {
+                let canonical = &mut kept[i];
+                if canonical.placements.is_empty() {
+                    canonical.placements.push(Matrix4::identity());
+                }
+                canonical.placements.push(matrix);
+                report.instanced += 1;
+            }
+            None => {
+                if !candidates.is_empty() {
+                    report.rejected += 1;
+                }
+                candidates.push(kept.len());
+                kept.push(mesh);
+            }
+        }
+    }
+
+    report.meshes_after = kept.len();
+    if report.instanced > 0 || report.rejected > 0 {
+        log::info!(
+            "{}: {} meshes became {} ({} turned into instances, {} looked alike but didn't line up)",
+            name,
+            report.meshes_before,
+            report.meshes_after,
+            report.instanced,
+            report.rejected,
+        );
+    }
+    (kept, report)
+}
+
+/**
+ * Instance buffers for drawing a model with placements, one per mesh.
+ * Each holds every object instance times every placement of that
+ * mesh. Meshes without placements still get a buffer, holding just
+ * the object instances, so every mesh can be drawn the same way with
+ * [crate::DrawModel::draw_model_placed].
+ */
+pub struct PlacementBuffers {
+    buffers: Vec<(wgpu::Buffer, u32)>,
+    /// What the buffers were built from, to tell when they're out of date
+    objects: Vec<InstanceRaw>,
+}
+
+impl PlacementBuffers {
+    pub fn new<T: ToRaw<Output = InstanceRaw>>(
+        device: &wgpu::Device,
+        model: &Model,
+        objects: &[T],
+    ) -> Self {
+        let objects = objects.iter().map(ToRaw::to_raw).collect::<Vec<_>>();
+        let identity = [Matrix4::identity()];
+        let buffers = model.meshes.iter()
+            .map(|mesh| {
+                let placements = if mesh.placements.is_empty() {
+                    &identity[..]
+                } else {
+                    &mesh.placements[..]
+                };
+                let raw = objects.iter()
+                    .flat_map(|object| placements.iter().map(move |p| object.placed(p)))
+                    .collect::<Vec<_>>();
+                let buffer = device.create_buffer_with_data(
+                    bytemuck::cast_slice(&raw),
+                    wgpu::BufferUsage::VERTEX,
+                );
+                (buffer, raw.len() as u32)
+            })
+            .collect();
+        Self { buffers, objects }
+    }
+
+    /**
+     * Builds the buffers again if `objects` moved, changed or came and
+     * went since they were last built, and returns whether it did.
+     * Another model means new buffers rather than this.
+     */
+    pub fn update<T: ToRaw<Output = InstanceRaw>>(
+        &mut self,
+        device: &wgpu::Device,
+        model: &Model,
+        objects: &[T],
+    ) -> bool {
+        let raw = objects.iter().map(ToRaw::to_raw).collect::<Vec<_>>();
+        let unchanged = self.buffers.len() == model.meshes.len()
+            && bytemuck::cast_slice::<_, u8>(&raw) == bytemuck::cast_slice::<_, u8>(&self.objects);
+        if unchanged {
+            return false;
+        }
+        *self = Self::new(device, model, objects);
+        true
+    }
+
+    /// The instance buffer for `mesh`, and how many instances are in it
+    pub fn get(&self, mesh: usize) -> (&wgpu::Buffer, u32) {
+        let (buffer, count) = &self.buffers[mesh];
+        (buffer, *count)
+    }
+
+    /// How many instances get drawn across every mesh
+    pub fn instance_count(&self) -> u32 {
+        self.buffers.iter().map(|(_, count)| count).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bounds::Aabb;
+    use crate::inspect::AttributeSource;
+    use crate::model::ModelVertex;
+
+    fn tetrahedron(matrix: Matrix4<f32>) -> MeshData {
+        let corners = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 2.0, 0.0),
+            Point3::new(0.0, 0.0, 3.0),
+        ];
+        let vertices = corners.iter()
+            .enumerate()
+            .map(|(i, c)| ModelVertex {
+                position: matrix.transform_point(*c).to_vec(),
+                tex_coords: [i as f32 * 0.25, 0.0].into(),
+                normal: [0.0, 1.0, 0.0].into(),
+                tangent: [0.0; 4].into(),
+            })
+            .collect::<Vec<_>>();
+        MeshData {
+            name: "tetrahedron".to_string(),
+            aabb: Aabb::from_points(vertices.iter().map(|v| Point3::from_vec(v.position))),
+            vertices,
+            indices: vec![0, 2, 1, 0, 1, 3, 0, 3, 2, 1, 2, 3],
+            material: 0,
+            has_tex_coords: true,
+            normals: AttributeSource::File,
+            tangents: AttributeSource::Missing,
+            placements: Vec::new(),
+            lods: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn copies_collapse_into_placements() {
+        let moved = Matrix4::from_translation(vec3(5.0, 0.0, -2.0))
+            * Matrix4::from_angle_y(Deg(70.0))
+            * Matrix4::from_scale(2.0);
+        let meshes = vec![
+            tetrahedron(Matrix4::identity()),
+            tetrahedron(moved),
+            tetrahedron(Matrix4::from_translation(vec3(-1.0, 0.0, 0.0))),
+            // Looks the same to the fingerprint, but a mirrored copy
+            // would be drawn inside out
+            tetrahedron(Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0)),
+        ];
+
+        let (kept, report) = auto_instance("test", meshes);
+        assert_eq!(report, AutoInstanceReport {
+            meshes_before: 4,
+            meshes_after: 2,
+            instanced: 2,
+            rejected: 1,
+        });
+
+        let placements = &kept[0].placements;
+        assert_eq!(placements.len(), 3);
+        assert_eq!(placements[0], Matrix4::identity());
+        for (col, expected) in [placements[1].x, placements[1].y, placements[1].z, placements[1].w]
+            .iter()
+            .zip(&[moved.x, moved.y, moved.z, moved.w])
+        {
+            assert!((col - expected).magnitude() < 1e-4, "{:?} != {:?}", col, expected);
+        }
+        assert!(kept[1].placements.is_empty());
+    }
+}
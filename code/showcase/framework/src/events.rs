@@ -0,0 +1,173 @@
+use cgmath::*;
+use std::path::PathBuf;
+use crate::picking::InstanceHandle;
+
+/**
+ * Something that happened in a demo that other code might care about.
+ * All but [DemoEvent::ModelLoaded] are plain data, so emitting them never
+ * allocates.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum DemoEvent {
+    /// A click landed on an instance
+    ObjectPicked {
+        instance: InstanceHandle,
+        /// Index into the model's meshes, when the click hit one
+        /// rather than just the instance's box
+        mesh: Option<usize>,
+        world_pos: Point3<f32>,
+    },
+    SelectionChanged {
+        /// How many instances are selected now
+        selected: usize,
+    },
+    /// A model got swapped in. Loading allocates plenty anyway.
+    ModelLoaded { path: PathBuf },
+    LightMoved { position: Point3<f32> },
+}
+
+/// From [EventBus::subscribe], for [EventBus::unsubscribe]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SubscriberId(usize);
+
+/**
+ * Where a demo tells the rest of the world what happened, so code
+ * building on it doesn't have to patch the demo's internals. Events are
+ * queued as they're emitted and handed out once a frame by
+ * [EventBus::dispatch], in the order they were emitted.
+ *
+ * There are two ways to listen. Callbacks from [EventBus::subscribe]
+ * get called with each event during the dispatch. Code that would
+ * rather pull can read [EventBus::delivered] any time until the next
+ * dispatch. Both queues keep their memory between frames, so once
+ * they've grown to a frame's worth of events nothing more gets
+ * allocated.
+ */
+#[derive(Default)]
+pub struct EventBus {
+    pending: Vec<DemoEvent>,
+    delivered: Vec<DemoEvent>,
+    subscribers: Vec<(SubscriberId, Box<dyn FnMut(&DemoEvent)>)>,
+    next_id: usize,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `event` for the next [EventBus::dispatch]
+    pub fn emit(&mut self, event: DemoEvent) {
+        self.pending.push(event);
+    }
+
+    pub fn subscribe<F: FnMut(&DemoEvent) + 'static>(&mut self, callback: F) -> SubscriberId {
+        let id = SubscriberId(self.next_id);
+        self.next_id += 1;
+        self.subscribers.push((id, Box::new(callback)));
+        id
+    }
+
+    /// Returns whether `id` was still subscribed
+    pub fn unsubscribe(&mut self, id: SubscriberId) -> bool {
+        let before = self.subscribers.len();
+        self.subscribers.retain(|(s, _)| *s != id);
+        self.subscribers.len() != before
+    }
+
+    /**
+     * Hands everything emitted since the last dispatch to every
+     * subscriber, one event at a time in the order they were emitted,
+     * and subscribers in the order they subscribed. Call this once a
+     * frame. Last frame's [EventBus::delivered] are dropped.
+     */
+    pub fn dispatch(&mut self) {
+        self.delivered.clear();
+        std::mem::swap(&mut self.pending, &mut self.delivered);
+        for event in &self.delivered {
+            for (_, callback) in &mut self.subscribers {
+                callback(event);
+            }
+        }
+    }
+
+    /// What the last [EventBus::dispatch] handed out
+    pub fn delivered(&self) -> &[DemoEvent] {
+        &self.delivered
+    }
+
+    /// Emitted, but not dispatched yet
+    pub fn pending(&self) -> &[DemoEvent] {
+        &self.pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn light(x: f32) -> DemoEvent {
+        DemoEvent::LightMoved { position: Point3::new(x, 0.0, 0.0) }
+    }
+
+    #[test]
+    fn events_arrive_once_in_emission_order() {
+        let mut bus = EventBus::new();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let first = {
+            let seen = seen.clone();
+            bus.subscribe(move |event| seen.borrow_mut().push(("first", event.clone())))
+        };
+        {
+            let seen = seen.clone();
+            bus.subscribe(move |event| seen.borrow_mut().push(("second", event.clone())));
+        }
+
+        bus.emit(light(1.0));
+        bus.emit(DemoEvent::SelectionChanged { selected: 3 });
+        // Nothing until the frame's dispatch
+        assert!(seen.borrow().is_empty());
+        bus.dispatch();
+        assert_eq!(*seen.borrow(), vec![
+            ("first", light(1.0)),
+            ("second", light(1.0)),
+            ("first", DemoEvent::SelectionChanged { selected: 3 }),
+            ("second", DemoEvent::SelectionChanged { selected: 3 }),
+        ]);
+        assert_eq!(bus.delivered(), &[light(1.0), DemoEvent::SelectionChanged { selected: 3 }][..]);
+
+        // Already delivered, so not again
+        seen.borrow_mut().clear();
+        assert!(bus.unsubscribe(first));
+        assert!(!bus.unsubscribe(first));
+        bus.emit(light(2.0));
+        bus.dispatch();
+        assert_eq!(*seen.borrow(), vec![("second", light(2.0))]);
+        bus.dispatch();
+        assert!(bus.delivered().is_empty());
+    }
+
+    #[test]
+    fn steady_frames_dont_allocate() {
+        let mut bus = EventBus::new();
+        let frame = |bus: &mut EventBus| {
+            for i in 0..8 {
+                bus.emit(light(i as f32));
+            }
+            bus.dispatch();
+        };
+        // The two queues swap every frame, so both need to grow once
+        frame(&mut bus);
+        frame(&mut bus);
+        let capacity = (bus.pending.capacity(), bus.delivered.capacity());
+        let pointers = (bus.pending.as_ptr(), bus.delivered.as_ptr());
+        for _ in 0..100 {
+            frame(&mut bus);
+        }
+        // Even number of frames, so they're back where they started
+        assert_eq!((bus.pending.capacity(), bus.delivered.capacity()), capacity);
+        assert_eq!((bus.pending.as_ptr(), bus.delivered.as_ptr()), pointers);
+    }
+}
@@ -0,0 +1,344 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::contact_shadow::ContactShadows;
+use crate::light::{Light, LightData};
+use crate::palette::{DebugColor, Palette};
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FogDesc {
+    /// Linear RGB
+    pub color: [f32; 3],
+    /// Exponential fog. 0 turns it off.
+    pub density: f32,
+}
+
+impl Default for FogDesc {
+    fn default() -> Self {
+        Self {
+            color: [0.5, 0.6, 0.7],
+            density: 0.0,
+        }
+    }
+}
+
+/**
+ * Ambient light that depends on which way a surface faces: `sky` from
+ * straight above, `equator` from the side and `ground` from below,
+ * blended by the world space normal's y. Each is a fraction of the
+ * main light's color, so a sun going down takes the ambient with it.
+ *
+ * The default is the flat 0.1 the lighting shaders always had. Image
+ * based lighting, once there is any, should replace this rather than
+ * add to it.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AmbientGradient {
+    /// Linear RGB
+    pub sky: [f32; 3],
+    pub equator: [f32; 3],
+    pub ground: [f32; 3],
+}
+
+impl Default for AmbientGradient {
+    fn default() -> Self {
+        Self {
+            sky: [0.1; 3],
+            equator: [0.1; 3],
+            ground: [0.1; 3],
+        }
+    }
+}
+
+impl AmbientGradient {
+    /// What gradient_ambient in lighting.glsl gives for `normal`
+    pub fn at(&self, normal: Vector3<f32>) -> Vector3<f32> {
+        let y = normal.normalize().y;
+        let equator = Vector3::from(self.equator);
+        if y >= 0.0 {
+            equator.lerp(self.sky.into(), y)
+        } else {
+            equator.lerp(self.ground.into(), -y)
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct FrameData {
+    /// w is the density
+    fog: Vector4<f32>,
+    /// Seconds since the demo started, then the frame counter. z and w
+    /// are padding.
+    time: Vector4<f32>,
+    /// w is padding
+    ambient: Vector4<f32>,
+    /// What selected instances get tinted. w is how much.
+    selection: Vector4<f32>,
+    /// The [AmbientGradient]. w is padding on all three.
+    ambient_sky: Vector4<f32>,
+    ambient_equator: Vector4<f32>,
+    ambient_ground: Vector4<f32>,
+}
+
+unsafe impl bytemuck::Pod for FrameData {}
+unsafe impl bytemuck::Zeroable for FrameData {}
+
+/// How much of [FrameUniforms::selection_color] selected instances get
+const SELECTION_TINT: f32 = 0.4;
+
+fn frame_data(
+    fog: &FogDesc,
+    gradient: &AmbientGradient,
+    time: f32,
+    frame: u32,
+    ambient: [f32; 3],
+    selection: [f32; 3],
+) -> FrameData {
+    FrameData {
+        fog: Vector3::from(fog.color).extend(fog.density),
+        // Exact up to 2^24 frames, which is days at 60 fps
+        time: Vector4::new(time, (frame % (1 << 24)) as f32, 0.0, 0.0),
+        ambient: Vector3::from(ambient).extend(0.0),
+        selection: Vector3::from(selection).extend(SELECTION_TINT),
+        ambient_sky: Vector3::from(gradient.sky).extend(0.0),
+        ambient_equator: Vector3::from(gradient.equator).extend(0.0),
+        ambient_ground: Vector3::from(gradient.ground).extend(0.0),
+    }
+}
+
+/**
+ * Scene data that's the same for every view of a frame. See
+ * [FrameBinding].
+ */
+pub struct FrameUniforms {
+    pub fog: FogDesc,
+    pub ambient_gradient: AmbientGradient,
+    pub time: f32,
+    /**
+     * Goes up by one each frame, for shaders that want different noise
+     * each frame, like interleaved_gradient_noise in common/noise.glsl.
+     * Anything that accumulates frames should set this to how many it
+     * has so far instead, so the noise lines up with what it's
+     * averaging.
+     */
+    pub frame: u32,
+    /// Ambient light on top of what the main light gives, like from
+    /// [crate::SunState::night_ambient]. Linear RGB.
+    pub ambient: [f32; 3],
+    /// Linear RGB, from [crate::DebugColor::Selection]
+    pub selection_color: [f32; 3],
+    buffer: wgpu::Buffer,
+}
+
+impl FrameUniforms {
+    pub fn new(device: &wgpu::Device, fog: FogDesc, ambient_gradient: AmbientGradient) -> Self {
+        let time = 0.0;
+        let frame = 0;
+        let ambient = [0.0; 3];
+        let selection_color = Palette::default().color(DebugColor::Selection).into();
+        let buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[frame_data(&fog, &ambient_gradient, time, frame, ambient, selection_color)]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        Self { fog, ambient_gradient, time, frame, ambient, selection_color, buffer }
+    }
+
+    /// Call this after changing any of the fields
+    pub fn update_buffer(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[frame_data(
+                &self.fog,
+                &self.ambient_gradient,
+                self.time,
+                self.frame,
+                self.ambient,
+                self.selection_color,
+            )]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<FrameData>() as _,
+        );
+    }
+}
+
+/**
+ * The per-frame group, which models use as group 2. Binding 0 is the
+ * [Light] and binding 1 is the [FrameUniforms]. With
+ * [FrameBinding::with_contact_shadows], bindings 2 to 4 are the
+ * [ContactShadows] depth, its sampler and its settings.
+ *
+ * Unlike the per-view group this doesn't change between views, so it
+ * only needs setting once per pass. Depth only passes (like shadows)
+ * can leave it out of their layout entirely.
+ */
+pub struct FrameBinding {
+    pub layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl FrameBinding {
+    pub fn new(device: &wgpu::Device, light: &Light, frame: &FrameUniforms) -> Self {
+        let layout = Self::create_layout(device, false);
+        let bind_group = Self::create_bind_group(device, &layout, light, frame, None);
+        Self { layout, bind_group }
+    }
+
+    pub fn with_contact_shadows(
+        device: &wgpu::Device,
+        light: &Light,
+        frame: &FrameUniforms,
+        contact: &ContactShadows,
+    ) -> Self {
+        let layout = Self::create_layout(device, true);
+        let bind_group = Self::create_bind_group(device, &layout, light, frame, Some(contact));
+        Self { layout, bind_group }
+    }
+
+    /**
+     * Makes a new bind group with the same layout, so pipelines made
+     * with [FrameBinding::layout] keep working. Needed after
+     * [ContactShadows::resize]. `contact` has to be there if and only
+     * if this was made with [FrameBinding::with_contact_shadows].
+     */
+    pub fn rebind(
+        &mut self,
+        device: &wgpu::Device,
+        light: &Light,
+        frame: &FrameUniforms,
+        contact: Option<&ContactShadows>,
+    ) {
+        self.bind_group = Self::create_bind_group(device, &self.layout, light, frame, contact);
+    }
+
+    fn create_layout(device: &wgpu::Device, contact_shadows: bool) -> wgpu::BindGroupLayout {
+        let mut bindings = vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+            },
+        ];
+        if contact_shadows {
+            bindings.extend_from_slice(&[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        component_type: wgpu::TextureComponentType::Float,
+                        dimension: wgpu::TextureViewDimension::D2,
+                    },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+            ]);
+        }
+        device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &bindings,
+                label: Some("FrameBinding::layout"),
+            }
+        )
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        light: &Light,
+        frame: &FrameUniforms,
+        contact: Option<&ContactShadows>,
+    ) -> wgpu::BindGroup {
+        let mut bindings = vec![
+            wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &light.buffer,
+                    range: 0..std::mem::size_of::<LightData>() as wgpu::BufferAddress,
+                },
+            },
+            wgpu::Binding {
+                binding: 1,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &frame.buffer,
+                    range: 0..std::mem::size_of::<FrameData>() as wgpu::BufferAddress,
+                },
+            },
+        ];
+        if let Some(contact) = contact {
+            bindings.extend(vec![
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(contact.depth_view()),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(contact.sampler()),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: contact.buffer(),
+                        range: 0..ContactShadows::buffer_size(),
+                    },
+                },
+            ]);
+        }
+        device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                bindings: &bindings,
+                label: Some("FrameBinding::bind_group"),
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ambient_gradient_blends_by_normal() {
+        // The default has to light things the way the flat ambient did
+        let flat = AmbientGradient::default();
+        for &normal in &[Vector3::unit_y(), Vector3::unit_x(), -Vector3::unit_y(), Vector3::new(1.0, -2.0, 3.0)] {
+            assert!((flat.at(normal) - Vector3::new(0.1, 0.1, 0.1)).magnitude() < 1e-6);
+        }
+
+        let gradient = AmbientGradient {
+            sky: [0.2, 0.3, 0.5],
+            equator: [0.1, 0.1, 0.1],
+            ground: [0.1, 0.06, 0.02],
+        };
+        for &(normal, expected) in &[
+            (Vector3::unit_y(), gradient.sky),
+            (Vector3::unit_z(), gradient.equator),
+            (-Vector3::unit_y() * 3.0, gradient.ground),
+        ] {
+            let got = gradient.at(normal);
+            assert!((got - Vector3::from(expected)).magnitude() < 1e-6, "{:?} gave {:?}", normal, got);
+        }
+        let halfway = gradient.at(Vector3::new(1.0, 1.0, 0.0));
+        assert!(halfway.z > 0.1 && halfway.z < 0.5, "{:?}", halfway);
+    }
+}
@@ -0,0 +1,602 @@
+use anyhow::*;
+use cgmath::*;
+use std::mem;
+use crate::bounds::Aabb;
+use crate::instance::InstanceRaw;
+use crate::model::Model;
+use crate::pipeline::create_shader_module;
+use crate::shader_matrix::*;
+use crate::texture::Texture;
+
+const HIZ_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+/// DrawIndexedIndirect is 5 u32s
+const ARGS_SIZE: wgpu::BufferAddress = 5 * 4;
+
+const FLAG_HAS_HISTORY: u32 = 1;
+const FLAG_REVERSED_Z: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct LevelParams {
+    src_size: [i32; 2],
+    dst_size: [i32; 2],
+    scale: i32,
+    reversed_z: u32,
+}
+
+unsafe impl bytemuck::Pod for LevelParams {}
+unsafe impl bytemuck::Zeroable for LevelParams {}
+
+struct Level {
+    bind_group: wgpu::BindGroup,
+    width: u32,
+    height: u32,
+    // The bind group needs these to stick around
+    _params: wgpu::Buffer,
+    _src_view: Option<wgpu::TextureView>,
+    _dst_view: wgpu::TextureView,
+}
+
+/**
+ * A mip chain where each texel holds the furthest depth of the pixels
+ * under it. If something's nearest point is behind that, it's hidden.
+ *
+ * The pyramid is built from the depth buffer at the end of a frame and
+ * used to cull the next one, so there's no need for a depth prepass.
+ * Recreate it when the depth buffer gets resized.
+ */
+pub struct HiZPyramid {
+    _texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    pub width: u32,
+    pub height: u32,
+    pub mip_level_count: u32,
+    pub reversed_z: bool,
+    pipeline: wgpu::ComputePipeline,
+    levels: Vec<Level>,
+    /// The camera the pyramid was last built with
+    view_proj: Matrix4<f32>,
+    has_history: bool,
+}
+
+impl HiZPyramid {
+    pub fn new(device: &wgpu::Device, depth: &Texture, reversed_z: bool) -> Result<Self> {
+        let width = depth.desc.size.width.max(1);
+        let height = depth.desc.size.height.max(1);
+        let mip_level_count = 32 - width.max(height).leading_zeros();
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("HiZPyramid::texture"),
+            size: wgpu::Extent3d { width, height, depth: 1 },
+            array_layer_count: 1,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HIZ_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::STORAGE,
+        });
+        let view = texture.create_default_view();
+        debug_assert_eq!(mip_chain(width, height).len(), mip_level_count as usize);
+        // We only ever use texelFetch, but GLSL still wants a sampler.
+        // The depth texture's own sampler is a comparison sampler, which
+        // doesn't work here.
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        component_type: wgpu::TextureComponentType::Float,
+                        dimension: wgpu::TextureViewDimension::D2,
+                    },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                        format: HIZ_FORMAT,
+                        readonly: false,
+                    },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+            ],
+            label: Some("HiZPyramid::layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+        });
+        let module = create_shader_module(device, include_bytes!("shaders/hiz.comp.spv"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            layout: &pipeline_layout,
+            compute_stage: wgpu::ProgrammableStageDescriptor {
+                module: &module,
+                entry_point: "main",
+            },
+        });
+
+        let mip_view = |level: u32| texture.create_view(&wgpu::TextureViewDescriptor {
+            format: HIZ_FORMAT,
+            dimension: wgpu::TextureViewDimension::D2,
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: level,
+            level_count: 1,
+            base_array_layer: 0,
+            array_layer_count: 1,
+        });
+
+        let mut levels = Vec::with_capacity(mip_level_count as usize);
+        let (mut src_width, mut src_height) = (width, height);
+        for (level, (dst_width, dst_height)) in (0..).zip(mip_chain(width, height)) {
+            // Level 0 is a straight copy of the depth buffer
+            let scale = if level == 0 { 1 } else { 2 };
+            let params = device.create_buffer_with_data(
+                bytemuck::cast_slice(&[LevelParams {
+                    src_size: [src_width as i32, src_height as i32],
+                    dst_size: [dst_width as i32, dst_height as i32],
+                    scale,
+                    reversed_z: reversed_z as u32,
+                }]),
+                wgpu::BufferUsage::UNIFORM,
+            );
+            let src_view = if level == 0 { None } else { Some(mip_view(level - 1)) };
+            let dst_view = mip_view(level);
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(
+                            src_view.as_ref().unwrap_or(&depth.view),
+                        ),
+                    },
+                    wgpu::Binding {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                    wgpu::Binding {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&dst_view),
+                    },
+                    wgpu::Binding {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &params,
+                            range: 0..mem::size_of::<LevelParams>() as wgpu::BufferAddress,
+                        },
+                    },
+                ],
+                label: Some("HiZPyramid::level"),
+            });
+            levels.push(Level {
+                bind_group,
+                width: dst_width,
+                height: dst_height,
+                _params: params,
+                _src_view: src_view,
+                _dst_view: dst_view,
+            });
+            src_width = dst_width;
+            src_height = dst_height;
+        }
+
+        Ok(Self {
+            _texture: texture,
+            view,
+            sampler,
+            width,
+            height,
+            mip_level_count,
+            reversed_z,
+            pipeline,
+            levels,
+            view_proj: Matrix4::identity(),
+            has_history: false,
+        })
+    }
+
+    /// Builds the pyramid from the depth buffer. Call this after the
+    /// main pass with the `view_proj` that pass was drawn with.
+    pub fn build(&mut self, encoder: &mut wgpu::CommandEncoder, view_proj: Matrix4<f32>) {
+        // One pass per level, so each level is finished being written
+        // before the next one reads it
+        for level in &self.levels {
+            let mut pass = encoder.begin_compute_pass();
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &level.bind_group, &[]);
+            pass.dispatch((level.width + 7) / 8, (level.height + 7) / 8, 1);
+        }
+        self.view_proj = view_proj;
+        self.has_history = true;
+    }
+
+    /// Forget the last frame, for when it doesn't match what's about to
+    /// be drawn (e.g. culling was off for a while). Until the next
+    /// [HiZPyramid::build] only frustum culling happens.
+    pub fn invalidate(&mut self) {
+        self.has_history = false;
+    }
+
+    /// What instances get tested with. The pyramid only means anything
+    /// seen from the camera it was built with, but without one there's
+    /// only frustum culling, and that wants this frame's camera.
+    fn cull_view_proj(&self, view_proj: Matrix4<f32>) -> Matrix4<f32> {
+        if self.has_history {
+            self.view_proj
+        } else {
+            view_proj
+        }
+    }
+}
+
+/// The size of every level of a pyramid over a `width` by `height`
+/// depth buffer, down to 1x1
+fn mip_chain(width: u32, height: u32) -> Vec<(u32, u32)> {
+    let mut levels = vec![(width.max(1), height.max(1))];
+    while let Some(&(w, h)) = levels.last().filter(|&&(w, h)| w > 1 || h > 1) {
+        levels.push(((w / 2).max(1), (h / 2).max(1)));
+    }
+    levels
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CullParams {
+    view_proj: ShaderMatrix,
+    aabb_min: Vector4<f32>,
+    aabb_max: Vector4<f32>,
+    counts: [u32; 4],
+    viewport: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for CullParams {}
+unsafe impl bytemuck::Zeroable for CullParams {}
+
+/**
+ * The compute pipeline that tests instances against a [HiZPyramid].
+ * One of these can be shared by every [CullBatch].
+ */
+pub struct HiZCuller {
+    pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+    /// How much closer than the pyramid something has to be to count
+    /// as visible. This hides the one frame of lag in the pyramid.
+    pub depth_epsilon: f32,
+}
+
+impl HiZCuller {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let storage = |binding, readonly| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::COMPUTE,
+            ty: wgpu::BindingType::StorageBuffer { dynamic: false, readonly },
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        component_type: wgpu::TextureComponentType::Float,
+                        dimension: wgpu::TextureViewDimension::D2,
+                    },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+                storage(3, true),
+                storage(4, false),
+                storage(5, false),
+            ],
+            label: Some("HiZCuller::layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+        });
+        let module = create_shader_module(device, include_bytes!("shaders/hiz_cull.comp.spv"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            layout: &pipeline_layout,
+            compute_stage: wgpu::ProgrammableStageDescriptor {
+                module: &module,
+                entry_point: "main",
+            },
+        });
+        Ok(Self { pipeline, layout, depth_epsilon: 0.0005 })
+    }
+
+    /**
+     * Culls the first `instance_count` instances of `batch`, from a
+     * camera at `view_proj`. Afterwards `batch.instance_buffer` holds
+     * the survivors and `batch.indirect_buffer` has the draw arguments
+     * for every mesh.
+     */
+    pub fn cull(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        batch: &CullBatch,
+        pyramid: &HiZPyramid,
+        view_proj: Matrix4<f32>,
+        instance_count: usize,
+    ) {
+        let bind_group = match &batch.bind_group {
+            Some(bind_group) => bind_group,
+            None => return,
+        };
+        let instance_count = instance_count.min(batch.bound_instances);
+
+        let mut flags = 0;
+        if pyramid.has_history { flags |= FLAG_HAS_HISTORY; }
+        if pyramid.reversed_z { flags |= FLAG_REVERSED_Z; }
+        let params = CullParams {
+            view_proj: to_shader_bytes(&pyramid.cull_view_proj(view_proj), MatrixKind::Combined),
+            aabb_min: batch.aabb.min.to_homogeneous(),
+            aabb_max: batch.aabb.max.to_homogeneous(),
+            counts: [
+                instance_count as u32,
+                batch.index_counts.len() as u32,
+                pyramid.mip_level_count,
+                flags,
+            ],
+            viewport: [pyramid.width as f32, pyramid.height as f32, self.depth_epsilon, 0.0],
+        };
+        let staging_params = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[params]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_params,
+            0,
+            &batch.params_buffer,
+            0,
+            mem::size_of::<CullParams>() as _,
+        );
+
+        // The shader only ever adds to instance_count, so it needs
+        // zeroing every frame
+        let args = batch.index_counts.iter()
+            .flat_map(|&count| vec![count, 0, 0, 0, 0])
+            .collect::<Vec<u32>>();
+        let staging_args = device.create_buffer_with_data(
+            bytemuck::cast_slice(&args),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_args,
+            0,
+            &batch.indirect_buffer,
+            0,
+            (args.len() * 4) as _,
+        );
+
+        if instance_count == 0 {
+            return;
+        }
+        let mut pass = encoder.begin_compute_pass();
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.dispatch((instance_count as u32 + 63) / 64, 1, 1);
+    }
+}
+
+/**
+ * The buffers for culling the instances of one model.
+ */
+pub struct CullBatch {
+    aabb: Aabb,
+    index_counts: Vec<u32>,
+    capacity: usize,
+    /// How many instances the buffer [CullBatch::bind] got can hold
+    bound_instances: usize,
+    params_buffer: wgpu::Buffer,
+    /// One set of DrawIndexedIndirect arguments per mesh
+    pub indirect_buffer: wgpu::Buffer,
+    /// The instances that survived, laid out like [InstanceRaw]
+    pub instance_buffer: wgpu::Buffer,
+    bind_group: Option<wgpu::BindGroup>,
+}
+
+impl CullBatch {
+    pub fn new(device: &wgpu::Device, model: &Model, capacity: usize) -> Self {
+        // The shader copies instances as 33 words
+        debug_assert_eq!(mem::size_of::<InstanceRaw>(), 33 * 4);
+
+        let index_counts = model.meshes.iter().map(|m| m.num_elements).collect::<Vec<_>>();
+        let capacity = capacity.max(1);
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CullBatch::params_buffer"),
+            size: mem::size_of::<CullParams>() as _,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let indirect_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CullBatch::indirect_buffer"),
+            size: ARGS_SIZE * index_counts.len().max(1) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::INDIRECT
+                | wgpu::BufferUsage::STORAGE
+                | wgpu::BufferUsage::COPY_DST
+                | wgpu::BufferUsage::COPY_SRC,
+        });
+        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CullBatch::instance_buffer"),
+            size: (mem::size_of::<InstanceRaw>() * capacity) as _,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::STORAGE,
+        });
+
+        Self {
+            aabb: model.aabb(),
+            index_counts,
+            capacity,
+            bound_instances: 0,
+            params_buffer,
+            indirect_buffer,
+            instance_buffer,
+            bind_group: None,
+        }
+    }
+
+    /**
+     * Points the batch at a pyramid and an instance buffer that's
+     * `instances_len` bytes long. Call this again whenever either is
+     * recreated. `instances` needs `STORAGE` usage. Only as many
+     * instances as fit in both it and the capacity the batch was made
+     * with get culled.
+     *
+     * An empty buffer keeps whatever was bound before, as wgpu won't
+     * bind nothing, and there's nothing to cull anyway.
+     */
+    pub fn bind(
+        &mut self,
+        device: &wgpu::Device,
+        culler: &HiZCuller,
+        pyramid: &HiZPyramid,
+        instances: &wgpu::Buffer,
+        instances_len: wgpu::BufferAddress,
+    ) {
+        let bound_instances = bound_instances(instances_len, self.capacity);
+        if bound_instances == 0 {
+            self.bound_instances = 0;
+            return;
+        }
+        self.bound_instances = bound_instances;
+        let instances_size = (mem::size_of::<InstanceRaw>() * bound_instances) as wgpu::BufferAddress;
+        self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &culler.layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.params_buffer,
+                        range: 0..mem::size_of::<CullParams>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&pyramid.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&pyramid.sampler),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: instances,
+                        range: 0..instances_size,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.instance_buffer,
+                        range: 0..instances_size,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.indirect_buffer,
+                        range: 0..ARGS_SIZE * self.index_counts.len().max(1) as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("CullBatch::bind_group"),
+        }));
+    }
+
+    /// How many instances get culled, at most
+    pub fn bound_instances(&self) -> usize {
+        self.bound_instances
+    }
+
+    /// Where mesh `mesh`'s draw arguments are in [CullBatch::indirect_buffer]
+    pub fn indirect_offset(mesh: usize) -> wgpu::BufferAddress {
+        mesh as wgpu::BufferAddress * ARGS_SIZE
+    }
+
+    /// Reads back how many instances survived the last cull. This
+    /// stalls until the GPU catches up, so it's only for debugging.
+    pub fn read_visible(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<u32> {
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("CullBatch::readback"),
+            size: 4,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        });
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("CullBatch::read_visible") }
+        );
+        // instance_count is the second word
+        encoder.copy_buffer_to_buffer(&self.indirect_buffer, 4, &readback, 0, 4);
+        queue.submit(&[encoder.finish()]);
+
+        let request = readback.map_read(0, 4);
+        device.poll(wgpu::Maintain::Wait);
+        let mapping = futures::executor::block_on(request)
+            .map_err(|_| anyhow!("Unable to read back the culling results"))?;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&mapping.as_slice()[..4]);
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+/// Whole instances in `len` bytes, up to `capacity`
+fn bound_instances(len: wgpu::BufferAddress, capacity: usize) -> usize {
+    ((len / mem::size_of::<InstanceRaw>() as wgpu::BufferAddress) as usize).min(capacity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pyramids_go_down_to_one_texel() {
+        let chain = mip_chain(1280, 720);
+        assert_eq!(chain.len(), (32 - 1280u32.leading_zeros()) as usize);
+        assert_eq!(&chain[..3], &[(1280, 720), (640, 360), (320, 180)]);
+        // The short side bottoms out first and stays there
+        assert_eq!(&chain[9..], &[(2, 1), (1, 1)]);
+        assert_eq!(mip_chain(1, 1), vec![(1, 1)]);
+        assert_eq!(mip_chain(0, 3), vec![(1, 3), (1, 1)]);
+    }
+
+    #[test]
+    fn batches_only_cull_what_the_buffer_holds() {
+        let size = mem::size_of::<InstanceRaw>() as wgpu::BufferAddress;
+        // Shrunk by deleting instances
+        assert_eq!(bound_instances(size * 3, 10), 3);
+        // A buffer bigger than the batch was made for
+        assert_eq!(bound_instances(size * 20, 10), 10);
+        assert_eq!(bound_instances(size - 1, 10), 0);
+    }
+}
@@ -0,0 +1,205 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use crate::model::ModelVertex;
+
+/// Which way is up in the file. Everything gets turned so that +Y is up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpAxis {
+    Y,
+    Z,
+    NegativeY,
+    NegativeZ,
+}
+
+impl UpAxis {
+    /// Rotates the file's up onto +Y. These are all rotations, so the
+    /// handedness of the model doesn't change.
+    fn rotation(self) -> Matrix4<f32> {
+        match self {
+            UpAxis::Y => Matrix4::identity(),
+            UpAxis::Z => Matrix4::from_angle_x(Deg(-90.0)),
+            UpAxis::NegativeY => Matrix4::from_angle_x(Deg(180.0)),
+            UpAxis::NegativeZ => Matrix4::from_angle_x(Deg(90.0)),
+        }
+    }
+}
+
+/// What one unit in the file is. Everything gets converted to meters.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Units {
+    Meters,
+    Centimeters,
+    Millimeters,
+    Inches,
+    /// How many meters one unit is
+    Custom(f32),
+}
+
+impl Units {
+    pub fn to_meters(self) -> f32 {
+        match self {
+            Units::Meters => 1.0,
+            Units::Centimeters => 0.01,
+            Units::Millimeters => 0.001,
+            Units::Inches => 0.0254,
+            Units::Custom(scale) => scale,
+        }
+    }
+}
+
+/**
+ * Puts a model into the framework's Y up, meters convention while it's
+ * being loaded. Because the vertices themselves get changed, the
+ * [crate::Aabb], picking and anything else on the CPU side see the
+ * same thing that gets drawn.
+ *
+ * `pre_transform` goes first, then the unit conversion, then the
+ * rotation for `up_axis`.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportTransform {
+    pub up_axis: UpAxis,
+    pub units: Units,
+    /// Column major, like `cgmath::Matrix4::from`
+    pub pre_transform: Option<[[f32; 4]; 4]>,
+}
+
+impl Default for ImportTransform {
+    fn default() -> Self {
+        Self {
+            up_axis: UpAxis::Y,
+            units: Units::Meters,
+            pre_transform: None,
+        }
+    }
+}
+
+impl ImportTransform {
+    /**
+     * What a file's format says. glTF is always Y up in meters. OBJ
+     * and STL don't say either way, so they get `None` and have to be
+     * set per model.
+     */
+    pub fn hint_for<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_string_lossy().to_lowercase();
+        match ext.as_str() {
+            "gltf" | "glb" => Some(Self::default()),
+            _ => None,
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        let pre = self.pre_transform.map(Matrix4::from).unwrap_or_else(Matrix4::identity);
+        self.up_axis.rotation() * Matrix4::from_scale(self.units.to_meters()) * pre
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.matrix() == Matrix4::identity()
+    }
+
+    /**
+     * Transforms `vertices` in place. Normals go through the inverse
+     * transpose so they stay perpendicular to the surface when the
+     * scale isn't uniform. If the transform mirrors the model, the
+     * triangles in `indices` get flipped so they still face outwards.
+     */
+    pub fn apply(&self, vertices: &mut [ModelVertex], indices: &mut [u32]) {
+        if self.is_identity() {
+            return;
+        }
+        let matrix = self.matrix();
+        let linear = Matrix3::from_cols(matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate());
+        let normal_matrix = match linear.invert() {
+            Some(inverse) => inverse.transpose(),
+            None => {
+                log::error!("The import transform squashes the model flat, so it's being ignored");
+                return;
+            }
+        };
+        let mirrored = linear.determinant() < 0.0;
+        let unit = |v: Vector3<f32>| if v.magnitude2() > 0.0 { v.normalize() } else { v };
+
+        for v in vertices.iter_mut() {
+            v.position = matrix.transform_point(Point3::from_vec(v.position)).to_vec();
+            v.normal = unit(normal_matrix * v.normal);
+            let tangent = unit(linear * v.tangent.truncate());
+            let handedness = if mirrored { -v.tangent.w } else { v.tangent.w };
+            v.tangent = tangent.extend(handedness);
+        }
+        if mirrored {
+            for triangle in indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vertex(position: [f32; 3], normal: [f32; 3]) -> ModelVertex {
+        ModelVertex {
+            position: position.into(),
+            tex_coords: Vector2::zero(),
+            normal: normal.into(),
+            tangent: Vector4::zero(),
+        }
+    }
+
+    #[test]
+    fn z_up_centimeters_matches_y_up_meters() {
+        // A slanted triangle facing up and towards +z, in meters with
+        // Y up
+        let y_up = vec![
+            vertex([0.0, 0.0, 0.0], [0.0, 0.6, 0.8]),
+            vertex([1.0, 0.0, 0.0], [0.0, 0.6, 0.8]),
+            vertex([0.0, 0.8, -0.6], [0.0, 0.6, 0.8]),
+        ];
+        // The same triangle exported Z up in centimeters, where Y up's
+        // (x, y, z) becomes (x, -z, y)
+        let mut z_up = y_up.iter()
+            .map(|v| vertex(
+                [v.position.x * 100.0, -v.position.z * 100.0, v.position.y * 100.0],
+                [v.normal.x, -v.normal.z, v.normal.y],
+            ))
+            .collect::<Vec<_>>();
+        let mut indices = vec![0, 1, 2];
+
+        let transform = ImportTransform {
+            up_axis: UpAxis::Z,
+            units: Units::Centimeters,
+            pre_transform: None,
+        };
+        transform.apply(&mut z_up, &mut indices);
+
+        for (a, b) in y_up.iter().zip(&z_up) {
+            assert!((a.position - b.position).magnitude() < 1e-5, "{:?} {:?}", a, b);
+            assert!((a.normal - b.normal).magnitude() < 1e-5, "{:?} {:?}", a, b);
+        }
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn mirroring_keeps_triangles_facing_out() {
+        let mut vertices = vec![
+            vertex([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            vertex([1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            vertex([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ];
+        let mut indices = vec![0, 1, 2];
+        let flip_x = Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0);
+        let transform = ImportTransform {
+            pre_transform: Some(flip_x.into()),
+            ..Default::default()
+        };
+        transform.apply(&mut vertices, &mut indices);
+
+        assert_eq!(indices, vec![0, 2, 1]);
+        let p = |i: u32| vertices[i as usize].position;
+        let face_normal = (p(indices[1]) - p(indices[0])).cross(p(indices[2]) - p(indices[0]));
+        assert!(face_normal.dot(vertices[0].normal) > 0.0);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use winit::event::*;
+
+/**
+ * A key plus the modifiers that need to be held with it.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct KeyBinding {
+    pub key: VirtualKeyCode,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl KeyBinding {
+    pub fn key(key: VirtualKeyCode) -> Self {
+        Self { key, ctrl: false, shift: false, alt: false }
+    }
+
+    pub fn ctrl(key: VirtualKeyCode) -> Self {
+        Self { ctrl: true, ..Self::key(key) }
+    }
+
+    pub fn shift(key: VirtualKeyCode) -> Self {
+        Self { shift: true, ..Self::key(key) }
+    }
+
+    pub fn alt(key: VirtualKeyCode) -> Self {
+        Self { alt: true, ..Self::key(key) }
+    }
+
+    pub fn with_modifiers(key: VirtualKeyCode, modifiers: ModifiersState) -> Self {
+        Self {
+            key,
+            ctrl: modifiers.ctrl(),
+            shift: modifiers.shift(),
+            alt: modifiers.alt(),
+        }
+    }
+}
+
+/**
+ * Maps key presses to actions, so that demos never match on raw keys
+ * and bindings can be changed at runtime. `A` is whatever action enum
+ * the demo defines.
+ */
+#[derive(Debug)]
+pub struct InputMap<A> {
+    bindings: HashMap<KeyBinding, A>,
+    modifiers: ModifiersState,
+}
+
+impl<A: Copy> InputMap<A> {
+    pub fn new() -> Self {
+        Self {
+            bindings: HashMap::new(),
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    /// Returns the action that was previously bound to `binding`
+    pub fn bind(&mut self, binding: KeyBinding, action: A) -> Option<A> {
+        self.bindings.insert(binding, action)
+    }
+
+    pub fn unbind(&mut self, binding: &KeyBinding) -> Option<A> {
+        self.bindings.remove(binding)
+    }
+
+    pub fn is_bound(&self, binding: &KeyBinding) -> bool {
+        self.bindings.contains_key(binding)
+    }
+
+    pub fn action(&self, binding: &KeyBinding) -> Option<A> {
+        self.bindings.get(binding).copied()
+    }
+
+    pub fn modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+
+    /// Keeps track of the modifier keys and returns the action for any
+    /// key that was just pressed.
+    pub fn process_event(&mut self, event: &WindowEvent) -> Option<A> {
+        match event {
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                None
+            }
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    virtual_keycode: Some(key),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => self.action(&KeyBinding::with_modifiers(*key, self.modifiers)),
+            _ => None,
+        }
+    }
+}
+
+impl<A: Copy> Default for InputMap<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
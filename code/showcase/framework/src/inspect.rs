@@ -0,0 +1,208 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use crate::assets::{PathResolver, ResolveStrategy};
+use crate::bounds::Aabb;
+use crate::dedup::AutoInstanceReport;
+use crate::model::{MaterialData, ModelData, ModelLoadOptions, TextureSource};
+
+/// Where a vertex attribute came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeSource {
+    /// Read from the model file
+    File,
+    /// Worked out by the loader
+    Generated,
+    /// Not in the file and not generated, so it's all zeros
+    Missing,
+}
+
+/// `min` and `max` as plain arrays, which read better in JSON than
+/// cgmath's points
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct BoundsReport {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl From<&Aabb> for BoundsReport {
+    fn from(aabb: &Aabb) -> Self {
+        Self { min: aabb.min.into(), max: aabb.max.into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MeshReport {
+    pub name: String,
+    pub vertices: usize,
+    pub indices: usize,
+    pub material: usize,
+    pub bounds: BoundsReport,
+    pub tex_coords: AttributeSource,
+    pub normals: AttributeSource,
+    pub tangents: AttributeSource,
+    /// How many times the mesh gets drawn, counting copies folded into
+    /// it by auto instancing
+    pub copies: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TextureReport {
+    /// What the model file said
+    pub written: String,
+    /// What that turned out to mean
+    pub path: PathBuf,
+    /// How [PathResolver] found it. Anything other than `as_written`
+    /// or `containing_folder` means the file's path was wrong and the
+    /// resolver had to fall back to looking elsewhere.
+    pub found_by: String,
+    pub file_format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// What it becomes on the GPU
+    pub gpu_format: String,
+}
+
+impl TextureReport {
+    fn new(source: &TextureSource) -> Self {
+        let found_by = match &source.strategy {
+            ResolveStrategy::AsWritten => "as_written".to_string(),
+            ResolveStrategy::ContainingFolder => "containing_folder".to_string(),
+            ResolveStrategy::SearchPath(root) => format!("search_path {}", root.display()),
+            ResolveStrategy::CaseInsensitive(root) => format!("case_insensitive {}", root.display()),
+            ResolveStrategy::Embedded => "embedded".to_string(),
+        };
+        // Only reads the header, so this is cheap even for big textures
+        let (width, height) = match image::image_dimensions(&source.path) {
+            Ok((w, h)) => (Some(w), Some(h)),
+            Err(_) => (None, None),
+        };
+        let file_format = image::ImageFormat::from_path(&source.path)
+            .ok()
+            .map(|f| format!("{:?}", f));
+        let gpu_format = if source.is_normal_map {
+            wgpu::TextureFormat::Rgba8Unorm
+        } else {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        };
+        Self {
+            written: source.written.clone(),
+            path: source.path.clone(),
+            found_by,
+            file_format,
+            width,
+            height,
+            gpu_format: format!("{:?}", gpu_format),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaterialReport {
+    pub name: String,
+    pub diffuse: TextureReport,
+    pub normal: TextureReport,
+    /// Set when the material has a [crate::ChannelPacking]
+    pub packed: Option<TextureReport>,
+    /// On because a mesh using this material has no UVs, unless the
+    /// load options said otherwise
+    pub triplanar: bool,
+}
+
+impl MaterialReport {
+    fn new(material: &MaterialData) -> Self {
+        Self {
+            name: material.name.clone(),
+            diffuse: TextureReport::new(&material.diffuse),
+            normal: TextureReport::new(&material.normal),
+            packed: material.packed.as_ref().map(TextureReport::new),
+            triplanar: material.params.triplanar != 0,
+        }
+    }
+}
+
+/**
+ * Everything the loader made of a model file, for figuring out why a
+ * model looks wrong. Get one from a loaded model with
+ * [crate::Model::describe], or without a GPU with
+ * [ModelReport::from_file].
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelReport {
+    pub path: PathBuf,
+    pub bounds: BoundsReport,
+    pub meshes: Vec<MeshReport>,
+    pub materials: Vec<MaterialReport>,
+    pub instancing: Option<AutoInstanceReport>,
+}
+
+impl ModelReport {
+    /// Does all the loading except for the GPU upload
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        options: &ModelLoadOptions,
+        resolver: &PathResolver,
+    ) -> Result<Self> {
+        Ok(Self::from_data(&ModelData::load_with_resolver(path, options, resolver)?))
+    }
+
+    pub fn from_data(data: &ModelData) -> Self {
+        let meshes = data.meshes.iter()
+            .map(|m| MeshReport {
+                name: m.name.clone(),
+                vertices: m.vertices.len(),
+                indices: m.indices.len(),
+                material: m.material,
+                bounds: (&m.aabb).into(),
+                tex_coords: if m.has_tex_coords { AttributeSource::File } else { AttributeSource::Missing },
+                normals: m.normals,
+                tangents: m.tangents,
+                copies: m.placements.len().max(1),
+            })
+            .collect();
+        Self {
+            path: data.path.clone(),
+            bounds: (&data.aabb()).into(),
+            meshes,
+            materials: data.materials.iter().map(MaterialReport::new).collect(),
+            instancing: data.instancing,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stl_report_needs_no_gpu() {
+        let path = std::env::temp_dir().join(format!("learn-wgpu-inspect-{}.stl", std::process::id()));
+        std::fs::write(&path, "solid test
+            facet normal 0 0 1
+                outer loop
+                    vertex 0 0 0
+                    vertex 1 0 0
+                    vertex 0 1 0
+                endloop
+            endfacet
+        endsolid test").unwrap();
+        let report = ModelReport::from_file(&path, &Default::default(), &Default::default());
+        let _ = std::fs::remove_file(&path);
+        let report = report.unwrap();
+
+        assert_eq!(report.meshes.len(), 1);
+        assert!(report.materials.is_empty());
+        let mesh = &report.meshes[0];
+        assert_eq!((mesh.vertices, mesh.indices), (3, 3));
+        assert_eq!(mesh.normals, AttributeSource::File);
+        assert_eq!(mesh.tex_coords, AttributeSource::Missing);
+        assert_eq!(mesh.tangents, AttributeSource::Missing);
+        assert_eq!(report.bounds, BoundsReport { min: [0.0; 3], max: [1.0, 1.0, 0.0] });
+        assert!(report.to_json().unwrap().contains("\"normals\": \"file\""));
+    }
+}
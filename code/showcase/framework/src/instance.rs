@@ -0,0 +1,197 @@
+use cgmath::*;
+use crate::buffer::{Buffer, ToRaw};
+use crate::model::Vertex;
+use crate::shader_matrix::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Instance {
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: f32,
+    /// Shaders tint selected instances
+    pub selected: bool,
+}
+
+impl Instance {
+    pub fn new<V: Into<Vector3<f32>>>(position: V) -> Self {
+        Self {
+            position: position.into(),
+            rotation: Quaternion::one(),
+            scale: 1.0,
+            selected: false,
+        }
+    }
+
+    pub fn calc_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.position)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_scale(self.scale)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct InstanceRaw {
+    model: ShaderMatrix,
+    /// Bit 0 is set when the instance is selected
+    flags: u32,
+    /// Where the instance was last frame, for motion vectors. The same
+    /// as `model` unless it moved, see [Buffer::start_motion_frame].
+    prev_model: ShaderMatrix,
+}
+
+impl InstanceRaw {
+    pub const SELECTED: u32 = 1;
+
+    /// The same instance with `placement` applied first, for drawing
+    /// one copy of an auto instanced mesh. See [crate::PlacementBuffers].
+    pub fn placed(&self, placement: &Matrix4<f32>) -> Self {
+        Self {
+            model: to_shader_bytes(&(Matrix4::from(self.model) * placement), MatrixKind::Model),
+            flags: self.flags,
+            prev_model: to_shader_bytes(&(Matrix4::from(self.prev_model) * placement), MatrixKind::Model),
+        }
+    }
+
+    /// Whether the instance moved since last frame
+    pub fn is_moving(&self) -> bool {
+        self.model != self.prev_model
+    }
+}
+
+unsafe impl bytemuck::Pod for InstanceRaw {}
+unsafe impl bytemuck::Zeroable for InstanceRaw {}
+
+impl ToRaw for Instance {
+    type Output = InstanceRaw;
+    fn to_raw(&self) -> InstanceRaw {
+        let model = to_shader_bytes(&self.calc_matrix(), MatrixKind::Model);
+        InstanceRaw {
+            model,
+            flags: if self.selected { InstanceRaw::SELECTED } else { 0 },
+            prev_model: model,
+        }
+    }
+
+    /// Keeps where `previous` was at the start of the frame, however
+    /// many times the instance gets updated during it
+    fn to_raw_over(&self, previous: &InstanceRaw) -> InstanceRaw {
+        InstanceRaw {
+            prev_model: previous.prev_model,
+            ..self.to_raw()
+        }
+    }
+}
+
+impl Buffer<Instance, InstanceRaw> {
+    /**
+     * Call this once at the start of every frame, before anything gets
+     * moved. Whatever moved last frame has its previous transform
+     * caught up with where it is now, so it stops blurring once it
+     * stops moving. Only the instances that moved get uploaded again,
+     * so a scene standing still costs nothing.
+     */
+    pub fn start_motion_frame(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        let mut dirty: Option<std::ops::Range<usize>> = None;
+        for (i, raw) in self.raw_buffer.data.iter_mut().enumerate() {
+            if raw.is_moving() {
+                raw.prev_model = raw.model;
+                dirty = Some(match dirty {
+                    Some(range) => range.start..i + 1,
+                    None => i..i + 1,
+                });
+            }
+        }
+        if let Some(range) = dirty {
+            // update_range builds the raw data again, and takes the
+            // previous transform from what's there now
+            self.update_range(device, encoder, range);
+        }
+    }
+}
+
+impl Vertex for InstanceRaw {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        use std::mem;
+        // A mat4 takes up 4 vertex slots, so we use locations 5
+        // through 8, the flags go in 9 and last frame's matrix in 10
+        // through 13. ModelVertex uses 0 through 4.
+        wgpu::VertexBufferDescriptor {
+            stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 6,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 7,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+                    shader_location: 8,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Uint,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 17]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 21]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 25]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 29]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float4,
+                },
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn previous_transform_survives_updates_within_a_frame() {
+        let mut instance = Instance::new((0.0, 0.0, 0.0));
+        let still = instance.to_raw();
+        assert!(!still.is_moving());
+
+        // Moved twice in one frame, it's still moving from where the
+        // frame started
+        instance.position.x = 1.0;
+        let once = instance.to_raw_over(&still);
+        instance.position.x = 2.0;
+        let twice = instance.to_raw_over(&once);
+        assert!(twice.is_moving());
+        assert_eq!(twice.prev_model, still.model);
+        assert_eq!(Matrix4::from(twice.model), Matrix4::from_translation(Vector3::new(2.0, 0.0, 0.0)));
+
+        let placement = Matrix4::from_scale(2.0);
+        let placed = twice.placed(&placement);
+        assert_eq!(Matrix4::from(placed.prev_model), placement);
+    }
+}
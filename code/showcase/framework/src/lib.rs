@@ -1,17 +1,122 @@
+mod adjacency;
+mod assets;
+mod bench;
+mod blob_shadow;
+mod blur;
+mod bounds;
 mod buffer;
 mod camera;
+mod capabilities;
+mod capture;
+mod cleanup;
+mod contact_shadow;
+mod dedup;
+mod events;
+mod frame;
+mod hiz;
+mod import;
+mod input;
+mod inspect;
+mod instance;
+mod instance_file;
 mod light;
+mod light_list;
+mod loader;
+mod lod;
+mod manifest;
+mod material_layout;
+mod minimap;
 mod model;
+mod motion_blur;
+mod normals;
+mod overdraw;
+mod pacing;
+mod packing;
+mod palette;
+mod picking;
 mod pipeline;
+mod preprocess;
+mod probe;
+mod profiler;
+mod recording;
+mod render_graph;
+mod resources;
+mod scene;
+mod seed;
+mod settings;
+mod shader_defines;
+mod shader_matrix;
+mod sky;
+mod sphere_tree;
+mod split;
+mod stl;
+mod sun;
+mod tangent;
 mod texture;
+mod texture_budget;
+mod thumbnail;
+mod tonemap;
+mod transparency;
+mod visibility;
 pub mod prelude;
 
+pub use adjacency::*;
+pub use assets::*;
+pub use bench::*;
+pub use blob_shadow::*;
+pub use blur::*;
+pub use bounds::*;
 pub use buffer::*;
 pub use camera::*;
+pub use capabilities::*;
+pub use capture::*;
+pub use cleanup::*;
+pub use contact_shadow::*;
+pub use dedup::*;
+pub use events::*;
+pub use frame::*;
+pub use hiz::*;
+pub use import::*;
+pub use input::*;
+pub use inspect::*;
+pub use instance::*;
+pub use instance_file::*;
 pub use light::*;
+pub use light_list::*;
+pub use loader::*;
+pub use lod::*;
+pub use manifest::*;
+pub use material_layout::*;
+pub use minimap::*;
 pub use model::*;
+pub use motion_blur::*;
+pub use overdraw::*;
+pub use pacing::*;
+pub use packing::*;
+pub use palette::*;
+pub use picking::*;
 pub use pipeline::*;
+pub use preprocess::*;
+pub use probe::*;
+pub use profiler::*;
+pub use recording::*;
+pub use render_graph::*;
+pub use resources::*;
+pub use scene::*;
+pub use seed::*;
+pub use settings::*;
+pub use shader_defines::*;
+pub use shader_matrix::*;
+pub use sky::*;
+pub use sphere_tree::*;
+pub use split::*;
+pub use sun::*;
 pub use texture::*;
+pub use texture_budget::*;
+pub use thumbnail::*;
+pub use tonemap::*;
+pub use transparency::*;
+pub use visibility::*;
 
 use anyhow::*;
 use cgmath::*;
@@ -27,6 +132,7 @@ pub struct Display {
     pub swap_chain: wgpu::SwapChain,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
+    pub caps: DeviceCapabilities,
 }
 
 impl Display {
@@ -40,10 +146,15 @@ impl Display {
             },
             wgpu::BackendBit::PRIMARY,
         ).await.context("Unable to find valid device!")?;
-        let (device, queue) = _adapter.request_device(&Default::default()).await;
+        let device_desc = wgpu::DeviceDescriptor::default();
+        let (device, queue) = _adapter.request_device(&device_desc).await;
+        let caps = DeviceCapabilities::new(&_adapter, &device_desc);
+        log::info!("{}", caps);
+        let format = caps.surface_format().get("surface format");
+        log::info!("Surface format: {:?}", format);
         let sc_desc = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
-            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            format,
             width: size.width,
             height: size.height,
             present_mode: wgpu::PresentMode::Fifo,
@@ -57,6 +168,7 @@ impl Display {
             swap_chain,
             device,
             queue,
+            caps,
         })
     }
 
@@ -65,17 +177,31 @@ impl Display {
         self.sc_desc.height = height;
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
     }
+
+    /// Some platforms don't do every mode, in which case the driver
+    /// picks the closest one it has
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        self.sc_desc.present_mode = present_mode;
+        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+    }
 }
 
 
 /**
- * Holds the camera data to be passed to wgpu.
+ * Holds the camera data to be passed to wgpu. This is everything that
+ * changes between views of the same frame.
  */
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct UniformData {
     view_position: cgmath::Vector4<f32>,
-    view_proj: cgmath::Matrix4<f32>,
+    view_proj: ShaderMatrix,
+    /// Used to turn a pixel back into a ray from the camera
+    inv_view_proj: ShaderMatrix,
+    /// Width and height in pixels, then one over each
+    viewport: cgmath::Vector4<f32>,
+    /// The view_proj from the update before, for motion vectors
+    prev_view_proj: ShaderMatrix,
 }
 
 unsafe impl bytemuck::Zeroable for UniformData {}
@@ -84,25 +210,73 @@ unsafe impl bytemuck::Pod for UniformData {}
 pub struct Uniforms {
     data: UniformData,
     buffer: wgpu::Buffer,
+    /// Whether prev_view_proj is a real camera yet
+    has_history: bool,
 }
 
 impl Uniforms {
     pub fn new(device: &wgpu::Device) -> Self {
         let data = UniformData {
             view_position: Zero::zero(),
-            view_proj: cgmath::Matrix4::identity(),
+            view_proj: to_shader_bytes(&Matrix4::identity(), MatrixKind::Combined),
+            inv_view_proj: to_shader_bytes(&Matrix4::identity(), MatrixKind::Combined),
+            viewport: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            prev_view_proj: to_shader_bytes(&Matrix4::identity(), MatrixKind::Combined),
         };
         let buffer = device.create_buffer_with_data(
             bytemuck::cast_slice(&[data]),
             wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
         );
 
-        Self { data, buffer }
+        Self { data, buffer, has_history: false }
     }
 
     pub fn update_view_proj(&mut self, camera: &camera::Camera, projection: &camera::Projection) {
-        self.data.view_position = camera.position.to_homogeneous();
-        self.data.view_proj = projection.calc_matrix() * camera.calc_matrix()
+        self.update_matrices(camera.position, camera.calc_matrix(), projection.calc_matrix());
+    }
+
+    /// Same as [Uniforms::update_view_proj], but for cameras other
+    /// than [camera::Camera], such as [camera::OrbitCamera]. Call
+    /// either once a frame, as the last call's matrix becomes
+    /// [Uniforms::prev_view_proj].
+    pub fn update_matrices(&mut self, position: Point3<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) {
+        debug_check_matrix(&view, MatrixKind::View);
+        debug_check_matrix(&proj, MatrixKind::Projection);
+        let view_proj = proj * view;
+        let inv_view_proj = view_proj.invert().unwrap_or_else(Matrix4::identity);
+        let view_proj = to_shader_bytes(&view_proj, MatrixKind::Combined);
+        self.data.prev_view_proj = if self.has_history { self.data.view_proj } else { view_proj };
+        self.has_history = true;
+        self.data.view_position = position.to_homogeneous();
+        self.data.view_proj = view_proj;
+        self.data.inv_view_proj = to_shader_bytes(&inv_view_proj, MatrixKind::Combined);
+    }
+
+    /// For when the camera jumps rather than moves, so the next update
+    /// doesn't look like the camera whipping around
+    pub fn reset_history(&mut self) {
+        self.has_history = false;
+    }
+
+    pub fn update_viewport(&mut self, width: u32, height: u32) {
+        let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+        self.data.viewport = Vector4::new(width, height, 1.0 / width, 1.0 / height);
+    }
+
+    pub fn view_position(&self) -> Point3<f32> {
+        Point3::from_vec(self.data.view_position.truncate())
+    }
+
+    pub fn view_proj(&self) -> Matrix4<f32> {
+        self.data.view_proj.into()
+    }
+
+    pub fn inv_view_proj(&self) -> Matrix4<f32> {
+        self.data.inv_view_proj.into()
+    }
+
+    pub fn prev_view_proj(&self) -> Matrix4<f32> {
+        self.data.prev_view_proj.into()
     }
 
     pub fn update_buffer(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
@@ -122,7 +296,9 @@ impl Uniforms {
 
 /**
  * Holds the wgpu::BindGroupLayout and one wgpu::BindGroup for the
- * just the Uniforms struct.
+ * just the Uniforms struct. This is the per-view group, which models
+ * use as group 1. Passes that render from somewhere other than the
+ * camera (shadows, reflections, split-screen) get their own.
  */
 pub struct UniformBinding {
     pub layout: wgpu::BindGroupLayout,
@@ -162,6 +338,26 @@ impl UniformBinding {
         Self { layout, bind_group }
     }
 
+    /// A bind group for another view's `uniforms` with this layout, so
+    /// it works with the same pipelines
+    pub fn bind_group_for(&self, device: &wgpu::Device, uniforms: &Uniforms) -> wgpu::BindGroup {
+        device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &uniforms.buffer,
+                            range: 0..std::mem::size_of::<UniformData>() as wgpu::BufferAddress,
+                        },
+                    },
+                ],
+                label: Some("UniformBinding::bind_group_for")
+            }
+        )
+    }
+
     pub fn rebind(&mut self, device: &wgpu::Device, uniforms: &Uniforms) {
         self.bind_group = device.create_bind_group(
             &wgpu::BindGroupDescriptor {
@@ -171,7 +367,7 @@ impl UniformBinding {
                         binding: 0,
                         resource: wgpu::BindingResource::Buffer {
                             buffer: &uniforms.buffer,
-                            range: 0..std::mem::size_of_val(&uniforms) as wgpu::BufferAddress,
+                            range: 0..std::mem::size_of::<UniformData>() as wgpu::BufferAddress,
                         },
                     },
                 ],
@@ -182,26 +378,90 @@ impl UniformBinding {
 }
 
 pub trait Demo: 'static + Sized {
+    /// Called before the window is created. Return something to open
+    /// the window at a saved size and position.
+    fn window_geometry() -> Option<WindowGeometry> {
+        None
+    }
     fn init(display: &Display) -> Result<Self, Error>;
     fn process_mouse(&mut self, dx: f64, dy: f64);
+    /// Returns true if the demo used the event. Demos that only care
+    /// about mouse motion can leave this alone.
+    fn input(&mut self, _display: &Display, _event: &WindowEvent) -> bool {
+        false
+    }
     fn resize(&mut self, display: &Display);
     fn update(&mut self, display: &Display, dt: Duration);
     fn render(&mut self, display: &mut Display);
+    /// Checked every frame, so changes take effect straight away
+    fn frame_pacing(&self) -> FramePacing {
+        FramePacing::default()
+    }
+    /// Called when the window is closed, before the event loop exits.
+    /// Anything that should survive a restart gets saved here.
+    fn exit(&mut self, _display: &Display) {}
+    /// Called before the window is created. Return something to record
+    /// input to a file, or replay it from one.
+    fn input_session() -> Option<InputSession> {
+        None
+    }
+    /// Called before the window is created. Return true for runs
+    /// nobody watches, like benchmarks. There's still a window, as the
+    /// swap chain needs its surface, it just never gets shown.
+    fn hide_window() -> bool {
+        false
+    }
+    /// Called about once a second. Whatever this returns goes in the
+    /// window title, next to the frame stats when there are any.
+    fn overlay_stats(&mut self, _display: &Display) -> Option<String> {
+        None
+    }
 }
 
 pub async fn run<D: Demo>() -> Result<(), Error> {
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new()
+    let geometry = D::window_geometry();
+    let mut builder = WindowBuilder::new()
         .with_title(env!("CARGO_PKG_NAME"))
-        .build(&event_loop)?;
+        .with_visible(!D::hide_window());
+    if let Some(g) = geometry.filter(|g| g.width > 0 && g.height > 0) {
+        builder = builder.with_inner_size(winit::dpi::PhysicalSize::new(g.width, g.height));
+    }
+    let window = builder.build(&event_loop)?;
+    if let Some((x, y)) = geometry.and_then(|g| g.position) {
+        window.set_outer_position(winit::dpi::PhysicalPosition::new(x, y));
+    }
+    let mut recorder = None;
+    let mut replay = None;
+    match D::input_session() {
+        Some(InputSession::Record(path)) => {
+            recorder = Some(InputRecorder::create(&path, recording::window_size(window.inner_size()))?);
+            log::info!("Recording input to {}", path.display());
+        }
+        Some(InputSession::Replay { path, fast }) => {
+            let loaded = InputReplay::load(&path)?;
+            let (width, height) = loaded.header.window_size;
+            window.set_inner_size(winit::dpi::PhysicalSize::new(width, height));
+            log::info!("Replaying {} frames from {}", loaded.frames_left(), path.display());
+            replay = Some((loaded, fast));
+        }
+        None => {}
+    }
     let mut display = Display::new(&window).await?;
+    check_matrix_conventions(&display.device, &display.queue)?;
     let mut demo = D::init(&mut display)?;
     let mut last_update = Instant::now();
     let mut is_resumed = true;
     let mut is_focused = true;
+    let mut limiter = FrameLimiter::new();
+    let mut frame_stats = FrameStats::new();
+    let mut showing_stats = false;
+    let mut last_stats = Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = if is_resumed && is_focused {
+        let pacing = demo.frame_pacing();
+        let is_running = is_resumed && (is_focused || !pacing.sleep_when_unfocused);
+        *control_flow = if is_running {
             ControlFlow::Poll
         } else {
             ControlFlow::Wait
@@ -211,44 +471,200 @@ pub async fn run<D: Demo>() -> Result<(), Error> {
             Event::Resumed => is_resumed = true,
             Event::Suspended => is_resumed = false,
             Event::RedrawRequested(wid) => if wid == window.id() {
-                let now = Instant::now();
-                let dt = now - last_update;
-                last_update = now;
+                let mut dt = last_update.elapsed();
+                let mut replay_done = false;
+                if let Some((replay, fast)) = &mut replay {
+                    match replay.next_frame() {
+                        Some((events, recorded_dt)) => {
+                            for event in &events {
+                                replay_event(&mut LiveInput {
+                                    demo: &mut demo,
+                                    display: &mut display,
+                                    window: &window,
+                                    control_flow,
+                                    is_focused: &mut is_focused,
+                                }, event);
+                            }
+                            if !*fast && recorded_dt > dt {
+                                std::thread::sleep(recorded_dt - dt);
+                            }
+                            dt = recorded_dt;
+                        }
+                        None => replay_done = true,
+                    }
+                }
+                if replay_done {
+                    log::info!("The replay has finished, back to live input");
+                    replay = None;
+                }
+                if let Some(r) = &mut recorder {
+                    if let Err(e) = r.end_frame(dt) {
+                        log::error!("Stopped recording input: {:?}", e);
+                        recorder = None;
+                    }
+                }
+                last_update = Instant::now();
 
+                if display.sc_desc.present_mode != pacing.present_mode() {
+                    display.set_present_mode(pacing.present_mode());
+                }
                 demo.update(&mut display, dt);
                 demo.render(&mut display);
+
+                // After presenting, so the wait doesn't land between
+                // measuring dt and using it
+                let target = pacing.limiter_target();
+                match target {
+                    Some(target) => limiter.wait(target),
+                    None => limiter.reset(),
+                }
+
+                // The window title stands in for a stats overlay, with
+                // frame stats while the limiter is on and whatever the
+                // demo has to say
+                frame_stats.push(dt);
+                if target.is_none() {
+                    frame_stats.clear();
+                }
+                if last_stats.elapsed() >= Duration::from_secs(1) {
+                    last_stats = Instant::now();
+                    let stats = target.map(|_| frame_stats.summary(target))
+                        .into_iter()
+                        .chain(demo.overlay_stats(&display))
+                        .collect::<Vec<_>>();
+                    if !stats.is_empty() {
+                        window.set_title(&format!("{} - {}", env!("CARGO_PKG_NAME"), stats.join(" | ")));
+                        showing_stats = true;
+                    } else if showing_stats {
+                        window.set_title(env!("CARGO_PKG_NAME"));
+                        showing_stats = false;
+                    }
+                }
             }
             Event::MainEventsCleared => {
-                if is_focused && is_resumed {
+                if is_running {
                     window.request_redraw();
                 } else {
                     // Freeze time while the demo is not in the foreground
                     last_update = Instant::now();
                 }
             }
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } => if is_focused && replay.is_none() {
+                if let Some(r) = &mut recorder {
+                    record(r, RecordedEvent::MouseMotion { dx: delta.0, dy: delta.1 });
+                }
+                demo.process_mouse(delta.0, delta.1);
+            }
             Event::WindowEvent {
                 event,
                 window_id,
                 ..
             } => if window_id == window.id() {
-                match event {
-                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                    WindowEvent::Focused(f) => is_focused = f,
-                    WindowEvent::ScaleFactorChanged {
-                        new_inner_size,
-                        ..
-                    } => {
-                        display.resize(new_inner_size.width, new_inner_size.height);
-                        demo.resize(&mut display);
-                    }
-                    WindowEvent::Resized(new_inner_size) => {
-                        display.resize(new_inner_size.width, new_inner_size.height);
-                        demo.resize(&mut display);
-                    }
-                    _ => {}
+                let recorded = RecordedEvent::from_window_event(&event);
+                // Real input gets ignored during a replay. Resizes and
+                // closing the window still go through, as the replay
+                // resizes the window itself.
+                let is_input = match recorded {
+                    Some(RecordedEvent::Resized { .. }) | Some(RecordedEvent::CloseRequested) | None => false,
+                    Some(_) => true,
+                };
+                if replay.is_some() && is_input {
+                    return;
                 }
+                if let (Some(r), Some(recorded)) = (&mut recorder, recorded) {
+                    record(r, recorded);
+                }
+                handle_window_event(&mut demo, &mut display, &event, control_flow, &mut is_focused);
             }
             _ => {}
         }
     });
+}
+
+fn record(recorder: &mut InputRecorder, event: RecordedEvent) {
+    if let Err(e) = recorder.record(event) {
+        log::error!("Unable to record input: {:?}", e);
+    }
+}
+
+/// Hands `event` to the demo, and does the default thing with it if
+/// the demo doesn't use it
+fn handle_window_event<D: Demo>(
+    demo: &mut D,
+    display: &mut Display,
+    event: &WindowEvent,
+    control_flow: &mut ControlFlow,
+    is_focused: &mut bool,
+) {
+    if demo.input(display, event) {
+        return;
+    }
+    match event {
+        WindowEvent::CloseRequested => {
+            demo.exit(display);
+            *control_flow = ControlFlow::Exit;
+        }
+        WindowEvent::Focused(f) => *is_focused = *f,
+        WindowEvent::ScaleFactorChanged {
+            new_inner_size,
+            ..
+        } => {
+            display.resize(new_inner_size.width, new_inner_size.height);
+            demo.resize(display);
+        }
+        WindowEvent::Resized(new_inner_size) => {
+            display.resize(new_inner_size.width, new_inner_size.height);
+            demo.resize(display);
+        }
+        _ => {}
+    }
+}
+
+/**
+ * Where [replay_event] sends things. [crate::run] hands it the demo and
+ * window, and tests can hand it something that only has the input
+ * handling.
+ */
+pub(crate) trait ReplayInput {
+    fn process_mouse(&mut self, dx: f64, dy: f64);
+    /// Everything a live window event would go through
+    fn input(&mut self, event: &WindowEvent);
+    fn set_inner_size(&mut self, width: u32, height: u32);
+}
+
+struct LiveInput<'a, D> {
+    demo: &'a mut D,
+    display: &'a mut Display,
+    window: &'a Window,
+    control_flow: &'a mut ControlFlow,
+    is_focused: &'a mut bool,
+}
+
+impl<D: Demo> ReplayInput for LiveInput<'_, D> {
+    fn process_mouse(&mut self, dx: f64, dy: f64) {
+        self.demo.process_mouse(dx, dy);
+    }
+
+    fn input(&mut self, event: &WindowEvent) {
+        handle_window_event(self.demo, self.display, event, self.control_flow, self.is_focused);
+    }
+
+    fn set_inner_size(&mut self, width: u32, height: u32) {
+        self.window.set_inner_size(winit::dpi::PhysicalSize::new(width, height));
+    }
+}
+
+/// Does what the recorded event did when it was recorded
+pub(crate) fn replay_event<I: ReplayInput>(input: &mut I, event: &RecordedEvent) {
+    match event {
+        RecordedEvent::MouseMotion { dx, dy } => input.process_mouse(*dx, *dy),
+        // The Resized event from the window will follow
+        RecordedEvent::Resized { width, height } => input.set_inner_size(*width, *height),
+        _ => if let Some(event) = event.to_window_event() {
+            input.input(&event);
+        }
+    }
 }
\ No newline at end of file
@@ -12,7 +12,7 @@ unsafe impl bytemuck::Zeroable for LightData {}
 
 pub struct Light {
     data: LightData,
-    buffer: wgpu::Buffer,
+    pub(crate) buffer: wgpu::Buffer,
 }
 
 impl Light {
@@ -28,9 +28,85 @@ impl Light {
 
         Self { data, buffer }
     }
+
+    pub fn position(&self) -> Vector3<f32> {
+        self.data.position.truncate()
+    }
+
+    pub fn set_position(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        position: Vector3<f32>,
+    ) {
+        self.data.position = position.extend(1.0);
+        self.update_buffer(device, encoder);
+    }
+
+    pub fn color(&self) -> Vector3<f32> {
+        self.data.color.truncate()
+    }
+
+    pub fn set_color(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        color: Vector3<f32>,
+    ) {
+        self.data.color = color.extend(1.0);
+        self.update_buffer(device, encoder);
+    }
+
+    pub fn update_buffer(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[self.data]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<LightData>() as _,
+        );
+    }
 }
 
 pub struct LightBinding {
     pub layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
-}
\ No newline at end of file
+}
+
+impl LightBinding {
+    pub fn new(device: &wgpu::Device, light: &Light) -> Self {
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                ],
+                label: Some("LightBinding::layout"),
+            }
+        );
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &light.buffer,
+                            range: 0..std::mem::size_of::<LightData>() as wgpu::BufferAddress,
+                        },
+                    },
+                ],
+                label: Some("LightBinding::bind_group"),
+            }
+        );
+
+        Self { layout, bind_group }
+    }
+}
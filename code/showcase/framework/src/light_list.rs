@@ -0,0 +1,294 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::bounds::Aabb;
+
+pub use crate::shader_defines::{LIGHTS_PER_OBJECT, MAX_LIGHTS};
+
+/// The last light an object uses starts fading out when the next one
+/// in line is within this fraction of its score. When they swap
+/// places the fading light is at zero, so nothing pops.
+const FADE_RANGE: f32 = 0.25;
+/// Stops lights inside an object's box from having an infinite score
+const MIN_DISTANCE2: f32 = 0.01;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointLight {
+    pub position: [f32; 3],
+    /// Linear RGB
+    pub color: [f32; 3],
+    pub intensity: f32,
+}
+
+impl PointLight {
+    /// A rough guess at how much this light adds to anything in `aabb`.
+    /// Shaders attenuate by distance squared, so we do the same using
+    /// the closest point of the box.
+    pub fn importance(&self, aabb: &Aabb) -> f32 {
+        let p = Point3::from(self.position);
+        let closest = Point3::new(
+            p.x.max(aabb.min.x).min(aabb.max.x),
+            p.y.max(aabb.min.y).min(aabb.max.y),
+            p.z.max(aabb.min.z).min(aabb.max.z),
+        );
+        self.intensity / p.distance2(closest).max(MIN_DISTANCE2)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct PointLightRaw {
+    /// w is the intensity
+    position: Vector4<f32>,
+    color: Vector4<f32>,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct LightListRaw {
+    lights: [PointLightRaw; MAX_LIGHTS],
+    count: [u32; 4],
+}
+
+unsafe impl bytemuck::Pod for LightListRaw {}
+unsafe impl bytemuck::Zeroable for LightListRaw {}
+
+/**
+ * Which lights an object uses. Unused slots have a weight of 0.
+ */
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ObjectLightsData {
+    pub indices: [u32; LIGHTS_PER_OBJECT],
+    pub weights: [f32; LIGHTS_PER_OBJECT],
+}
+
+unsafe impl bytemuck::Pod for ObjectLightsData {}
+unsafe impl bytemuck::Zeroable for ObjectLightsData {}
+
+/**
+ * Sorts `lights` by how much they add to `aabb` and keeps the top
+ * [LIGHTS_PER_OBJECT]. Only indices get stored, so the lights
+ * themselves are only uploaded once for everybody.
+ */
+pub fn select_lights(lights: &[PointLight], aabb: &Aabb) -> ObjectLightsData {
+    let mut scored = lights.iter()
+        .take(MAX_LIGHTS)
+        .enumerate()
+        .map(|(i, light)| (i, light.importance(aabb)))
+        .filter(|(_, score)| *score > 0.0)
+        .collect::<Vec<_>>();
+    // Ties are broken by index so the order doesn't flicker
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+
+    let mut data = ObjectLightsData::default();
+    for (slot, &(index, _)) in scored.iter().take(LIGHTS_PER_OBJECT).enumerate() {
+        data.indices[slot] = index as u32;
+        data.weights[slot] = 1.0;
+    }
+
+    // Soften the cut off between the last light we keep and the first
+    // one we drop
+    if scored.len() > LIGHTS_PER_OBJECT {
+        let last = scored[LIGHTS_PER_OBJECT - 1].1;
+        let next = scored[LIGHTS_PER_OBJECT].1;
+        let t = ((last - next) / (last * FADE_RANGE)).min(1.0).max(0.0);
+        data.weights[LIGHTS_PER_OBJECT - 1] = t * t * (3.0 - 2.0 * t);
+    }
+    data
+}
+
+/**
+ * Every point light in the scene in one uniform buffer. Objects pick
+ * which of these they want with [ObjectLights].
+ */
+pub struct LightList {
+    pub lights: Vec<PointLight>,
+    buffer: wgpu::Buffer,
+}
+
+impl LightList {
+    pub fn new(device: &wgpu::Device, lights: Vec<PointLight>) -> Self {
+        if lights.len() > MAX_LIGHTS {
+            log::warn!("Only the first {} of {} lights will be used", MAX_LIGHTS, lights.len());
+        }
+        let buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[Self::raw(&lights)]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        Self { lights, buffer }
+    }
+
+    fn raw(lights: &[PointLight]) -> LightListRaw {
+        let mut raw = LightListRaw {
+            lights: [PointLightRaw { position: Vector4::zero(), color: Vector4::zero() }; MAX_LIGHTS],
+            count: [0; 4],
+        };
+        for (i, light) in lights.iter().take(MAX_LIGHTS).enumerate() {
+            raw.lights[i] = PointLightRaw {
+                position: Vector3::from(light.position).extend(light.intensity),
+                color: Vector3::from(light.color).extend(1.0),
+            };
+        }
+        raw.count[0] = lights.len().min(MAX_LIGHTS) as u32;
+        raw
+    }
+
+    /// Call this after changing [LightList::lights]
+    pub fn update_buffer(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[Self::raw(&self.lights)]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<LightListRaw>() as _,
+        );
+    }
+
+    /// Binding 0 is the [LightList], binding 1 is the object's
+    /// [ObjectLightsData]
+    pub fn create_object_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                ],
+                label: Some("LightList::object_layout"),
+            }
+        )
+    }
+}
+
+/**
+ * The lights one object (or group of instances) is shaded with. Call
+ * [ObjectLights::update] each frame, or whenever the object or the
+ * lights move.
+ */
+pub struct ObjectLights {
+    pub data: ObjectLightsData,
+    buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl ObjectLights {
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, list: &LightList) -> Self {
+        let data = ObjectLightsData::default();
+        let buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[data]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &list.buffer,
+                            range: 0..std::mem::size_of::<LightListRaw>() as wgpu::BufferAddress,
+                        },
+                    },
+                    wgpu::Binding {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &buffer,
+                            range: 0..std::mem::size_of::<ObjectLightsData>() as wgpu::BufferAddress,
+                        },
+                    },
+                ],
+                label: Some("ObjectLights::bind_group"),
+            }
+        );
+        Self { data, buffer, bind_group }
+    }
+
+    /// Picks the lights for anything inside `aabb`. Nothing gets
+    /// uploaded if the choice didn't change.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        list: &LightList,
+        aabb: &Aabb,
+    ) {
+        let data = select_lights(&list.lights, aabb);
+        if data == self.data {
+            return;
+        }
+        self.data = data;
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[data]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<ObjectLightsData>() as _,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn light(x: f32, intensity: f32) -> PointLight {
+        PointLight { position: [x, 0.0, 0.0], color: [1.0; 3], intensity }
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb::from_points(vec![Point3::new(-0.5, -0.5, -0.5), Point3::new(0.5, 0.5, 0.5)])
+    }
+
+    #[test]
+    fn keeps_the_brightest_lights() {
+        let lights = vec![
+            light(10.0, 1.0),
+            light(1.5, 1.0),
+            light(-2.5, 1.0),
+            light(100.0, 1.0),
+            light(3.5, 100.0),
+            light(0.0, 0.5),
+        ];
+        let data = select_lights(&lights, &unit_box());
+        // 5 is inside the box, 4 is far but bright
+        assert_eq!(data.indices, [5, 4, 1, 2]);
+        // Light 0 is nowhere near 2, so there's no fading
+        assert_eq!(data.weights, [1.0; 4]);
+    }
+
+    #[test]
+    fn last_light_fades_near_the_cut_off() {
+        // Lights 3 and 4 are almost the same distance away
+        let lights = vec![
+            light(1.0, 1.0),
+            light(-1.0, 1.0),
+            light(1.5, 1.0),
+            light(2.0, 1.0),
+            light(2.01, 1.0),
+        ];
+        let data = select_lights(&lights, &unit_box());
+        assert_eq!(data.indices[3], 3);
+        assert!(data.weights[3] < 0.1);
+    }
+
+    #[test]
+    fn fewer_lights_than_slots() {
+        let data = select_lights(&[light(1.0, 1.0)], &unit_box());
+        assert_eq!(data.weights, [1.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::*;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
+use crate::assets::PathResolver;
+use crate::material_layout::MaterialLayout;
+use crate::model::{Model, ModelData, ModelLoadOptions, TextureSource};
+use crate::profiler::Profiler;
+use crate::texture::TextureCache;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadStage {
+    /// Reading the model file, cleanup, normals and tangents
+    Parsing,
+    /// Decoding texture files
+    Textures,
+    /// Putting it all on the GPU. This happens on the main thread in
+    /// [DecodedModel::upload_in_steps].
+    Upload,
+}
+
+impl LoadStage {
+    /// What the stage is called in a [Profiler] trace
+    pub fn scope_name(&self) -> &'static str {
+        match self {
+            LoadStage::Parsing => "model parsing",
+            LoadStage::Textures => "model textures",
+            LoadStage::Upload => "model upload",
+        }
+    }
+}
+
+/// How far along a [LoadHandle] is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub stage: LoadStage,
+    /// Items finished in this stage
+    pub done: usize,
+    pub total: usize,
+    /// Bytes read from disk so far, over every stage
+    pub bytes: u64,
+}
+
+impl LoadProgress {
+    fn new(stage: LoadStage, done: usize, total: usize, bytes: u64) -> Self {
+        Self { stage, done, total, bytes }
+    }
+
+    /// How much of the current stage is done, from 0 to 1
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.done as f32 / self.total as f32
+        }
+    }
+
+    /// Something like `Textures [#####.....] 2/4 1.5MB`, for a status line
+    pub fn bar(&self, width: usize) -> String {
+        let filled = ((self.fraction() * width as f32).round() as usize).min(width);
+        format!(
+            "{:?} [{}{}] {}/{} {:.1}MB",
+            self.stage,
+            "#".repeat(filled),
+            ".".repeat(width - filled),
+            self.done,
+            self.total,
+            self.bytes as f64 / (1024.0 * 1024.0),
+        )
+    }
+}
+
+/// The error a load ends with when it's cancelled
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The load was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/**
+ * A model with its textures decoded, ready for [DecodedModel::upload].
+ * Nothing in here touches the GPU, so a cancelled load has nothing to
+ * clean up but memory.
+ */
+pub struct DecodedModel {
+    pub data: ModelData,
+    /// Keyed the same way as [TextureCache], by path and whether it's
+    /// a normal map
+    pub images: Vec<(PathBuf, bool, image::DynamicImage)>,
+    /// Bytes read from disk to get here
+    pub bytes: u64,
+}
+
+impl DecodedModel {
+    pub fn upload<'a>(
+        &self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+    ) -> Result<(Model<'a>, Vec<wgpu::CommandBuffer>)> {
+        self.upload_into(device, layout, &mut TextureCache::new())
+    }
+
+    /// [DecodedModel::upload] into a cache that outlives the model,
+    /// like one with a [TextureCache::budget]
+    pub fn upload_into<'a>(
+        &self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        textures: &mut TextureCache<'a>,
+    ) -> Result<(Model<'a>, Vec<wgpu::CommandBuffer>)> {
+        self.upload_in_steps(device, layout, textures, &mut |_| {})
+    }
+
+    /**
+     * [DecodedModel::upload_into], reporting [LoadStage::Upload] the
+     * same way [load_in_steps] reports the other stages. Each texture
+     * is a work item, and so is the model's own buffers at the end.
+     */
+    pub fn upload_in_steps<'a>(
+        &self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        textures: &mut TextureCache<'a>,
+        report: &mut dyn FnMut(LoadProgress),
+    ) -> Result<(Model<'a>, Vec<wgpu::CommandBuffer>)> {
+        let total = self.images.len() + 1;
+        let mut cmds = Vec::new();
+        for (i, (path, is_normal_map, image)) in self.images.iter().enumerate() {
+            report(LoadProgress::new(LoadStage::Upload, i, total, self.bytes));
+            textures.insert_image(device, path, *is_normal_map, image, &mut cmds)?;
+        }
+        report(LoadProgress::new(LoadStage::Upload, total - 1, total, self.bytes));
+        let (model, model_cmds) = Model::from_data(device, layout, &self.data, textures)?;
+        cmds.extend(model_cmds);
+        report(LoadProgress::new(LoadStage::Upload, total, total, self.bytes));
+        Ok((model, cmds))
+    }
+}
+
+/**
+ * Does everything [ModelLoader::spawn] does, on the calling thread.
+ * `cancel` is checked between work items, so a cancelled load stops
+ * after the texture it's decoding at the time. `report` gets called
+ * once before each item and once at the end of each stage.
+ */
+pub fn load_in_steps(
+    path: &Path,
+    options: &ModelLoadOptions,
+    resolver: &PathResolver,
+    cancel: &AtomicBool,
+    report: &mut dyn FnMut(LoadProgress),
+) -> Result<DecodedModel> {
+    let check = || -> Result<()> {
+        if cancel.load(Ordering::Relaxed) {
+            Err(Error::new(Cancelled))
+        } else {
+            Ok(())
+        }
+    };
+
+    let mut bytes = 0;
+    report(LoadProgress::new(LoadStage::Parsing, 0, 1, bytes));
+    check()?;
+    let data = ModelData::load_with_resolver(path, options, resolver)?;
+    bytes += std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    report(LoadProgress::new(LoadStage::Parsing, 1, 1, bytes));
+
+    let mut wanted: Vec<&TextureSource> = Vec::new();
+    for material in &data.materials {
+        for &source in [&material.diffuse, &material.normal].iter().chain(material.packed.as_ref().iter()) {
+            if !wanted.iter().any(|w| w.path == source.path && w.is_normal_map == source.is_normal_map) {
+                wanted.push(source);
+            }
+        }
+    }
+
+    let mut images = Vec::with_capacity(wanted.len());
+    for (i, source) in wanted.iter().enumerate() {
+        report(LoadProgress::new(LoadStage::Textures, i, wanted.len(), bytes));
+        check()?;
+        let image = match source.pack_unsaved() {
+            Some(packed) => packed?,
+            None => image::open(&source.path)
+                .with_context(|| format!("Unable to load {}", source.path.display()))?,
+        };
+        bytes += std::fs::metadata(&source.path).map(|m| m.len()).unwrap_or(0);
+        images.push((source.path.clone(), source.is_normal_map, image));
+    }
+    report(LoadProgress::new(LoadStage::Textures, wanted.len(), wanted.len(), bytes));
+    check()?;
+
+    Ok(DecodedModel { data, images, bytes })
+}
+
+/**
+ * Loads models on a background thread called "loader". See
+ * [LoadHandle]. Each stage shows up in `profiler`'s traces on that
+ * thread's row.
+ */
+pub struct ModelLoader;
+
+impl ModelLoader {
+    pub fn spawn(path: PathBuf, options: ModelLoadOptions, resolver: PathResolver, profiler: Profiler) -> LoadHandle {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(Mutex::new(LoadProgress::new(LoadStage::Parsing, 0, 1, 0)));
+        let (sender, result) = mpsc::channel();
+
+        let thread_cancel = cancel.clone();
+        let thread_progress = progress.clone();
+        let thread_path = path.clone();
+        std::thread::Builder::new()
+            .name("loader".to_string())
+            .spawn(move || {
+                let mut stage = (LoadStage::Parsing, Instant::now());
+                let loaded = load_in_steps(&thread_path, &options, &resolver, &thread_cancel, &mut |p| {
+                    if p.stage != stage.0 {
+                        profiler.record_load(stage.0.scope_name(), stage.1, stage.1.elapsed());
+                        stage = (p.stage, Instant::now());
+                    }
+                    *thread_progress.lock().unwrap() = p;
+                });
+                profiler.record_load(stage.0.scope_name(), stage.1, stage.1.elapsed());
+                // Nobody's listening if the handle was dropped
+                let _ = sender.send(loaded);
+            })
+            .expect("Unable to start the loader thread");
+
+        LoadHandle { path, cancel, progress, result }
+    }
+}
+
+/**
+ * A load running on another thread. Poll it with
+ * [LoadHandle::try_finish] once a frame. Dropping the handle cancels
+ * the load.
+ */
+pub struct LoadHandle {
+    path: PathBuf,
+    cancel: Arc<AtomicBool>,
+    progress: Arc<Mutex<LoadProgress>>,
+    result: mpsc::Receiver<Result<DecodedModel>>,
+}
+
+impl LoadHandle {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The latest progress. Stages only ever go forward.
+    pub fn progress(&self) -> LoadProgress {
+        *self.progress.lock().unwrap()
+    }
+
+    /// Stops the load before its next work item. It'll finish with
+    /// [Cancelled].
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// `None` while the load is still going
+    pub fn try_finish(&self) -> Option<Result<DecodedModel>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(anyhow!("The loader thread for {} panicked", self.path.display()))),
+        }
+    }
+}
+
+impl Drop for LoadHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assets::test::Fixture;
+    use crate::material_layout::MaterialLayoutDesc;
+
+    /// An OBJ with one material and two textures
+    fn quad(fixture: &Fixture) -> PathBuf {
+        fixture.write("quad.mtl", "\
+            newmtl brick\n\
+            map_Kd diffuse.png\n\
+            map_Bump normal.png\n");
+        for file in &["diffuse.png", "normal.png"] {
+            image::RgbaImage::new(2, 2).save(fixture.root.join(file)).unwrap();
+        }
+        fixture.write("quad.obj", "\
+            mtllib quad.mtl\n\
+            v 0 0 0\n\
+            v 1 0 0\n\
+            v 0 1 0\n\
+            vt 0 0\n\
+            vt 1 0\n\
+            vt 0 1\n\
+            usemtl brick\n\
+            f 1/1 2/2 3/3\n")
+    }
+
+    fn load(path: &Path, cancel_at: Option<(LoadStage, usize)>) -> (Result<DecodedModel>, Vec<LoadProgress>) {
+        let cancel = AtomicBool::new(false);
+        let mut seen = Vec::new();
+        let result = load_in_steps(
+            path,
+            &Default::default(),
+            &Default::default(),
+            &cancel,
+            &mut |p| {
+                if cancel_at == Some((p.stage, p.done)) {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                seen.push(p);
+            },
+        );
+        (result, seen)
+    }
+
+    #[test]
+    fn progress_goes_forward() {
+        let fixture = Fixture::new();
+        let (result, seen) = load(&quad(&fixture), None);
+        let decoded = result.unwrap();
+        assert_eq!(decoded.images.len(), 2);
+
+        let steps = seen.iter().map(|p| (p.stage, p.done, p.total)).collect::<Vec<_>>();
+        assert_eq!(steps, vec![
+            (LoadStage::Parsing, 0, 1),
+            (LoadStage::Parsing, 1, 1),
+            (LoadStage::Textures, 0, 2),
+            (LoadStage::Textures, 1, 2),
+            (LoadStage::Textures, 2, 2),
+        ]);
+        assert!(seen.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+        assert!(seen.last().unwrap().bytes > 0);
+        assert_eq!(decoded.bytes, seen.last().unwrap().bytes);
+    }
+
+    #[test]
+    fn uploading_reports_its_stage() {
+        let (device, _queue) = match crate::thumbnail::test_device() {
+            Some(device) => device,
+            None => return,
+        };
+        let fixture = Fixture::new();
+        let decoded = load(&quad(&fixture), None).0.unwrap();
+        let layout = MaterialLayout::new(&device, MaterialLayoutDesc::default());
+        let mut textures = TextureCache::new();
+        let mut seen = Vec::new();
+        decoded.upload_in_steps(&device, &layout, &mut textures, &mut |p| seen.push(p)).unwrap();
+
+        let steps = seen.iter().map(|p| (p.stage, p.done, p.total)).collect::<Vec<_>>();
+        assert_eq!(steps, vec![
+            (LoadStage::Upload, 0, 3),
+            (LoadStage::Upload, 1, 3),
+            (LoadStage::Upload, 2, 3),
+            (LoadStage::Upload, 3, 3),
+        ]);
+        assert!(seen.iter().all(|p| p.bytes == decoded.bytes));
+        assert_eq!(textures.len(), 2);
+    }
+
+    #[test]
+    fn cancelling_stops_between_textures() {
+        let fixture = Fixture::new();
+        let (result, seen) = load(&quad(&fixture), Some((LoadStage::Textures, 1)));
+        let error = result.err().unwrap();
+        assert!(error.is::<Cancelled>());
+        // The second texture never got started
+        assert_eq!(seen.last().map(|p| (p.stage, p.done)), Some((LoadStage::Textures, 1)));
+    }
+}
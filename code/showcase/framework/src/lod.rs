@@ -0,0 +1,240 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::model::MeshData;
+
+/**
+ * A cheaper version of a mesh, as a range of the mesh's index buffer.
+ * The indices point into the same vertex buffer as the full mesh, so
+ * switching levels is only a different range in the draw call. Level
+ * 0 is the mesh itself and doesn't get one of these.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeshLod {
+    pub first_index: u32,
+    pub index_count: u32,
+}
+
+/// `"Rock_LOD2"` is level 2 of `"Rock"`. The suffix is what most
+/// exporters write, in any case. `_LOD0` is the full mesh, and there
+/// can be up to 9 more.
+pub fn lod_level(name: &str) -> Option<(&str, usize)> {
+    let at = name.len().checked_sub(5)?;
+    let (base, suffix) = (name.get(..at)?, name.get(at..)?);
+    if !suffix.get(..4)?.eq_ignore_ascii_case("_lod") {
+        return None;
+    }
+    let level = suffix.get(4..)?.parse().ok()?;
+    Some((base, level))
+}
+
+/**
+ * Folds meshes named like `Rock_LOD1` into `Rock`, appending their
+ * vertices and indices after the mesh's own and adding a [MeshLod] for
+ * each. `Rock_LOD0` just becomes `Rock`. Levels whose mesh can't be
+ * found stay as meshes of their own. A level uses the full mesh's
+ * material and AABB, whatever the file says.
+ */
+pub fn fold_lods(meshes: Vec<MeshData>) -> Vec<MeshData> {
+    let mut kept = Vec::new();
+    let mut levels = Vec::new();
+    for mut mesh in meshes {
+        match lod_level(&mesh.name).map(|(base, level)| (base.to_string(), level)) {
+            Some((base, 0)) => {
+                mesh.name = base;
+                kept.push(mesh);
+            }
+            Some((base, level)) => levels.push((base, level, mesh)),
+            None => kept.push(mesh),
+        }
+    }
+    // In level order, so each mesh's lods come out in order too
+    levels.sort_by_key(|(_, level, _)| *level);
+    for (base, level, lod) in levels {
+        let mesh = match kept.iter_mut().find(|m| m.name == base && m.lods.len() + 1 == level) {
+            Some(mesh) => mesh,
+            None => {
+                if kept.iter().any(|m| m.name == base) {
+                    log::warn!("{} skips a level of detail, so it's drawn as its own mesh", lod.name);
+                }
+                kept.push(lod);
+                continue;
+            }
+        };
+        let offset = mesh.vertices.len() as u32;
+        mesh.lods.push(MeshLod {
+            first_index: mesh.indices.len() as u32,
+            index_count: lod.indices.len() as u32,
+        });
+        mesh.vertices.extend(lod.vertices);
+        mesh.indices.extend(lod.indices.iter().map(|i| i + offset));
+    }
+    kept
+}
+
+/**
+ * How one pass picks levels of detail. Each halving of a mesh's size on
+ * screen (or in the shadow map) moves it down a level, then `bias`
+ * more. Passes that don't need detail, like shadows, can afford to be
+ * much pickier than the main pass.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LodPolicy {
+    /// Extra levels to drop on top of what the size asks for
+    pub bias: usize,
+    /// Never draw anything more detailed than this
+    pub min_level: usize,
+    /// Meshes smaller than this many pixels or texels across are
+    /// skipped outright. 0 draws everything.
+    pub min_size: f32,
+    /// Meshes at least this many pixels or texels across get level 0
+    /// (before `bias` and `min_level`)
+    pub full_detail_size: f32,
+}
+
+impl Default for LodPolicy {
+    fn default() -> Self {
+        Self {
+            bias: 0,
+            min_level: 0,
+            min_size: 0.0,
+            full_detail_size: 256.0,
+        }
+    }
+}
+
+impl LodPolicy {
+    /// Shadow maps rarely need the density, so never level 0, drop an
+    /// extra level and skip anything under a couple of texels
+    pub fn shadow() -> Self {
+        Self {
+            bias: 1,
+            min_level: 1,
+            min_size: 2.0,
+            ..Default::default()
+        }
+    }
+
+    /**
+     * The level to draw a mesh with `lod_count` extra levels at, when
+     * it's `size` pixels or texels across. None means skip it.
+     */
+    pub fn select(&self, lod_count: usize, size: f32) -> Option<usize> {
+        if size < self.min_size {
+            return None;
+        }
+        let by_size = if size >= self.full_detail_size || size <= 0.0 {
+            0
+        } else {
+            (self.full_detail_size / size).log2().floor() as usize
+        };
+        Some((by_size + self.bias).max(self.min_level).min(lod_count))
+    }
+}
+
+/// How many pixels across a sphere of `radius` is, `distance` away
+/// from a perspective camera
+pub fn projected_size(radius: f32, distance: f32, fovy: Rad<f32>, viewport_height: u32) -> f32 {
+    if distance <= radius {
+        return f32::INFINITY;
+    }
+    radius / (distance * (fovy.0 * 0.5).tan()) * viewport_height as f32
+}
+
+/// The same for an orthographic view covering `half_extent` either
+/// side of the middle, like a directional light's shadow map
+pub fn projected_size_ortho(radius: f32, half_extent: f32, resolution: u32) -> f32 {
+    radius / half_extent * resolution as f32
+}
+
+/// What a pass drew, for [crate::Profiler::record_counter]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LodStats {
+    pub triangles: u64,
+    /// Meshes [LodPolicy::select] said to skip
+    pub skipped: u64,
+}
+
+impl LodStats {
+    /// Counts a draw of `index_count` indices, `instances` times
+    pub fn add(&mut self, index_count: u32, instances: u32) {
+        self.triangles += index_count as u64 / 3 * instances as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bounds::Aabb;
+    use crate::inspect::AttributeSource;
+    use crate::model::ModelVertex;
+
+    fn mesh(name: &str, triangles: u32) -> MeshData {
+        let vertices = (0..triangles * 3)
+            .map(|i| ModelVertex {
+                position: [i as f32, 0.0, 0.0].into(),
+                tex_coords: [0.0; 2].into(),
+                normal: [0.0, 1.0, 0.0].into(),
+                tangent: [0.0; 4].into(),
+            })
+            .collect::<Vec<_>>();
+        MeshData {
+            name: name.to_string(),
+            aabb: Aabb::from_points(vertices.iter().map(|v| Point3::from_vec(v.position))),
+            indices: (0..vertices.len() as u32).collect(),
+            vertices,
+            material: 0,
+            has_tex_coords: false,
+            normals: AttributeSource::File,
+            tangents: AttributeSource::Missing,
+            placements: Vec::new(),
+            lods: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn lod_meshes_fold_into_ranges() {
+        assert_eq!(lod_level("Rock_LOD2"), Some(("Rock", 2)));
+        assert_eq!(lod_level("rock_lod0"), Some(("rock", 0)));
+        assert_eq!(lod_level("Rock"), None);
+        assert_eq!(lod_level("Rock_LODx"), None);
+
+        let folded = fold_lods(vec![
+            mesh("Rock_LOD2", 1),
+            mesh("Rock_LOD0", 8),
+            mesh("Tree", 4),
+            mesh("Rock_LOD1", 3),
+            mesh("Bush_LOD1", 2),
+        ]);
+        let names = folded.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Rock", "Tree", "Bush_LOD1"]);
+
+        let rock = &folded[0];
+        assert_eq!(rock.lods, vec![
+            MeshLod { first_index: 24, index_count: 9 },
+            MeshLod { first_index: 33, index_count: 3 },
+        ]);
+        assert_eq!(rock.lod0_indices().len(), 24);
+        assert_eq!(rock.vertices.len(), (8 + 3 + 1) * 3);
+        // Level 2's indices got moved past everything before them
+        assert_eq!(rock.indices[33], 33);
+        assert!(folded[1].lods.is_empty());
+    }
+
+    #[test]
+    fn shadows_pick_coarser_levels_than_the_main_pass() {
+        let main = LodPolicy::default();
+        let shadow = LodPolicy::shadow();
+        assert_eq!(main.select(3, 1000.0), Some(0));
+        assert_eq!(main.select(3, 64.0), Some(2));
+        assert_eq!(main.select(3, 0.5), Some(3));
+        // Even up close, and no further than the mesh has
+        assert_eq!(shadow.select(3, 1000.0), Some(1));
+        assert_eq!(shadow.select(3, 64.0), Some(3));
+        assert_eq!(shadow.select(0, 1000.0), Some(0));
+        assert_eq!(shadow.select(3, 1.0), None);
+
+        let size = projected_size(1.0, 10.0, Deg(90.0).into(), 1000);
+        assert!((size - 100.0).abs() < 1e-3, "{}", size);
+        assert!((projected_size_ortho(1.0, 50.0, 2048) - 40.96).abs() < 1e-3);
+    }
+}
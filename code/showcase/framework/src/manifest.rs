@@ -0,0 +1,330 @@
+//! This file is also pulled into the build scripts with `#[path]`, so
+//! it can only use std.
+
+use std::fmt;
+use std::path::Path;
+
+/// The first line of every manifest
+const HEADER: &str = "# learn-wgpu asset manifest";
+
+/**
+ * 64 bit FNV-1a. Nowhere near good enough to keep anyone out, but it's
+ * plenty for telling whether a file changed since the build.
+ */
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// The way manifests spell `path`, whatever the platform
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").trim_start_matches("./").to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetEntry {
+    /// Relative to the folder that was scanned, with `/` between folders
+    pub path: String,
+    pub size: u64,
+    /// See [content_hash]
+    pub hash: u64,
+}
+
+impl AssetEntry {
+    pub fn from_bytes(path: &str, bytes: &[u8]) -> Self {
+        Self { path: normalize(path), size: bytes.len() as u64, hash: content_hash(bytes) }
+    }
+}
+
+/// A line of a manifest that couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestError {
+    /// Starts at 1
+    pub line: usize,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {} of the asset manifest is malformed", self.line)
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/**
+ * Every file under a folder when the build ran, with its size and
+ * hash. The viewer's build script writes one for `res/` and embeds
+ * it, so a missing or renamed file gets caught at startup by
+ * [AssetManifest::check] rather than halfway through loading.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetManifest {
+    /// Sorted by path
+    pub entries: Vec<AssetEntry>,
+}
+
+impl AssetManifest {
+    /// Reads every file under `root`, however deep
+    pub fn scan<P: AsRef<Path>>(root: P) -> std::io::Result<Self> {
+        let root = root.as_ref();
+        let mut entries = Vec::new();
+        let mut folders = vec![root.to_path_buf()];
+        while let Some(folder) = folders.pop() {
+            for entry in std::fs::read_dir(&folder)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    folders.push(path);
+                    continue;
+                }
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                entries.push(AssetEntry::from_bytes(&relative.to_string_lossy(), &std::fs::read(&path)?));
+            }
+        }
+        Ok(Self::new(entries))
+    }
+
+    pub fn new(mut entries: Vec<AssetEntry>) -> Self {
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Self { entries }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&AssetEntry> {
+        let path = normalize(path);
+        self.entries
+            .binary_search_by(|e| e.path.as_str().cmp(&path))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    /// One `hash size path` line per file, after a header
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", HEADER);
+        for entry in &self.entries {
+            text += &format!("{:016x} {} {}\n", entry.hash, entry.size, entry.path);
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ManifestError> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            entries.push(parse_line(line).ok_or(ManifestError { line: i + 1 })?);
+        }
+        Ok(Self::new(entries))
+    }
+
+    /// Checks `required` against the files under `root`
+    pub fn check<P: AsRef<Path>>(&self, root: P, required: &[&str]) -> AssetProblems {
+        let root = root.as_ref();
+        self.check_with(required, |path| std::fs::read(root.join(path)).ok())
+    }
+
+    /**
+     * Looks at every file in `required`, using `read` to get what's
+     * there now (`None` for missing), and reports everything that's
+     * wrong at once.
+     */
+    pub fn check_with<F>(&self, required: &[&str], mut read: F) -> AssetProblems
+    where
+        F: FnMut(&str) -> Option<Vec<u8>>,
+    {
+        let mut problems = AssetProblems::default();
+        for path in required {
+            let expected = match self.get(path) {
+                Some(expected) => expected,
+                None => {
+                    problems.unknown.push(normalize(path));
+                    continue;
+                }
+            };
+            match read(&expected.path) {
+                None => problems.missing.push(expected.path.clone()),
+                Some(bytes) => {
+                    if AssetEntry::from_bytes(&expected.path, &bytes) != *expected {
+                        problems.modified.push(expected.path.clone());
+                    }
+                }
+            }
+        }
+        problems
+    }
+}
+
+fn parse_line(line: &str) -> Option<AssetEntry> {
+    // The path goes last, so it can have spaces in it
+    let mut parts = line.splitn(3, ' ');
+    let hash = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let size = parts.next()?.parse().ok()?;
+    let path = normalize(parts.next()?);
+    Some(AssetEntry { path, size, hash })
+}
+
+/// What [AssetManifest::check] found wrong
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetProblems {
+    pub missing: Vec<String>,
+    /// There, but not what was there when the manifest was made
+    pub modified: Vec<String>,
+    /// Not in the manifest at all, usually because it was renamed
+    /// before building
+    pub unknown: Vec<String>,
+}
+
+impl AssetProblems {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty() && self.unknown.is_empty()
+    }
+
+    /// `Err(self)` unless there's nothing wrong, for use with `?`
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for AssetProblems {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Missing or modified assets:")?;
+        let kinds = [
+            ("missing", &self.missing),
+            ("modified since the build", &self.modified),
+            ("not part of the build", &self.unknown),
+        ];
+        for (kind, paths) in kinds.iter() {
+            for path in paths.iter() {
+                write!(f, "\n  {} ({})", path, kind)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssetProblems {}
+
+/**
+ * Files packed into the binary, by the same paths as an
+ * [AssetManifest]. See [crate::Model::load_from_memory].
+ */
+#[derive(Debug, Copy, Clone)]
+pub struct EmbeddedAssets {
+    files: &'static [(&'static str, &'static [u8])],
+}
+
+impl EmbeddedAssets {
+    pub fn new(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        Self { files }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&'static [u8]> {
+        let path = normalize(path);
+        self.files.iter().find(|(p, _)| *p == path).map(|(_, bytes)| *bytes)
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &'static str> {
+        self.files.iter().map(|(path, _)| *path)
+    }
+
+    /// Checks `required` against what got embedded
+    pub fn check(&self, manifest: &AssetManifest, required: &[&str]) -> AssetProblems {
+        manifest.check_with(required, |path| self.get(path).map(|bytes| bytes.to_vec()))
+    }
+
+    /**
+     * What a build script writes for [EmbeddedAssets::new] to take:
+     * a slice of `include_bytes!` for every file in `manifest`, which
+     * was scanned from `root`.
+     */
+    pub fn source(manifest: &AssetManifest, root: &Path) -> String {
+        let mut source = "&[\n".to_string();
+        for entry in &manifest.entries {
+            let file = root.join(&entry.path);
+            source += &format!("    ({:?}, include_bytes!({:?})),\n", entry.path, file.to_string_lossy());
+        }
+        source += "]\n";
+        source
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn manifest() -> AssetManifest {
+        AssetManifest::new(vec![
+            AssetEntry::from_bytes("cube.obj", b"v 0 0 0"),
+            AssetEntry::from_bytes("textures\\cube-diffuse.jpg", b"not really a jpeg"),
+        ])
+    }
+
+    #[test]
+    fn manifests_round_trip() {
+        let manifest = manifest();
+        assert_eq!(AssetManifest::parse(&manifest.to_text()), Ok(manifest.clone()));
+        assert_eq!(manifest.get("textures/cube-diffuse.jpg").map(|e| e.size), Some(17));
+        assert_eq!(AssetManifest::parse("# header\n0123 nope cube.obj\n"), Err(ManifestError { line: 2 }));
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let manifest = manifest();
+        let root = std::env::temp_dir().join(format!("learn-wgpu-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        // The texture is there, but not what the build saw
+        std::fs::create_dir_all(root.join("textures")).unwrap();
+        std::fs::write(root.join("textures/cube-diffuse.jpg"), b"not really a jpeg either").unwrap();
+        let problems = manifest.check(&root, &["cube.obj", "textures/cube-diffuse.jpg", "Cube.obj"]);
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(problems.missing, vec!["cube.obj"]);
+        assert_eq!(problems.modified, vec!["textures/cube-diffuse.jpg"]);
+        assert_eq!(problems.unknown, vec!["Cube.obj"]);
+        let message = problems.to_string();
+        assert!(message.contains("cube.obj (missing)"), "{}", message);
+        assert!(message.contains("textures/cube-diffuse.jpg (modified since the build)"), "{}", message);
+
+        static FILES: &[(&str, &[u8])] = &[("cube.obj", b"v 0 0 0")];
+        let embedded = EmbeddedAssets::new(FILES);
+        assert!(embedded.check(&manifest, &["cube.obj"]).is_empty());
+        assert_eq!(embedded.check(&manifest, &["textures/cube-diffuse.jpg"]).missing.len(), 1);
+    }
+
+    #[test]
+    fn embedded_cube_finds_its_textures() {
+        use crate::assets::ResolveStrategy;
+        use crate::model::{ModelData, ModelLoadOptions};
+        use crate::texture::TextureCache;
+        use image::GenericImageView;
+
+        static FILES: &[(&str, &[u8])] = &[
+            ("models/cube.obj", include_bytes!("../../viewer/res/cube.obj")),
+            ("models/cube.mtl", include_bytes!("../../viewer/res/cube.mtl")),
+            ("models/cube-diffuse.jpg", include_bytes!("../../viewer/res/cube-diffuse.jpg")),
+            ("models/cube-normal.png", include_bytes!("../../viewer/res/cube-normal.png")),
+        ];
+        let assets = EmbeddedAssets::new(FILES);
+        let data = ModelData::load_from_memory("models/cube.obj", &assets, &ModelLoadOptions::default()).unwrap();
+        assert!(!data.meshes.is_empty());
+        let material = &data.materials[0];
+        assert_eq!(material.diffuse.path, Path::new("models/cube-diffuse.jpg"));
+        assert_eq!(material.normal.path, Path::new("models/cube-normal.png"));
+        assert_eq!(material.diffuse.strategy, ResolveStrategy::Embedded);
+
+        // Nothing's at those paths on disk, so only the embedded
+        // cache can open them
+        let mut textures = TextureCache::new();
+        assert!(textures.open(&material.diffuse.path).is_err());
+        textures.embedded = Some(assets);
+        for source in &[&material.diffuse, &material.normal] {
+            let image = textures.open(&source.path).unwrap();
+            assert!(image.width() > 0, "{}", source.path.display());
+        }
+
+        let missing = ModelData::load_from_memory("cube.obj", &assets, &ModelLoadOptions::default());
+        assert_eq!(missing.err().unwrap().to_string(), "cube.obj isn't embedded");
+    }
+}
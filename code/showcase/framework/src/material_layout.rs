@@ -0,0 +1,199 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// What goes in a binding of a material's bind group
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MaterialSlot {
+    DiffuseTexture,
+    DiffuseSampler,
+    NormalTexture,
+    NormalSampler,
+    /// See [crate::ChannelPacking]
+    PackedTexture,
+    PackedSampler,
+    /// The [crate::MaterialParams] uniform buffer
+    Params,
+}
+
+impl MaterialSlot {
+    fn binding_type(self) -> wgpu::BindingType {
+        match self {
+            MaterialSlot::DiffuseTexture | MaterialSlot::NormalTexture | MaterialSlot::PackedTexture => {
+                wgpu::BindingType::SampledTexture {
+                    multisampled: false,
+                    component_type: wgpu::TextureComponentType::Float,
+                    dimension: wgpu::TextureViewDimension::D2,
+                }
+            }
+            MaterialSlot::DiffuseSampler | MaterialSlot::NormalSampler | MaterialSlot::PackedSampler => {
+                wgpu::BindingType::Sampler { comparison: false }
+            }
+            MaterialSlot::Params => wgpu::BindingType::UniformBuffer { dynamic: false },
+        }
+    }
+}
+
+/**
+ * Describes the bindings of the material group (group 0). Both the
+ * pipeline layout and every [crate::Material] bind group get built
+ * from one of these, so they can't drift apart.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MaterialLayoutDesc {
+    /// `(binding, slot)` pairs
+    pub entries: Vec<(u32, MaterialSlot)>,
+}
+
+impl Default for MaterialLayoutDesc {
+    /// The layout `shader.frag` expects
+    fn default() -> Self {
+        Self {
+            entries: vec![
+                (0, MaterialSlot::DiffuseTexture),
+                (1, MaterialSlot::DiffuseSampler),
+                (2, MaterialSlot::NormalTexture),
+                (3, MaterialSlot::NormalSampler),
+                (4, MaterialSlot::Params),
+                (5, MaterialSlot::PackedTexture),
+                (6, MaterialSlot::PackedSampler),
+            ],
+        }
+    }
+}
+
+impl MaterialLayoutDesc {
+    /// Two descriptions with the same entries get the same id
+    pub fn id(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn slot(&self, binding: u32) -> Option<MaterialSlot> {
+        self.entries.iter().find(|(b, _)| *b == binding).map(|(_, slot)| *slot)
+    }
+
+    /**
+     * Checks that a material built with `material` can be used where
+     * this layout is expected. The error names the material and the
+     * first binding that doesn't match.
+     */
+    pub fn check(&self, material_name: &str, material: &MaterialLayoutDesc) -> Result<(), MaterialLayoutError> {
+        if self.id() == material.id() {
+            return Ok(());
+        }
+        let mut bindings = self.entries.iter()
+            .chain(&material.entries)
+            .map(|(b, _)| *b)
+            .collect::<Vec<_>>();
+        bindings.sort();
+        bindings.dedup();
+        for binding in bindings {
+            let expected = self.slot(binding);
+            let found = material.slot(binding);
+            if expected != found {
+                return Err(MaterialLayoutError {
+                    material: material_name.to_string(),
+                    binding,
+                    expected,
+                    found,
+                });
+            }
+        }
+        // Same bindings listed in a different order. wgpu doesn't
+        // care about that.
+        Ok(())
+    }
+}
+
+/// See [MaterialLayoutDesc::check]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaterialLayoutError {
+    pub material: String,
+    pub binding: u32,
+    /// What the pipeline wants. None if it doesn't use the binding.
+    pub expected: Option<MaterialSlot>,
+    /// What the material has. None if it's missing.
+    pub found: Option<MaterialSlot>,
+}
+
+impl fmt::Display for MaterialLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let describe = |slot: Option<MaterialSlot>| match slot {
+            Some(slot) => format!("{:?}", slot),
+            None => "nothing".to_string(),
+        };
+        write!(
+            f,
+            "Material {:?} doesn't match the pipeline's layout: binding {} should be {}, but the material has {}",
+            self.material,
+            self.binding,
+            describe(self.expected),
+            describe(self.found),
+        )
+    }
+}
+
+impl std::error::Error for MaterialLayoutError {}
+
+/**
+ * A [MaterialLayoutDesc] and the `wgpu::BindGroupLayout` made from it.
+ * Pass this to [crate::Model::load] and use [MaterialLayout::layout] as
+ * group 0 of the pipeline layout.
+ */
+pub struct MaterialLayout {
+    pub desc: MaterialLayoutDesc,
+    pub layout: wgpu::BindGroupLayout,
+}
+
+impl MaterialLayout {
+    pub fn new(device: &wgpu::Device, desc: MaterialLayoutDesc) -> Self {
+        let entries = desc.entries.iter()
+            .map(|&(binding, slot)| wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: slot.binding_type(),
+            })
+            .collect::<Vec<_>>();
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &entries,
+                label: Some("MaterialLayout::layout"),
+            }
+        );
+        Self { desc, layout }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.desc.id()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mismatched_material_names_the_binding() {
+        let pipeline = MaterialLayoutDesc::default();
+        // Someone forgot the params buffer
+        let mut material = MaterialLayoutDesc::default();
+        material.entries.retain(|(_, slot)| *slot != MaterialSlot::Params);
+
+        assert_ne!(pipeline.id(), material.id());
+        let error = pipeline.check("brick", &material).unwrap_err();
+        assert_eq!(error, MaterialLayoutError {
+            material: "brick".to_string(),
+            binding: 4,
+            expected: Some(MaterialSlot::Params),
+            found: None,
+        });
+        assert_eq!(
+            error.to_string(),
+            "Material \"brick\" doesn't match the pipeline's layout: binding 4 should be Params, but the material has nothing",
+        );
+
+        assert_eq!(pipeline.check("brick", &MaterialLayoutDesc::default()), Ok(()));
+    }
+}
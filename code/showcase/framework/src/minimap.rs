@@ -0,0 +1,476 @@
+use anyhow::*;
+use cgmath::*;
+use crate::blur::BlurPass;
+use crate::bounds::Aabb;
+use crate::camera::{OrbitCamera, OPENGL_TO_WGPU_MATRIX};
+use crate::capabilities::is_srgb_format;
+use crate::palette::{DebugColor, Palette};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::settings::RenderSettings;
+use crate::texture::Texture;
+use crate::{UniformBinding, Uniforms};
+
+/// Width and height of the offscreen target, in pixels
+pub const MINIMAP_SIZE: u32 = 256;
+
+/// Gap between the minimap and the corner of the window, in pixels
+const MINIMAP_MARGIN: f32 = 16.0;
+
+/// How much bigger than the scene the map is, so things at the edge
+/// aren't cut off
+const MINIMAP_PADDING: f32 = 1.1;
+
+/// How far the map's colors spread into the border, in map pixels
+const MINIMAP_GLOW_RADIUS: usize = 8;
+
+/**
+ * A top down orthographic view of a box, looking down -Y with -Z at
+ * the top of the map. Map coordinates are UVs: (0, 0) is the top left
+ * corner and (1, 1) the bottom right, so +X goes right and +Z goes
+ * down.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MinimapView {
+    /// Middle of the map, at the top of the box
+    pub center: Point3<f32>,
+    /// Half the width of the square of ground that's shown
+    pub half_extent: f32,
+    /// How far down from `center` gets drawn
+    pub depth: f32,
+}
+
+impl MinimapView {
+    pub fn fit(aabb: &Aabb) -> Self {
+        if aabb.is_empty() {
+            return Self { center: Point3::new(0.0, 1.0, 0.0), half_extent: 1.0, depth: 2.0 };
+        }
+        let size = aabb.size();
+        let mid = aabb.center();
+        Self {
+            // A little above the top so nothing touches the near plane
+            center: Point3::new(mid.x, aabb.max.y + 1.0, mid.z),
+            half_extent: (size.x.max(size.z) * 0.5 * MINIMAP_PADDING).max(0.01),
+            depth: size.y + 2.0,
+        }
+    }
+
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        let below = self.center - Vector3::unit_y();
+        Matrix4::look_at(self.center, below, -Vector3::unit_z())
+    }
+
+    pub fn proj_matrix(&self) -> Matrix4<f32> {
+        let h = self.half_extent;
+        OPENGL_TO_WGPU_MATRIX * ortho(-h, h, -h, h, 0.0, self.depth)
+    }
+
+    /// Where `point` ends up on the map. Height is ignored.
+    pub fn world_to_uv(&self, point: Point3<f32>) -> Point2<f32> {
+        Point2::new(
+            0.5 + (point.x - self.center.x) / (2.0 * self.half_extent),
+            0.5 + (point.z - self.center.z) / (2.0 * self.half_extent),
+        )
+    }
+
+    /// The point on the map at `uv`, at height `y`
+    pub fn uv_to_world(&self, uv: Point2<f32>, y: f32) -> Point3<f32> {
+        Point3::new(
+            self.center.x + (uv.x - 0.5) * 2.0 * self.half_extent,
+            y,
+            self.center.z + (uv.y - 0.5) * 2.0 * self.half_extent,
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct MinimapUniform {
+    /// Where the map goes on screen, as min and max in NDC
+    rect: [f32; 4],
+    /// xy is the camera's position on the map, zw which way it faces
+    marker: [f32; 4],
+    /// rgb is the color, w the width as a fraction of the map
+    border: [f32; 4],
+    /// rgb is the color of the camera arrow. w is 1 when the shader has
+    /// to sRGB encode, as the output format doesn't.
+    marker_color: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for MinimapUniform {}
+unsafe impl bytemuck::Zeroable for MinimapUniform {}
+
+/**
+ * Draws the scene from above into a small offscreen target, and puts
+ * that in the top right corner of the screen with a border and an
+ * arrow for the camera. The border glows with a blur of whatever's on
+ * the map next to it.
+ *
+ * The scene is drawn by the demo, as only it knows what's in it. Use
+ * [Minimap::begin_scene_pass] with [Minimap::view_bind_group] as the
+ * per-view group, then [Minimap::end_scene_pass] once the pass is
+ * done, then [Minimap::composite] after tonemapping. The
+ * scene pass only needs to happen every [Minimap::interval] frames,
+ * see [Minimap::should_redraw].
+ */
+pub struct Minimap<'a> {
+    pub view: MinimapView,
+    /// Frames between redraws of the map. 1 redraws every frame.
+    pub interval: u32,
+    /// Where the camera arrow's color comes from
+    pub palette: Palette,
+    frames_since_redraw: u32,
+    uniforms: Uniforms,
+    view_bind_group: wgpu::BindGroup,
+    color: Texture<'a>,
+    depth: Texture<'a>,
+    /// The map blurred, for the border
+    glow: Texture<'a>,
+    blur: BlurPass<'a>,
+    pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    encode_srgb: bool,
+}
+
+impl<'a> Minimap<'a> {
+    /// `view_binding` is the main camera's, so the map's per-view group
+    /// matches the pipelines it's drawn with
+    pub fn new(
+        device: &wgpu::Device,
+        view_binding: &UniformBinding,
+        settings: &RenderSettings,
+    ) -> Result<Self> {
+        let extent = wgpu::Extent3d { width: MINIMAP_SIZE, height: MINIMAP_SIZE, depth: 1 };
+        let color = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("Minimap::color"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::HDR_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let depth = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("Minimap::depth"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        });
+        let glow = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("Minimap::glow"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::HDR_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let blur = BlurPass::new(device, Texture::HDR_FORMAT)?;
+
+        let mut uniforms = Uniforms::new(device);
+        uniforms.update_viewport(MINIMAP_SIZE, MINIMAP_SIZE);
+        let view_bind_group = view_binding.bind_group_for(device, &uniforms);
+
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                    },
+                ],
+                label: Some("Minimap::layout"),
+            }
+        );
+        // The texture's own sampler is a comparison sampler, the same
+        // as for TonemapPass
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let uniform_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[MinimapUniform {
+                rect: [0.0; 4],
+                marker: [0.0; 4],
+                border: [0.0; 4],
+                marker_color: [0.0; 4],
+            }]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&color.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &uniform_buffer,
+                        range: 0..std::mem::size_of::<MinimapUniform>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&glow.view),
+                },
+            ],
+            label: Some("Minimap::bind_group"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&layout],
+            }
+        );
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .color_output(settings)
+            .vertex_shader(include_bytes!("shaders/minimap.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/minimap.frag.spv"))
+            .build(device)?;
+
+        Ok(Self {
+            view: MinimapView::fit(&Aabb::empty()),
+            interval: 4,
+            palette: Palette::default(),
+            // So the first frame draws it
+            frames_since_redraw: u32::MAX,
+            uniforms,
+            view_bind_group,
+            color,
+            depth,
+            glow,
+            blur,
+            pipeline,
+            uniform_buffer,
+            bind_group,
+            encode_srgb: !is_srgb_format(settings.surface_format),
+        })
+    }
+
+    /// Fits the map to `aabb` and redraws it next frame
+    pub fn fit(&mut self, aabb: &Aabb) {
+        self.view = MinimapView::fit(aabb);
+        self.frames_since_redraw = u32::MAX;
+    }
+
+    /// Call once a frame. True when the scene pass is due.
+    pub fn should_redraw(&mut self) -> bool {
+        if self.frames_since_redraw >= self.interval.max(1) - 1 {
+            self.frames_since_redraw = 0;
+            true
+        } else {
+            self.frames_since_redraw += 1;
+            false
+        }
+    }
+
+    /// The per-view group looking down on the map
+    pub fn view_bind_group(&self) -> &wgpu::BindGroup {
+        &self.view_bind_group
+    }
+
+    /// Writes the map's view to the GPU. Call this before
+    /// [Minimap::begin_scene_pass].
+    pub fn update_view(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        self.uniforms.update_matrices(self.view.center, self.view.view_matrix(), self.view.proj_matrix());
+        self.uniforms.update_buffer(device, encoder);
+    }
+
+    /// Clears the map's targets, ready for the scene to be drawn
+    pub fn begin_scene_pass<'b>(
+        &'b self,
+        encoder: &'b mut wgpu::CommandEncoder,
+        clear_color: wgpu::Color,
+    ) -> wgpu::RenderPass<'b> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &self.color.view,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Clear,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color,
+                }
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.depth.view,
+                depth_load_op: wgpu::LoadOp::Clear,
+                depth_store_op: wgpu::StoreOp::Store,
+                clear_depth: 1.0,
+                stencil_load_op: wgpu::LoadOp::Clear,
+                stencil_store_op: wgpu::StoreOp::Store,
+                clear_stencil: 0,
+            }),
+        })
+    }
+
+    /// Blurs what [Minimap::begin_scene_pass] drew, for the border
+    pub fn end_scene_pass(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        self.blur.run(device, encoder, &self.color, &self.glow.view, MINIMAP_GLOW_RADIUS);
+    }
+
+    /// Where the map is drawn on a `width` by `height` window: the top
+    /// left corner and the size, in pixels. It shrinks on small windows.
+    pub fn screen_rect(width: u32, height: u32) -> (f32, f32, f32) {
+        let size = (MINIMAP_SIZE as f32).min(width.min(height) as f32 / 3.0);
+        (width as f32 - MINIMAP_MARGIN - size, MINIMAP_MARGIN, size)
+    }
+
+    /// The map position under the pixel `(x, y)`, if it's on the map
+    pub fn hit(x: f32, y: f32, width: u32, height: u32) -> Option<Point2<f32>> {
+        let (left, top, size) = Self::screen_rect(width, height);
+        let uv = Point2::new((x - left) / size, (y - top) / size);
+        if uv.x >= 0.0 && uv.x <= 1.0 && uv.y >= 0.0 && uv.y <= 1.0 {
+            Some(uv)
+        } else {
+            None
+        }
+    }
+
+    /// `camera` moved so it looks at the point under `uv`, keeping its
+    /// angle and distance
+    pub fn teleport(&self, camera: &OrbitCamera, uv: Point2<f32>) -> OrbitCamera {
+        OrbitCamera {
+            target: self.view.uv_to_world(uv, camera.target.y),
+            ..*camera
+        }
+    }
+
+    /// Draws the map over `output`, with an arrow showing where
+    /// `camera` is and which way it's facing
+    pub fn composite(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+        camera: &OrbitCamera,
+    ) {
+        let (left, top, size) = Self::screen_rect(width, height);
+        let to_ndc = |x: f32, y: f32| [x / width as f32 * 2.0 - 1.0, 1.0 - y / height as f32 * 2.0];
+        let min = to_ndc(left, top + size);
+        let max = to_ndc(left + size, top);
+
+        let eye = self.view.world_to_uv(camera.eye());
+        let facing = -camera.direction();
+        let encode_srgb = if self.encode_srgb { 1.0 } else { 0.0 };
+        let uniform = MinimapUniform {
+            rect: [min[0], min[1], max[0], max[1]],
+            marker: [eye.x, eye.y, facing.x, facing.z],
+            border: [0.9, 0.9, 0.9, 2.0 / size],
+            marker_color: self.palette.color(DebugColor::CameraMarker).extend(encode_srgb).into(),
+        };
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[uniform]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.uniform_buffer,
+            0,
+            std::mem::size_of::<MinimapUniform>() as _,
+        );
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Load,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..6, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn map_coordinates_match_the_projection() {
+        let aabb = Aabb::from_points(vec![Point3::new(-4.0, 0.0, -1.0), Point3::new(2.0, 3.0, 5.0)]);
+        let view = MinimapView::fit(&aabb);
+        let view_proj = view.proj_matrix() * view.view_matrix();
+
+        for &point in &[Point3::new(-4.0, 0.0, -1.0), Point3::new(2.0, 3.0, 5.0), Point3::new(0.5, 1.0, 0.0)] {
+            let uv = view.world_to_uv(point);
+            // Everything in the box is on the map, and in front of the
+            // camera
+            assert!(uv.x > 0.0 && uv.x < 1.0 && uv.y > 0.0 && uv.y < 1.0, "{:?}", uv);
+            let clip = view_proj * point.to_homogeneous();
+            let ndc = clip.truncate() / clip.w;
+            assert!(ndc.z >= 0.0 && ndc.z <= 1.0, "{:?}", ndc);
+            // NDC has y up, UVs have v down
+            let expected = Point2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+            assert!((uv - expected).magnitude() < 1e-5, "{:?} != {:?}", uv, expected);
+
+            let back = view.uv_to_world(uv, point.y);
+            assert!((back - point).magnitude() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn clicks_only_hit_the_map() {
+        // A third of the height, as 256 pixels would be too big
+        let (left, top, size) = Minimap::screen_rect(1280, 720);
+        assert_eq!((left, top, size), (1024.0, 16.0, 240.0));
+
+        let middle = Minimap::hit(left + 120.0, top + 120.0, 1280, 720).unwrap();
+        assert!((middle - Point2::new(0.5, 0.5)).magnitude() < 1e-6);
+        assert!(Minimap::hit(left - 1.0, top + 1.0, 1280, 720).is_none());
+        assert!(Minimap::hit(100.0, 100.0, 1280, 720).is_none());
+    }
+}
@@ -1,7 +1,26 @@
+use std::collections::HashMap;
 use std::ops::Range;
-use std::path::Path;
+use std::rc::Rc;
+use std::path::{Path, PathBuf};
 use anyhow::*;
+use cgmath::*;
+use serde::{Deserialize, Serialize};
 
+use crate::assets::{PathResolver, ResolveStrategy};
+use crate::bounds::Aabb;
+use crate::cleanup;
+use crate::dedup::{self, AutoInstanceReport, PlacementBuffers};
+use crate::import::ImportTransform;
+use crate::lod::{self, MeshLod};
+use crate::inspect::{AttributeSource, ModelReport};
+use crate::manifest::EmbeddedAssets;
+use crate::material_layout::{MaterialLayout, MaterialLayoutDesc, MaterialLayoutError, MaterialSlot};
+use crate::normals;
+use crate::packing::{pack_files, ChannelPacking, PackedTextureCache};
+use crate::resources::GpuResources;
+use crate::stl;
+use crate::tangent;
+use crate::split;
 use crate::texture;
 
 pub trait Vertex {
@@ -9,13 +28,14 @@ pub trait Vertex {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct ModelVertex {
-    position: cgmath::Vector3<f32>,
-    tex_coords: cgmath::Vector2<f32>,
-    normal: cgmath::Vector3<f32>,
-    tangent: cgmath::Vector3<f32>,
-    bitangent: cgmath::Vector3<f32>,
+    pub(crate) position: cgmath::Vector3<f32>,
+    pub(crate) tex_coords: cgmath::Vector2<f32>,
+    pub(crate) normal: cgmath::Vector3<f32>,
+    /// w is the handedness. The bitangent is
+    /// `cross(normal, tangent.xyz) * tangent.w`.
+    pub(crate) tangent: cgmath::Vector4<f32>,
 }
 
 unsafe impl bytemuck::Zeroable for ModelVertex {}
@@ -43,27 +63,121 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float3,
                 },
-                // Tangent and bitangent
+                // Tangent and handedness
                 wgpu::VertexAttributeDescriptor {
                     offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
                     shader_location: 3,
-                    format: wgpu::VertexFormat::Float3,
-                },
-                wgpu::VertexAttributeDescriptor {
-                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                    format: wgpu::VertexFormat::Float3,
+                    format: wgpu::VertexFormat::Float4,
                 },
             ],
         }
     }
 }
 
+/**
+ * Per material values that aren't textures. This is bound right after
+ * the textures in the material's bind group.
+ */
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaterialParams {
+    /// Non-zero when the fragment shader should ignore the mesh's UVs
+    /// and project the textures along the world axes instead.
+    pub triplanar: u32,
+    /// How many times the texture repeats per world unit when using
+    /// triplanar projection.
+    pub triplanar_scale: f32,
+    /// The specular exponent
+    pub shininess: f32,
+    /// How much light wraps around the silhouette. 0 turns it off.
+    pub rim: f32,
+    /// Multiplies the diffuse texture. Linear RGBA.
+    pub tint: [f32; 4],
+    /// Non-zero to light back faces as if they faced the camera. For
+    /// thin things like leaves and cloth that are only one triangle
+    /// thick, which go black from behind otherwise. This is only about
+    /// shading, culling is still up to the pipeline.
+    pub two_sided_lighting: u32,
+    /// How much light from behind a two sided surface shows through
+    /// on the side facing the camera. 0 turns it off.
+    pub translucency: f32,
+    /// Uniform structs round up to 16 bytes
+    #[serde(skip)]
+    _padding: [f32; 2],
+}
+
+unsafe impl bytemuck::Zeroable for MaterialParams {}
+unsafe impl bytemuck::Pod for MaterialParams {}
+
+impl Default for MaterialParams {
+    fn default() -> Self {
+        Self {
+            triplanar: 0,
+            triplanar_scale: 1.0,
+            shininess: 32.0,
+            rim: 0.0,
+            tint: [1.0; 4],
+            two_sided_lighting: 0,
+            translucency: 0.0,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+impl MaterialParams {
+    pub fn with_triplanar(mut self, triplanar: bool, scale: f32) -> Self {
+        self.triplanar = triplanar as u32;
+        self.triplanar_scale = scale;
+        self
+    }
+}
+
+/// Changes to some of a material's [MaterialParams], like from a scene
+/// file. Anything left as `None` keeps the original's value.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialOverrides {
+    pub shininess: Option<f32>,
+    pub rim: Option<f32>,
+    pub tint: Option<[f32; 4]>,
+    pub two_sided_lighting: Option<bool>,
+    pub translucency: Option<f32>,
+}
+
+impl MaterialOverrides {
+    pub fn apply(&self, mut params: MaterialParams) -> MaterialParams {
+        if let Some(shininess) = self.shininess {
+            params.shininess = shininess;
+        }
+        if let Some(rim) = self.rim {
+            params.rim = rim;
+        }
+        if let Some(tint) = self.tint {
+            params.tint = tint;
+        }
+        if let Some(two_sided) = self.two_sided_lighting {
+            params.two_sided_lighting = two_sided as u32;
+        }
+        if let Some(translucency) = self.translucency {
+            params.translucency = translucency;
+        }
+        params
+    }
+}
+
 pub struct Material<'a> {
     pub name: String,
-    pub diffuse_texture: texture::Texture<'a>,
-    pub normal_texture: texture::Texture<'a>,
+    /// Shared with any other material that uses the same file, see
+    /// [texture::TextureCache]
+    pub diffuse_texture: Rc<texture::Texture<'a>>,
+    pub normal_texture: Rc<texture::Texture<'a>>,
+    /// Occlusion, roughness and metalness, see [ChannelPacking]
+    pub packed_texture: Rc<texture::Texture<'a>>,
+    pub params: MaterialParams,
+    pub params_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+    /// What [Material::bind_group] was built with
+    pub layout: MaterialLayoutDesc,
 }
 
 impl<'a> Material<'a> {
@@ -72,36 +186,256 @@ impl<'a> Material<'a> {
         name: &str, 
         diffuse_texture: texture::Texture<'a>, 
         normal_texture: texture::Texture<'a>,
-        layout: &wgpu::BindGroupLayout,
+        packed_texture: texture::Texture<'a>,
+        layout: &MaterialLayout,
     ) -> Self {
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        Self::with_params(device, name, diffuse_texture, normal_texture, packed_texture, MaterialParams::default(), layout)
+    }
+
+    pub fn with_params(
+        device: &wgpu::Device, 
+        name: &str, 
+        diffuse_texture: texture::Texture<'a>, 
+        normal_texture: texture::Texture<'a>,
+        packed_texture: texture::Texture<'a>,
+        params: MaterialParams,
+        layout: &MaterialLayout,
+    ) -> Self {
+        Self::with_shared_textures(
+            device,
+            name,
+            Rc::new(diffuse_texture),
+            Rc::new(normal_texture),
+            Rc::new(packed_texture),
+            params,
             layout,
-            bindings: &[
-                wgpu::Binding {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::Binding {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-                wgpu::Binding {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                },
-                wgpu::Binding {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                },
-            ],
-            label: Some(name),
-        });
+        )
+    }
+
+    pub fn with_shared_textures(
+        device: &wgpu::Device, 
+        name: &str, 
+        diffuse_texture: Rc<texture::Texture<'a>>, 
+        normal_texture: Rc<texture::Texture<'a>>,
+        packed_texture: Rc<texture::Texture<'a>>,
+        params: MaterialParams,
+        layout: &MaterialLayout,
+    ) -> Self {
+        let params_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[params]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        let bind_group = Self::create_bind_group(
+            device,
+            name,
+            [&*diffuse_texture, &*normal_texture, &*packed_texture],
+            &params_buffer,
+            layout,
+        );
 
         Self { 
             name: String::from(name),
             diffuse_texture,
             normal_texture,
+            packed_texture,
+            params,
+            params_buffer,
             bind_group,
+            layout: layout.desc.clone(),
+        }
+    }
+
+    /// `textures` are the diffuse, normal and packed textures
+    fn create_bind_group(
+        device: &wgpu::Device,
+        name: &str,
+        textures: [&texture::Texture<'a>; 3],
+        params_buffer: &wgpu::Buffer,
+        layout: &MaterialLayout,
+    ) -> wgpu::BindGroup {
+        let [diffuse_texture, normal_texture, packed_texture] = textures;
+        let params_range = 0..std::mem::size_of::<MaterialParams>() as wgpu::BufferAddress;
+        let bindings = layout.desc.entries.iter()
+            .map(|&(binding, slot)| wgpu::Binding {
+                binding,
+                resource: match slot {
+                    MaterialSlot::DiffuseTexture => wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    MaterialSlot::DiffuseSampler => wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                    MaterialSlot::NormalTexture => wgpu::BindingResource::TextureView(&normal_texture.view),
+                    MaterialSlot::NormalSampler => wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                    MaterialSlot::PackedTexture => wgpu::BindingResource::TextureView(&packed_texture.view),
+                    MaterialSlot::PackedSampler => wgpu::BindingResource::Sampler(&packed_texture.sampler),
+                    MaterialSlot::Params => wgpu::BindingResource::Buffer {
+                        buffer: params_buffer,
+                        range: params_range.clone(),
+                    },
+                },
+            })
+            .collect::<Vec<_>>();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &layout.layout,
+            bindings: &bindings,
+            label: Some(name),
+        })
+    }
+
+    /**
+     * Switches any of this material's textures that are `swap.old` to
+     * `swap.new`, and rebuilds the bind group if one was. Returns
+     * whether it was.
+     */
+    pub fn replace_texture(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        swap: &texture::TextureSwap<'a>,
+    ) -> bool {
+        let mut replaced = false;
+        for texture in [&mut self.diffuse_texture, &mut self.normal_texture, &mut self.packed_texture].iter_mut() {
+            if Rc::ptr_eq(&**texture, &swap.old) {
+                **texture = swap.new.clone();
+                replaced = true;
+            }
+        }
+        if replaced {
+            self.bind_group = Self::create_bind_group(
+                device,
+                &self.name,
+                [&*self.diffuse_texture, &*self.normal_texture, &*self.packed_texture],
+                &self.params_buffer,
+                layout,
+            );
+        }
+        replaced
+    }
+
+    /**
+     * A new material with the same textures as this one and its own
+     * `params`. The textures aren't copied, only the bind group is new,
+     * so this is cheap enough to do at runtime. Add it to a model with
+     * [Model::add_material] to use it on some of its meshes.
+     */
+    pub fn instantiate(
+        &self,
+        device: &wgpu::Device,
+        name: &str,
+        params: MaterialParams,
+        layout: &MaterialLayout,
+    ) -> Self {
+        Self::with_shared_textures(
+            device,
+            name,
+            self.diffuse_texture.clone(),
+            self.normal_texture.clone(),
+            self.packed_texture.clone(),
+            params,
+            layout,
+        )
+    }
+
+    /// Writes `params` to the GPU using a staging buffer, the same way
+    /// [crate::Uniforms::update_buffer] does.
+    pub fn update_params(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        params: MaterialParams,
+    ) {
+        self.params = params;
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[self.params]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.params_buffer,
+            0,
+            std::mem::size_of::<MaterialParams>() as _,
+        );
+    }
+}
+
+/**
+ * Settings that change how [Model::load_with_options] processes a file.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelLoadOptions {
+    /// Forces triplanar projection on (or off) for every material. When
+    /// this is `None` the loader turns it on for any material used by a
+    /// mesh that doesn't have texture coordinates.
+    pub triplanar: Option<bool>,
+    pub triplanar_scale: f32,
+    /// Throw away degenerate triangles and check the winding of what's
+    /// left. The counts get logged.
+    pub cleanup: bool,
+    /// Flip triangles that are wound the opposite way to their
+    /// neighbors. Without this they're only counted.
+    pub fix_winding: bool,
+    /// Also give the vertex and index buffers `STORAGE` usage so
+    /// compute shaders can work on them. See [Mesh::storage_bind_group].
+    pub storage_buffers: bool,
+    /// Recompute the normals, putting hard edges wherever faces meet at
+    /// more than this many degrees. `None` keeps the normals from the
+    /// file, except for OBJ files that don't have any, which get
+    /// smoothed at 30 degrees.
+    pub smoothing_angle: Option<f32>,
+    /// Converts the file to Y up and meters. `None` uses whatever the
+    /// file format says (see [ImportTransform::hint_for]), and leaves
+    /// the model alone if it says nothing.
+    pub import: Option<ImportTransform>,
+    /// Look for meshes that are copies of each other with a transform
+    /// baked in, and draw them as placements of one mesh. See
+    /// [dedup::auto_instance].
+    pub auto_instance: bool,
+    /// Packed textures by material name. These win over any the
+    /// material file asks for with [ChannelPacking::from_mtl].
+    pub packing: HashMap<String, ChannelPacking>,
+    /// Meshes with a vertex or index buffer bigger than this many bytes
+    /// get split into smaller ones by [Model::from_data]. Usually
+    /// [crate::DeviceCapabilities::max_buffer_size]. `None` uses
+    /// [crate::DEFAULT_MAX_BUFFER_SIZE], which is safe everywhere.
+    pub max_buffer_size: Option<u64>,
+}
+
+impl ModelLoadOptions {
+    fn import_transform(&self, path: &Path) -> ImportTransform {
+        self.import
+            .or_else(|| ImportTransform::hint_for(path))
+            .unwrap_or_default()
+    }
+
+    fn vertex_usage(&self) -> wgpu::BufferUsage {
+        if self.storage_buffers {
+            wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_SRC | wgpu::BufferUsage::COPY_DST
+        } else {
+            wgpu::BufferUsage::VERTEX
+        }
+    }
+
+    fn index_usage(&self) -> wgpu::BufferUsage {
+        if self.storage_buffers {
+            wgpu::BufferUsage::INDEX | wgpu::BufferUsage::STORAGE
+        } else {
+            wgpu::BufferUsage::INDEX
+        }
+    }
+}
+
+impl Default for ModelLoadOptions {
+    fn default() -> Self {
+        Self {
+            triplanar: None,
+            triplanar_scale: 1.0,
+            cleanup: true,
+            fix_winding: true,
+            storage_buffers: false,
+            smoothing_angle: None,
+            import: None,
+            auto_instance: false,
+            packing: HashMap::new(),
+            max_buffer_size: None,
         }
     }
 }
@@ -110,54 +444,667 @@ pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
+    /// Just level 0's. The rest of the index buffer is [Mesh::lods].
     pub num_elements: u32,
+    /// Every level's
+    pub num_vertices: u32,
     pub material: usize,
+    /// False when the source file didn't have any UVs for this mesh.
+    pub has_tex_coords: bool,
+    pub aabb: Aabb,
+    /// Whether the buffers can be bound as storage buffers
+    pub storage: bool,
+    /// Where copies found by [ModelLoadOptions::auto_instance] go,
+    /// relative to the model. Empty means the mesh is only drawn where
+    /// it is.
+    pub placements: Vec<Matrix4<f32>>,
+    /// Hidden meshes are skipped by everything in [DrawModel] that
+    /// draws a whole model. See [crate::MeshVisibility].
+    pub visible: bool,
+    /// Cheaper versions, after level 0. See [lod::fold_lods].
+    pub lods: Vec<MeshLod>,
+}
+
+impl Mesh {
+    /// The indices to draw for `level`. Levels past the last one get
+    /// the last one.
+    pub fn lod_indices(&self, level: usize) -> Range<u32> {
+        match level.min(self.lods.len()) {
+            0 => 0..self.num_elements,
+            level => {
+                let lod = self.lods[level - 1];
+                lod.first_index..lod.first_index + lod.index_count
+            }
+        }
+    }
+
+    pub fn vertex_buffer_size(&self) -> wgpu::BufferAddress {
+        (self.num_vertices as usize * std::mem::size_of::<ModelVertex>()) as _
+    }
+
+    pub fn index_buffer_size(&self) -> wgpu::BufferAddress {
+        let count = self.lods.last().map_or(self.num_elements, |l| l.first_index + l.index_count);
+        (count as usize * std::mem::size_of::<u32>()) as _
+    }
+
+    /**
+     * Layout for [Mesh::storage_bind_group]. Binding 0 is the vertices
+     * (read-write) and binding 1 is the indices (read only), both
+     * visible to compute shaders.
+     *
+     * Shaders should read vertices as an array of floats, 12 per
+     * vertex, as std430 would pad a `vec3` out to 16 bytes and the
+     * layout wouldn't match [ModelVertex].
+     */
+    pub fn storage_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::COMPUTE,
+                        ty: wgpu::BindingType::StorageBuffer { dynamic: false, readonly: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::COMPUTE,
+                        ty: wgpu::BindingType::StorageBuffer { dynamic: false, readonly: true },
+                    },
+                ],
+                label: Some("Mesh::storage_layout"),
+            }
+        )
+    }
+
+    /// Only works for meshes loaded with
+    /// [ModelLoadOptions::storage_buffers] turned on
+    pub fn storage_bind_group(
+        &self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Result<wgpu::BindGroup> {
+        if !self.storage {
+            bail!("{} wasn't loaded with storage_buffers turned on", self.name);
+        }
+        Ok(device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &self.vertex_buffer,
+                            range: 0..self.vertex_buffer_size(),
+                        },
+                    },
+                    wgpu::Binding {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &self.index_buffer,
+                            range: 0..self.index_buffer_size(),
+                        },
+                    },
+                ],
+                label: Some("Mesh::storage_bind_group"),
+            }
+        ))
+    }
+}
+
+/**
+ * Refers to one mesh in a list of models. Handles can go stale when
+ * models are swapped out, so always check them with
+ * [MeshHandle::is_valid] before using them.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct MeshHandle {
+    pub model: usize,
+    pub mesh: usize,
+}
+
+impl MeshHandle {
+    pub fn is_valid(&self, models: &[&Model]) -> bool {
+        models.get(self.model)
+            .map(|m| self.mesh < m.meshes.len())
+            .unwrap_or(false)
+    }
 }
 
 pub struct Model<'a> {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material<'a>>,
+    report: ModelReport,
 }
 
 impl<'a> Model<'a> {
+    /// The box around every mesh in the model, in model space
+    pub fn aabb(&self) -> Aabb {
+        self.meshes.iter().fold(Aabb::empty(), |aabb, m| aabb.union(&placed_aabb(&m.aabb, &m.placements)))
+    }
+
+    /// Whether any mesh needs drawing with
+    /// [DrawModel::draw_model_placed]
+    pub fn has_placements(&self) -> bool {
+        self.meshes.iter().any(|m| !m.placements.is_empty())
+    }
+
     pub fn load<P: AsRef<Path>>(
         device: &wgpu::Device,
-        layout: &wgpu::BindGroupLayout,
+        layout: &MaterialLayout,
         path: P,
     ) -> Result<(Self, Vec<wgpu::CommandBuffer>)> {
-        let (obj_models, obj_materials) = tobj::load_obj(path.as_ref(), true)?;
+        Self::load_with_options(device, layout, path, &ModelLoadOptions::default())
+    }
 
-        // We're assuming that the texture files are stored with the obj file
-        let containing_folder = path.as_ref()
-            .parent()
-            .context("Unable to find parent for model")?;
+    pub fn load_with_options<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        path: P,
+        options: &ModelLoadOptions,
+    ) -> Result<(Self, Vec<wgpu::CommandBuffer>)> {
+        Self::load_with_resolver(device, layout, path, options, &PathResolver::default())
+    }
+
+    /// Files the model refers to (materials and textures) are found
+    /// with `resolver`. [crate::AssetManager::load_model] calls this.
+    pub fn load_with_resolver<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        path: P,
+        options: &ModelLoadOptions,
+        resolver: &PathResolver,
+    ) -> Result<(Self, Vec<wgpu::CommandBuffer>)> {
+        let data = ModelData::load_with_resolver(path, options, resolver)?;
+        Self::from_data(device, layout, &data, &mut texture::TextureCache::new())
+    }
+
+    /// [ModelData::load_from_memory], with the textures read out of
+    /// `assets` too
+    pub fn load_from_memory(
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        name: &str,
+        assets: &EmbeddedAssets,
+        options: &ModelLoadOptions,
+    ) -> Result<(Self, Vec<wgpu::CommandBuffer>)> {
+        let data = ModelData::load_from_memory(name, assets, options)?;
+        let mut textures = texture::TextureCache::new();
+        textures.embedded = Some(*assets);
+        Self::from_data(device, layout, &data, &mut textures)
+    }
+
+    /// The buffers this model has on the GPU. Textures aren't
+    /// included, as other models can share them.
+    pub fn gpu_resources(&self) -> GpuResources {
+        let mut resources = GpuResources::default();
+        for mesh in &self.meshes {
+            resources.add(GpuResources {
+                buffers: 2,
+                buffer_bytes: mesh.vertex_buffer_size() + mesh.index_buffer_size(),
+            });
+        }
+        resources.add(GpuResources {
+            buffers: self.materials.len(),
+            buffer_bytes: (self.materials.len() * std::mem::size_of::<MaterialParams>()) as u64,
+        });
+        resources
+    }
+
+    /// Returns the index to put in [Mesh::material]
+    pub fn add_material(&mut self, material: Material<'a>) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
 
-        // Our `Texure` struct currently returns a `CommandBuffer` when it's created so we need to collect those and return them.
+    /// [Material::replace_texture] for every material, for the swaps
+    /// from [texture::TextureCache::enforce_budget]
+    pub fn replace_textures(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        swaps: &[texture::TextureSwap<'a>],
+    ) {
+        for material in &mut self.materials {
+            for swap in swaps {
+                material.replace_texture(device, layout, swap);
+            }
+        }
+    }
+
+    pub fn find_material(&self, name: &str) -> Option<usize> {
+        self.materials.iter().position(|m| m.name == name)
+    }
+
+    /// What the loader made of the file. See [ModelReport].
+    pub fn describe(&self) -> &ModelReport {
+        &self.report
+    }
+
+    /**
+     * Puts `data` on the GPU. Textures come from `textures`, so models
+     * that share a cache share any textures they have in common. Like
+     * [texture::Texture::load], the uploads are returned as command
+     * buffers for the caller to submit.
+     */
+    pub fn from_data(
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        data: &ModelData,
+        textures: &mut texture::TextureCache<'a>,
+    ) -> Result<(Self, Vec<wgpu::CommandBuffer>)> {
         let mut command_buffers = Vec::new();
 
         let mut materials = Vec::new();
-        for mat in obj_materials {
-            let name = mat.name;
-            let diffuse_path = mat.diffuse_texture;
-            let (diffuse_texture, cmds) = texture::Texture::load(device, containing_folder.join(diffuse_path), false)?;
-            command_buffers.push(cmds);
-            
-            let normal_path = mat.unknown_param.get("map_Bump")
-                .with_context(|| format!("No normal map specified for {}", name))?;
-            let (normal_texture, cmds) = texture::Texture::load(device, containing_folder.join(normal_path), true)?;
-            command_buffers.push(cmds);
-
-            materials.push(Material::new(
+        for mat in &data.materials {
+            let diffuse_texture = textures.load(device, &mat.diffuse.path, false, &mut command_buffers)?;
+            let normal_texture = textures.load(device, &mat.normal.path, true, &mut command_buffers)?;
+            let packed_texture = match &mat.packed {
+                Some(packed) => textures.load_source(device, packed, &mut command_buffers)?,
+                None => textures.neutral_packed(device, &mut command_buffers)?,
+            };
+            materials.push(Material::with_shared_textures(
                 device,
-                &name,
+                &mat.name,
                 diffuse_texture,
                 normal_texture,
+                packed_texture,
+                mat.params,
                 layout,
             ));
         }
 
+        let upload = |m: &MeshData| {
+            let vertex_buffer = device.create_buffer_with_data(
+                bytemuck::cast_slice(&m.vertices),
+                data.options.vertex_usage(),
+            );
+            let index_buffer = device.create_buffer_with_data(
+                bytemuck::cast_slice(&m.indices),
+                data.options.index_usage(),
+            );
+            Mesh {
+                name: m.name.clone(),
+                vertex_buffer,
+                index_buffer,
+                num_elements: m.lod0_indices().len() as u32,
+                num_vertices: m.vertices.len() as u32,
+                material: m.material,
+                has_tex_coords: m.has_tex_coords,
+                aabb: m.aabb,
+                storage: data.options.storage_buffers,
+                placements: m.placements.clone(),
+                visible: true,
+                lods: m.lods.clone(),
+            }
+        };
+        // Creating a buffer past the device's limit panics, so anything
+        // that big gets split first. Splitting copies the mesh, so it's
+        // only done to the ones that need it.
+        let max_buffer_size = data.options.max_buffer_size.unwrap_or(crate::DEFAULT_MAX_BUFFER_SIZE);
+        let mut meshes = Vec::new();
+        for m in &data.meshes {
+            if split::mesh_fits(m, max_buffer_size) {
+                meshes.push(upload(m));
+            } else {
+                meshes.extend(split::split_mesh(m, max_buffer_size).iter().map(&upload));
+            }
+        }
+
+        let report = ModelReport::from_data(data);
+        Ok((Self { meshes, materials, report }, command_buffers))
+    }
+}
+
+/// A file a material refers to, and how it was found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextureSource {
+    pub written: String,
+    pub path: PathBuf,
+    pub strategy: ResolveStrategy,
+    pub is_normal_map: bool,
+    /// The red, green and blue sources of a packed texture that gets
+    /// made while loading. `path` is where the [PackedTextureCache]
+    /// keeps it, but it's only ever packed in memory here. The viewer
+    /// saves it there with [PackedTextureCache::save_packed].
+    #[serde(default)]
+    pub channels: Option<[Option<PathBuf>; 3]>,
+}
+
+impl TextureSource {
+    fn resolve(
+        resolver: &PathResolver,
+        written: &str,
+        containing_folder: &Path,
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        let (path, strategy) = resolver.resolve_logged(written, containing_folder)?;
+        Ok(Self { written: written.to_string(), path, strategy, is_normal_map, channels: None })
+    }
+
+    /**
+     * Where the packed texture for `packing` is, or would be in the
+     * [PackedTextureCache]. Nothing gets written, see
+     * [TextureSource::pack_unsaved]. It gets loaded the same way as a
+     * normal map, as the channels aren't colors and shouldn't be
+     * treated as sRGB.
+     */
+    fn resolve_packed(
+        resolver: &PathResolver,
+        packing: &ChannelPacking,
+        containing_folder: &Path,
+    ) -> Result<Self> {
+        if let Some(packed) = &packing.packed {
+            return Self::resolve(resolver, packed, containing_folder, true);
+        }
+        let mut sources = [None, None, None];
+        for (source, written) in sources.iter_mut().zip(&packing.channels()) {
+            if let Some(written) = written {
+                *source = Some(resolver.resolve_logged(written, containing_folder)?.0);
+            }
+        }
+        let path = PackedTextureCache::in_settings_dir().path_for(&sources)?;
+        let written = packing.channels().iter()
+            .map(|c| c.unwrap_or("-"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(Self {
+            written,
+            path,
+            strategy: ResolveStrategy::AsWritten,
+            is_normal_map: true,
+            channels: Some(sources),
+        })
+    }
+
+    /// A packed texture that hasn't been saved yet, packed in memory.
+    /// `None` for everything that can be read from `path`.
+    pub fn pack_unsaved(&self) -> Option<Result<image::DynamicImage>> {
+        let channels = self.channels.as_ref().filter(|_| !self.path.exists())?;
+        Some(pack_files(&self.written, channels).map(image::DynamicImage::ImageRgba8))
+    }
+}
+
+/// Where the files an obj refers to come from
+trait ObjFiles {
+    fn load_mtl(&self, written: &Path) -> tobj::MTLLoadResult;
+    fn texture(&self, written: &str, is_normal_map: bool) -> Result<TextureSource>;
+    fn packed(&self, name: &str, packing: &ChannelPacking) -> Result<TextureSource>;
+}
+
+struct OnDisk<'r> {
+    resolver: &'r PathResolver,
+    containing_folder: &'r Path,
+}
+
+impl ObjFiles for OnDisk<'_> {
+    fn load_mtl(&self, written: &Path) -> tobj::MTLLoadResult {
+        match self.resolver.resolve(&written.to_string_lossy(), self.containing_folder) {
+            Ok(resolved) => tobj::load_mtl(resolved),
+            Err(e) => {
+                log::error!("{:?}", e);
+                Err(tobj::LoadError::OpenFileFailed)
+            }
+        }
+    }
+
+    fn texture(&self, written: &str, is_normal_map: bool) -> Result<TextureSource> {
+        TextureSource::resolve(self.resolver, written, self.containing_folder, is_normal_map)
+    }
+
+    fn packed(&self, _name: &str, packing: &ChannelPacking) -> Result<TextureSource> {
+        TextureSource::resolve_packed(self.resolver, packing, self.containing_folder)
+    }
+}
+
+/// See [ModelData::load_from_memory]
+struct Embedded<'r> {
+    assets: &'r EmbeddedAssets,
+    /// Where the obj is among the embedded files
+    folder: &'r Path,
+}
+
+impl Embedded<'_> {
+    fn find(&self, written: &str) -> Option<String> {
+        let written = written.trim().replace('\\', "/");
+        let beside = self.folder.join(&written).to_string_lossy().replace('\\', "/");
+        std::iter::once(beside)
+            .chain(std::iter::once(written))
+            .find(|path| self.assets.get(path).is_some())
+    }
+}
+
+impl ObjFiles for Embedded<'_> {
+    fn load_mtl(&self, written: &Path) -> tobj::MTLLoadResult {
+        match self.find(&written.to_string_lossy()).and_then(|path| self.assets.get(&path)) {
+            Some(mut bytes) => tobj::load_mtl_buf(&mut bytes),
+            None => {
+                log::error!("{} isn't embedded", written.display());
+                Err(tobj::LoadError::OpenFileFailed)
+            }
+        }
+    }
+
+    fn texture(&self, written: &str, is_normal_map: bool) -> Result<TextureSource> {
+        let path = self.find(written).with_context(|| format!("{:?} isn't embedded", written))?;
+        Ok(TextureSource {
+            written: written.to_string(),
+            path: PathBuf::from(path),
+            strategy: ResolveStrategy::Embedded,
+            is_normal_map,
+            channels: None,
+        })
+    }
+
+    fn packed(&self, name: &str, packing: &ChannelPacking) -> Result<TextureSource> {
+        match &packing.packed {
+            Some(packed) => self.texture(packed, true),
+            None => bail!("{} packs its channels while loading, which embedded models can't do. Pack it ahead of time.", name),
+        }
+    }
+}
+
+/// A material before its textures have been loaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialData {
+    pub name: String,
+    pub diffuse: TextureSource,
+    pub normal: TextureSource,
+    /// See [ChannelPacking]. Materials without one get a texture that
+    /// doesn't change anything.
+    #[serde(default)]
+    pub packed: Option<TextureSource>,
+    pub params: MaterialParams,
+}
+
+/// A mesh that's been through all the processing, but isn't on the GPU
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    pub material: usize,
+    pub has_tex_coords: bool,
+    pub normals: AttributeSource,
+    pub tangents: AttributeSource,
+    pub aabb: Aabb,
+    /// See [Mesh::placements]
+    #[serde(default)]
+    pub placements: Vec<Matrix4<f32>>,
+    /// See [Mesh::lods]. Their indices come after level 0's in
+    /// `indices`, and their vertices after level 0's in `vertices`.
+    #[serde(default)]
+    pub lods: Vec<MeshLod>,
+}
+
+impl MeshData {
+    pub fn lod0_indices(&self) -> &[u32] {
+        let end = self.lods.first().map_or(self.indices.len(), |l| l.first_index as usize);
+        &self.indices[..end]
+    }
+}
+
+/// `aabb` around every placement, or just `aabb` without any
+pub(crate) fn placed_aabb(aabb: &Aabb, placements: &[Matrix4<f32>]) -> Aabb {
+    if placements.is_empty() {
+        return *aabb;
+    }
+    placements.iter().fold(Aabb::empty(), |placed, p| placed.union(&aabb.transform(p)))
+}
+
+/**
+ * The part of loading a model that doesn't need a GPU: parsing, the
+ * [ImportTransform], cleanup, normals and tangents. Texture files are
+ * found but not read. None of this touches wgpu, so it can be tested,
+ * cached or done on another thread, and then handed to
+ * [Model::from_data].
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelData {
+    pub path: PathBuf,
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    /// What the data was made with. [Model::from_data] needs the
+    /// buffer usages from it.
+    pub options: ModelLoadOptions,
+    /// Set when [ModelLoadOptions::auto_instance] was on
+    #[serde(default)]
+    pub instancing: Option<AutoInstanceReport>,
+}
+
+impl ModelData {
+    pub fn load<P: AsRef<Path>>(path: P, options: &ModelLoadOptions) -> Result<Self> {
+        Self::load_with_resolver(path, options, &PathResolver::default())
+    }
+
+    pub fn load_with_resolver<P: AsRef<Path>>(
+        path: P,
+        options: &ModelLoadOptions,
+        resolver: &PathResolver,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let is_stl = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("stl"))
+            .unwrap_or(false);
+        let (meshes, materials) = if is_stl {
+            (Self::load_stl(path, &std::fs::read(path)?, options)?, Vec::new())
+        } else {
+            Self::load_obj(path, options, resolver)?
+        };
+        Ok(Self::from_meshes(path, meshes, materials, options))
+    }
+
+    /**
+     * Loads `name` out of `assets` rather than the file system. Files
+     * it refers to are looked for next to it first, then by the path
+     * as written. Channel packing needs the files on disk, so embedded
+     * materials can only use textures that were packed already.
+     */
+    pub fn load_from_memory(name: &str, assets: &EmbeddedAssets, options: &ModelLoadOptions) -> Result<Self> {
+        let path = Path::new(name);
+        let bytes = assets.get(name).with_context(|| format!("{} isn't embedded", name))?;
+        let is_stl = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("stl"))
+            .unwrap_or(false);
+        let (meshes, materials) = if is_stl {
+            (Self::load_stl(path, bytes, options)?, Vec::new())
+        } else {
+            let folder = path.parent().unwrap_or_else(|| Path::new(""));
+            Self::parse_obj(path, &mut &bytes[..], options, &Embedded { assets, folder })?
+        };
+        Ok(Self::from_meshes(path, meshes, materials, options))
+    }
+
+    fn from_meshes(path: &Path, meshes: Vec<MeshData>, materials: Vec<MaterialData>, options: &ModelLoadOptions) -> Self {
+        let meshes = lod::fold_lods(meshes);
+        let (meshes, instancing) = if options.auto_instance {
+            let (meshes, report) = dedup::auto_instance(&path.display().to_string(), meshes);
+            (meshes, Some(report))
+        } else {
+            (meshes, None)
+        };
+        Self {
+            path: path.to_path_buf(),
+            meshes,
+            materials,
+            options: options.clone(),
+            instancing,
+        }
+    }
+
+    /// The box around every mesh, in model space
+    pub fn aabb(&self) -> Aabb {
+        self.meshes.iter().fold(Aabb::empty(), |aabb, m| aabb.union(&placed_aabb(&m.aabb, &m.placements)))
+    }
+
+    fn load_obj(
+        path: &Path,
+        options: &ModelLoadOptions,
+        resolver: &PathResolver,
+    ) -> Result<(Vec<MeshData>, Vec<MaterialData>)> {
+        // We're assuming that the texture files are stored with the obj file
+        let containing_folder = path
+            .parent()
+            .context("Unable to find parent for model")?;
+
+        let mut reader = std::io::BufReader::new(
+            std::fs::File::open(path)
+                .with_context(|| format!("Unable to open {}", path.display()))?
+        );
+        Self::parse_obj(path, &mut reader, options, &OnDisk { resolver, containing_folder })
+    }
+
+    fn parse_obj<R: std::io::BufRead>(
+        path: &Path,
+        reader: &mut R,
+        options: &ModelLoadOptions,
+        files: &dyn ObjFiles,
+    ) -> Result<(Vec<MeshData>, Vec<MaterialData>)> {
+        let (obj_models, obj_materials) = tobj::load_obj_buf(reader, true, |mtl_path| files.load_mtl(mtl_path))?;
+
+        // Materials used by a mesh without UVs get triplanar projection
+        // unless the caller says otherwise.
+        let mut needs_triplanar = vec![false; obj_materials.len()];
+        for m in &obj_models {
+            if m.mesh.texcoords.is_empty() {
+                if let Some(id) = m.mesh.material_id {
+                    needs_triplanar[id] = true;
+                }
+            }
+        }
+
+        let mut materials = Vec::new();
+        for (i, mat) in obj_materials.into_iter().enumerate() {
+            let packing = options.packing.get(&mat.name)
+                .cloned()
+                .or_else(|| ChannelPacking::from_mtl(&mat));
+            let packed = match packing {
+                Some(packing) => Some(files.packed(&mat.name, &packing)?),
+                None => None,
+            };
+            let name = mat.name;
+            let diffuse = files.texture(&mat.diffuse_texture, false)?;
+            // tobj reads both map_Bump and bump into this
+            let normal_path = Some(&mat.normal_texture)
+                .filter(|path| !path.is_empty())
+                .with_context(|| format!("No normal map specified for {}", name))?;
+            let normal = files.texture(normal_path, true)?;
+
+            let triplanar = options.triplanar.unwrap_or(needs_triplanar[i]);
+            let params = MaterialParams::default()
+                .with_triplanar(triplanar, options.triplanar_scale);
+
+            materials.push(MaterialData { name, diffuse, normal, packed, params });
+        }
+
+        let import = options.import_transform(path);
         let mut meshes = Vec::new();
         for m in obj_models {
+            // Triplanar projection doesn't need UVs, so we just zero
+            // them out when they're missing.
+            let has_tex_coords = !m.mesh.texcoords.is_empty();
+            let has_normals = !m.mesh.normals.is_empty();
             let mut vertices = Vec::new();
             for i in 0..m.mesh.positions.len() / 3 {
                 vertices.push(ModelVertex {
@@ -166,146 +1113,309 @@ impl<'a> Model<'a> {
                         m.mesh.positions[i * 3 + 1],
                         m.mesh.positions[i * 3 + 2],
                     ].into(),
-                    tex_coords: [
-                        m.mesh.texcoords[i * 2], 
-                        m.mesh.texcoords[i * 2 + 1]
-                    ].into(),
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ].into(),
+                    tex_coords: if has_tex_coords {
+                        [
+                            m.mesh.texcoords[i * 2], 
+                            m.mesh.texcoords[i * 2 + 1]
+                        ].into()
+                    } else {
+                        [0.0; 2].into()
+                    },
+                    normal: if has_normals {
+                        [
+                            m.mesh.normals[i * 3],
+                            m.mesh.normals[i * 3 + 1],
+                            m.mesh.normals[i * 3 + 2],
+                        ].into()
+                    } else {
+                        [0.0; 3].into()
+                    },
                     // We'll calculate these later
-                    tangent: [0.0; 3].into(),
-                    bitangent: [0.0; 3].into(),
+                    tangent: [0.0; 4].into(),
                 });
             }
 
-            let indices = &m.mesh.indices;
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0 = v0.position;
-                let pos1 = v1.position;
-                let pos2 = v2.position;
-
-                let uv0 = v0.tex_coords;
-                let uv1 = v1.tex_coords;
-                let uv2 = v2.tex_coords;
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided 
-                // the solution!
-                let r = 1.0 / (delta_uv1 .x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-                
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent = tangent;
-                vertices[c[1] as usize].tangent = tangent;
-                vertices[c[2] as usize].tangent = tangent;
-
-                vertices[c[0] as usize].bitangent = bitangent;
-                vertices[c[1] as usize].bitangent = bitangent;
-                vertices[c[2] as usize].bitangent = bitangent;
+            let mut indices = m.mesh.indices.clone();
+            import.apply(&mut vertices, &mut indices);
+            if options.cleanup {
+                let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+                cleanup::cleanup(&m.name, &positions, &mut indices, options.fix_winding);
             }
 
-            let vertex_buffer = device.create_buffer_with_data(
-                bytemuck::cast_slice(&vertices),
-                wgpu::BufferUsage::VERTEX,
-            );
+            let smoothing_angle = options.smoothing_angle
+                .or(if has_normals { None } else { Some(normals::DEFAULT_SMOOTHING_ANGLE) });
+            if let Some(angle) = smoothing_angle {
+                normals::recalculate_normals(&mut vertices, &mut indices, angle);
+            }
 
-            let index_buffer = device.create_buffer_with_data(
-                bytemuck::cast_slice(indices),
-                wgpu::BufferUsage::INDEX,
-            );
+            // Without UVs there's nothing to derive tangents from, and
+            // triplanar mapping builds its own basis in the shader
+            // anyway.
+            if has_tex_coords {
+                tangent::generate_tangents(&mut vertices, &mut indices);
+            }
 
-            meshes.push(Mesh {
+            meshes.push(MeshData {
                 name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
+                aabb: Aabb::from_points(vertices.iter().map(|v| Point3::from_vec(v.position))),
+                vertices,
+                indices,
                 material: m.mesh.material_id.unwrap_or(0),
+                has_tex_coords,
+                normals: if smoothing_angle.is_some() { AttributeSource::Generated } else { AttributeSource::File },
+                tangents: if has_tex_coords { AttributeSource::Generated } else { AttributeSource::Missing },
+                placements: Vec::new(),
+                lods: Vec::new(),
             });
         }
 
-        Ok((Self { meshes, materials }, command_buffers))
+        Ok((meshes, materials))
+    }
+
+    /**
+     * STL files only contain triangles, so the resulting model has a
+     * single mesh and no materials. Draw it with
+     * [DrawModel::draw_model_instanced_with_material] and a material
+     * that has triplanar projection turned on.
+     */
+    fn load_stl(path: &Path, bytes: &[u8], options: &ModelLoadOptions) -> Result<Vec<MeshData>> {
+        let triangles = stl::parse(bytes)
+            .with_context(|| format!("Unable to parse {}", path.display()))?;
+
+        // STL doesn't share vertices between facets, so every facet gets
+        // its own three vertices with the facet normal.
+        let mut vertices = Vec::with_capacity(triangles.len() * 3);
+        for tri in &triangles {
+            for position in &tri.vertices {
+                vertices.push(ModelVertex {
+                    position: (*position).into(),
+                    tex_coords: [0.0; 2].into(),
+                    normal: tri.normal.into(),
+                    tangent: [0.0; 4].into(),
+                });
+            }
+        }
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut indices = (0..vertices.len() as u32).collect::<Vec<_>>();
+        options.import_transform(path).apply(&mut vertices, &mut indices);
+        if options.cleanup {
+            let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+            cleanup::cleanup(&name, &positions, &mut indices, options.fix_winding);
+        }
+        // This also joins up the facets, so smoothed STL models end
+        // up with far fewer vertices
+        if let Some(angle) = options.smoothing_angle {
+            normals::recalculate_normals(&mut vertices, &mut indices, angle);
+        }
+
+        Ok(vec![MeshData {
+            name,
+            aabb: Aabb::from_points(vertices.iter().map(|v| Point3::from_vec(v.position))),
+            vertices,
+            indices,
+            material: 0,
+            has_tex_coords: false,
+            normals: if options.smoothing_angle.is_some() { AttributeSource::Generated } else { AttributeSource::File },
+            tangents: AttributeSource::Missing,
+            placements: Vec::new(),
+            lods: Vec::new(),
+        }])
     }
 }
 
+/**
+ * Draws with group 0 set to the material and group 1 set to `view`, the
+ * per-view group (see [crate::UniformBinding]). Group 2, the per-frame
+ * group (see [crate::FrameBinding]), is the same for every draw so it's
+ * left for the caller to set once per pass. Rendering the same scene
+ * from somewhere else only needs a different `view`.
+ *
+ * Draws go through a [ModelPass]. In debug builds, setting the
+ * pipeline with [DrawModel::set_model_pipeline] makes every draw in
+ * that pass check the material against the pipeline's [MaterialLayout].
+ * A mismatch panics with a [crate::MaterialLayoutError] naming the
+ * material and binding, instead of a wgpu validation error that doesn't
+ * say either.
+ */
 pub trait DrawModel<'a, 'b>
 where
     'b: 'a,
 {
+    /// Same as `set_pipeline`, but remembers which material layout
+    /// `pipeline` was made with
+    fn set_model_pipeline(
+        &mut self,
+        pipeline: &'b wgpu::RenderPipeline,
+        material_layout: &MaterialLayout,
+    );
+
     fn draw_mesh(
         &mut self,
         mesh: &'b Mesh,
         material: &'b Material,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
     );
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'b Mesh,
         material: &'b Material,
         instances: Range<u32>,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
+    );
+    /// [DrawModel::draw_mesh_instanced] with one of [Mesh::lods]
+    /// instead of the full mesh. Level 0 is the full mesh.
+    fn draw_mesh_lod_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        level: usize,
+        instances: Range<u32>,
+        view: &'b wgpu::BindGroup,
     );
 
     fn draw_model(
         &mut self,
         model: &'b Model,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
     );
     fn draw_model_instanced(
         &mut self,
         model: &'b Model,
         instances: Range<u32>,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
     );
     fn draw_model_instanced_with_material(
         &mut self,
         model: &'b Model,
         material: &'b Material,
         instances: Range<u32>,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
+    );
+
+    /// Like [DrawModel::draw_mesh_instanced], but the instance count
+    /// comes from `indirect` at `offset`, usually filled in by
+    /// [crate::HiZCuller]
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        indirect: &'b wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+        view: &'b wgpu::BindGroup,
+    );
+    /// `indirect` has one set of arguments per mesh, in order
+    fn draw_model_indirect(
+        &mut self,
+        model: &'b Model,
+        indirect: &'b wgpu::Buffer,
+        view: &'b wgpu::BindGroup,
     );
+    fn draw_model_indirect_with_material(
+        &mut self,
+        model: &'b Model,
+        material: &'b Material,
+        indirect: &'b wgpu::Buffer,
+        view: &'b wgpu::BindGroup,
+    );
+
+    /// For models with [Mesh::placements]. Each mesh gets its own
+    /// instance buffer from `placements`, so this sets slot 1 itself.
+    fn draw_model_placed(
+        &mut self,
+        model: &'b Model,
+        placements: &'b PlacementBuffers,
+        view: &'b wgpu::BindGroup,
+    );
+    fn draw_model_placed_with_material(
+        &mut self,
+        model: &'b Model,
+        material: &'b Material,
+        placements: &'b PlacementBuffers,
+        view: &'b wgpu::BindGroup,
+    );
+}
+
+/**
+ * A render pass that models get drawn in, made with
+ * `ModelPass::from(encoder.begin_render_pass(..))`. It's a
+ * `wgpu::RenderPass` for everything else. The material layout the
+ * pipeline expects goes away with the pass, so nothing carries over
+ * into the next one.
+ */
+pub struct ModelPass<'a> {
+    pass: wgpu::RenderPass<'a>,
+    expected: LayoutExpectation,
+}
+
+impl<'a> From<wgpu::RenderPass<'a>> for ModelPass<'a> {
+    fn from(pass: wgpu::RenderPass<'a>) -> Self {
+        Self { pass, expected: LayoutExpectation::default() }
+    }
+}
+
+impl<'a> std::ops::Deref for ModelPass<'a> {
+    type Target = wgpu::RenderPass<'a>;
+    fn deref(&self) -> &Self::Target {
+        &self.pass
+    }
+}
+
+impl<'a> std::ops::DerefMut for ModelPass<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.pass
+    }
+}
+
+/// What one [ModelPass] checks its materials against
+#[derive(Debug, Default)]
+struct LayoutExpectation {
+    /// None until a pipeline is set with [DrawModel::set_model_pipeline]
+    layout: Option<MaterialLayoutDesc>,
+}
+
+impl LayoutExpectation {
+    fn check(&self, material_name: &str, material: &MaterialLayoutDesc) -> Result<(), MaterialLayoutError> {
+        match &self.layout {
+            Some(expected) => expected.check(material_name, material),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> ModelPass<'a> {
+    fn check_material(&self, material: &Material) {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.expected.check(&material.name, &material.layout) {
+                panic!("{}", e);
+            }
+        }
+    }
 }
 
-impl<'a, 'b> DrawModel<'a, 'b> for wgpu::RenderPass<'a>
+impl<'a, 'b> DrawModel<'a, 'b> for ModelPass<'a>
 where
     'b: 'a,
 {
+    fn set_model_pipeline(
+        &mut self,
+        pipeline: &'b wgpu::RenderPipeline,
+        material_layout: &MaterialLayout,
+    ) {
+        self.expected.layout = Some(material_layout.desc.clone());
+        self.set_pipeline(pipeline);
+    }
+
     fn draw_mesh(
         &mut self,
         mesh: &'b Mesh,
         material: &'b Material,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
     ) {
-        self.draw_mesh_instanced(mesh, material, 0..1, uniforms, light);
+        self.draw_mesh_instanced(mesh, material, 0..1, view);
     }
 
     fn draw_mesh_instanced(
@@ -313,36 +1423,44 @@ where
         mesh: &'b Mesh,
         material: &'b Material,
         instances: Range<u32>,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
+    ) {
+        self.draw_mesh_lod_instanced(mesh, material, 0, instances, view);
+    }
+
+    fn draw_mesh_lod_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        level: usize,
+        instances: Range<u32>,
+        view: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, &mesh.vertex_buffer, 0, 0);
         self.set_index_buffer(&mesh.index_buffer, 0, 0);
+        self.check_material(material);
         self.set_bind_group(0, &material.bind_group, &[]);
-        self.set_bind_group(1, &uniforms, &[]);
-        self.set_bind_group(2, &light, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.set_bind_group(1, view, &[]);
+        self.draw_indexed(mesh.lod_indices(level), 0, instances);
     }
 
     fn draw_model(
         &mut self,
         model: &'b Model,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
     ) {
-        self.draw_model_instanced(model, 0..1, uniforms, light);
+        self.draw_model_instanced(model, 0..1, view);
     }
 
     fn draw_model_instanced(
         &mut self,
         model: &'b Model,
         instances: Range<u32>,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
+        for mesh in model.meshes.iter().filter(|mesh| mesh.visible) {
             let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), uniforms, light);
+            self.draw_mesh_instanced(mesh, material, instances.clone(), view);
         }
     }
 
@@ -351,11 +1469,80 @@ where
         model: &'b Model,
         material: &'b Material,
         instances: Range<u32>,
-        uniforms: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
+        view: &'b wgpu::BindGroup,
+    ) {
+        for mesh in model.meshes.iter().filter(|mesh| mesh.visible) {
+            self.draw_mesh_instanced(mesh, material, instances.clone(), view);
+        }
+    }
+
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        indirect: &'b wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+        view: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, &mesh.vertex_buffer, 0, 0);
+        self.set_index_buffer(&mesh.index_buffer, 0, 0);
+        self.check_material(material);
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.set_bind_group(1, view, &[]);
+        self.draw_indexed_indirect(indirect, offset);
+    }
+
+    fn draw_model_indirect(
+        &mut self,
+        model: &'b Model,
+        indirect: &'b wgpu::Buffer,
+        view: &'b wgpu::BindGroup,
+    ) {
+        for (i, mesh) in model.meshes.iter().enumerate().filter(|(_, mesh)| mesh.visible) {
+            let material = &model.materials[mesh.material];
+            let offset = crate::CullBatch::indirect_offset(i);
+            self.draw_mesh_indirect(mesh, material, indirect, offset, view);
+        }
+    }
+
+    fn draw_model_indirect_with_material(
+        &mut self,
+        model: &'b Model,
+        material: &'b Material,
+        indirect: &'b wgpu::Buffer,
+        view: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
-            self.draw_mesh_instanced(mesh, material, instances.clone(), uniforms, light);
+        for (i, mesh) in model.meshes.iter().enumerate().filter(|(_, mesh)| mesh.visible) {
+            let offset = crate::CullBatch::indirect_offset(i);
+            self.draw_mesh_indirect(mesh, material, indirect, offset, view);
+        }
+    }
+
+    fn draw_model_placed(
+        &mut self,
+        model: &'b Model,
+        placements: &'b PlacementBuffers,
+        view: &'b wgpu::BindGroup,
+    ) {
+        for (i, mesh) in model.meshes.iter().enumerate().filter(|(_, mesh)| mesh.visible) {
+            let (buffer, count) = placements.get(i);
+            self.set_vertex_buffer(1, buffer, 0, 0);
+            let material = &model.materials[mesh.material];
+            self.draw_mesh_instanced(mesh, material, 0..count, view);
+        }
+    }
+
+    fn draw_model_placed_with_material(
+        &mut self,
+        model: &'b Model,
+        material: &'b Material,
+        placements: &'b PlacementBuffers,
+        view: &'b wgpu::BindGroup,
+    ) {
+        for (i, mesh) in model.meshes.iter().enumerate().filter(|(_, mesh)| mesh.visible) {
+            let (buffer, count) = placements.get(i);
+            self.set_vertex_buffer(1, buffer, 0, 0);
+            self.draw_mesh_instanced(mesh, material, 0..count, view);
         }
     }
 }
@@ -436,14 +1623,64 @@ where
         uniforms: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
+        for mesh in model.meshes.iter().filter(|mesh| mesh.visible) {
             self.draw_light_mesh_instanced(mesh, instances.clone(), uniforms, light);
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn obj_loads_without_a_gpu_and_round_trips() {
+        let path = std::env::temp_dir().join(format!("learn-wgpu-model-data-{}.obj", std::process::id()));
+        std::fs::write(&path, "\
+            v 0 0 0\n\
+            v 1 0 0\n\
+            v 0 1 0\n\
+            vt 0 0\n\
+            vt 1 0\n\
+            vt 0 1\n\
+            f 1/1 2/2 3/3\n").unwrap();
+        let data = ModelData::load(&path, &ModelLoadOptions::default());
+        let _ = std::fs::remove_file(&path);
+        let data = data.unwrap();
+
+        assert_eq!(data.meshes.len(), 1);
+        let mesh = &data.meshes[0];
+        assert_eq!(mesh.indices.len(), 3);
+        assert!(mesh.has_tex_coords);
+        // No normals in the file, so they get smoothed in. The triangle
+        // faces +z.
+        assert_eq!(mesh.normals, AttributeSource::Generated);
+        assert_eq!(mesh.tangents, AttributeSource::Generated);
+        for v in &mesh.vertices {
+            assert!((v.normal - Vector3::unit_z()).magnitude() < 1e-5);
+        }
+
+        let text = ron::to_string(&data).unwrap();
+        let back: ModelData = ron::from_str(&text).unwrap();
+        assert_eq!(back.meshes[0].indices, mesh.indices);
+        assert_eq!(back.meshes[0].aabb, mesh.aabb);
+        assert_eq!(back.path, data.path);
+    }
+
+    #[test]
+    fn material_checks_stay_in_their_pass() {
+        let mut shadow_only = MaterialLayoutDesc::default();
+        shadow_only.entries.retain(|(_, slot)| matches!(slot, MaterialSlot::DiffuseTexture | MaterialSlot::DiffuseSampler));
 
-pub struct ModelPass {
-    pipeline: wgpu::RenderPipeline,
-    // uniforms: 
-}
\ No newline at end of file
+        // What set_model_pipeline does to the first pass
+        let mut first = LayoutExpectation::default();
+        first.layout = Some(MaterialLayoutDesc::default());
+        assert!(first.check("brick", &shadow_only).is_err());
+        assert_eq!(first.check("brick", &MaterialLayoutDesc::default()), Ok(()));
+
+        // The next pass hasn't set a model pipeline, so it has nothing to
+        // hold the material to
+        let second = LayoutExpectation::default();
+        assert_eq!(second.check("brick", &shadow_only), Ok(()));
+    }
+}
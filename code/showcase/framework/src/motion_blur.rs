@@ -0,0 +1,448 @@
+use anyhow::*;
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::shader_matrix::{to_shader_bytes, MatrixKind, ShaderMatrix};
+use crate::texture::Texture;
+use crate::Uniforms;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MotionBlurMode {
+    Off,
+    /// Blurs by how the camera moved, from the depth buffer and the two
+    /// frames' matrices. Cheap, but moving objects stay sharp unless
+    /// the camera follows them.
+    Camera,
+    /// Blurs by the velocity buffer the main pass writes, so moving
+    /// objects get blurred too
+    PerObject,
+}
+
+impl Default for MotionBlurMode {
+    fn default() -> Self {
+        MotionBlurMode::Off
+    }
+}
+
+impl MotionBlurMode {
+    /// Off, then camera only, then per object, then off again
+    pub fn cycle(self) -> Self {
+        match self {
+            MotionBlurMode::Off => MotionBlurMode::Camera,
+            MotionBlurMode::Camera => MotionBlurMode::PerObject,
+            MotionBlurMode::PerObject => MotionBlurMode::Off,
+        }
+    }
+
+    fn index(self) -> f32 {
+        match self {
+            MotionBlurMode::Off => 0.0,
+            MotionBlurMode::Camera => 1.0,
+            MotionBlurMode::PerObject => 2.0,
+        }
+    }
+}
+
+/// More taps than this and the blur pass costs more than the scene
+pub const MAX_MOTION_BLUR_SAMPLES: u32 = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionBlurSettings {
+    pub mode: MotionBlurMode,
+    /**
+     * How much of the frame the shutter is open for. 0.5 is what film
+     * calls a 180° shutter, where things blur over half of how far they
+     * moved since last frame. 1 blurs the whole way.
+     */
+    pub shutter: f32,
+    /// Taps along each pixel's motion, up to [MAX_MOTION_BLUR_SAMPLES]
+    pub samples: u32,
+    /// No pixel gets blurred further than this many pixels, however
+    /// fast it moved
+    pub max_blur: f32,
+    /// Leave the blur in frame dumps. Off, as a dump is usually for
+    /// looking at what got rendered rather than what it looked like.
+    pub in_captures: bool,
+}
+
+impl Default for MotionBlurSettings {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            shutter: 0.5,
+            samples: 8,
+            max_blur: 32.0,
+            in_captures: false,
+        }
+    }
+}
+
+// Matches the MotionBlur block in motion_blur.frag
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct MotionBlurData {
+    inv_view_proj: ShaderMatrix,
+    prev_view_proj: ShaderMatrix,
+    /// Mode, shutter, samples and max blur in pixels
+    params: [f32; 4],
+    /// Width and height in pixels, then one over each
+    viewport: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for MotionBlurData {}
+unsafe impl bytemuck::Zeroable for MotionBlurData {}
+
+fn motion_blur_data(
+    settings: &MotionBlurSettings,
+    inv_view_proj: Matrix4<f32>,
+    prev_view_proj: Matrix4<f32>,
+    width: u32,
+    height: u32,
+) -> MotionBlurData {
+    let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+    MotionBlurData {
+        inv_view_proj: to_shader_bytes(&inv_view_proj, MatrixKind::Combined),
+        prev_view_proj: to_shader_bytes(&prev_view_proj, MatrixKind::Combined),
+        params: [
+            settings.mode.index(),
+            settings.shutter.max(0.0).min(1.0),
+            // Fewer than 2 taps can't blur anything
+            settings.samples.max(2).min(MAX_MOTION_BLUR_SAMPLES) as f32,
+            settings.max_blur.max(0.0),
+        ],
+        viewport: [width, height, 1.0 / width, 1.0 / height],
+    }
+}
+
+/**
+ * Blurs the HDR image along how each pixel moved since last frame,
+ * before tonemapping. The main pass writes the motion to
+ * [MotionBlurPass::velocity], as a second color target in
+ * [Texture::VELOCITY_FORMAT]: shaders project each vertex with both
+ * [Uniforms::prev_view_proj] and the instance's previous transform and
+ * write the difference in texture coordinates. Pixels nothing was drawn
+ * on, like the sky, get the camera's motion instead.
+ *
+ * Recreate the targets with [MotionBlurPass::resize] along with the
+ * depth buffer.
+ */
+pub struct MotionBlurPass {
+    velocity: Texture<'static>,
+    /// A copy of the scene, as the blur writes back into the HDR target
+    source: Texture<'static>,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    /// Linear for the color, nearest for depth and velocity
+    samplers: [wgpu::Sampler; 2],
+    buffer: wgpu::Buffer,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl MotionBlurPass {
+    pub fn new(device: &wgpu::Device, depth: &Texture, width: u32, height: u32) -> Result<Self> {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                texture_entry(0),
+                texture_entry(1),
+                texture_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+            ],
+            label: Some("MotionBlurPass::layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+        });
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .color_solid(Texture::HDR_FORMAT)
+            // The same full screen triangle
+            .vertex_shader(include_bytes!("shaders/tonemap.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/motion_blur.frag.spv"))
+            .build(device)?;
+
+        let sampler = |filter| device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        // Depth can't be filtered, and velocity shouldn't be, as an
+        // edge between a moving and a still object would get a motion
+        // neither of them has
+        let samplers = [sampler(wgpu::FilterMode::Linear), sampler(wgpu::FilterMode::Nearest)];
+
+        let data = motion_blur_data(&Default::default(), Matrix4::identity(), Matrix4::identity(), width, height);
+        let buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[data]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+
+        let (velocity, source) = Self::create_targets(device, width, height);
+        let bind_group = Self::create_bind_group(device, &layout, &velocity, &source, depth, &samplers, &buffer);
+        Ok(Self { velocity, source, layout, bind_group, samplers, buffer, pipeline })
+    }
+
+    fn create_targets(device: &wgpu::Device, width: u32, height: u32) -> (Texture<'static>, Texture<'static>) {
+        let target = |label, format, usage| Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d { width: width.max(1), height: height.max(1), depth: 1 },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+        });
+        let velocity = target(
+            "MotionBlurPass::velocity",
+            Texture::VELOCITY_FORMAT,
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        );
+        let source = target(
+            "MotionBlurPass::source",
+            Texture::HDR_FORMAT,
+            wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+        );
+        (velocity, source)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        velocity: &Texture,
+        source: &Texture,
+        depth: &Texture,
+        samplers: &[wgpu::Sampler; 2],
+        buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&velocity.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&depth.view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&samplers[0]),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&samplers[1]),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer,
+                        range: 0..std::mem::size_of::<MotionBlurData>() as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("MotionBlurPass::bind_group"),
+        })
+    }
+
+    /// `depth` is the new depth buffer, the same size
+    pub fn resize(&mut self, device: &wgpu::Device, depth: &Texture, width: u32, height: u32) {
+        let (velocity, source) = Self::create_targets(device, width, height);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.layout,
+            &velocity,
+            &source,
+            depth,
+            &self.samplers,
+            &self.buffer,
+        );
+        self.velocity = velocity;
+        self.source = source;
+    }
+
+    /// The main pass's second color target. Clear it to 0, no motion.
+    pub fn velocity(&self) -> &wgpu::TextureView {
+        &self.velocity.view
+    }
+
+    /// Call this once the main camera's [Uniforms] are up to date
+    pub fn update(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        uniforms: &Uniforms,
+        settings: &MotionBlurSettings,
+    ) {
+        let size = self.source.desc.size;
+        let data = motion_blur_data(settings, uniforms.inv_view_proj(), uniforms.prev_view_proj(), size.width, size.height);
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[data]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<MotionBlurData>() as _,
+        );
+    }
+
+    /**
+     * Blurs `hdr` in place, after everything has been drawn to it and
+     * before tonemapping. It needs `COPY_SRC`, which
+     * [Texture::create_hdr_texture] has. Don't call this when the mode
+     * is [MotionBlurMode::Off].
+     */
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, hdr: &Texture) {
+        encoder.copy_texture_to_texture(
+            wgpu::TextureCopyView {
+                texture: &hdr.texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::TextureCopyView {
+                texture: &self.source.texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            self.source.desc.size,
+        );
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &hdr.view,
+                resolve_target: None,
+                // Every pixel gets written
+                load_op: wgpu::LoadOp::Load,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+fn texture_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::SampledTexture {
+            multisampled: false,
+            component_type: wgpu::TextureComponentType::Float,
+            dimension: wgpu::TextureViewDimension::D2,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// camera_velocity in motion_blur.frag
+    fn camera_velocity(data: &MotionBlurData, uv: Vector2<f32>, depth: f32) -> Vector2<f32> {
+        let inv_view_proj = Matrix4::from(data.inv_view_proj);
+        let prev_view_proj = Matrix4::from(data.prev_view_proj);
+        let ndc = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+        let world = inv_view_proj * ndc;
+        let prev = prev_view_proj * (world.truncate() / world.w).extend(1.0);
+        if prev.w <= 0.0 {
+            return Vector2::zero();
+        }
+        let prev_ndc = prev.truncate().truncate() / prev.w;
+        uv - vec2(prev_ndc.x * 0.5 + 0.5, 0.5 - prev_ndc.y * 0.5)
+    }
+
+    /// Where `point` ends up on screen, and its depth
+    fn project(view_proj: Matrix4<f32>, point: Point3<f32>) -> (Vector2<f32>, f32) {
+        let clip = view_proj * point.to_homogeneous();
+        let ndc = clip.truncate() / clip.w;
+        (vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5), ndc.z)
+    }
+
+    #[test]
+    fn camera_motion_reprojects_the_depth() {
+        let proj = perspective(Deg(60.0), 16.0 / 9.0, 0.1, 100.0);
+        let view_proj = |eye: Point3<f32>| proj * Matrix4::look_at_dir(eye, -Vector3::unit_z(), Vector3::unit_y());
+        let now = view_proj(Point3::new(0.5, 0.0, 0.0));
+        let before = view_proj(Point3::new(0.0, 0.0, 0.0));
+        let data = motion_blur_data(&MotionBlurSettings::default(), now.invert().unwrap(), before, 1280, 720);
+
+        let mut last_x = f32::MIN;
+        // Nearest first
+        for &point in &[Point3::new(0.0, 0.0, -5.0), Point3::new(-2.0, 1.0, -10.0), Point3::new(3.0, -1.0, -40.0)] {
+            let (uv, depth) = project(now, point);
+            let (prev_uv, _) = project(before, point);
+            let velocity = camera_velocity(&data, uv, depth);
+            assert!((velocity - (uv - prev_uv)).magnitude() < 1e-4, "{:?}: {:?}", point, velocity);
+            // The camera went right, so everything slid left, and
+            // further away moved less
+            assert!(velocity.x < 0.0 && velocity.y.abs() < 1e-4, "{:?}: {:?}", point, velocity);
+            assert!(velocity.x > last_x, "{:?}: {:?}", point, velocity);
+            last_x = velocity.x;
+        }
+
+        // Nothing moved, nothing blurs
+        let still = motion_blur_data(&MotionBlurSettings::default(), now.invert().unwrap(), now, 1280, 720);
+        let (uv, depth) = project(now, Point3::new(1.0, 2.0, -7.0));
+        assert!(camera_velocity(&still, uv, depth).magnitude() < 1e-4);
+
+        // Behind where the camera was, so there's no telling
+        let turned = proj * Matrix4::look_at_dir(Point3::new(0.0, 0.0, 0.0), Vector3::unit_z(), Vector3::unit_y());
+        let data = motion_blur_data(&MotionBlurSettings::default(), now.invert().unwrap(), turned, 1280, 720);
+        assert_eq!(camera_velocity(&data, uv, depth), Vector2::zero());
+    }
+
+    #[test]
+    fn blur_settings_are_clamped() {
+        let data = motion_blur_data(&MotionBlurSettings::default(), Matrix4::identity(), Matrix4::identity(), 800, 0);
+        assert_eq!(data.params, [0.0, 0.5, 8.0, 32.0]);
+        assert_eq!(data.viewport, [800.0, 1.0, 1.0 / 800.0, 1.0]);
+
+        let silly = MotionBlurSettings {
+            mode: MotionBlurMode::PerObject,
+            shutter: 3.0,
+            samples: 1,
+            max_blur: -4.0,
+            in_captures: true,
+        };
+        let data = motion_blur_data(&silly, Matrix4::identity(), Matrix4::identity(), 1, 1);
+        assert_eq!(data.params, [2.0, 1.0, 2.0, 0.0]);
+
+        let mut mode = MotionBlurMode::Off;
+        for _ in 0..3 {
+            mode = mode.cycle();
+        }
+        assert_eq!(mode, MotionBlurMode::Off);
+    }
+}
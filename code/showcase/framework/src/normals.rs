@@ -0,0 +1,210 @@
+use cgmath::*;
+use std::collections::HashMap;
+use crate::adjacency::Adjacency;
+use crate::model::ModelVertex;
+
+/// What files without normals get smoothed with
+pub const DEFAULT_SMOOTHING_ANGLE: f32 = 30.0;
+
+/// Keeps track of which triangle corners share a normal
+struct Groups {
+    parents: Vec<usize>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Self { parents: (0..len).collect() }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+/**
+ * Replaces every normal with one worked out from the triangles.
+ *
+ * Neighboring triangles whose faces meet at more than
+ * `smoothing_angle` degrees get a hard edge between them, and
+ * everything else is smoothed. 0 gives a faceted look and 180
+ * smooths everything. Vertices sitting on a hard edge get split,
+ * which is why this takes `vertices` as a `Vec` and rewrites
+ * `indices`.
+ *
+ * Triangles are matched up by position using [Adjacency], so vertices
+ * the file already split for UV seams still get the same normal.
+ */
+pub fn recalculate_normals(vertices: &mut Vec<ModelVertex>, indices: &mut [u32], smoothing_angle: f32) {
+    let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+    let adjacency = Adjacency::new(&positions, indices);
+    let num_triangles = adjacency.num_triangles();
+
+    let unit = |n: Vector3<f32>| if n.magnitude2() > 0.0 { n.normalize() } else { n };
+    let face_normals = indices.chunks_exact(3)
+        .map(|c| {
+            let p0 = positions[c[0] as usize];
+            let p1 = positions[c[1] as usize];
+            let p2 = positions[c[2] as usize];
+            unit((p1 - p0).cross(p2 - p0))
+        })
+        .collect::<Vec<_>>();
+    let min_cos = Deg(smoothing_angle.max(0.0).min(180.0)).cos();
+
+    // Corner `t * 3 + k` is corner k of triangle t. Corners across a
+    // smooth edge from each other end up in the same group.
+    let mut groups = Groups::new(num_triangles * 3);
+    for t in 0..num_triangles as u32 {
+        let tri = adjacency.triangles[t as usize];
+        let n = face_normals[t as usize];
+        for (k, other) in adjacency.neighbors(t) {
+            // Everything gets visited from both sides
+            if other < t {
+                continue;
+            }
+            if n.dot(face_normals[other as usize]) < min_cos {
+                continue;
+            }
+            let other_tri = adjacency.triangles[other as usize];
+            for &corner in &[k, (k + 1) % 3] {
+                let id = tri[corner];
+                if let Some(other_corner) = other_tri.iter().position(|&o| o == id) {
+                    groups.union(t as usize * 3 + corner, other as usize * 3 + other_corner);
+                }
+            }
+        }
+    }
+
+    // Faces are weighted by the angle at each corner. Weighting by
+    // area instead lets the way a quad was split into triangles
+    // skew the result.
+    let mut normals: HashMap<usize, Vector3<f32>> = HashMap::new();
+    for (corner, &index) in indices.iter().enumerate().take(num_triangles * 3) {
+        let (t, k) = (corner / 3, corner % 3);
+        let p = positions[index as usize];
+        let a = positions[indices[t * 3 + (k + 1) % 3] as usize] - p;
+        let b = positions[indices[t * 3 + (k + 2) % 3] as usize] - p;
+        let angle = if a.magnitude2() > 0.0 && b.magnitude2() > 0.0 { a.angle(b).0 } else { 0.0 };
+        let group = groups.find(corner);
+        *normals.entry(group).or_insert_with(Vector3::zero) += face_normals[t] * angle;
+    }
+
+    // Corners in the same group at the same place become one vertex,
+    // unless the file split them for a UV seam
+    let mut remap: HashMap<(u32, usize, [u32; 2]), u32> = HashMap::new();
+    let mut new_vertices = Vec::with_capacity(vertices.len());
+    for (corner, index) in indices.iter_mut().enumerate().take(num_triangles * 3) {
+        let group = groups.find(corner);
+        let old = *index as usize;
+        let uv = vertices[old].tex_coords;
+        let key = (adjacency.vertex_ids[old], group, [uv.x.to_bits(), uv.y.to_bits()]);
+        *index = *remap.entry(key).or_insert_with(|| {
+            let mut vertex = vertices[old];
+            vertex.normal = unit(normals[&group]);
+            new_vertices.push(vertex);
+            new_vertices.len() as u32 - 1
+        });
+    }
+    *vertices = new_vertices;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SEGMENTS: usize = 16;
+
+    fn vertex(position: Vector3<f32>) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords: Vector2::zero(),
+            normal: Vector3::zero(),
+            tangent: Vector4::zero(),
+        }
+    }
+
+    /// A closed cylinder along y, with every triangle getting its
+    /// own vertices like an STL file would. 16 segments puts 22.5
+    /// degrees between the side faces.
+    fn cylinder() -> (Vec<ModelVertex>, Vec<u32>) {
+        let ring = |i: usize, y: f32| {
+            let a = Rad::full_turn() * (i % SEGMENTS) as f32 / SEGMENTS as f32;
+            Vector3::new(a.cos(), y, -a.sin())
+        };
+        let mut vertices = Vec::new();
+        let mut push = |a, b, c| {
+            vertices.push(vertex(a));
+            vertices.push(vertex(b));
+            vertices.push(vertex(c));
+        };
+        for i in 0..SEGMENTS {
+            let (b0, b1) = (ring(i, 0.0), ring(i + 1, 0.0));
+            let (t0, t1) = (ring(i, 1.0), ring(i + 1, 1.0));
+            push(b0, b1, t1);
+            push(b0, t1, t0);
+            push(Vector3::new(0.0, 1.0, 0.0), t0, t1);
+            push(Vector3::new(0.0, 0.0, 0.0), b1, b0);
+        }
+        let indices = (0..vertices.len() as u32).collect();
+        (vertices, indices)
+    }
+
+    fn is_cap(v: &ModelVertex) -> bool {
+        v.normal.y.abs() > 0.999
+    }
+
+    #[test]
+    fn smooth_sides_with_hard_caps() {
+        let (mut vertices, mut indices) = cylinder();
+        recalculate_normals(&mut vertices, &mut indices, 30.0);
+
+        for c in indices.chunks_exact(3) {
+            let corners = [vertices[c[0] as usize], vertices[c[1] as usize], vertices[c[2] as usize]];
+            let cap_corners = corners.iter().filter(|v| is_cap(v)).count();
+            // The seam stays hard, so no triangle mixes the two
+            assert!(cap_corners == 0 || cap_corners == 3);
+            if cap_corners == 0 {
+                for v in &corners {
+                    // Smooth sides point straight out from the axis
+                    let radial = Vector3::new(v.position.x, 0.0, v.position.z).normalize();
+                    assert!(v.normal.dot(radial) > 0.9999, "{:?}", v);
+                }
+            }
+        }
+        // Each ring vertex is shared by the side and one cap
+        assert_eq!(vertices.len(), SEGMENTS * 4 + 2);
+    }
+
+    #[test]
+    fn small_angle_facets_the_sides() {
+        let (mut vertices, mut indices) = cylinder();
+        recalculate_normals(&mut vertices, &mut indices, 5.0);
+
+        for c in indices.chunks_exact(3) {
+            let normals = [
+                vertices[c[0] as usize].normal,
+                vertices[c[1] as usize].normal,
+                vertices[c[2] as usize].normal,
+            ];
+            // Every triangle gets its face normal...
+            assert!(normals[0].dot(normals[1]) > 0.9999);
+            assert!(normals[0].dot(normals[2]) > 0.9999);
+            if !is_cap(&vertices[c[0] as usize]) {
+                // ...which is never the radial direction at a corner
+                let p = vertices[c[0] as usize].position;
+                let radial = Vector3::new(p.x, 0.0, p.z).normalize();
+                assert!(normals[0].dot(radial) < 0.999);
+            }
+        }
+    }
+}
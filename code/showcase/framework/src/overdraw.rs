@@ -0,0 +1,562 @@
+use anyhow::*;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::capabilities::is_srgb_format;
+use crate::instance::InstanceRaw;
+use crate::material_layout::MaterialLayout;
+use crate::model::{DrawModel, ModelPass, ModelVertex};
+use crate::palette::Palette;
+use crate::pipeline::RenderPipelineBuilder;
+use crate::settings::RenderSettings;
+use crate::texture::Texture;
+use crate::tonemap::linear_to_srgb;
+
+pub use crate::shader_defines::OVERDRAW_TILE_SIZE;
+/// Fragments per pixel that get the hottest color
+pub const OVERDRAW_HEAT_MAX: f32 = 8.0;
+/// How much of the scene shows through the heat
+const HEAT_ALPHA: u8 = 160;
+/// Buffer to texture copies need rows to be a multiple of this
+const ROW_ALIGNMENT: u32 = 256;
+
+type Mapping = Pin<Box<dyn Future<Output = Result<wgpu::BufferReadMapping, wgpu::BufferAsyncErr>>>>;
+
+/// How a `width` by `height` screen gets cut into tiles
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TileGrid {
+    pub width: u32,
+    pub height: u32,
+    pub tiles_x: u32,
+    pub tiles_y: u32,
+}
+
+impl TileGrid {
+    pub fn new(width: u32, height: u32) -> Self {
+        let tiles = |size: u32| ((size + OVERDRAW_TILE_SIZE - 1) / OVERDRAW_TILE_SIZE).max(1);
+        Self { width, height, tiles_x: tiles(width), tiles_y: tiles(height) }
+    }
+
+    pub fn tile_count(&self) -> usize {
+        (self.tiles_x * self.tiles_y) as usize
+    }
+
+    /// How many pixels tile `i` covers. The ones along the right and
+    /// bottom edges can be cut short.
+    pub fn tile_pixels(&self, i: usize) -> u32 {
+        let (x, y) = (i as u32 % self.tiles_x, i as u32 / self.tiles_x);
+        let span = |tile: u32, size: u32| size.saturating_sub(tile * OVERDRAW_TILE_SIZE).min(OVERDRAW_TILE_SIZE);
+        span(x, self.width) * span(y, self.height)
+    }
+
+    /// Row size of the heat texture's upload
+    fn padded_row_bytes(&self) -> u32 {
+        let row = self.tiles_x * 4;
+        (row + ROW_ALIGNMENT - 1) / ROW_ALIGNMENT * ROW_ALIGNMENT
+    }
+}
+
+/// How many fragments got shaded in each tile, from [OverdrawCounter::poll]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverdrawCounts {
+    pub grid: TileGrid,
+    /// Row by row from the top left, like the screen
+    pub fragments: Vec<u32>,
+}
+
+impl OverdrawCounts {
+    /// Fragments per pixel in tile `i`. 1 means every pixel was shaded
+    /// once, 3 that each got shaded three times on average.
+    pub fn per_pixel(&self, i: usize) -> f32 {
+        self.fragments[i] as f32 / self.grid.tile_pixels(i).max(1) as f32
+    }
+
+    /// Fragments per pixel over the whole screen
+    pub fn average(&self) -> f32 {
+        let total = self.fragments.iter().map(|&f| f as u64).sum::<u64>();
+        total as f32 / (self.grid.width as u64 * self.grid.height as u64).max(1) as f32
+    }
+
+    /// Fragments per pixel in the worst tile
+    pub fn worst(&self) -> f32 {
+        (0..self.fragments.len()).map(|i| self.per_pixel(i)).fold(0.0, f32::max)
+    }
+
+    /**
+     * One sRGB encoded RGBA texel per tile, with rows padded the way
+     * buffer to texture copies want. Colors go from the bottom of
+     * `palette`'s sequential ramp at no overdraw to the top at
+     * [OVERDRAW_HEAT_MAX]. Tiles nothing was drawn in stay clear.
+     */
+    pub fn heat_rgba(&self, palette: &Palette) -> Vec<u8> {
+        let row_bytes = self.grid.padded_row_bytes() as usize;
+        let mut bytes = vec![0; row_bytes * self.grid.tiles_y as usize];
+        for (i, &fragments) in self.fragments.iter().enumerate() {
+            if fragments == 0 {
+                continue;
+            }
+            let color = palette.sequential(self.per_pixel(i) / OVERDRAW_HEAT_MAX);
+            let (x, y) = (i % self.grid.tiles_x as usize, i / self.grid.tiles_x as usize);
+            let texel = y * row_bytes + x * 4;
+            for (c, &linear) in [color.x, color.y, color.z].iter().enumerate() {
+                bytes[texel + c] = (linear_to_srgb(linear) * 255.0).round() as u8;
+            }
+            bytes[texel + 3] = HEAT_ALPHA;
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for OverdrawCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Overdraw: {:.2} fragments per pixel, {:.1} in the worst {}x{} tile",
+            self.average(),
+            self.worst(),
+            OVERDRAW_TILE_SIZE,
+            OVERDRAW_TILE_SIZE,
+        )
+    }
+}
+
+enum CountState {
+    Idle,
+    /// The copy is in an encoder that hasn't been submitted yet
+    Copied,
+    Mapping(Mapping),
+}
+
+/// Everything that depends on the size of the screen
+struct Tiles {
+    grid: TileGrid,
+    counts: wgpu::Buffer,
+    readback: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    heat: Texture<'static>,
+    overlay_bind_group: wgpu::BindGroup,
+}
+
+/**
+ * Counts how many fragments get shaded in each [OVERDRAW_TILE_SIZE]
+ * square of the screen, and draws that over the frame as a heatmap.
+ * Where it's hot, the same pixels are being shaded over and over.
+ *
+ * Counting is a separate pass over the same draws as the scene, with a
+ * fragment shader that does nothing but bump an atomic per tile. It
+ * doesn't test depth, so everything that gets rasterized counts, even
+ * what ends up hidden. Only one count is in flight at a time, and one
+ * is only started every [OverdrawCounter::interval] frames, as reading
+ * back is the slow part. Reads never stall, like [crate::PixelProbe].
+ */
+pub struct OverdrawCounter {
+    layout: wgpu::BindGroupLayout,
+    overlay_layout: wgpu::BindGroupLayout,
+    count_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+    sampler: wgpu::Sampler,
+    heat_format: wgpu::TextureFormat,
+    tiles: Tiles,
+    state: CountState,
+    frames_since_count: u32,
+    last: Option<OverdrawCounts>,
+    /// Frames between counts
+    pub interval: u32,
+}
+
+impl OverdrawCounter {
+    /**
+     * `view_layout` is what the scene's view bind group uses, like
+     * [crate::UniformBinding::layout]. It's bound second, after the
+     * material, so the usual [DrawModel] calls draw into the count.
+     */
+    pub fn new(
+        device: &wgpu::Device,
+        material_layout: &MaterialLayout,
+        view_layout: &wgpu::BindGroupLayout,
+        settings: &RenderSettings,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::StorageBuffer { dynamic: false, readonly: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+            ],
+            label: Some("OverdrawCounter::layout"),
+        });
+        let overlay_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        component_type: wgpu::TextureComponentType::Float,
+                        dimension: wgpu::TextureViewDimension::D2,
+                    },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+            ],
+            label: Some("OverdrawCounter::overlay_layout"),
+        });
+
+        let count_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&material_layout.layout, view_layout, &layout],
+        });
+        let count_pipeline = RenderPipelineBuilder::new()
+            .layout(&count_layout)
+            // Every fragment counts, hidden or not
+            .depth_no_stencil(Texture::DEPTH_FORMAT, false, wgpu::CompareFunction::Always)
+            .vertex_buffer::<ModelVertex>()
+            .vertex_buffer::<InstanceRaw>()
+            .vertex_shader(include_bytes!("shaders/overdraw.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/overdraw.frag.spv"))
+            .build(device)?;
+
+        let overlay_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&overlay_layout],
+        });
+        let overlay_pipeline = RenderPipelineBuilder::new()
+            .layout(&overlay_pipeline_layout)
+            .color_state(wgpu::ColorStateDescriptor {
+                format: settings.surface_format,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            // The same full screen triangle
+            .vertex_shader(include_bytes!("shaders/tonemap.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/overdraw_overlay.frag.spv"))
+            .build(device)?;
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        // The heat is always uploaded sRGB encoded. An sRGB texture
+        // decodes it so the output can encode it again, and a plain one
+        // hands it straight to an output that doesn't.
+        let heat_format = if is_srgb_format(settings.surface_format) {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Rgba8Unorm
+        };
+
+        let tiles = Self::create_tiles(device, &layout, &overlay_layout, &sampler, heat_format, width, height);
+        Ok(Self {
+            layout,
+            overlay_layout,
+            count_pipeline,
+            overlay_pipeline,
+            sampler,
+            heat_format,
+            tiles,
+            state: CountState::Idle,
+            frames_since_count: u32::MAX,
+            last: None,
+            interval: 30,
+        })
+    }
+
+    fn create_tiles(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        overlay_layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        heat_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Tiles {
+        let grid = TileGrid::new(width, height);
+        let size = (grid.tile_count() * std::mem::size_of::<u32>()) as wgpu::BufferAddress;
+        let counts = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("OverdrawCounter::counts"),
+            size,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::COPY_SRC,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("OverdrawCounter::readback"),
+            size,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        });
+        let grid_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[grid.tiles_x, grid.tiles_y, 0, 0]),
+            wgpu::BufferUsage::UNIFORM,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer { buffer: &counts, range: 0..size },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &grid_buffer,
+                        range: 0..std::mem::size_of::<[u32; 4]>() as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("OverdrawCounter::bind_group"),
+        });
+
+        let heat = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("OverdrawCounter::heat"),
+            size: wgpu::Extent3d { width: grid.tiles_x, height: grid.tiles_y, depth: 1 },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: heat_format,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        let overlay_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: overlay_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&heat.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("OverdrawCounter::overlay_bind_group"),
+        });
+        Tiles { grid, counts, readback, bind_group, heat, overlay_bind_group }
+    }
+
+    /// Call this when the window resizes. Anything in flight is
+    /// dropped, and the overlay stays off until the next count.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.tiles = Self::create_tiles(
+            device,
+            &self.layout,
+            &self.overlay_layout,
+            &self.sampler,
+            self.heat_format,
+            width,
+            height,
+        );
+        self.state = CountState::Idle;
+        self.last = None;
+    }
+
+    /// Whether to count this frame. Call it once per frame, as it's
+    /// also what keeps track of the interval.
+    pub fn should_count(&mut self) -> bool {
+        match self.state {
+            CountState::Idle => {}
+            _ => return false,
+        }
+        self.frames_since_count = self.frames_since_count.saturating_add(1);
+        if self.frames_since_count < self.interval {
+            return false;
+        }
+        self.frames_since_count = 0;
+        true
+    }
+
+    /**
+     * Clears the counters and starts the counting pass. Draw the scene
+     * into it with the usual [DrawModel] calls, then hand the encoder
+     * to [OverdrawCounter::end_count]. `depth` only needs to be the
+     * size of the screen, nothing reads or writes it.
+     */
+    pub fn begin_count_pass<'b>(
+        &'b self,
+        device: &wgpu::Device,
+        encoder: &'b mut wgpu::CommandEncoder,
+        depth: &'b Texture,
+        material_layout: &MaterialLayout,
+    ) -> ModelPass<'b> {
+        let size = (self.tiles.grid.tile_count() * std::mem::size_of::<u32>()) as wgpu::BufferAddress;
+        let zeros = device.create_buffer_with_data(&vec![0; size as usize], wgpu::BufferUsage::COPY_SRC);
+        encoder.copy_buffer_to_buffer(&zeros, 0, &self.tiles.counts, 0, size);
+
+        let mut pass = ModelPass::from(encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &depth.view,
+                depth_load_op: wgpu::LoadOp::Load,
+                depth_store_op: wgpu::StoreOp::Store,
+                clear_depth: 1.0,
+                stencil_load_op: wgpu::LoadOp::Load,
+                stencil_store_op: wgpu::StoreOp::Store,
+                clear_stencil: 0,
+            }),
+        }));
+        pass.set_model_pipeline(&self.count_pipeline, material_layout);
+        pass.set_bind_group(2, &self.tiles.bind_group, &[]);
+        pass
+    }
+
+    /// Copies the counts out once the pass has ended
+    pub fn end_count(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let size = (self.tiles.grid.tile_count() * std::mem::size_of::<u32>()) as wgpu::BufferAddress;
+        encoder.copy_buffer_to_buffer(&self.tiles.counts, 0, &self.tiles.readback, 0, size);
+        self.state = CountState::Copied;
+    }
+
+    /// Call this once the encoder passed to [OverdrawCounter::end_count]
+    /// has been submitted
+    pub fn after_submit(&mut self) {
+        if let CountState::Copied = self.state {
+            let size = (self.tiles.grid.tile_count() * std::mem::size_of::<u32>()) as wgpu::BufferAddress;
+            self.state = CountState::Mapping(Box::pin(self.tiles.readback.map_read(0, size)));
+        }
+    }
+
+    /// Returns the last count once the GPU has finished with it, and
+    /// uploads it for [OverdrawCounter::draw_overlay]
+    pub fn poll(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        palette: &Palette,
+    ) -> Option<&OverdrawCounts> {
+        let result = match &mut self.state {
+            CountState::Mapping(mapping) => {
+                device.poll(wgpu::Maintain::Poll);
+                let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+                match mapping.as_mut().poll(&mut cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return None,
+                }
+            }
+            _ => return None,
+        };
+        self.state = CountState::Idle;
+        let mapping = match result {
+            Ok(mapping) => mapping,
+            Err(_) => {
+                log::error!("Unable to read back the overdraw counts");
+                return None;
+            }
+        };
+        let fragments = mapping.as_slice()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let counts = OverdrawCounts { grid: self.tiles.grid, fragments };
+
+        let grid = self.tiles.grid;
+        let staging_buffer = device.create_buffer_with_data(&counts.heat_rgba(palette), wgpu::BufferUsage::COPY_SRC);
+        encoder.copy_buffer_to_texture(
+            wgpu::BufferCopyView {
+                buffer: &staging_buffer,
+                offset: 0,
+                bytes_per_row: grid.padded_row_bytes(),
+                rows_per_image: grid.tiles_y,
+            },
+            wgpu::TextureCopyView {
+                texture: &self.tiles.heat.texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::Extent3d { width: grid.tiles_x, height: grid.tiles_y, depth: 1 },
+        );
+        self.last = Some(counts);
+        self.last.as_ref()
+    }
+
+    pub fn last(&self) -> Option<&OverdrawCounts> {
+        self.last.as_ref()
+    }
+
+    /// Blends the heatmap over `output`, once there's been a count
+    pub fn draw_overlay(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        if self.last.is_none() {
+            return;
+        }
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Load,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.overlay_pipeline);
+        pass.set_bind_group(0, &self.tiles.overlay_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::palette::PaletteScheme;
+
+    #[test]
+    fn edge_tiles_are_cut_short() {
+        let grid = TileGrid::new(100, 40);
+        assert_eq!((grid.tiles_x, grid.tiles_y), (4, 2));
+        assert_eq!(grid.tile_pixels(0), 32 * 32);
+        // The last column is 4 wide and the last row 8 high
+        assert_eq!(grid.tile_pixels(3), 4 * 32);
+        assert_eq!(grid.tile_pixels(7), 4 * 8);
+        let pixels = (0..grid.tile_count()).map(|i| grid.tile_pixels(i)).sum::<u32>();
+        assert_eq!(pixels, 100 * 40);
+        assert_eq!(TileGrid::new(0, 0).tile_count(), 1);
+    }
+
+    #[test]
+    fn heat_follows_fragments_per_pixel() {
+        let grid = TileGrid::new(64, 40);
+        // Nothing, once per pixel, then way past the hottest
+        let fragments = vec![0, 32 * 32, 32 * 8 * 3 / 2, 20 * 32 * 8];
+        let counts = OverdrawCounts { grid, fragments };
+        assert_eq!(counts.per_pixel(1), 1.0);
+        assert_eq!(counts.per_pixel(2), 1.5);
+        assert_eq!(counts.worst(), 20.0);
+        let total = 32 * 32 + 32 * 8 * 3 / 2 + 20 * 32 * 8;
+        assert!((counts.average() - total as f32 / (64.0 * 40.0)).abs() < 1e-6);
+
+        let palette = Palette::new(PaletteScheme::ColorBlindSafe);
+        let heat = counts.heat_rgba(&palette);
+        assert_eq!(heat.len(), 256 * 2);
+        let texel = |x: usize, y: usize| &heat[y * 256 + x * 4..y * 256 + x * 4 + 4];
+        // Empty tiles let the scene through untouched
+        assert_eq!(texel(0, 0), &[0, 0, 0, 0]);
+        assert_eq!(texel(1, 0)[3], HEAT_ALPHA);
+        // Clamped to the top of the ramp
+        let hottest = palette.sequential(1.0);
+        let expected = [hottest.x, hottest.y, hottest.z].iter()
+            .map(|&c| (linear_to_srgb(c) * 255.0).round() as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(&texel(1, 1)[..3], &expected[..]);
+    }
+}
@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many frames [FrameStats] averages over
+const STATS_FRAMES: usize = 120;
+
+/// A cap on how often frames get drawn. Only used when vsync is off,
+/// since vsync already caps it at the display's refresh rate.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FrameLimit {
+    Off,
+    Fps30,
+    Fps60,
+    Fps120,
+    /// Frames per second
+    Custom(f32),
+}
+
+impl FrameLimit {
+    /// How long each frame should take. None means as fast as possible.
+    pub fn frame_time(self) -> Option<Duration> {
+        let fps = match self {
+            FrameLimit::Off => return None,
+            FrameLimit::Fps30 => 30.0,
+            FrameLimit::Fps60 => 60.0,
+            FrameLimit::Fps120 => 120.0,
+            FrameLimit::Custom(fps) => fps,
+        };
+        if fps > 0.0 && fps.is_finite() {
+            Some(Duration::from_secs_f32(1.0 / fps))
+        } else {
+            None
+        }
+    }
+
+    /// Steps through the presets, for binding to a key
+    pub fn next(self) -> Self {
+        match self {
+            FrameLimit::Off => FrameLimit::Fps30,
+            FrameLimit::Fps30 => FrameLimit::Fps60,
+            FrameLimit::Fps60 => FrameLimit::Fps120,
+            FrameLimit::Fps120 | FrameLimit::Custom(_) => FrameLimit::Off,
+        }
+    }
+}
+
+/**
+ * How [crate::run] paces frames. Demos hand this over through
+ * [crate::Demo::frame_pacing] and it gets looked at every frame, so
+ * it can be changed while running.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FramePacing {
+    /// Present with `Fifo` when on, `Immediate` when off
+    pub vsync: bool,
+    /// Ignored while `vsync` is on
+    pub limit: FrameLimit,
+    /// Stop drawing entirely while the window isn't focused. When this
+    /// is off the demo keeps running in the background.
+    pub sleep_when_unfocused: bool,
+}
+
+impl Default for FramePacing {
+    fn default() -> Self {
+        Self {
+            vsync: true,
+            limit: FrameLimit::Off,
+            sleep_when_unfocused: true,
+        }
+    }
+}
+
+impl FramePacing {
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        if self.vsync {
+            wgpu::PresentMode::Fifo
+        } else {
+            wgpu::PresentMode::Immediate
+        }
+    }
+
+    /// The frame time the limiter should hold, if it should run at all
+    pub fn limiter_target(&self) -> Option<Duration> {
+        if self.vsync {
+            None
+        } else {
+            self.limit.frame_time()
+        }
+    }
+}
+
+/**
+ * Holds frames to a target frame time. `thread::sleep` can wake up a
+ * good while after it's asked to, depending on the OS timer, so it
+ * sleeps until just short of the deadline and spins the rest of the
+ * way. How short is worked out once at startup by [FrameLimiter::new].
+ */
+pub struct FrameLimiter {
+    /// How much later than asked `thread::sleep` wakes up, worst case
+    sleep_slack: Duration,
+    deadline: Option<Instant>,
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        Self {
+            sleep_slack: Self::calibrate(),
+            deadline: None,
+        }
+    }
+
+    /// Measures how late short sleeps are. Takes around 10ms.
+    pub fn calibrate() -> Duration {
+        let asked = Duration::from_millis(1);
+        let mut worst = Duration::from_millis(0);
+        for _ in 0..8 {
+            let start = Instant::now();
+            std::thread::sleep(asked);
+            worst = worst.max(start.elapsed().checked_sub(asked).unwrap_or_default());
+        }
+        // A little extra, since eight samples won't catch the worst case
+        (worst + Duration::from_micros(250)).min(Duration::from_millis(20))
+    }
+
+    pub fn sleep_slack(&self) -> Duration {
+        self.sleep_slack
+    }
+
+    /// Waits until one `target` after the last frame. Call this once a
+    /// frame, after presenting.
+    pub fn wait(&mut self, target: Duration) {
+        let deadline = next_deadline(self.deadline, target, Instant::now());
+        self.deadline = Some(deadline);
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let remaining = deadline - now;
+            if remaining > self.sleep_slack {
+                std::thread::sleep(remaining - self.sleep_slack);
+            } else {
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    /// Forgets the last deadline, like when the limiter gets turned off
+    /// so it doesn't try to catch up when it's turned back on
+    pub fn reset(&mut self) {
+        self.deadline = None;
+    }
+}
+
+/**
+ * Deadlines count on from the last one rather than from now, so a
+ * wake up that's a bit late doesn't make the next frame late too.
+ * If a frame ran so long that the deadline has already passed, the
+ * schedule starts over from now. Catching up would mean a burst of
+ * unlimited frames.
+ */
+fn next_deadline(previous: Option<Instant>, target: Duration, now: Instant) -> Instant {
+    match previous {
+        Some(previous) if previous + target >= now => previous + target,
+        _ => now,
+    }
+}
+
+/// A rolling window of frame times, for checking that pacing is even
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+    frame_times: VecDeque<Duration>,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        Self { frame_times: VecDeque::with_capacity(STATS_FRAMES) }
+    }
+
+    pub fn push(&mut self, dt: Duration) {
+        if self.frame_times.len() == STATS_FRAMES {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(dt);
+    }
+
+    pub fn clear(&mut self) {
+        self.frame_times.clear();
+    }
+
+    fn millis(&self) -> impl Iterator<Item = f32> + '_ {
+        self.frame_times.iter().map(|dt| dt.as_secs_f32() * 1000.0)
+    }
+
+    /// Average frame time in milliseconds
+    pub fn mean_ms(&self) -> f32 {
+        if self.frame_times.is_empty() {
+            return 0.0;
+        }
+        self.millis().sum::<f32>() / self.frame_times.len() as f32
+    }
+
+    /// Standard deviation of the frame time in milliseconds. Good
+    /// pacing keeps this well under a millisecond.
+    pub fn jitter_ms(&self) -> f32 {
+        if self.frame_times.len() < 2 {
+            return 0.0;
+        }
+        let mean = self.mean_ms();
+        let variance = self.millis().map(|ms| (ms - mean) * (ms - mean)).sum::<f32>()
+            / (self.frame_times.len() - 1) as f32;
+        variance.sqrt()
+    }
+
+    /// Something like `16.68ms (target 16.67ms, jitter 0.12ms)`
+    pub fn summary(&self, target: Option<Duration>) -> String {
+        match target {
+            Some(target) => format!(
+                "{:.2}ms (target {:.2}ms, jitter {:.2}ms)",
+                self.mean_ms(),
+                target.as_secs_f32() * 1000.0,
+                self.jitter_ms(),
+            ),
+            None => format!("{:.2}ms (jitter {:.2}ms)", self.mean_ms(), self.jitter_ms()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deadlines_keep_their_rhythm_unless_a_frame_overruns() {
+        let target = Duration::from_millis(10);
+        let start = Instant::now();
+
+        // The first frame has nothing to wait for
+        assert_eq!(next_deadline(None, target, start), start);
+
+        // Woke up 2ms late, but the next deadline is still 10ms after
+        // the last one rather than 10ms after now
+        let late = start + Duration::from_millis(2);
+        assert_eq!(next_deadline(Some(start), target, late), start + target);
+
+        // A 50ms hitch starts the schedule over
+        let hitch = start + Duration::from_millis(50);
+        assert_eq!(next_deadline(Some(start), target, hitch), hitch);
+    }
+
+    #[test]
+    fn limiter_only_runs_without_vsync() {
+        let mut pacing = FramePacing { limit: FrameLimit::Fps60, ..Default::default() };
+        assert_eq!(pacing.limiter_target(), None);
+        pacing.vsync = false;
+        let target = pacing.limiter_target().unwrap();
+        assert!((target.as_secs_f32() - 1.0 / 60.0).abs() < 1e-6);
+
+        assert_eq!(FrameLimit::Custom(0.0).frame_time(), None);
+        assert_eq!(FrameLimit::Off.frame_time(), None);
+    }
+
+    #[test]
+    fn even_frames_have_no_jitter() {
+        let mut stats = FrameStats::new();
+        for _ in 0..10 {
+            stats.push(Duration::from_millis(16));
+        }
+        assert!((stats.mean_ms() - 16.0).abs() < 1e-4);
+        assert!(stats.jitter_ms() < 1e-4);
+
+        stats.clear();
+        for &ms in &[10, 20, 10, 20] {
+            stats.push(Duration::from_millis(ms));
+        }
+        assert!((stats.mean_ms() - 15.0).abs() < 1e-4);
+        // The sample standard deviation of 10, 20, 10, 20
+        assert!((stats.jitter_ms() - (100.0f32 / 3.0).sqrt()).abs() < 1e-3);
+    }
+}
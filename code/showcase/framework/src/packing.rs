@@ -0,0 +1,302 @@
+use anyhow::*;
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use crate::model::ModelData;
+
+/// What each channel of a packed texture is when there's no file for
+/// it: no occlusion, fully smooth and not metallic, which is the same
+/// as not having a packed texture at all
+pub const NEUTRAL_PACKED: [u8; 4] = [255, 0, 0, 255];
+
+/**
+ * Which grayscale file goes in which channel of a material's packed
+ * texture. The shader reads red as ambient occlusion, green as
+ * roughness and blue as how metallic it is, the same order glTF uses.
+ * File names are relative to the model, like the ones in an MTL file.
+ *
+ * In a scene file this goes in [crate::ModelOverrides::packing], like
+ * `"brick": (roughness: Some("brick_rough.png"))`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelPacking {
+    /// An image that's packed already, like one from `--pack-textures`.
+    /// The other fields are ignored when this is set.
+    pub packed: Option<String>,
+    pub occlusion: Option<String>,
+    pub roughness: Option<String>,
+    pub metallic: Option<String>,
+}
+
+impl ChannelPacking {
+    /**
+     * The sources an MTL file names, if it names any. `map_Pr` and
+     * `map_Pm` come from the PBR extension to MTL. Occlusion doesn't
+     * have a standard name, so `map_ao` is used, falling back to the
+     * ambient map.
+     */
+    pub fn from_mtl(material: &tobj::Material) -> Option<Self> {
+        let param = |key: &str| material.unknown_param.get(key).cloned();
+        let ambient = Some(material.ambient_texture.clone()).filter(|t| !t.is_empty());
+        let packing = Self {
+            packed: None,
+            occlusion: param("map_ao").or(ambient),
+            roughness: param("map_Pr"),
+            metallic: param("map_Pm"),
+        };
+        if packing.is_empty() {
+            None
+        } else {
+            Some(packing)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packed.is_none() && self.channels().iter().all(Option::is_none)
+    }
+
+    /// The red, green and blue sources
+    pub fn channels(&self) -> [Option<&str>; 3] {
+        [
+            self.occlusion.as_deref(),
+            self.roughness.as_deref(),
+            self.metallic.as_deref(),
+        ]
+    }
+}
+
+/**
+ * Packs up to three grayscale images into the red, green and blue
+ * channels of one texture. Missing channels get [NEUTRAL_PACKED].
+ * Sources that aren't all the same size get resized to the largest
+ * one, with a warning, as that usually means one of them was exported
+ * wrong.
+ */
+pub fn pack_channels(name: &str, sources: [Option<&DynamicImage>; 3]) -> RgbaImage {
+    let (width, height) = sources.iter()
+        .flatten()
+        .map(|image| image.dimensions())
+        .max_by_key(|(w, h)| w * h)
+        .unwrap_or((1, 1));
+
+    let channels = sources.iter()
+        .map(|source| source.map(|image| {
+            let gray = image.to_luma();
+            if gray.dimensions() == (width, height) {
+                gray
+            } else {
+                log::warn!(
+                    "{}: resizing a {}x{} channel to {}x{} to match the others",
+                    name,
+                    gray.width(),
+                    gray.height(),
+                    width,
+                    height,
+                );
+                image::imageops::resize(&gray, width, height, image::imageops::FilterType::Triangle)
+            }
+        }))
+        .collect::<Vec<Option<GrayImage>>>();
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let mut pixel = NEUTRAL_PACKED;
+        for (i, channel) in channels.iter().enumerate() {
+            if let Some(channel) = channel {
+                pixel[i] = channel.get_pixel(x, y)[0];
+            }
+        }
+        image::Rgba(pixel)
+    })
+}
+
+/**
+ * Keeps packed textures on disk, next to the thumbnails in
+ * [crate::settings_dir], so each set of sources only gets packed once.
+ * They're keyed by a hash of the source files and which channel each
+ * went in, so editing any of them packs them again.
+ */
+pub struct PackedTextureCache {
+    dir: PathBuf,
+}
+
+impl PackedTextureCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `packed` in [crate::settings_dir]
+    pub fn in_settings_dir() -> Self {
+        Self::new(crate::settings_dir().join("packed"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the packed texture for the already resolved `sources`
+    /// goes
+    pub fn path_for(&self, sources: &[Option<PathBuf>; 3]) -> Result<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        for (i, source) in sources.iter().enumerate() {
+            hasher.write_usize(i);
+            if let Some(path) = source {
+                let bytes = std::fs::read(path)
+                    .with_context(|| format!("Unable to read {}", path.display()))?;
+                hasher.write(&bytes);
+            }
+        }
+        Ok(self.dir.join(format!("{:016x}.png", hasher.finish())))
+    }
+
+    /// Packs `sources` into the cache, unless it's there already, and
+    /// returns where it is
+    pub fn get_or_pack(&self, name: &str, sources: &[Option<PathBuf>; 3]) -> Result<PathBuf> {
+        let cached = self.path_for(sources)?;
+        if cached.exists() {
+            return Ok(cached);
+        }
+        let packed = pack_files(name, sources)?;
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Unable to create {}", self.dir.display()))?;
+        packed.save(&cached)
+            .with_context(|| format!("Unable to save {}", cached.display()))?;
+        Ok(cached)
+    }
+
+    /**
+     * Saves the packed textures `data` made in memory while loading,
+     * so the next load finds them here. Loading never writes anything
+     * itself, so only the viewer calls this.
+     */
+    pub fn save_packed(&self, data: &ModelData) -> Result<()> {
+        for material in &data.materials {
+            if let Some(channels) = material.packed.as_ref().and_then(|p| p.channels.as_ref()) {
+                self.get_or_pack(&material.name, channels)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// [pack_channels] on files
+pub fn pack_files(name: &str, sources: &[Option<PathBuf>; 3]) -> Result<RgbaImage> {
+    let mut images = Vec::new();
+    for source in sources {
+        images.push(match source {
+            Some(path) => Some(image::open(path)
+                .with_context(|| format!("Unable to load {}", path.display()))?),
+            None => None,
+        });
+    }
+    Ok(pack_channels(name, [images[0].as_ref(), images[1].as_ref(), images[2].as_ref()]))
+}
+
+/**
+ * What `--pack-textures` does. Packs the channels of every material in
+ * the MTL file that has any, writes each as `<material>_orm.png` next
+ * to the MTL file, and returns a [crate::ModelOverrides::packing] that
+ * points at them, ready to paste into a scene file.
+ */
+pub fn pack_mtl_textures(mtl_path: &Path) -> Result<HashMap<String, ChannelPacking>> {
+    let is_mtl = mtl_path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("mtl"))
+        .unwrap_or(false);
+    ensure!(is_mtl, "Only MTL files can be packed, and {} isn't one", mtl_path.display());
+    let folder = mtl_path.parent().context("Unable to find parent for the MTL file")?;
+    let (materials, _) = tobj::load_mtl(mtl_path)
+        .with_context(|| format!("Unable to read {}", mtl_path.display()))?;
+
+    let mut overrides = HashMap::new();
+    for material in &materials {
+        let packing = match ChannelPacking::from_mtl(material) {
+            Some(packing) => packing,
+            None => continue,
+        };
+        let sources = resolve_channels(&packing, folder);
+        let packed = pack_files(&material.name, &sources)?;
+        let file_name = format!("{}_orm.png", material.name);
+        let out = folder.join(&file_name);
+        packed.save(&out)
+            .with_context(|| format!("Unable to save {}", out.display()))?;
+        overrides.insert(material.name.clone(), ChannelPacking {
+            packed: Some(file_name),
+            ..Default::default()
+        });
+    }
+    Ok(overrides)
+}
+
+/// The channel sources as paths relative to `folder`
+fn resolve_channels(packing: &ChannelPacking, folder: &Path) -> [Option<PathBuf>; 3] {
+    let [r, g, b] = packing.channels();
+    let resolve = |written: Option<&str>| written.map(|w| folder.join(w));
+    [resolve(r), resolve(g), resolve(b)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gray(width: u32, height: u32, value: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, image::Luma([value])))
+    }
+
+    #[test]
+    fn channels_land_where_they_were_assigned() {
+        let roughness = gray(4, 4, 100);
+        let metallic = gray(2, 2, 200);
+        let packed = pack_channels("test", [None, Some(&roughness), Some(&metallic)]);
+
+        // The smaller source got scaled up to match
+        assert_eq!(packed.dimensions(), (4, 4));
+        for pixel in packed.pixels() {
+            let [r, g, b, a] = pixel.0;
+            assert_eq!((r, g, a), (NEUTRAL_PACKED[0], 100, 255));
+            assert!((b as i32 - 200).abs() <= 1, "{}", b);
+        }
+
+        let empty = pack_channels("test", [None, None, None]);
+        assert_eq!(empty.dimensions(), (1, 1));
+        assert_eq!(empty.get_pixel(0, 0).0, NEUTRAL_PACKED);
+    }
+
+    #[test]
+    fn loading_packs_in_memory() {
+        let fixture = crate::assets::test::Fixture::new();
+        fixture.write("stone.mtl", "\
+            newmtl stone\n\
+            map_Kd diffuse.png\n\
+            map_Bump normal.png\n\
+            map_Pr rough.png\n");
+        for file in &["diffuse.png", "normal.png"] {
+            image::RgbaImage::new(2, 2).save(fixture.root.join(file)).unwrap();
+        }
+        gray(2, 2, 100).save(fixture.root.join("rough.png")).unwrap();
+        let obj = fixture.write("stone.obj", "\
+            mtllib stone.mtl\n\
+            v 0 0 0\n\
+            v 1 0 0\n\
+            v 0 1 0\n\
+            vt 0 0\n\
+            vt 1 0\n\
+            vt 0 1\n\
+            usemtl stone\n\
+            f 1/1 2/2 3/3\n");
+
+        let data = ModelData::load(obj, &Default::default()).unwrap();
+        let packed = data.materials[0].packed.as_ref().unwrap();
+        // Nothing went in the settings dir
+        assert!(!packed.path.exists());
+        let image = packed.pack_unsaved().unwrap().unwrap().to_rgba();
+        assert_eq!(image.get_pixel(0, 0).0, [NEUTRAL_PACKED[0], 100, NEUTRAL_PACKED[2], 255]);
+
+        let cache = PackedTextureCache::new(fixture.root.join("packed"));
+        cache.save_packed(&data).unwrap();
+        let saved = cache.path_for(packed.channels.as_ref().unwrap()).unwrap();
+        assert_eq!(image::open(saved).unwrap().to_rgba(), image);
+    }
+}
@@ -0,0 +1,259 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Which colors the debug views use. Every debug color comes from here,
+ * so changing [crate::RenderSettings::palette] changes all of them at
+ * once.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaletteScheme {
+    /// The bright primaries the demos started out with. Easy to tell
+    /// apart with normal color vision, but red and green are the same
+    /// to about one in twelve men.
+    Classic,
+    /// Okabe and Ito's categorical colors and viridis for heatmaps,
+    /// which stay apart with any of the common kinds of color
+    /// blindness
+    ColorBlindSafe,
+}
+
+impl Default for PaletteScheme {
+    fn default() -> Self {
+        PaletteScheme::ColorBlindSafe
+    }
+}
+
+/**
+ * What a debug color is for. Each is an index into the categorical
+ * palette, so they stay in step across schemes.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugColor {
+    /// Tint on selected instances
+    Selection = 0,
+    /// The camera arrow on the minimap
+    CameraMarker = 1,
+}
+
+/// sRGB, as that's what palettes get published in
+const CLASSIC_CATEGORICAL: [[u8; 3]; 7] = [
+    [255, 188, 89],
+    [255, 237, 124],
+    [255, 0, 0],
+    [0, 255, 0],
+    [0, 0, 255],
+    [255, 0, 255],
+    [0, 255, 255],
+];
+
+/// Blue to green to red, the classic "jet" ramp
+const CLASSIC_SEQUENTIAL: [[u8; 3]; 5] = [
+    [0, 0, 255],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 255, 0],
+    [255, 0, 0],
+];
+
+/// Okabe and Ito (2008), without the black
+const SAFE_CATEGORICAL: [[u8; 3]; 7] = [
+    [230, 159, 0],
+    [86, 180, 233],
+    [0, 158, 115],
+    [240, 228, 66],
+    [0, 114, 178],
+    [213, 94, 0],
+    [204, 121, 167],
+];
+
+/// Viridis. Its brightness only ever goes up, which is what keeps it
+/// readable without any hue at all.
+const SAFE_SEQUENTIAL: [[u8; 3]; 5] = [
+    [68, 1, 84],
+    [59, 82, 139],
+    [33, 145, 140],
+    [94, 201, 98],
+    [253, 231, 37],
+];
+
+/// The decoding an sRGB texture applies when it's read
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.040_45 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_linear(c: [u8; 3]) -> Vector3<f32> {
+    Vector3::new(c[0], c[1], c[2]).map(|c| srgb_to_linear(c as f32 / 255.0))
+}
+
+/**
+ * The colors of one [PaletteScheme]. Everything comes out in linear
+ * RGB, ready for a uniform.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Palette {
+    pub scheme: PaletteScheme,
+}
+
+impl Palette {
+    pub fn new(scheme: PaletteScheme) -> Self {
+        Self { scheme }
+    }
+
+    fn tables(&self) -> (&'static [[u8; 3]], &'static [[u8; 3]]) {
+        match self.scheme {
+            PaletteScheme::Classic => (&CLASSIC_CATEGORICAL, &CLASSIC_SEQUENTIAL),
+            PaletteScheme::ColorBlindSafe => (&SAFE_CATEGORICAL, &SAFE_SEQUENTIAL),
+        }
+    }
+
+    /// How many categorical colors there are before they repeat
+    pub fn categorical_len(&self) -> usize {
+        self.tables().0.len()
+    }
+
+    /// A color for telling things apart, like one per mesh. `id` wraps
+    /// around, so any number works.
+    pub fn categorical(&self, id: usize) -> Vector3<f32> {
+        let table = self.tables().0;
+        to_linear(table[id % table.len()])
+    }
+
+    pub fn color(&self, color: DebugColor) -> Vector3<f32> {
+        self.categorical(color as usize)
+    }
+
+    /// A color for an amount, like in a heatmap. `t` goes from 0 to 1,
+    /// values outside get clamped.
+    pub fn sequential(&self, t: f32) -> Vector3<f32> {
+        let table = self.tables().1;
+        let t = t.max(0.0).min(1.0) * (table.len() - 1) as f32;
+        let i = (t.floor() as usize).min(table.len() - 2);
+        to_linear(table[i]).lerp(to_linear(table[i + 1]), t - i as f32)
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new(PaletteScheme::default())
+    }
+}
+
+/**
+ * A kind of color blindness to simulate on the final image, so
+ * screenshots can be checked by people who don't have it. See
+ * [crate::RenderSettings::simulate_color_blindness].
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorBlindness {
+    /// No red cones
+    Protanopia,
+    /// No green cones, the most common kind
+    Deuteranopia,
+    /// No blue cones
+    Tritanopia,
+}
+
+impl ColorBlindness {
+    /// Each one in turn, then `None` for turning it off again
+    pub fn cycle(current: Option<Self>) -> Option<Self> {
+        match current {
+            None => Some(ColorBlindness::Protanopia),
+            Some(ColorBlindness::Protanopia) => Some(ColorBlindness::Deuteranopia),
+            Some(ColorBlindness::Deuteranopia) => Some(ColorBlindness::Tritanopia),
+            Some(ColorBlindness::Tritanopia) => None,
+        }
+    }
+
+    /**
+     * Machado, Oliveira and Fernandes (2009) at full severity. These
+     * work on linear RGB. Each row sums to 1, so grays stay gray.
+     */
+    pub fn matrix(&self) -> Matrix3<f32> {
+        let rows = match self {
+            ColorBlindness::Protanopia => [
+                [0.152_286, 1.052_583, -0.204_868],
+                [0.114_503, 0.786_281, 0.099_216],
+                [-0.003_882, -0.048_116, 1.051_998],
+            ],
+            ColorBlindness::Deuteranopia => [
+                [0.367_322, 0.860_646, -0.227_968],
+                [0.280_085, 0.672_501, 0.047_413],
+                [-0.011_820, 0.042_940, 0.968_881],
+            ],
+            ColorBlindness::Tritanopia => [
+                [1.255_528, -0.076_749, -0.178_779],
+                [-0.078_411, 0.930_809, 0.147_602],
+                [0.004_733, 0.691_367, 0.303_900],
+            ],
+        };
+        // cgmath takes columns
+        Matrix3::from(rows).transpose()
+    }
+}
+
+/// What the tonemap pass does with [ColorBlindness], for checking on
+/// the CPU. `linear` is after tonemapping, so between 0 and 1.
+pub fn simulate_color_blindness(linear: Vector3<f32>, kind: Option<ColorBlindness>) -> Vector3<f32> {
+    match kind {
+        Some(kind) => (kind.matrix() * linear).map(|c| c.max(0.0).min(1.0)),
+        None => linear,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tonemap::linear_to_srgb;
+
+    const KINDS: [ColorBlindness; 3] = [
+        ColorBlindness::Protanopia,
+        ColorBlindness::Deuteranopia,
+        ColorBlindness::Tritanopia,
+    ];
+
+    fn luminance(c: Vector3<f32>) -> f32 {
+        c.dot(Vector3::new(0.2126, 0.7152, 0.0722))
+    }
+
+    #[test]
+    fn safe_palettes_survive_simulation() {
+        let palette = Palette::new(PaletteScheme::ColorBlindSafe);
+        for &kind in &KINDS {
+            let seen = |c: Vector3<f32>| simulate_color_blindness(c, Some(kind)).map(linear_to_srgb);
+            // Every pair of categorical colors still looks different
+            for a in 0..palette.categorical_len() {
+                for b in a + 1..palette.categorical_len() {
+                    let distance = (seen(palette.categorical(a)) - seen(palette.categorical(b))).magnitude();
+                    assert!(distance > 0.1, "{:?}: {} and {} are {} apart", kind, a, b, distance);
+                }
+            }
+            // And heatmaps still read from dark to bright
+            let mut last = -1.0;
+            for i in 0..=16 {
+                let l = luminance(simulate_color_blindness(palette.sequential(i as f32 / 16.0), Some(kind)));
+                assert!(l > last, "{:?} at {}", kind, i);
+                last = l;
+            }
+        }
+    }
+
+    #[test]
+    fn simulation_keeps_grays() {
+        let gray = Vector3::new(0.3, 0.3, 0.3);
+        for &kind in &KINDS {
+            let seen = simulate_color_blindness(gray, Some(kind));
+            assert!((seen - gray).magnitude() < 1e-5, "{:?}", kind);
+        }
+        assert_eq!(simulate_color_blindness(gray, None), gray);
+
+        let palette = Palette::new(PaletteScheme::Classic);
+        assert_eq!(palette.categorical(palette.categorical_len() + 2), palette.categorical(2));
+        assert_eq!(palette.sequential(-1.0), palette.sequential(0.0));
+        assert_eq!(palette.sequential(1.0), Vector3::new(1.0, 0.0, 0.0));
+    }
+}
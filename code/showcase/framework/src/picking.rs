@@ -0,0 +1,270 @@
+use cgmath::*;
+use std::collections::BTreeSet;
+use serde::{Deserialize, Serialize};
+use crate::bounds::Aabb;
+use crate::instance::Instance;
+use crate::model::{placed_aabb, Model};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    /// Not necessarily unit length. Distances returned by the
+    /// intersection tests are in multiples of this.
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    /// The ray through pixel (`x`, `y`) of a `width` by `height`
+    /// window, going away from the camera
+    pub fn from_screen(x: f32, y: f32, width: f32, height: f32, inv_view_proj: &Matrix4<f32>) -> Self {
+        let ndc_x = x / width * 2.0 - 1.0;
+        let ndc_y = 1.0 - y / height * 2.0;
+        // wgpu's depth goes from 0 at the near plane to 1 at the far
+        // plane
+        let near = inv_view_proj * Vector4::new(ndc_x, ndc_y, 0.0, 1.0);
+        let far = inv_view_proj * Vector4::new(ndc_x, ndc_y, 1.0, 1.0);
+        let near = Point3::from_homogeneous(near);
+        let far = Point3::from_homogeneous(far);
+        Self { origin: near, direction: far - near }
+    }
+
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Ray {
+        Ray {
+            origin: matrix.transform_point(self.origin),
+            direction: matrix.transform_vector(self.direction),
+        }
+    }
+
+    pub fn at(&self, t: f32) -> Point3<f32> {
+        self.origin + self.direction * t
+    }
+
+    /// Slab test. Returns how far along the ray it enters the box, or 0
+    /// if it starts inside it.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        if aabb.is_empty() {
+            return None;
+        }
+        let mut t_min = 0.0f32;
+        let mut t_max = f32::INFINITY;
+        for axis in 0..3 {
+            let inv = 1.0 / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inv;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inv;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // NaN happens when the ray is parallel to the slab and
+            // starts on its edge, so these are written to ignore it
+            if t0 > t_min { t_min = t0; }
+            if t1 < t_max { t_max = t1; }
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+}
+
+/**
+ * One instance of one model. Like [crate::MeshHandle], `model` is an
+ * index into whatever list of models the demo keeps.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct InstanceHandle {
+    pub model: usize,
+    pub instance: usize,
+}
+
+/// A model's bounds and where it's drawn, which is all picking needs
+pub struct PickTarget<'a> {
+    pub aabb: Aabb,
+    pub instances: &'a [Instance],
+}
+
+/**
+ * Finds the closest instance whose box the ray hits. The test is done
+ * in model space, so rotated instances are tested against their own
+ * box rather than the looser world space one.
+ */
+pub fn pick_instance(ray: &Ray, targets: &[PickTarget]) -> Option<InstanceHandle> {
+    pick_instance_hit(ray, targets).map(|(handle, _)| handle)
+}
+
+/// Like [pick_instance], but also says how far along `ray` the hit
+/// was, for use with [Ray::at]
+pub fn pick_instance_hit(ray: &Ray, targets: &[PickTarget]) -> Option<(InstanceHandle, f32)> {
+    let mut closest = None;
+    let mut closest_t = f32::INFINITY;
+    for (model, target) in targets.iter().enumerate() {
+        for (instance, data) in target.instances.iter().enumerate() {
+            let inverse = match data.calc_matrix().invert() {
+                Some(m) => m,
+                // Zero scale, so there's nothing to click on
+                None => continue,
+            };
+            // Transforming the direction without normalizing it keeps
+            // t the same in both spaces
+            let local = ray.transform(&inverse);
+            if let Some(t) = local.intersect_aabb(&target.aabb) {
+                if t < closest_t {
+                    closest_t = t;
+                    closest = Some((InstanceHandle { model, instance }, t));
+                }
+            }
+        }
+    }
+    closest
+}
+
+/**
+ * Which of `model`'s visible meshes the ray hits first on `instance`,
+ * by their boxes, as an index into [Model::meshes]. Meshes that are
+ * hidden can't be clicked on, which is what you'd expect.
+ */
+pub fn pick_mesh(ray: &Ray, model: &Model, instance: &Instance) -> Option<usize> {
+    let local = ray.transform(&instance.calc_matrix().invert()?);
+    model.meshes.iter()
+        .enumerate()
+        .filter(|(_, mesh)| mesh.visible)
+        .filter_map(|(i, mesh)| {
+            let aabb = placed_aabb(&mesh.aabb, &mesh.placements);
+            local.intersect_aabb(&aabb).map(|t| (i, t))
+        })
+        .fold(None, |closest: Option<(usize, f32)>, (i, t)| match closest {
+            Some((_, closest_t)) if closest_t <= t => closest,
+            _ => Some((i, t)),
+        })
+        .map(|(i, _)| i)
+}
+
+/// Every instance whose center lands inside the rectangle between
+/// `corner_a` and `corner_b` in window coordinates
+pub fn instances_in_rect(
+    corner_a: (f32, f32),
+    corner_b: (f32, f32),
+    width: f32,
+    height: f32,
+    view_proj: &Matrix4<f32>,
+    targets: &[PickTarget],
+) -> Vec<InstanceHandle> {
+    let (min_x, max_x) = (corner_a.0.min(corner_b.0), corner_a.0.max(corner_b.0));
+    let (min_y, max_y) = (corner_a.1.min(corner_b.1), corner_a.1.max(corner_b.1));
+    let mut handles = Vec::new();
+    for (model, target) in targets.iter().enumerate() {
+        let center = target.aabb.center();
+        for (instance, data) in target.instances.iter().enumerate() {
+            let world = data.calc_matrix().transform_point(center);
+            let clip = view_proj * world.to_homogeneous();
+            // Behind the camera
+            if clip.w <= 0.0 {
+                continue;
+            }
+            let x = (clip.x / clip.w + 1.0) * 0.5 * width;
+            let y = (1.0 - clip.y / clip.w) * 0.5 * height;
+            if x >= min_x && x <= max_x && y >= min_y && y <= max_y {
+                handles.push(InstanceHandle { model, instance });
+            }
+        }
+    }
+    handles
+}
+
+/**
+ * The set of selected instances. It's ordered so that walking it
+ * backwards visits each model's instances from last to first, which is
+ * the order you want when deleting them.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    handles: BTreeSet<InstanceHandle>,
+}
+
+impl Selection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn contains(&self, handle: &InstanceHandle) -> bool {
+        self.handles.contains(handle)
+    }
+
+    pub fn clear(&mut self) {
+        self.handles.clear();
+    }
+
+    /// Plain click: select only this
+    pub fn replace(&mut self, handle: InstanceHandle) {
+        self.handles.clear();
+        self.handles.insert(handle);
+    }
+
+    /// Ctrl-click: add it if it isn't selected, remove it if it is
+    pub fn toggle(&mut self, handle: InstanceHandle) {
+        if !self.handles.remove(&handle) {
+            self.handles.insert(handle);
+        }
+    }
+
+    pub fn extend<I: IntoIterator<Item = InstanceHandle>>(&mut self, handles: I) {
+        self.handles.extend(handles);
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &InstanceHandle> {
+        self.handles.iter()
+    }
+
+    /// Drops anything that doesn't exist anymore. `counts` is how many
+    /// instances each model has.
+    pub fn retain_valid(&mut self, counts: &[usize]) {
+        self.handles.retain(|h| counts.get(h.model).map(|&n| h.instance < n).unwrap_or(false));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::from_points(vec![Point3::new(-0.5, -0.5, -0.5), Point3::new(0.5, 0.5, 0.5)])
+    }
+
+    #[test]
+    fn picks_the_closest_instance() {
+        let instances = vec![
+            Instance::new((0.0, 0.0, -5.0)),
+            Instance::new((0.0, 0.0, -2.0)),
+            Instance::new((3.0, 0.0, -2.0)),
+        ];
+        let targets = [PickTarget { aabb: unit_box(), instances: &instances }];
+        let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: -Vector3::unit_z() };
+        assert_eq!(pick_instance(&ray, &targets), Some(InstanceHandle { model: 0, instance: 1 }));
+
+        let miss = Ray { origin: Point3::new(1.5, 0.0, 0.0), direction: -Vector3::unit_z() };
+        assert_eq!(pick_instance(&miss, &targets), None);
+    }
+
+    #[test]
+    fn toggle_builds_a_multi_selection() {
+        let a = InstanceHandle { model: 0, instance: 0 };
+        let b = InstanceHandle { model: 0, instance: 3 };
+        let mut selection = Selection::new();
+        selection.replace(a);
+        selection.toggle(b);
+        assert_eq!(selection.len(), 2);
+        selection.toggle(a);
+        assert!(!selection.contains(&a));
+        assert!(selection.contains(&b));
+
+        selection.retain_valid(&[2]);
+        assert!(selection.is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::*;
 use crate::model::Vertex;
+use crate::settings::RenderSettings;
 
 pub struct RenderPipelineBuilder<'a> {
     layout: Option<&'a wgpu::PipelineLayout>,
@@ -111,6 +112,17 @@ impl<'a> RenderPipelineBuilder<'a> {
         )
     }
 
+    /// [RenderPipelineBuilder::color_solid] for pipelines that draw to
+    /// the swap chain, in whatever format it ended up with
+    pub fn color_output(&mut self, settings: &RenderSettings) -> &mut Self {
+        self.color_solid(settings.surface_format)
+    }
+
+    /// The format of each color target, in order
+    pub fn color_formats(&self) -> Vec<wgpu::TextureFormat> {
+        self.color_states.iter().map(|cs| cs.format).collect()
+    }
+
     pub fn depth_stencil_state(&mut self, dss: wgpu::DepthStencilStateDescriptor) -> &mut Self {
         self.depth_stencil_state = Some(dss);
         self
@@ -239,7 +251,7 @@ impl<'a> RenderPipelineBuilder<'a> {
 }
 
 
-fn create_shader_module(device: &wgpu::Device, spirv: &[u8]) -> wgpu::ShaderModule {
+pub(crate) fn create_shader_module(device: &wgpu::Device, spirv: &[u8]) -> wgpu::ShaderModule {
     device.create_shader_module(
         &wgpu::read_spirv(
             std::io::Cursor::new(
@@ -247,4 +259,77 @@ fn create_shader_module(device: &wgpu::Device, spirv: &[u8]) -> wgpu::ShaderModu
             )
         ).unwrap()
     )
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cgmath::Vector3;
+    use crate::capabilities::is_bgra_format;
+    use crate::capture::read_pixels;
+    use crate::texture::Texture;
+    use crate::tonemap::{linear_to_srgb, tonemap, TonemapPass};
+
+    /// Tonemaps the same color onto every kind of swap chain. They only
+    /// all come out the same if the pipeline was made for the format
+    /// in the settings, and knows whether to do the sRGB encoding.
+    #[test]
+    fn swap_chain_pipelines_follow_the_settings() {
+        let (device, queue) = match crate::thumbnail::test_device() {
+            Some(device) => device,
+            None => return,
+        };
+        let color = Vector3::new(0.2, 0.5, 4.0);
+        let ldr = tonemap(color);
+        let expected = [ldr.x, ldr.y, ldr.z].iter()
+            .map(|&c| (linear_to_srgb(c) * 255.0).round() as i32)
+            .collect::<Vec<_>>();
+
+        for &format in &crate::SURFACE_FORMAT_PREFERENCE {
+            let settings = RenderSettings { surface_format: format, ..Default::default() };
+            let sc_desc = wgpu::SwapChainDescriptor {
+                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+                format,
+                width: 4,
+                height: 4,
+                present_mode: wgpu::PresentMode::Fifo,
+            };
+            let hdr = Texture::create_hdr_texture(&device, &sc_desc);
+            let tonemap = TonemapPass::new(&device, &hdr, &settings).unwrap();
+            assert_eq!(tonemap.output_format(), format);
+            let target = Texture::from_descriptor(&device, wgpu::TextureDescriptor {
+                label: Some("swap_chain_pipelines_follow_the_settings::target"),
+                size: wgpu::Extent3d { width: 4, height: 4, depth: 1 },
+                array_layer_count: 1,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+            });
+
+            let mut encoder = device.create_command_encoder(
+                &wgpu::CommandEncoderDescriptor { label: Some("swap_chain_pipelines_follow_the_settings::encoder") }
+            );
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &hdr.view,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Clear,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color { r: color.x as f64, g: color.y as f64, b: color.z as f64, a: 1.0 },
+                }],
+                depth_stencil_attachment: None,
+            });
+            tonemap.render(&mut encoder, &target.view);
+            queue.submit(&[encoder.finish()]);
+
+            let pixels = read_pixels(&device, &queue, &target.texture, 4, 4, 4).unwrap();
+            let channels = if is_bgra_format(format) { [2, 1, 0] } else { [0, 1, 2] };
+            for (&channel, &expected) in channels.iter().zip(&expected) {
+                let got = pixels[channel] as i32;
+                assert!((got - expected).abs() <= 1, "{:?}: {} instead of {}", format, got, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! This file is also pulled into the build scripts with `#[path]`, so
+//! it can only use std.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Where a line of preprocessed source came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    /// Starts at 1, like compiler messages
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+#[derive(Debug)]
+pub enum PreprocessError {
+    /// The shader itself couldn't be read
+    Missing(PathBuf),
+    /// None of the include roots have the file
+    IncludeNotFound { include: String, at: SourceLine },
+    /// The files that include each other, ending with the one that
+    /// closes the loop
+    IncludeCycle(Vec<PathBuf>),
+    Malformed { text: String, at: SourceLine },
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreprocessError::Missing(path) => write!(f, "Unable to read {}", path.display()),
+            PreprocessError::IncludeNotFound { include, at } => {
+                write!(f, "{}: can't find {:?} in any include root", at, include)
+            }
+            PreprocessError::IncludeCycle(chain) => {
+                let chain = chain.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+                write!(f, "Include cycle: {}", chain.join(" -> "))
+            }
+            PreprocessError::Malformed { text, at } => write!(f, "{}: bad directive {:?}", at, text),
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/**
+ * The output of [ShaderPreprocessor::process], ready to go to the
+ * compiler.
+ */
+#[derive(Debug, Clone)]
+pub struct PreprocessedShader {
+    pub source: String,
+    /// The shader and everything it included. When any of these
+    /// change, the shader needs compiling again.
+    pub dependencies: Vec<PathBuf>,
+    line_map: Vec<SourceLine>,
+}
+
+impl PreprocessedShader {
+    /// Where line `line` (starting at 1) of [PreprocessedShader::source]
+    /// came from
+    pub fn source_line(&self, line: usize) -> Option<&SourceLine> {
+        line.checked_sub(1).and_then(|i| self.line_map.get(i))
+    }
+
+    /**
+     * Rewrites compiler messages of the form `name:line: message`,
+     * where `name` is what the source was compiled as, so they point
+     * at the file and line the code actually came from.
+     */
+    pub fn map_errors(&self, name: &str, messages: &str) -> String {
+        let prefix = format!("{}:", name);
+        messages.lines()
+            .map(|message| {
+                if !message.starts_with(&prefix) {
+                    return message.to_string();
+                }
+                let rest = &message[prefix.len()..];
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let mapped = rest[..digits].parse().ok().and_then(|line| self.source_line(line));
+                match mapped {
+                    Some(at) => format!("{}{}", at, &rest[digits..]),
+                    None => message.to_string(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/**
+ * Handles `#include "path"` and injects `#define`s, so shader variants
+ * and shared code can come from one source.
+ *
+ * Includes are looked up in each include root in turn. A file only
+ * ever gets included once per shader (as if every file started with
+ * `#pragma once`), and a file that ends up including itself is an
+ * error.
+ *
+ * The defines go right after the `#version` line, which GLSL needs to
+ * come first.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ShaderPreprocessor {
+    include_roots: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+}
+
+impl ShaderPreprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_include_root<P: Into<PathBuf>>(&mut self, root: P) -> &mut Self {
+        self.include_roots.push(root.into());
+        self
+    }
+
+    /// Later defines with the same name replace earlier ones
+    pub fn define<N: Into<String>, V: ToString>(&mut self, name: N, value: V) -> &mut Self {
+        let name = name.into();
+        let value = value.to_string();
+        match self.defines.iter_mut().find(|(n, _)| *n == name) {
+            Some(define) => define.1 = value,
+            None => self.defines.push((name, value)),
+        }
+        self
+    }
+
+    pub fn process<P: AsRef<Path>>(&self, path: P) -> Result<PreprocessedShader, PreprocessError> {
+        self.process_with(path.as_ref(), |p| std::fs::read_to_string(p).ok())
+    }
+
+    /// Same as [ShaderPreprocessor::process], but files are read with
+    /// `read`, which returns `None` for files that don't exist
+    pub fn process_with<F>(&self, path: &Path, mut read: F) -> Result<PreprocessedShader, PreprocessError>
+    where
+        F: FnMut(&Path) -> Option<String>,
+    {
+        let src = read(path).ok_or_else(|| PreprocessError::Missing(path.to_path_buf()))?;
+        let mut output = Output {
+            source: String::new(),
+            line_map: Vec::new(),
+            dependencies: vec![path.to_path_buf()],
+            included: HashSet::new(),
+        };
+        output.included.insert(path.to_path_buf());
+        let mut stack = vec![path.to_path_buf()];
+        self.expand(path, &src, &mut read, &mut stack, &mut output, true)?;
+        Ok(PreprocessedShader {
+            source: output.source,
+            dependencies: output.dependencies,
+            line_map: output.line_map,
+        })
+    }
+
+    fn expand<F>(
+        &self,
+        path: &Path,
+        src: &str,
+        read: &mut F,
+        stack: &mut Vec<PathBuf>,
+        output: &mut Output,
+        is_root: bool,
+    ) -> Result<(), PreprocessError>
+    where
+        F: FnMut(&Path) -> Option<String>,
+    {
+        let mut needs_defines = is_root;
+        for (i, text) in src.lines().enumerate() {
+            let at = SourceLine { file: path.to_path_buf(), line: i + 1 };
+            let trimmed = text.trim_start();
+
+            if trimmed.starts_with("#include") {
+                let include = parse_include(&trimmed["#include".len()..])
+                    .ok_or_else(|| PreprocessError::Malformed { text: text.to_string(), at: at.clone() })?;
+                let (resolved, included_src) = self.include_roots.iter()
+                    .map(|root| root.join(include))
+                    .find_map(|candidate| read(&candidate).map(|src| (candidate, src)))
+                    .ok_or_else(|| PreprocessError::IncludeNotFound { include: include.to_string(), at })?;
+
+                if stack.contains(&resolved) {
+                    let mut chain = stack.clone();
+                    chain.push(resolved);
+                    return Err(PreprocessError::IncludeCycle(chain));
+                }
+                if !output.included.insert(resolved.clone()) {
+                    continue;
+                }
+                output.dependencies.push(resolved.clone());
+                stack.push(resolved.clone());
+                self.expand(&resolved, &included_src, read, stack, output, false)?;
+                stack.pop();
+                continue;
+            }
+
+            output.push(text, at);
+            if needs_defines && trimmed.starts_with("#version") {
+                needs_defines = false;
+                for (j, (name, value)) in self.defines.iter().enumerate() {
+                    let at = SourceLine { file: PathBuf::from("<define>"), line: j + 1 };
+                    output.push(&format!("#define {} {}", name, value), at);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Output {
+    source: String,
+    line_map: Vec<SourceLine>,
+    dependencies: Vec<PathBuf>,
+    included: HashSet<PathBuf>,
+}
+
+impl Output {
+    fn push(&mut self, text: &str, at: SourceLine) {
+        self.source.push_str(text);
+        self.source.push('\n');
+        self.line_map.push(at);
+    }
+}
+
+/// The path out of ` "common/lighting.glsl"`
+fn parse_include(rest: &str) -> Option<&str> {
+    let rest = rest.trim();
+    if !rest.starts_with('"') {
+        return None;
+    }
+    let rest = &rest[1..];
+    let end = rest.find('"')?;
+    let path = &rest[..end];
+    // Only a comment is allowed after the path
+    let after = rest[end + 1..].trim();
+    if path.is_empty() || !(after.is_empty() || after.starts_with("//")) {
+        return None;
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn files(files: &[(&str, &str)]) -> HashMap<PathBuf, String> {
+        files.iter().map(|(p, s)| (PathBuf::from(p), s.to_string())).collect()
+    }
+
+    fn process(files: &HashMap<PathBuf, String>, preprocessor: &ShaderPreprocessor) -> Result<PreprocessedShader, PreprocessError> {
+        preprocessor.process_with(Path::new("shader.frag"), |p| files.get(p).cloned())
+    }
+
+    fn preprocessor() -> ShaderPreprocessor {
+        let mut preprocessor = ShaderPreprocessor::new();
+        preprocessor.add_include_root("shaders");
+        preprocessor
+    }
+
+    #[test]
+    fn includes_and_defines_keep_their_lines() {
+        let files = files(&[
+            ("shader.frag", "#version 450\n#include \"common/lighting.glsl\"\nvoid main() {}\n"),
+            ("shaders/common/lighting.glsl", "// lighting\nvec3 light() { return vec3(1.0); }\n"),
+        ]);
+        let mut preprocessor = preprocessor();
+        preprocessor.define("MAX_LIGHTS", 16);
+        let shader = process(&files, &preprocessor).unwrap();
+
+        assert_eq!(
+            shader.source,
+            "#version 450\n#define MAX_LIGHTS 16\n// lighting\nvec3 light() { return vec3(1.0); }\nvoid main() {}\n",
+        );
+        let lighting = Path::new("shaders/common/lighting.glsl");
+        assert_eq!(shader.source_line(4).unwrap(), &SourceLine { file: lighting.to_path_buf(), line: 2 });
+        assert_eq!(shader.source_line(5).unwrap(), &SourceLine { file: "shader.frag".into(), line: 3 });
+        assert_eq!(shader.dependencies, vec![PathBuf::from("shader.frag"), lighting.to_path_buf()]);
+
+        let mapped = shader.map_errors("shader.frag", "shader.frag:4: error: 'x' : undeclared identifier");
+        assert_eq!(mapped, "shaders/common/lighting.glsl:2: error: 'x' : undeclared identifier");
+    }
+
+    #[test]
+    fn repeated_includes_only_count_once() {
+        let files = files(&[
+            ("shader.frag", "#include \"a.glsl\"\n#include \"b.glsl\"\n"),
+            ("shaders/a.glsl", "#include \"b.glsl\"\na\n"),
+            ("shaders/b.glsl", "b\n"),
+        ]);
+        let shader = process(&files, &preprocessor()).unwrap();
+        assert_eq!(shader.source, "b\na\n");
+    }
+
+    #[test]
+    fn cycles_are_an_error() {
+        let files = files(&[
+            ("shader.frag", "#include \"a.glsl\"\n"),
+            ("shaders/a.glsl", "#include \"b.glsl\"\n"),
+            ("shaders/b.glsl", "#include \"a.glsl\"\n"),
+        ]);
+        match process(&files, &preprocessor()) {
+            Err(PreprocessError::IncludeCycle(chain)) => assert_eq!(chain, vec![
+                PathBuf::from("shader.frag"),
+                PathBuf::from("shaders/a.glsl"),
+                PathBuf::from("shaders/b.glsl"),
+                PathBuf::from("shaders/a.glsl"),
+            ]),
+            other => panic!("Expected a cycle, got {:?}", other),
+        }
+    }
+}
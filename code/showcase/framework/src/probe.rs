@@ -0,0 +1,318 @@
+use cgmath::*;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use crate::capabilities::is_bgra_format;
+use crate::texture::Texture;
+use crate::tonemap::{self, TonemapPass, TonemapUniform};
+
+/// Buffer copies need rows to be a multiple of this, even for one pixel
+const SLOT_SIZE: wgpu::BufferAddress = 256;
+const HDR_SLOT: wgpu::BufferAddress = 0;
+const DISPLAYED_SLOT: wgpu::BufferAddress = SLOT_SIZE;
+const DEPTH_SLOT: wgpu::BufferAddress = SLOT_SIZE * 2;
+
+type Mapping = Pin<Box<dyn Future<Output = Result<wgpu::BufferReadMapping, wgpu::BufferAsyncErr>>>>;
+
+enum ProbeState {
+    Idle,
+    /// The copies are in an encoder that hasn't been submitted yet
+    Copied { x: u32, y: u32 },
+    Mapping { x: u32, y: u32, mapping: Mapping },
+}
+
+/// What one pixel looked like. See [PixelProbe].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProbeSample {
+    pub x: u32,
+    pub y: u32,
+    /// Straight out of the HDR target, before tonemapping
+    pub linear: Vector4<f32>,
+    /// RGBA as it went to the screen, so sRGB encoded. BGRA outputs
+    /// get swapped back.
+    pub displayed: [u8; 4],
+    /// The depth buffer's value, from 0 at the near plane to 1 at the
+    /// far plane
+    pub depth: f32,
+}
+
+impl ProbeSample {
+    /// What [ProbeSample::displayed] should be if the output is sRGB
+    /// and the only thing between the two is [tonemap::tonemap]. When
+    /// this doesn't match, something's encoding the color twice (or
+    /// not at all).
+    pub fn expected_srgb(&self) -> [u8; 3] {
+        let c = tonemap::tonemap(self.linear.truncate());
+        let encode = |c: f32| (tonemap::linear_to_srgb(c) * 255.0).round() as u8;
+        [encode(c.x), encode(c.y), encode(c.z)]
+    }
+
+    /// Where the pixel is in the world. None if nothing was drawn there.
+    pub fn world_position(&self, width: u32, height: u32, inv_view_proj: &Matrix4<f32>) -> Option<Point3<f32>> {
+        if self.depth >= 1.0 {
+            return None;
+        }
+        let ndc_x = (self.x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+        let ndc_y = 1.0 - (self.y as f32 + 0.5) / height as f32 * 2.0;
+        let world = inv_view_proj * Vector4::new(ndc_x, ndc_y, self.depth, 1.0);
+        Some(Point3::from_homogeneous(world))
+    }
+}
+
+impl fmt::Display for ProbeSample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b, _] = self.displayed;
+        let [er, eg, eb] = self.expected_srgb();
+        write!(
+            f,
+            "({}, {}): linear ({:.3}, {:.3}, {:.3}), displayed #{:02x}{:02x}{:02x} (expected #{:02x}{:02x}{:02x}), depth {:.5}",
+            self.x, self.y,
+            self.linear.x, self.linear.y, self.linear.z,
+            r, g, b,
+            er, eg, eb,
+            self.depth,
+        )
+    }
+}
+
+/**
+ * Reads back one pixel of the HDR target, the tonemapped output and
+ * the depth buffer, for answering questions like "is this light
+ * actually brighter than 1".
+ *
+ * The swap chain can't be copied from, so the displayed value comes
+ * from running [TonemapPass] again into a 1x1 target of the same
+ * format. It's the same shader reading the same pixel, so it's what
+ * ended up on screen.
+ *
+ * Reads never stall. [PixelProbe::capture] records the copies,
+ * [PixelProbe::after_submit] starts mapping them and the result turns
+ * up from [PixelProbe::poll] a frame or so later. Only one read is in
+ * flight at a time, and captures are limited to one per
+ * [PixelProbe::interval].
+ */
+pub struct PixelProbe {
+    target: Texture<'static>,
+    output_format: wgpu::TextureFormat,
+    offset_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    readback: wgpu::Buffer,
+    state: ProbeState,
+    last_capture: Option<Instant>,
+    pub interval: Duration,
+}
+
+impl PixelProbe {
+    /// Reads in whatever format `tonemap` was made for
+    pub fn new(device: &wgpu::Device, tonemap: &TonemapPass, hdr: &Texture) -> Self {
+        let output_format = tonemap.output_format();
+        let target = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("PixelProbe::target"),
+            size: wgpu::Extent3d { width: 1, height: 1, depth: 1 },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: output_format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let offset_buffer = TonemapPass::create_offset_buffer(device, output_format);
+        let bind_group = tonemap.create_bind_group(device, hdr, &offset_buffer);
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("PixelProbe::readback"),
+            size: SLOT_SIZE * 3,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        });
+        Self {
+            target,
+            output_format,
+            offset_buffer,
+            bind_group,
+            readback,
+            state: ProbeState::Idle,
+            last_capture: None,
+            interval: Duration::from_millis(250),
+        }
+    }
+
+    /// Call this after recreating `hdr`
+    pub fn resize(&mut self, device: &wgpu::Device, tonemap: &TonemapPass, hdr: &Texture) {
+        self.bind_group = tonemap.create_bind_group(device, hdr, &self.offset_buffer);
+    }
+
+    /**
+     * Copies pixel (`x`, `y`) out of `hdr` and `depth`. Call this
+     * after the frame has been drawn. Returns false when a read is
+     * still in flight or it's too soon since the last one.
+     */
+    pub fn capture(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        tonemap: &TonemapPass,
+        hdr: &Texture,
+        depth: &Texture,
+        x: u32,
+        y: u32,
+    ) -> bool {
+        match self.state {
+            ProbeState::Idle => {}
+            _ => return false,
+        }
+        if let Some(last) = self.last_capture {
+            if last.elapsed() < self.interval {
+                return false;
+            }
+        }
+        let size = hdr.desc.size;
+        if x >= size.width || y >= size.height {
+            return false;
+        }
+
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[TonemapUniform::new([x as i32, y as i32], tonemap.encodes_srgb(), tonemap.color_blindness())]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.offset_buffer,
+            0,
+            std::mem::size_of::<TonemapUniform>() as _,
+        );
+        tonemap.draw(encoder, &self.target.view, &self.bind_group);
+
+        let origin = wgpu::Origin3d { x, y, z: 0 };
+        self.copy_pixel(encoder, &hdr.texture, origin, HDR_SLOT);
+        self.copy_pixel(encoder, &self.target.texture, wgpu::Origin3d::ZERO, DISPLAYED_SLOT);
+        self.copy_pixel(encoder, &depth.texture, origin, DEPTH_SLOT);
+
+        self.state = ProbeState::Copied { x, y };
+        self.last_capture = Some(Instant::now());
+        true
+    }
+
+    fn copy_pixel(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        origin: wgpu::Origin3d,
+        offset: wgpu::BufferAddress,
+    ) {
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin,
+            },
+            wgpu::BufferCopyView {
+                buffer: &self.readback,
+                offset,
+                bytes_per_row: SLOT_SIZE as u32,
+                rows_per_image: 1,
+            },
+            wgpu::Extent3d { width: 1, height: 1, depth: 1 },
+        );
+    }
+
+    /// Call this once the encoder passed to [PixelProbe::capture] has
+    /// been submitted
+    pub fn after_submit(&mut self) {
+        if let ProbeState::Copied { x, y } = self.state {
+            let mapping = Box::pin(self.readback.map_read(0, SLOT_SIZE * 3));
+            self.state = ProbeState::Mapping { x, y, mapping };
+        }
+    }
+
+    /// Returns the last capture once the GPU has finished with it
+    pub fn poll(&mut self, device: &wgpu::Device) -> Option<ProbeSample> {
+        let (x, y, result) = match &mut self.state {
+            ProbeState::Mapping { x, y, mapping } => {
+                device.poll(wgpu::Maintain::Poll);
+                let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+                match mapping.as_mut().poll(&mut cx) {
+                    Poll::Ready(result) => (*x, *y, result),
+                    Poll::Pending => return None,
+                }
+            }
+            _ => return None,
+        };
+        self.state = ProbeState::Idle;
+        let mapping = match result {
+            Ok(mapping) => mapping,
+            Err(_) => {
+                log::error!("Unable to read back the probed pixel");
+                return None;
+            }
+        };
+        Some(decode(mapping.as_slice(), self.output_format, x, y))
+    }
+}
+
+fn decode(bytes: &[u8], output_format: wgpu::TextureFormat, x: u32, y: u32) -> ProbeSample {
+    let slot = |offset: wgpu::BufferAddress| &bytes[offset as usize..(offset + SLOT_SIZE) as usize];
+
+    let hdr = slot(HDR_SLOT);
+    let half = |i: usize| f16_to_f32(u16::from_le_bytes([hdr[i * 2], hdr[i * 2 + 1]]));
+    let linear = Vector4::new(half(0), half(1), half(2), half(3));
+
+    let displayed = slot(DISPLAYED_SLOT);
+    let displayed = if is_bgra_format(output_format) {
+        [displayed[2], displayed[1], displayed[0], displayed[3]]
+    } else {
+        [displayed[0], displayed[1], displayed[2], displayed[3]]
+    };
+
+    let depth = slot(DEPTH_SLOT);
+    let depth = f32::from_le_bytes([depth[0], depth[1], depth[2], depth[3]]);
+
+    ProbeSample { x, y, linear, displayed, depth }
+}
+
+/// [Texture::HDR_FORMAT] is half floats, which Rust doesn't have
+pub(crate) fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_each_slot() {
+        let mut bytes = vec![0u8; (SLOT_SIZE * 3) as usize];
+        // 1.0, 2.5, 0.5 and 1.0 as halfs
+        for (i, half) in [0x3c00u16, 0x4100, 0x3800, 0x3c00].iter().enumerate() {
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&half.to_le_bytes());
+        }
+        let s = DISPLAYED_SLOT as usize;
+        bytes[s..s + 4].copy_from_slice(&[10, 20, 30, 255]);
+        let d = DEPTH_SLOT as usize;
+        bytes[d..d + 4].copy_from_slice(&0.75f32.to_le_bytes());
+
+        let sample = decode(&bytes, wgpu::TextureFormat::Bgra8UnormSrgb, 3, 4);
+        assert_eq!(sample.linear, Vector4::new(1.0, 2.5, 0.5, 1.0));
+        // Swapped back to RGBA
+        assert_eq!(sample.displayed, [30, 20, 10, 255]);
+        assert_eq!(sample.depth, 0.75);
+        // And left alone when it's already RGBA
+        let sample = decode(&bytes, wgpu::TextureFormat::Rgba8Unorm, 3, 4);
+        assert_eq!(sample.displayed, [10, 20, 30, 255]);
+
+        // 1 / (1 + 1) = 0.5 is 188 in sRGB
+        assert_eq!(sample.expected_srgb()[0], 188);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+    }
+}
@@ -0,0 +1,354 @@
+use anyhow::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use crate::lod::LodStats;
+
+/// GPU timings don't happen on a real thread, so they get their own
+/// row in the trace.
+const GPU_TID: u32 = 0;
+
+/**
+ * One entry in a chrome://tracing file. See the "Trace Event Format"
+ * document for what the fields mean. Times are in microseconds.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: &'static str,
+    pub ph: &'static str,
+    pub ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<HashMap<&'static str, serde_json::Value>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChromeTrace {
+    #[serde(rename = "traceEvents")]
+    pub trace_events: Vec<TraceEvent>,
+}
+
+impl ChromeTrace {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path.as_ref(), self.to_json()?)
+            .with_context(|| format!("Unable to write trace to {}", path.as_ref().display()))
+    }
+}
+
+struct Capture {
+    frames_left: usize,
+    events: Vec<TraceEvent>,
+    threads: HashMap<ThreadId, u32>,
+}
+
+impl Capture {
+    fn tid(&mut self) -> u32 {
+        let thread = std::thread::current();
+        let next = self.threads.len() as u32 + 1;
+        let events = &mut self.events;
+        *self.threads.entry(thread.id()).or_insert_with(|| {
+            // Name the row so the trace viewer shows "main" instead
+            // of a number
+            let name = thread.name().unwrap_or("unnamed").to_string();
+            let mut args = HashMap::new();
+            args.insert("name", name.into());
+            events.push(TraceEvent {
+                name: "thread_name".to_string(),
+                cat: "__metadata",
+                ph: "M",
+                ts: 0.0,
+                dur: None,
+                pid: 1,
+                tid: next,
+                s: None,
+                args: Some(args),
+            });
+            next
+        })
+    }
+}
+
+struct Inner {
+    capture: Option<Capture>,
+    finished: Option<ChromeTrace>,
+}
+
+/**
+ * Records how long things take over a window of frames and turns that
+ * into a file you can open in chrome://tracing (or Perfetto). It's
+ * cheap to clone and can be used from loader threads.
+ *
+ * Nothing is recorded until [Profiler::start_capture] is called, so
+ * leaving scopes in the code is fine.
+ */
+#[derive(Clone)]
+pub struct Profiler {
+    epoch: Instant,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            inner: Arc::new(Mutex::new(Inner {
+                capture: None,
+                finished: None,
+            })),
+        }
+    }
+
+    /// Records the next `frames` frames. Frames are counted by
+    /// [Profiler::end_frame].
+    pub fn start_capture(&self, frames: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut events = Vec::new();
+        events.push(TraceEvent {
+            name: "thread_name".to_string(),
+            cat: "__metadata",
+            ph: "M",
+            ts: 0.0,
+            dur: None,
+            pid: 1,
+            tid: GPU_TID,
+            s: None,
+            args: Some(std::iter::once(("name", "gpu".into())).collect()),
+        });
+        inner.capture = Some(Capture {
+            frames_left: frames.max(1),
+            events,
+            threads: HashMap::new(),
+        });
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.inner.lock().unwrap().capture.is_some()
+    }
+
+    /// Returns the trace once the capture is done
+    pub fn take_trace(&self) -> Option<ChromeTrace> {
+        self.inner.lock().unwrap().finished.take()
+    }
+
+    /// Times everything until the returned value is dropped
+    pub fn scope(&self, name: &'static str) -> ProfileScope {
+        ProfileScope {
+            profiler: self,
+            name,
+            cat: "cpu",
+            start: Instant::now(),
+        }
+    }
+
+    /// [Profiler::scope] for a step of starting up or loading, which
+    /// ends up in the trace as `"load"` rather than `"cpu"`
+    pub fn load_scope(&self, name: &'static str) -> ProfileScope {
+        ProfileScope {
+            profiler: self,
+            name,
+            cat: "load",
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record_cpu(&self, name: &str, start: Instant, duration: Duration) {
+        self.record_scope("cpu", name, start, duration);
+    }
+
+    /// [Profiler::load_scope] for a step that doesn't fit in a Rust
+    /// scope, like a background load's stages
+    pub fn record_load(&self, name: &str, start: Instant, duration: Duration) {
+        self.record_scope("load", name, start, duration);
+    }
+
+    fn record_scope(&self, cat: &'static str, name: &str, start: Instant, duration: Duration) {
+        let ts = self.micros(start);
+        self.with_capture(|capture| {
+            let tid = capture.tid();
+            capture.events.push(TraceEvent {
+                name: name.to_string(),
+                cat,
+                ph: "X",
+                ts,
+                dur: Some(duration.as_secs_f64() * 1e6),
+                pid: 1,
+                tid,
+                s: None,
+                args: None,
+            });
+        });
+    }
+
+    /// For GPU work timed some other way. The pass is placed at
+    /// `start` on the "gpu" row.
+    pub fn record_gpu_pass(&self, name: &str, start: Instant, duration: Duration) {
+        let ts = self.micros(start);
+        self.with_capture(|capture| {
+            capture.events.push(TraceEvent {
+                name: name.to_string(),
+                cat: "gpu",
+                ph: "X",
+                ts,
+                dur: Some(duration.as_secs_f64() * 1e6),
+                pid: 1,
+                tid: GPU_TID,
+                s: None,
+                args: None,
+            });
+        });
+    }
+
+    /**
+     * Something counted rather than timed, like how many triangles a
+     * pass drew. The trace shows each `name` as a graph across the
+     * capture.
+     */
+    pub fn record_counter(&self, name: &str, value: u64) {
+        let ts = self.micros(Instant::now());
+        self.with_capture(|capture| {
+            capture.events.push(TraceEvent {
+                name: name.to_string(),
+                cat: "counter",
+                ph: "C",
+                ts,
+                dur: None,
+                pid: 1,
+                tid: GPU_TID,
+                s: None,
+                args: Some(std::iter::once(("value", value.into())).collect()),
+            });
+        });
+    }
+
+    /// [Profiler::record_counter] for what a pass drew, as
+    /// `"<pass> triangles"` and `"<pass> skipped"`
+    pub fn record_lod_stats(&self, pass: &str, stats: &LodStats) {
+        self.record_counter(&format!("{} triangles", pass), stats.triangles);
+        self.record_counter(&format!("{} skipped", pass), stats.skipped);
+    }
+
+    /// Marks the frame boundary and counts down the capture
+    pub fn end_frame(&self) {
+        let ts = self.micros(Instant::now());
+        let mut inner = self.inner.lock().unwrap();
+        let done = match &mut inner.capture {
+            Some(capture) => {
+                let tid = capture.tid();
+                capture.events.push(TraceEvent {
+                    name: "frame".to_string(),
+                    cat: "frame",
+                    ph: "i",
+                    ts,
+                    dur: None,
+                    pid: 1,
+                    tid,
+                    // Global instant events draw a line across every row
+                    s: Some("g"),
+                    args: None,
+                });
+                capture.frames_left -= 1;
+                capture.frames_left == 0
+            }
+            None => false,
+        };
+        if done {
+            let capture = inner.capture.take().unwrap();
+            inner.finished = Some(ChromeTrace { trace_events: capture.events });
+        }
+    }
+
+    fn micros(&self, t: Instant) -> f64 {
+        t.saturating_duration_since(self.epoch).as_secs_f64() * 1e6
+    }
+
+    fn with_capture<F: FnOnce(&mut Capture)>(&self, f: F) {
+        if let Some(capture) = &mut self.inner.lock().unwrap().capture {
+            f(capture);
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ProfileScope<'a> {
+    profiler: &'a Profiler,
+    name: &'static str,
+    cat: &'static str,
+    start: Instant,
+}
+
+impl<'a> Drop for ProfileScope<'a> {
+    fn drop(&mut self) {
+        self.profiler.record_scope(self.cat, self.name, self.start, self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_stops_after_frames() {
+        let profiler = Profiler::new();
+        profiler.start_capture(2);
+        for _ in 0..3 {
+            let _scope = profiler.scope("update");
+            drop(_scope);
+            profiler.end_frame();
+        }
+        assert!(!profiler.is_capturing());
+
+        let trace = profiler.take_trace().unwrap();
+        let frames = trace.trace_events.iter().filter(|e| e.ph == "i").count();
+        let scopes = trace.trace_events.iter().filter(|e| e.name == "update").count();
+        assert_eq!(frames, 2);
+        assert_eq!(scopes, 2);
+        assert!(trace.to_json().unwrap().starts_with("{\"traceEvents\":["));
+    }
+
+    #[test]
+    fn threads_get_their_own_rows() {
+        let profiler = Profiler::new();
+        profiler.start_capture(1);
+        let start = Instant::now();
+        profiler.record_gpu_pass("main", start, Duration::from_millis(2));
+        let loader = profiler.clone();
+        std::thread::Builder::new()
+            .name("loader".to_string())
+            .spawn(move || loader.record_load("model parsing", start, Duration::from_millis(1)))
+            .unwrap()
+            .join()
+            .unwrap();
+        drop(profiler.scope("update"));
+        profiler.end_frame();
+
+        let trace = profiler.take_trace().unwrap();
+        let event = |name: &str| trace.trace_events.iter().find(|e| e.name == name).unwrap();
+        assert_eq!((event("main").cat, event("main").tid), ("gpu", GPU_TID));
+        assert_eq!(event("model parsing").cat, "load");
+        let row_name = |tid| trace.trace_events.iter()
+            .find(|e| e.ph == "M" && e.tid == tid)
+            .and_then(|e| e.args.as_ref()?.get("name")?.as_str().map(str::to_string));
+        assert_eq!(row_name(GPU_TID).as_deref(), Some("gpu"));
+        assert_eq!(row_name(event("model parsing").tid).as_deref(), Some("loader"));
+        assert_ne!(event("model parsing").tid, event("update").tid);
+        assert_ne!(event("update").tid, GPU_TID);
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use winit::dpi::{LogicalPosition, PhysicalPosition, PhysicalSize};
+use winit::event::*;
+
+/// Written at the top of every recording, so other files get turned
+/// away straight away
+const FORMAT: &str = "learn-wgpu input";
+/// Bump this whenever [RecordedEvent] changes in a way old files can't
+/// be read as
+pub const RECORDING_VERSION: u32 = 1;
+
+/**
+ * What [crate::run] should do with input, from
+ * [crate::Demo::input_session].
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputSession {
+    /// Act on real input and write it to this file as it happens
+    Record(PathBuf),
+    /// Ignore real input and act on a recording instead. With `fast`
+    /// frames are drawn as quickly as they can be rather than at the
+    /// recorded rate. Either way each frame gets the recorded `dt`.
+    Replay { path: PathBuf, fast: bool },
+}
+
+/// The first line of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub format: String,
+    pub version: u32,
+    /// The window's inner size when recording started. Replays resize
+    /// the window to this first, as picking depends on it.
+    pub window_size: (u32, u32),
+}
+
+/**
+ * The parts of winit's events that demos act on, in a form that can
+ * be written to a file. Anything else never reaches a recording.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    /// What [crate::Demo::process_mouse] gets
+    MouseMotion { dx: f64, dy: f64 },
+    Key { key: Option<VirtualKeyCode>, scancode: u32, state: ElementState },
+    /// [ModifiersState] bits
+    Modifiers(u32),
+    CursorMoved { x: f64, y: f64 },
+    MouseInput { button: MouseButton, state: ElementState },
+    /// In lines, or pixels when `pixels` is set
+    MouseWheel { dx: f32, dy: f32, pixels: bool },
+    Resized { width: u32, height: u32 },
+    DroppedFile(PathBuf),
+    CloseRequested,
+    /// The end of a frame, with the dt [crate::Demo::update] got
+    Frame { dt_micros: u64 },
+}
+
+impl RecordedEvent {
+    /// `None` for anything demos don't act on
+    pub fn from_window_event(event: &WindowEvent) -> Option<Self> {
+        Some(match event {
+            WindowEvent::KeyboardInput { input, .. } => RecordedEvent::Key {
+                key: input.virtual_keycode,
+                scancode: input.scancode,
+                state: input.state,
+            },
+            WindowEvent::ModifiersChanged(modifiers) => RecordedEvent::Modifiers(modifiers.bits()),
+            WindowEvent::CursorMoved { position, .. } => RecordedEvent::CursorMoved { x: position.x, y: position.y },
+            WindowEvent::MouseInput { button, state, .. } => RecordedEvent::MouseInput { button: *button, state: *state },
+            WindowEvent::MouseWheel { delta, .. } => match delta {
+                MouseScrollDelta::LineDelta(dx, dy) => RecordedEvent::MouseWheel { dx: *dx, dy: *dy, pixels: false },
+                MouseScrollDelta::PixelDelta(p) => RecordedEvent::MouseWheel { dx: p.x as f32, dy: p.y as f32, pixels: true },
+            },
+            WindowEvent::Resized(size) => RecordedEvent::Resized { width: size.width, height: size.height },
+            WindowEvent::DroppedFile(path) => RecordedEvent::DroppedFile(path.clone()),
+            WindowEvent::CloseRequested => RecordedEvent::CloseRequested,
+            _ => return None,
+        })
+    }
+
+    /**
+     * The window event to hand the demo, or `None` for the ones that
+     * aren't window events. [RecordedEvent::Resized] isn't handed over
+     * either, as the window gets resized instead and the real event
+     * follows.
+     */
+    pub fn to_window_event(&self) -> Option<WindowEvent<'static>> {
+        // Demos never look at which device an event came from
+        let device_id = unsafe { DeviceId::dummy() };
+        #[allow(deprecated)]
+        Some(match self {
+            RecordedEvent::Key { key, scancode, state } => WindowEvent::KeyboardInput {
+                device_id,
+                input: KeyboardInput {
+                    scancode: *scancode,
+                    state: *state,
+                    virtual_keycode: *key,
+                    modifiers: ModifiersState::empty(),
+                },
+                is_synthetic: false,
+            },
+            RecordedEvent::Modifiers(bits) => WindowEvent::ModifiersChanged(ModifiersState::from_bits_truncate(*bits)),
+            RecordedEvent::CursorMoved { x, y } => WindowEvent::CursorMoved {
+                device_id,
+                position: PhysicalPosition::new(*x, *y),
+                modifiers: ModifiersState::empty(),
+            },
+            RecordedEvent::MouseInput { button, state } => WindowEvent::MouseInput {
+                device_id,
+                state: *state,
+                button: *button,
+                modifiers: ModifiersState::empty(),
+            },
+            RecordedEvent::MouseWheel { dx, dy, pixels } => WindowEvent::MouseWheel {
+                device_id,
+                delta: if *pixels {
+                    MouseScrollDelta::PixelDelta(LogicalPosition::new(*dx as f64, *dy as f64))
+                } else {
+                    MouseScrollDelta::LineDelta(*dx, *dy)
+                },
+                phase: TouchPhase::Moved,
+                modifiers: ModifiersState::empty(),
+            },
+            RecordedEvent::DroppedFile(path) => WindowEvent::DroppedFile(path.clone()),
+            RecordedEvent::CloseRequested => WindowEvent::CloseRequested,
+            RecordedEvent::MouseMotion { .. } | RecordedEvent::Resized { .. } | RecordedEvent::Frame { .. } => {
+                return None;
+            }
+        })
+    }
+}
+
+/// One line of a recording after the header
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RecordedLine {
+    frame: u64,
+    event: RecordedEvent,
+}
+
+/**
+ * Writes input to a file as JSON lines. Each frame gets flushed as it
+ * ends, so a recording that ends in a panic still has everything up to
+ * the frame that panicked.
+ */
+pub struct InputRecorder {
+    writer: BufWriter<std::fs::File>,
+    frame: u64,
+}
+
+impl InputRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, window_size: (u32, u32)) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        let header = RecordingHeader { format: FORMAT.to_string(), version: RECORDING_VERSION, window_size };
+        writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+        Ok(Self { writer, frame: 0 })
+    }
+
+    pub fn record(&mut self, event: RecordedEvent) -> Result<()> {
+        let end_of_frame = matches!(event, RecordedEvent::Frame { .. });
+        let line = RecordedLine { frame: self.frame, event };
+        writeln!(self.writer, "{}", serde_json::to_string(&line)?)?;
+        if end_of_frame {
+            self.frame += 1;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the current frame
+    pub fn end_frame(&mut self, dt: Duration) -> Result<()> {
+        self.record(RecordedEvent::Frame { dt_micros: dt.as_micros() as u64 })
+    }
+}
+
+/// A recording read back in, handed out one frame at a time
+pub struct InputReplay {
+    pub header: RecordingHeader,
+    frames: VecDeque<(Vec<RecordedEvent>, Duration)>,
+}
+
+impl InputReplay {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        Self::parse(BufReader::new(file))
+            .with_context(|| format!("Unable to replay {}", path.display()))
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut lines = reader.lines();
+        let first = lines.next().context("The recording is empty")??;
+        let header: RecordingHeader = serde_json::from_str(&first)
+            .map_err(|_| anyhow!("This isn't an input recording"))?;
+        ensure!(header.format == FORMAT, "This isn't an input recording");
+        ensure!(
+            header.version == RECORDING_VERSION,
+            "The recording is version {}, but only version {} can be replayed. Record it again with this build.",
+            header.version,
+            RECORDING_VERSION,
+        );
+
+        let mut frames = VecDeque::new();
+        let mut events = Vec::new();
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line: RecordedLine = serde_json::from_str(&line)
+                .with_context(|| format!("Line {} isn't a recorded event", number + 2))?;
+            ensure!(
+                line.frame == frames.len() as u64,
+                "Line {} is for frame {}, but frame {} was expected",
+                number + 2,
+                line.frame,
+                frames.len(),
+            );
+            match line.event {
+                RecordedEvent::Frame { dt_micros } => {
+                    frames.push_back((std::mem::take(&mut events), Duration::from_micros(dt_micros)));
+                }
+                event => events.push(event),
+            }
+        }
+        // Events after the last frame ended, like from a panic during
+        // it, still get their frame
+        if !events.is_empty() {
+            frames.push_back((events, Duration::from_millis(16)));
+        }
+        Ok(Self { header, frames })
+    }
+
+    pub fn frames_left(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The events to hand over before the next update, and the dt to
+    /// give it. `None` once the recording runs out.
+    pub fn next_frame(&mut self) -> Option<(Vec<RecordedEvent>, Duration)> {
+        self.frames.pop_front()
+    }
+}
+
+/// `size` as the header stores it
+pub(crate) fn window_size(size: PhysicalSize<u32>) -> (u32, u32) {
+    (size.width, size.height)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::{Camera, CameraController};
+    use crate::input::{InputMap, KeyBinding};
+
+    #[test]
+    fn recordings_round_trip() {
+        let path = std::env::temp_dir().join(format!("learn-wgpu-input-{}.jsonl", std::process::id()));
+        let events = vec![
+            RecordedEvent::MouseMotion { dx: 3.0, dy: -1.5 },
+            RecordedEvent::Modifiers(ModifiersState::CTRL.bits()),
+            RecordedEvent::Key { key: Some(VirtualKeyCode::Numpad7), scancode: 71, state: ElementState::Pressed },
+            RecordedEvent::MouseWheel { dx: 0.0, dy: -2.0, pixels: false },
+        ];
+        let result = (|| -> Result<InputReplay> {
+            let mut recorder = InputRecorder::create(&path, (800, 600))?;
+            recorder.record(events[0].clone())?;
+            recorder.end_frame(Duration::from_millis(16))?;
+            for event in &events[1..] {
+                recorder.record(event.clone())?;
+            }
+            recorder.end_frame(Duration::from_millis(20))?;
+            drop(recorder);
+            InputReplay::load(&path)
+        })();
+        let _ = std::fs::remove_file(&path);
+        let mut replay = result.unwrap();
+
+        assert_eq!(replay.header.window_size, (800, 600));
+        assert_eq!(replay.frames_left(), 2);
+        assert_eq!(replay.next_frame(), Some((events[..1].to_vec(), Duration::from_millis(16))));
+        assert_eq!(replay.next_frame(), Some((events[1..].to_vec(), Duration::from_millis(20))));
+        assert_eq!(replay.next_frame(), None);
+
+        // What the demo gets handed is what it would have got live
+        let key = events[2].to_window_event().unwrap();
+        assert_eq!(RecordedEvent::from_window_event(&key).as_ref(), Some(&events[2]));
+        assert!(events[0].to_window_event().is_none());
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let header = RecordingHeader { format: FORMAT.to_string(), version: RECORDING_VERSION + 1, window_size: (1, 1) };
+        let file = format!("{}\n", serde_json::to_string(&header).unwrap());
+        let error = InputReplay::parse(file.as_bytes()).err().unwrap().to_string();
+        assert!(error.contains("only version 1 can be replayed"), "{}", error);
+
+        let error = InputReplay::parse("{\"not\": \"a recording\"}\n".as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(), "This isn't an input recording");
+    }
+
+    /// The input handling a demo would have, for replaying into
+    struct Driven {
+        map: InputMap<&'static str>,
+        actions: Vec<&'static str>,
+        controller: CameraController,
+        camera: Camera,
+    }
+
+    impl Driven {
+        fn new() -> Self {
+            let mut map = InputMap::new();
+            map.bind(KeyBinding::key(VirtualKeyCode::Numpad7), "top");
+            map.bind(KeyBinding::ctrl(VirtualKeyCode::Numpad7), "bottom");
+            Self {
+                map,
+                actions: Vec::new(),
+                controller: CameraController::new(2.0, 0.1),
+                camera: Camera::new((0.0, 0.0, 0.0), cgmath::Rad(0.0), cgmath::Rad(0.0)),
+            }
+        }
+
+        /// What [crate::run] does with a replayed frame
+        fn replay_frame(&mut self, events: &[RecordedEvent], dt: Duration) {
+            for event in events {
+                crate::replay_event(self, event);
+            }
+            self.controller.update_camera(&mut self.camera, dt);
+        }
+    }
+
+    impl crate::ReplayInput for Driven {
+        fn process_mouse(&mut self, dx: f64, dy: f64) {
+            self.controller.process_mouse(dx, dy);
+        }
+
+        fn input(&mut self, event: &WindowEvent) {
+            match event {
+                WindowEvent::KeyboardInput {
+                    input: KeyboardInput { virtual_keycode: Some(key), state, .. },
+                    ..
+                } if self.controller.process_keyboard(*key, *state) => {}
+                _ => self.actions.extend(self.map.process_event(event)),
+            }
+        }
+
+        fn set_inner_size(&mut self, _width: u32, _height: u32) {}
+    }
+
+    #[test]
+    fn replays_drive_the_camera() {
+        let path = std::env::temp_dir().join(format!("learn-wgpu-replay-{}.jsonl", std::process::id()));
+        let key = |key, state| RecordedEvent::Key { key: Some(key), scancode: 0, state };
+        let frames = vec![
+            vec![key(VirtualKeyCode::W, ElementState::Pressed)],
+            vec![],
+            vec![
+                key(VirtualKeyCode::W, ElementState::Released),
+                RecordedEvent::Modifiers(ModifiersState::CTRL.bits()),
+                key(VirtualKeyCode::Numpad7, ElementState::Pressed),
+                RecordedEvent::Modifiers(0),
+            ],
+            vec![RecordedEvent::MouseMotion { dx: 10.0, dy: 0.0 }],
+            vec![key(VirtualKeyCode::Numpad7, ElementState::Pressed)],
+        ];
+        let result = (|| -> Result<InputReplay> {
+            let mut recorder = InputRecorder::create(&path, (800, 600))?;
+            for events in &frames {
+                for event in events {
+                    recorder.record(event.clone())?;
+                }
+                recorder.end_frame(Duration::from_millis(500))?;
+            }
+            drop(recorder);
+            InputReplay::load(&path)
+        })();
+        let _ = std::fs::remove_file(&path);
+        let mut replay = result.unwrap();
+
+        let mut driven = Driven::new();
+        let mut positions = Vec::new();
+        while let Some((events, dt)) = replay.next_frame() {
+            driven.replay_frame(&events, dt);
+            positions.push(driven.camera.position);
+        }
+
+        // Half a second at 2 units a second, twice, then W is let go
+        let xs = positions.iter().map(|p| p.x).collect::<Vec<_>>();
+        assert_eq!(xs, vec![1.0, 2.0, 2.0, 2.0, 2.0]);
+        // Ctrl was still held for the first Numpad7
+        assert_eq!(driven.actions, vec!["bottom", "top"]);
+        // The mouse turned it 10 * 0.1 * 0.5 radians
+        let expected = Camera::new((2.0, 0.0, 0.0), cgmath::Rad(0.5), cgmath::Rad(0.0)).calc_matrix();
+        let difference = driven.camera.calc_matrix() - expected;
+        assert!((0..4).all(|c| cgmath::InnerSpace::magnitude(difference[c]) < 1e-5), "{:?}", difference);
+    }
+}
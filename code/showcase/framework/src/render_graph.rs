@@ -0,0 +1,613 @@
+use anyhow::*;
+use std::fmt::Write;
+use std::time::Instant;
+use crate::profiler::Profiler;
+use crate::texture::Texture;
+
+/// What passes call the things they read and write
+pub type ResourceKey = &'static str;
+
+/**
+ * A texture the graph allocates itself, for something that only lives
+ * between two passes of a frame. Transients with the same desc share a
+ * texture when their lifetimes don't overlap.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TransientDesc {
+    pub format: wgpu::TextureFormat,
+    /// 1 is the size of the screen, 2 half of it and so on
+    pub divisor: u32,
+}
+
+struct Resource {
+    key: ResourceKey,
+    transient: Option<TransientDesc>,
+}
+
+/**
+ * One pass in a [RenderGraph]. Writing a resource without reading it
+ * means drawing it from scratch, and that pass always goes first. Past
+ * that, a pass sees a resource the way the passes added before it left
+ * it. Reading waits for the last modifier (a pass that reads and writes
+ * it) added earlier, and modifying waits for everything added earlier
+ * that reads it.
+ */
+pub struct PassNode<C> {
+    name: &'static str,
+    reads: Vec<ResourceKey>,
+    writes: Vec<ResourceKey>,
+    history: Vec<ResourceKey>,
+    enabled: Box<dyn Fn(&C) -> bool>,
+    execute: Box<dyn FnMut(&mut C, &mut PassContext)>,
+}
+
+impl<C> PassNode<C> {
+    pub fn new<F>(name: &'static str, execute: F) -> Self
+    where
+        F: FnMut(&mut C, &mut PassContext) + 'static,
+    {
+        Self {
+            name,
+            reads: Vec::new(),
+            writes: Vec::new(),
+            history: Vec::new(),
+            enabled: Box::new(|_| true),
+            execute: Box::new(execute),
+        }
+    }
+
+    pub fn reads(mut self, keys: &[ResourceKey]) -> Self {
+        self.reads.extend_from_slice(keys);
+        self
+    }
+
+    pub fn writes(mut self, keys: &[ResourceKey]) -> Self {
+        self.writes.extend_from_slice(keys);
+        self
+    }
+
+    /// Reads and writes
+    pub fn modifies(self, keys: &[ResourceKey]) -> Self {
+        self.reads(keys).writes(keys)
+    }
+
+    /// Reads what last frame left in `keys`, so this has to run before
+    /// anything writes them this frame
+    pub fn reads_history(mut self, keys: &[ResourceKey]) -> Self {
+        self.history.extend_from_slice(keys);
+        self
+    }
+
+    /// Checked once at the start of each frame. Passes that read what
+    /// a skipped pass wrote still run, with whatever's left in there.
+    pub fn enabled_when<F: Fn(&C) -> bool + 'static>(mut self, enabled: F) -> Self {
+        self.enabled = Box::new(enabled);
+        self
+    }
+
+    fn uses(&self, key: ResourceKey) -> bool {
+        self.reads.contains(&key) || self.writes.contains(&key) || self.history.contains(&key)
+    }
+}
+
+/// What a pass gets to record with
+pub struct PassContext<'r> {
+    pub device: &'r wgpu::Device,
+    pub encoder: &'r mut wgpu::CommandEncoder,
+    /// The swap chain's texture for this frame
+    pub output: &'r wgpu::TextureView,
+    /// From [RenderGraph::resize]
+    pub width: u32,
+    pub height: u32,
+    bound: &'r [(ResourceKey, usize)],
+    pool: &'r [Texture<'static>],
+}
+
+impl<'r> PassContext<'r> {
+    /// The texture behind a [RenderGraph::transient]. Whatever a pass
+    /// before this one in the frame didn't write is garbage.
+    pub fn transient(&self, key: ResourceKey) -> Option<&'r Texture<'static>> {
+        let pool = self.pool;
+        self.bound.iter()
+            .find(|(k, _)| *k == key)
+            .map(move |(_, slot)| &pool[*slot])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Lifetime {
+    key: ResourceKey,
+    /// Positions in the resolved order
+    first: usize,
+    last: usize,
+    /// Which of the pool's textures, for transients
+    slot: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Schedule {
+    /// Indices into the graph's passes
+    order: Vec<usize>,
+    lifetimes: Vec<Lifetime>,
+    slots: Vec<TransientDesc>,
+}
+
+/**
+ * Works out the order of a frame's passes from what they read and
+ * write, rather than from where somebody spliced them into the render
+ * function. Adding a pass is adding a [PassNode] with its resources.
+ *
+ * Resources are only names. Ones the state owns, like the depth
+ * texture, get [RenderGraph::import]ed and the passes find them on the
+ * state as before. [RenderGraph::transient] ones get allocated by the
+ * graph, and reused between passes when they can be.
+ *
+ * `C` is whatever the passes draw from, usually the demo itself. To run
+ * the graph from a method of the state it lives on, take it out first:
+ * `let mut graph = std::mem::take(&mut self.graph);`
+ */
+pub struct RenderGraph<C> {
+    passes: Vec<PassNode<C>>,
+    resources: Vec<Resource>,
+    /// The enabled passes it was resolved for, and None if they don't
+    /// make a working order
+    compiled: Option<(Vec<bool>, Option<Schedule>)>,
+    size: (u32, u32),
+    pool: Vec<Texture<'static>>,
+    pool_descs: Vec<TransientDesc>,
+    bound: Vec<(ResourceKey, usize)>,
+    profiler: Option<Profiler>,
+}
+
+impl<C> Default for RenderGraph<C> {
+    fn default() -> Self {
+        Self {
+            passes: Vec::new(),
+            resources: Vec::new(),
+            compiled: None,
+            size: (1, 1),
+            pool: Vec::new(),
+            pool_descs: Vec::new(),
+            bound: Vec::new(),
+            profiler: None,
+        }
+    }
+}
+
+impl<C> RenderGraph<C> {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            size: (width.max(1), height.max(1)),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * Each pass gets recorded in a [Profiler::scope] of its name. While
+     * the profiler is capturing, each pass also gets submitted on its
+     * own and waited for, which is how long it took on the GPU. That
+     * makes captured frames slower than the rest, so don't read too
+     * much into their frame times.
+     */
+    pub fn with_profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    /// Something that lives outside the graph, like the state's depth
+    /// texture or the swap chain
+    pub fn import(&mut self, key: ResourceKey) {
+        self.add_resource(key, None);
+    }
+
+    pub fn transient(&mut self, key: ResourceKey, desc: TransientDesc) {
+        self.add_resource(key, Some(desc));
+    }
+
+    fn add_resource(&mut self, key: ResourceKey, transient: Option<TransientDesc>) {
+        self.resources.retain(|r| r.key != key);
+        self.resources.push(Resource { key, transient });
+        self.compiled = None;
+    }
+
+    pub fn add_pass(&mut self, pass: PassNode<C>) {
+        self.passes.push(pass);
+        self.compiled = None;
+    }
+
+    /// Transients get reallocated at the new size on the next frame
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.size = (width.max(1), height.max(1));
+        self.pool.clear();
+        self.pool_descs.clear();
+    }
+
+    fn enabled(&self, state: &C) -> Vec<bool> {
+        self.passes.iter().map(|p| (p.enabled)(state)).collect()
+    }
+
+    fn compile(&self, enabled: &[bool]) -> Result<Schedule> {
+        let active = (0..self.passes.len()).filter(|&i| enabled[i]).collect::<Vec<_>>();
+        for &i in &active {
+            let pass = &self.passes[i];
+            let keys = pass.reads.iter().chain(&pass.writes).chain(&pass.history);
+            for key in keys {
+                if !self.resources.iter().any(|r| r.key == *key) {
+                    bail!("{} uses {}, which was never imported or made transient", pass.name, key);
+                }
+            }
+        }
+
+        // after[a] is every pass that has to wait for a
+        let mut after = vec![Vec::new(); self.passes.len()];
+        let mut edge = |a: usize, b: usize| {
+            if a != b && !after[a].contains(&b) {
+                after[a].push(b);
+            }
+        };
+        for resource in &self.resources {
+            let key = resource.key;
+            let users = active.iter().copied().filter(|&i| self.passes[i].uses(key)).collect::<Vec<_>>();
+            let producers = users.iter()
+                .copied()
+                .filter(|&i| self.passes[i].writes.contains(&key) && !self.passes[i].reads.contains(&key))
+                .collect::<Vec<_>>();
+            if let [a, b, ..] = producers[..] {
+                bail!("{} and {} both write {} from scratch", self.passes[a].name, self.passes[b].name, key);
+            }
+            let producer = producers.first().copied();
+            let history = users.iter().copied().filter(|&i| self.passes[i].history.contains(&key));
+
+            if resource.transient.is_some() {
+                if let Some(i) = history.clone().next() {
+                    bail!("{} wants last frame's {}, which is transient and gone by then", self.passes[i].name, key);
+                }
+                let reader = users.iter().find(|&&i| self.passes[i].reads.contains(&key));
+                if let (None, Some(&i)) = (producer, reader) {
+                    bail!("{} reads {}, but nothing enabled writes it first", self.passes[i].name, key);
+                }
+            }
+
+            let mut last_writer = producer;
+            let mut readers = Vec::new();
+            for &i in &users {
+                let pass = &self.passes[i];
+                if Some(i) == producer || !pass.reads.contains(&key) {
+                    continue;
+                }
+                if let Some(w) = last_writer {
+                    edge(w, i);
+                }
+                if pass.writes.contains(&key) {
+                    for &r in &readers {
+                        edge(r, i);
+                    }
+                    readers.clear();
+                    last_writer = Some(i);
+                } else {
+                    readers.push(i);
+                }
+            }
+            // Before anything puts this frame's version in there
+            for h in history {
+                let writers = users.iter().filter(|&&i| self.passes[i].writes.contains(&key));
+                for &w in writers {
+                    edge(h, w);
+                }
+            }
+        }
+
+        // Kahn's, always taking the earliest added pass that's ready so
+        // the order doesn't shuffle around between runs
+        let mut waiting_on = vec![0; self.passes.len()];
+        for &a in &active {
+            for &b in &after[a] {
+                waiting_on[b] += 1;
+            }
+        }
+        let mut done = vec![false; self.passes.len()];
+        let mut order = Vec::with_capacity(active.len());
+        while order.len() < active.len() {
+            match active.iter().copied().find(|&i| !done[i] && waiting_on[i] == 0) {
+                Some(i) => {
+                    done[i] = true;
+                    order.push(i);
+                    for &b in &after[i] {
+                        waiting_on[b] -= 1;
+                    }
+                }
+                None => {
+                    let stuck = active.iter()
+                        .filter(|&&i| !done[i])
+                        .map(|&i| self.passes[i].name)
+                        .collect::<Vec<_>>();
+                    bail!("These passes all wait on each other: {}", stuck.join(", "));
+                }
+            }
+        }
+
+        let mut lifetimes = Vec::new();
+        for resource in &self.resources {
+            let mut used = order.iter()
+                .enumerate()
+                .filter(|(_, &i)| self.passes[i].uses(resource.key))
+                .map(|(at, _)| at);
+            if let Some(first) = used.next() {
+                let last = used.last().unwrap_or(first);
+                lifetimes.push(Lifetime { key: resource.key, first, last, slot: None });
+            }
+        }
+
+        // Earliest first, each transient takes the first texture that
+        // looks the same and that nothing needs any more
+        let mut by_first = (0..lifetimes.len()).collect::<Vec<_>>();
+        by_first.sort_by_key(|&l| lifetimes[l].first);
+        let mut slots = Vec::<TransientDesc>::new();
+        let mut free_after = Vec::<usize>::new();
+        for l in by_first {
+            let desc = match self.resources.iter().find(|r| r.key == lifetimes[l].key).and_then(|r| r.transient) {
+                Some(desc) => desc,
+                None => continue,
+            };
+            let first = lifetimes[l].first;
+            let slot = match (0..slots.len()).find(|&s| slots[s] == desc && free_after[s] < first) {
+                Some(s) => s,
+                None => {
+                    slots.push(desc);
+                    free_after.push(0);
+                    slots.len() - 1
+                }
+            };
+            free_after[slot] = lifetimes[l].last;
+            lifetimes[l].slot = Some(slot);
+        }
+
+        Ok(Schedule { order, lifetimes, slots })
+    }
+
+    fn allocate(&mut self, device: &wgpu::Device, slots: &[TransientDesc]) {
+        if self.pool_descs[..] == slots[..] {
+            return;
+        }
+        let (width, height) = self.size;
+        self.pool = slots.iter()
+            .map(|desc| {
+                let divisor = desc.divisor.max(1);
+                Texture::from_descriptor(device, wgpu::TextureDescriptor {
+                    label: Some("RenderGraph::transient"),
+                    size: wgpu::Extent3d {
+                        width: (width / divisor).max(1),
+                        height: (height / divisor).max(1),
+                        depth: 1,
+                    },
+                    array_layer_count: 1,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: desc.format,
+                    usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                        | wgpu::TextureUsage::SAMPLED
+                        | wgpu::TextureUsage::COPY_SRC
+                        | wgpu::TextureUsage::COPY_DST,
+                })
+            })
+            .collect();
+        self.pool_descs = slots.to_vec();
+    }
+
+    /**
+     * Records every enabled pass into `encoder`, in dependency order.
+     * The order only gets worked out again when a different set of
+     * passes is enabled. One that doesn't work gets reported once, then
+     * frames record nothing until something gets toggled. `queue` is
+     * only used for the GPU timings, see [RenderGraph::with_profiler].
+     */
+    pub fn execute(
+        &mut self,
+        state: &mut C,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) -> Result<()> {
+        let enabled = self.enabled(state);
+        if self.compiled.as_ref().map_or(true, |(mask, _)| *mask != enabled) {
+            let schedule = self.compile(&enabled);
+            let failed = schedule.as_ref().err().map(|e| anyhow!("Render graph: {}", e));
+            self.compiled = Some((enabled, schedule.ok()));
+            if let Some(e) = failed {
+                return Err(e);
+            }
+        }
+        let schedule = match &self.compiled {
+            Some((_, Some(schedule))) => schedule.clone(),
+            _ => return Ok(()),
+        };
+
+        self.allocate(device, &schedule.slots);
+        self.bound.clear();
+        for lifetime in &schedule.lifetimes {
+            if let Some(slot) = lifetime.slot {
+                self.bound.push((lifetime.key, slot));
+            }
+        }
+
+        let (width, height) = self.size;
+        let Self { passes, pool, bound, profiler, .. } = self;
+        let gpu_timer = profiler.as_ref().filter(|p| p.is_capturing());
+        if gpu_timer.is_some() {
+            // Whatever got recorded before the graph isn't any pass's
+            submit_and_wait(device, queue, encoder);
+        }
+        for &i in &schedule.order {
+            let pass = &mut passes[i];
+            {
+                let _scope = profiler.as_ref().map(|p| p.scope(pass.name));
+                let mut context = PassContext {
+                    device,
+                    encoder: &mut *encoder,
+                    output,
+                    width,
+                    height,
+                    bound: &bound[..],
+                    pool: &pool[..],
+                };
+                (pass.execute)(state, &mut context);
+            }
+            if let Some(profiler) = gpu_timer {
+                let start = Instant::now();
+                submit_and_wait(device, queue, encoder);
+                profiler.record_gpu_pass(pass.name, start, start.elapsed());
+            }
+        }
+        Ok(())
+    }
+
+    /// The order the passes would run in with `state`, what got skipped
+    /// and which passes each resource lives between
+    pub fn describe(&self, state: &C) -> String {
+        let enabled = self.enabled(state);
+        let schedule = match self.compile(&enabled) {
+            Ok(schedule) => schedule,
+            Err(e) => return format!("Render graph is broken: {}", e),
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(out, "Render graph, {} of {} passes:", schedule.order.len(), self.passes.len());
+        for (at, &i) in schedule.order.iter().enumerate() {
+            let pass = &self.passes[i];
+            let mut parts = Vec::new();
+            if !pass.history.is_empty() {
+                parts.push(format!("last frame's {}", pass.history.join(", ")));
+            }
+            if !pass.reads.is_empty() {
+                parts.push(format!("reads {}", pass.reads.join(", ")));
+            }
+            if !pass.writes.is_empty() {
+                parts.push(format!("writes {}", pass.writes.join(", ")));
+            }
+            let _ = writeln!(out, "{:>4}. {:<20} {}", at + 1, pass.name, parts.join("; "));
+        }
+        let skipped = (0..self.passes.len())
+            .filter(|&i| !enabled[i])
+            .map(|i| self.passes[i].name)
+            .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            let _ = writeln!(out, "Skipped: {}", skipped.join(", "));
+        }
+
+        let _ = writeln!(out, "Resources:");
+        for lifetime in &schedule.lifetimes {
+            let span = format!("{}..{}", lifetime.first + 1, lifetime.last + 1);
+            let kind = match lifetime.slot {
+                Some(slot) => {
+                    let desc = schedule.slots[slot];
+                    format!("transient {:?} at 1/{}, texture {}", desc.format, desc.divisor.max(1), slot)
+                }
+                None => "imported".to_string(),
+            };
+            let _ = writeln!(out, "{:>6} {:<20} {}", span, lifetime.key, kind);
+        }
+        out
+    }
+}
+
+/// Sends off what's in `encoder` so far and carries on in a new one
+fn submit_and_wait(device: &wgpu::Device, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+    let done = std::mem::replace(
+        encoder,
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("render_graph::encoder") }),
+    );
+    queue.submit(&[done.finish()]);
+    device.poll(wgpu::Maintain::Wait);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(name: &'static str) -> PassNode<()> {
+        PassNode::new(name, |_, _| {})
+    }
+
+    fn names(graph: &RenderGraph<()>, enabled: &[bool]) -> Result<Vec<&'static str>> {
+        let schedule = graph.compile(enabled)?;
+        Ok(schedule.order.iter().map(|&i| graph.passes[i].name).collect())
+    }
+
+    #[test]
+    fn passes_run_in_resource_order() {
+        let mut graph = RenderGraph::<()>::new(64, 64);
+        for key in &["hdr", "velocity", "depth", "hiz", "culled", "output"] {
+            graph.import(key);
+        }
+        // Nowhere near the order they run in, but each reader after
+        // whatever it wants to see modify the resource
+        graph.add_pass(node("hiz_build").reads(&["depth"]).writes(&["hiz"]));
+        graph.add_pass(node("glass").modifies(&["hdr"]).reads(&["depth"]));
+        graph.add_pass(node("blur").modifies(&["hdr"]).reads(&["velocity"]));
+        graph.add_pass(node("tonemap").reads(&["hdr"]).writes(&["output"]));
+        graph.add_pass(node("cull").reads_history(&["hiz"]).writes(&["culled"]));
+        graph.add_pass(node("main").reads(&["culled"]).writes(&["hdr", "velocity", "depth"]));
+
+        let all = [true; 6];
+        assert_eq!(names(&graph, &all).unwrap(), vec!["cull", "main", "hiz_build", "glass", "blur", "tonemap"]);
+        let no_blur = [true, true, false, true, true, true];
+        assert_eq!(names(&graph, &no_blur).unwrap(), vec!["cull", "main", "hiz_build", "glass", "tonemap"]);
+
+        graph.add_pass(node("also_main").writes(&["depth"]));
+        let error = names(&graph, &[true; 7]).unwrap_err().to_string();
+        assert!(error.contains("main and also_main"), "{}", error);
+
+        let mut graph = RenderGraph::<()>::new(64, 64);
+        graph.import("a");
+        graph.import("b");
+        graph.add_pass(node("one").reads(&["a"]).writes(&["b"]));
+        graph.add_pass(node("two").reads(&["b"]).writes(&["a"]));
+        assert!(names(&graph, &[true; 2]).unwrap_err().to_string().contains("one, two"));
+
+        let mut graph = RenderGraph::<()>::new(64, 64);
+        graph.add_pass(node("lost").reads(&["nowhere"]));
+        assert!(names(&graph, &[true]).is_err());
+    }
+
+    #[test]
+    fn transients_share_textures_when_they_can() {
+        let half = TransientDesc { format: wgpu::TextureFormat::Rgba16Float, divisor: 2 };
+        let mut graph = RenderGraph::<()>::new(64, 64);
+        graph.import("hdr");
+        graph.transient("ssao", TransientDesc { format: wgpu::TextureFormat::R8Unorm, divisor: 2 });
+        graph.transient("bright", half);
+        graph.transient("bloom", half);
+        graph.add_pass(node("ssao").writes(&["ssao"]));
+        graph.add_pass(node("main").reads(&["ssao"]).writes(&["hdr"]));
+        graph.add_pass(node("bright").reads(&["hdr"]).writes(&["bright"]));
+        graph.add_pass(node("blur").reads(&["bright"]).writes(&["bloom"]));
+        graph.add_pass(node("composite").modifies(&["hdr"]).reads(&["bloom"]));
+
+        let schedule = graph.compile(&[true; 5]).unwrap();
+        let slot = |key| schedule.lifetimes.iter().find(|l| l.key == key).unwrap().slot;
+        assert_eq!(slot("hdr"), None);
+        // Bright and bloom are both alive during the blur, and the ssao
+        // texture is a different format
+        assert_eq!(schedule.slots.len(), 3);
+        assert_ne!(slot("bright"), slot("bloom"));
+
+        // Another half size target that comes after bright is done with
+        graph.transient("late", half);
+        graph.add_pass(node("late").reads(&["hdr"]).writes(&["late"]));
+        graph.add_pass(node("late_use").reads(&["late"]).writes(&["hdr"]));
+        let error = graph.compile(&[true; 7]).unwrap_err().to_string();
+        assert!(error.contains("main and late_use"), "{}", error);
+        graph.passes.pop();
+        graph.add_pass(node("late_use").modifies(&["hdr"]).reads(&["late"]));
+        let schedule = graph.compile(&[true; 7]).unwrap();
+        let late = schedule.lifetimes.iter().find(|l| l.key == "late").unwrap();
+        assert_eq!(late.slot, schedule.lifetimes.iter().find(|l| l.key == "bright").unwrap().slot);
+        assert_eq!(schedule.slots.len(), 3);
+
+        // Nothing writes the ssao texture with its pass off
+        let error = graph.compile(&[false, true, true, true, true, true, true]).unwrap_err().to_string();
+        assert!(error.contains("main reads ssao"), "{}", error);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::model::Model;
+use crate::texture::TextureCache;
+
+/// Buffers on the GPU, see [ResourceTracker]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct GpuResources {
+    pub buffers: usize,
+    pub buffer_bytes: u64,
+}
+
+impl GpuResources {
+    pub fn add(&mut self, other: GpuResources) {
+        self.buffers += other.buffers;
+        self.buffer_bytes += other.buffer_bytes;
+    }
+}
+
+impl fmt::Display for GpuResources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} buffers, {:.1} MB", self.buffers, self.buffer_bytes as f64 / (1 << 20) as f64)
+    }
+}
+
+/// What [ResourceTracker::unload_model] got rid of
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Unloaded {
+    pub resources: GpuResources,
+    pub textures_evicted: usize,
+}
+
+/**
+ * Running totals of the buffers models have on the GPU, by whatever
+ * name the demo gives each one. Textures are shared between models, so
+ * they're counted by the [TextureCache] instead.
+ *
+ * Tracking a name again replaces what it had, so a model that gets
+ * swapped for another under the same name is never counted twice.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ResourceTracker {
+    owners: HashMap<String, GpuResources>,
+}
+
+impl ResourceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track(&mut self, owner: &str, resources: GpuResources) {
+        self.owners.insert(owner.to_string(), resources);
+    }
+
+    pub fn track_model(&mut self, owner: &str, model: &Model) {
+        self.track(owner, model.gpu_resources());
+    }
+
+    /// Forgets `owner`, returning what it had
+    pub fn release(&mut self, owner: &str) -> GpuResources {
+        self.owners.remove(owner).unwrap_or_default()
+    }
+
+    /**
+     * Drops `model`, which frees its buffers, then evicts every texture
+     * nothing else is using any more. Anything built from the model,
+     * like its cull batch or placements, still has to be dropped by
+     * whoever made it.
+     */
+    pub fn unload_model(&mut self, owner: &str, model: Model, textures: &mut TextureCache) -> Unloaded {
+        let resources = self.release(owner);
+        drop(model);
+        let unloaded = Unloaded { resources, textures_evicted: textures.evict_unused() };
+        log::info!(
+            "Unloaded {}: {}, {} textures evicted",
+            owner,
+            unloaded.resources,
+            unloaded.textures_evicted,
+        );
+        unloaded
+    }
+
+    pub fn totals(&self) -> GpuResources {
+        let mut totals = GpuResources::default();
+        for &resources in self.owners.values() {
+            totals.add(resources);
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::material_layout::{MaterialLayout, MaterialLayoutDesc};
+    use crate::model::{ModelData, ModelLoadOptions};
+    use std::path::Path;
+
+    #[test]
+    fn reloading_returns_to_baseline() {
+        let (device, _queue) = match crate::thumbnail::test_device() {
+            Some(device) => device,
+            None => return,
+        };
+        let layout = MaterialLayout::new(&device, MaterialLayoutDesc::default());
+        let res = Path::new(env!("CARGO_MANIFEST_DIR")).join("../viewer/res");
+        let options = ModelLoadOptions::default();
+        let quad_data = ModelData::load(res.join("quad.obj"), &options).unwrap();
+        let cube_data = ModelData::load(res.join("cube.obj"), &options).unwrap();
+
+        let mut textures = TextureCache::new();
+        let mut tracker = ResourceTracker::new();
+        // The quad borrows the cube's textures
+        let (quad, _) = Model::from_data(&device, &layout, &quad_data, &mut textures).unwrap();
+        tracker.track_model("quad", &quad);
+        let baseline = tracker.totals();
+        assert_eq!(textures.len(), 2);
+
+        let assets = crate::AssetManager::new(&res);
+        for _ in 0..500 {
+            let (cube, _) = Model::from_data(&device, &layout, &cube_data, &mut textures).unwrap();
+            let resources = cube.gpu_resources();
+            tracker.track_model("cube.obj", &cube);
+            // Opening over it again replaces it
+            tracker.track_model("cube.obj", &cube);
+            assert_eq!(tracker.totals().buffer_bytes, baseline.buffer_bytes + resources.buffer_bytes);
+            assert_eq!(textures.len(), 2);
+
+            let unloaded = assets.unload("cube.obj", cube, &mut tracker, &mut textures);
+            assert_eq!(unloaded, Unloaded { resources, textures_evicted: 0 });
+            assert_eq!(tracker.totals(), baseline);
+            assert_eq!(textures.len(), 2);
+        }
+
+        // Now nothing else has the textures
+        let unloaded = tracker.unload_model("quad", quad, &mut textures);
+        assert_eq!(unloaded, Unloaded { resources: baseline, textures_evicted: 2 });
+        assert_eq!(tracker.totals(), GpuResources::default());
+        assert!(textures.is_empty());
+        assert_eq!(tracker.release("cube.obj"), GpuResources::default());
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use crate::frame::{AmbientGradient, FogDesc};
+use crate::import::ImportTransform;
+use crate::instance::Instance;
+use crate::light_list::PointLight;
+use crate::material_layout::MaterialLayout;
+use crate::model::{MaterialOverrides, Model, ModelLoadOptions};
+use crate::packing::ChannelPacking;
+use crate::seed::Seed;
+use crate::sky::SkyDesc;
+use crate::sun::SunDesc;
+use crate::transparency::TransparencyMode;
+use crate::visibility::VisibilityTrack;
+
+/**
+ * The parts of a scene that live in a `.ron` file rather than in code.
+ * Every field has a default, so an empty `()` file is a valid scene.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SceneDesc {
+    /// Height of the ground plane that blob shadows get projected onto
+    pub ground_height: f32,
+    /// Where every random number in the scene comes from. A seed passed
+    /// on the command line wins over this.
+    pub seed: Option<Seed>,
+    pub sky: SkyDesc,
+    /// Drives the main light and the sky's sun from a time and place.
+    /// Without one they stay where the demo and [SceneDesc::sky] put
+    /// them.
+    pub sun: Option<SunDesc>,
+    /// Extra lights on top of the demo's main light. Each object only
+    /// gets shaded by the few that matter most to it.
+    pub lights: Vec<PointLight>,
+    pub fog: FogDesc,
+    /// Something like `(sky: (0.15, 0.2, 0.3), ground: (0.08, 0.06, 0.04))`.
+    /// Colors left out stay at the flat default.
+    pub ambient: AmbientGradient,
+    /// Tweaks to how particular models get loaded, keyed by file name
+    pub models: HashMap<String, ModelOverrides>,
+    /// Copies of model materials with different params, keyed by the
+    /// name the copy gets
+    pub material_instances: HashMap<String, MaterialInstanceDesc>,
+    /// Big sets of instances to stream in from binary files, see
+    /// [crate::StreamedInstances]
+    pub instance_files: Vec<InstanceFileDesc>,
+    /// Meshes to show and hide as the scene's timeline plays, matched by
+    /// name in every model
+    pub visibility_tracks: Vec<VisibilityTrack>,
+    /// `Sorted` or `WeightedBlended`, for scenes whose glass sorting
+    /// gets wrong. Left out keeps [crate::RenderSettings::transparency].
+    pub transparency: Option<TransparencyMode>,
+    /// Lots of cubes behind a wall, for seeing what occlusion culling
+    /// gets rid of
+    pub cube_city: Option<CubeCityDesc>,
+}
+
+/**
+ * The parts of [ModelLoadOptions] that make sense to change per model
+ * from a scene file. Anything left out keeps whatever the demo asked
+ * for.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelOverrides {
+    pub smoothing_angle: Option<f32>,
+    /// Something like `(up_axis: Z, units: Millimeters)`
+    pub import: Option<ImportTransform>,
+    pub auto_instance: Option<bool>,
+    /// Packed textures by material name, like `--pack-textures` prints
+    pub packing: HashMap<String, ChannelPacking>,
+}
+
+impl ModelOverrides {
+    pub fn apply(&self, options: &mut ModelLoadOptions) {
+        if let Some(angle) = self.smoothing_angle {
+            options.smoothing_angle = Some(angle);
+        }
+        if let Some(import) = self.import {
+            options.import = Some(import);
+        }
+        if let Some(auto_instance) = self.auto_instance {
+            options.auto_instance = auto_instance;
+        }
+        options.packing.extend(self.packing.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+}
+
+/**
+ * A [crate::Material::instantiate] from a scene file, like
+ * `(model: "cube.obj", material: "Material.001", params: (rim: Some(0.5)))`.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialInstanceDesc {
+    /// File name of the model, like the keys of [SceneDesc::models]
+    pub model: String,
+    /// Which of its materials to copy
+    pub material: String,
+    /// Meshes to switch over to the copy. Empty means every mesh that
+    /// used the original.
+    #[serde(default)]
+    pub meshes: Vec<String>,
+    #[serde(default)]
+    pub params: MaterialOverrides,
+}
+
+impl MaterialInstanceDesc {
+    /// Adds the instance to `model` and points the meshes at it
+    pub fn apply(
+        &self,
+        device: &wgpu::Device,
+        layout: &MaterialLayout,
+        name: &str,
+        model: &mut Model,
+    ) -> Result<()> {
+        let parent = model.find_material(&self.material)
+            .with_context(|| format!("{} has no material called {:?} for {}", self.model, self.material, name))?;
+        let params = self.params.apply(model.materials[parent].params);
+        let instance = model.materials[parent].instantiate(device, name, params, layout);
+        let index = model.add_material(instance);
+        for mesh in &mut model.meshes {
+            let wanted = if self.meshes.is_empty() {
+                mesh.material == parent
+            } else {
+                self.meshes.contains(&mesh.name)
+            };
+            if wanted {
+                mesh.material = index;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * An instance file written by `--generate-instances`, or anything else
+ * that writes the same format, like
+ * `(path: "forest.bin", model: "cube.obj")`.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceFileDesc {
+    /// Relative to the scene file
+    pub path: String,
+    /// File name of the model to draw, like the keys of [SceneDesc::models]
+    pub model: String,
+}
+
+/**
+ * A grid of cube towers with a wall of cubes in front, like
+ * `(blocks: 40, wall_height: 10)`. Cubes are the 2 unit cube.obj ones
+ * standing on y = -1. The city goes off towards -z from behind the
+ * wall, so from the usual camera the wall hides most of it.
+ */
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CubeCityDesc {
+    /// Towers along each side
+    pub blocks: u32,
+    /// From one tower to the next
+    pub spacing: f32,
+    /// Cubes in the tallest tower. Each tower's height comes from the
+    /// scene's seed.
+    pub max_floors: u32,
+    /// In cubes, and 0 for no wall
+    pub wall_height: u32,
+    /// Where the wall is. The first row of towers is one spacing behind.
+    pub wall_z: f32,
+}
+
+impl Default for CubeCityDesc {
+    fn default() -> Self {
+        Self {
+            blocks: 20,
+            spacing: 4.0,
+            max_floors: 6,
+            wall_height: 8,
+            wall_z: -6.0,
+        }
+    }
+}
+
+/// What [CubeCityDesc::build] makes
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeCity {
+    pub towers: Vec<Instance>,
+    pub wall: Vec<Instance>,
+}
+
+impl CubeCityDesc {
+    pub fn build(&self, seed: Seed) -> CubeCity {
+        let mut rng = seed.derive("cube_city").rng();
+        let half_width = (self.blocks.max(1) - 1) as f32 * self.spacing / 2.0;
+        let mut towers = Vec::new();
+        for row in 0..self.blocks {
+            for column in 0..self.blocks {
+                let x = column as f32 * self.spacing - half_width;
+                let z = self.wall_z - (row + 1) as f32 * self.spacing;
+                let floors = 1 + (rng.next_u64() % self.max_floors.max(1) as u64) as u32;
+                towers.extend((0..floors).map(|floor| Instance::new((x, floor as f32 * 2.0, z))));
+            }
+        }
+
+        // Wide enough to cover the city, one cube thick
+        let wall_cubes = (half_width / 2.0).ceil() as i32 + 1;
+        let mut wall = Vec::new();
+        for floor in 0..self.wall_height {
+            for i in -wall_cubes..=wall_cubes {
+                wall.push(Instance::new((i as f32 * 2.0, floor as f32 * 2.0, self.wall_z)));
+            }
+        }
+        CubeCity { towers, wall }
+    }
+}
+
+impl Default for SceneDesc {
+    fn default() -> Self {
+        Self {
+            ground_height: 0.0,
+            seed: None,
+            sky: SkyDesc::default(),
+            sun: None,
+            lights: Vec::new(),
+            fog: FogDesc::default(),
+            ambient: AmbientGradient::default(),
+            models: HashMap::new(),
+            material_instances: HashMap::new(),
+            instance_files: Vec::new(),
+            visibility_tracks: Vec::new(),
+            transparency: None,
+            cube_city: None,
+        }
+    }
+}
+
+impl SceneDesc {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let src = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Unable to read scene {}", path.as_ref().display()))?;
+        Self::parse(&src)
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        Ok(ron::de::from_str(src)?)
+    }
+
+    /// `options` with any overrides for the model at `path` applied
+    pub fn model_options(&self, path: &str, mut options: ModelLoadOptions) -> ModelLoadOptions {
+        let overrides = Path::new(path)
+            .file_name()
+            .and_then(|name| self.models.get(name.to_string_lossy().as_ref()));
+        if let Some(overrides) = overrides {
+            overrides.apply(&mut options);
+        }
+        options
+    }
+
+    /// The instances declared for the model at `path`, by name
+    pub fn material_instances_for<'a>(&'a self, path: &str) -> impl Iterator<Item = (&'a str, &'a MaterialInstanceDesc)> + 'a {
+        let file_name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.material_instances.iter()
+            .filter(move |(_, desc)| desc.model == file_name)
+            .map(|(name, desc)| (name.as_str(), desc))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn material_instances_parse_and_override_params() {
+        let scene = SceneDesc::parse(r#"(
+            material_instances: {
+                "shiny_brick": (
+                    model: "cube.obj",
+                    material: "Material.001",
+                    params: (shininess: Some(128.0), rim: Some(0.5)),
+                ),
+                "leaf": (
+                    model: "quad.obj",
+                    material: "Leaf",
+                    params: (two_sided_lighting: Some(true), translucency: Some(0.6)),
+                ),
+            },
+        )"#).unwrap();
+
+        let found = scene.material_instances_for("res/cube.obj").collect::<Vec<_>>();
+        assert_eq!(found.len(), 1);
+        let (name, desc) = found[0];
+        assert_eq!(name, "shiny_brick");
+        assert!(desc.meshes.is_empty());
+        assert_eq!(scene.material_instances_for("torus.stl").count(), 0);
+
+        let params = desc.params.apply(Default::default());
+        assert_eq!(params.shininess, 128.0);
+        assert_eq!(params.rim, 0.5);
+        // Left alone
+        assert_eq!(params.tint, [1.0; 4]);
+        assert_eq!(params.two_sided_lighting, 0);
+
+        let (_, leaf) = scene.material_instances_for("quad.obj").next().unwrap();
+        let params = leaf.params.apply(Default::default());
+        assert_eq!((params.two_sided_lighting, params.translucency), (1, 0.6));
+        // The shader reads it as a std140 block
+        assert_eq!(std::mem::size_of_val(&params) % 16, 0);
+    }
+
+    #[test]
+    fn cube_cities_hide_behind_their_wall() {
+        let desc = CubeCityDesc { blocks: 4, spacing: 4.0, max_floors: 3, wall_height: 5, wall_z: -6.0 };
+        let city = desc.build(Seed::DEFAULT);
+        // At least a floor and at most three for each of the 16 towers
+        assert!((16..=48).contains(&city.towers.len()), "{}", city.towers.len());
+        assert!(city.towers.iter().all(|t| t.position.z < desc.wall_z && t.position.y <= 4.0));
+        let wall_max_x = city.wall.iter().map(|c| c.position.x).fold(0.0, f32::max);
+        let city_max_x = city.towers.iter().map(|t| t.position.x).fold(0.0, f32::max);
+        assert!(wall_max_x >= city_max_x);
+        assert_eq!(city.wall.len() % 5, 0);
+
+        // Same seed, same city
+        assert_eq!(desc.build(Seed::DEFAULT), city);
+        assert_ne!(desc.build(Seed(7)).towers, city.towers);
+    }
+}
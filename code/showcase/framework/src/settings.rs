@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/**
+ * Where demos keep things that should survive a restart. Set
+ * `LEARN_WGPU_SETTINGS_DIR` to put them somewhere else.
+ */
+pub fn settings_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("LEARN_WGPU_SETTINGS_DIR") {
+        return PathBuf::from(dir);
+    }
+    dirs::config_dir()
+        .map(|dir| dir.join("learn-wgpu"))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/**
+ * Where the window was and how big it was, so it can be put back
+ * there next time. See [crate::Demo::window_geometry].
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    pub width: u32,
+    pub height: u32,
+    /// The outer position. Some platforms (Wayland) never tell us this.
+    #[serde(default)]
+    pub position: Option<(i32, i32)>,
+}
+
+/**
+ * Knobs that change how a frame gets rendered. Passes read these
+ * instead of each demo keeping its own flags.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    /// Real shadow mapping. When this is off (or the demo doesn't have
+    /// shadow mapping yet) [crate::BlobShadows] are drawn instead.
+    pub shadows: bool,
+    /// Cull instances against last frame's depth with
+    /// [crate::HiZCuller] and draw the survivors indirectly
+    pub occlusion_culling: bool,
+    pub pacing: crate::FramePacing,
+    /// Colors for selection, markers and the like
+    pub palette: crate::PaletteScheme,
+    /// Shows the final image the way someone with this kind of color
+    /// blindness would see it
+    pub simulate_color_blindness: Option<crate::ColorBlindness>,
+    /// Bytes of texture memory to stay under, see
+    /// [crate::TextureBudget]. `None` uses
+    /// [crate::DeviceCapabilities::texture_budget].
+    pub texture_budget: Option<u64>,
+    /// How glass gets blended. Scenes can pick, see
+    /// [crate::SceneDesc::transparency].
+    pub transparency: crate::TransparencyMode,
+    /// Short shadows where things touch, see [crate::ContactShadows]
+    pub contact_shadows: crate::ContactShadowSettings,
+    pub motion_blur: crate::MotionBlurSettings,
+    /// What the swap chain was made with, which every pipeline that
+    /// draws to it uses for its color state. This belongs to the
+    /// display, so it's never saved.
+    #[serde(skip, default = "default_surface_format")]
+    pub surface_format: wgpu::TextureFormat,
+}
+
+fn default_surface_format() -> wgpu::TextureFormat {
+    crate::SURFACE_FORMAT_PREFERENCE[0]
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            shadows: false,
+            occlusion_culling: false,
+            pacing: Default::default(),
+            palette: Default::default(),
+            simulate_color_blindness: None,
+            texture_budget: None,
+            transparency: Default::default(),
+            contact_shadows: Default::default(),
+            motion_blur: Default::default(),
+            surface_format: default_surface_format(),
+        }
+    }
+}
+
+impl RenderSettings {
+    /// The defaults, with the format `display` negotiated
+    pub fn for_display(display: &crate::Display) -> Self {
+        Self {
+            surface_format: display.sc_desc.format,
+            ..Default::default()
+        }
+    }
+
+    pub fn use_blob_shadows(&self) -> bool {
+        !self.shadows
+    }
+
+    pub fn texture_budget(&self, caps: &crate::DeviceCapabilities) -> crate::TextureBudget {
+        crate::TextureBudget::new(self.texture_budget.unwrap_or_else(|| caps.texture_budget()))
+    }
+
+    pub fn palette(&self) -> crate::Palette {
+        crate::Palette::new(self.palette)
+    }
+}
@@ -0,0 +1,19 @@
+//! Numbers both the shaders and the Rust side need to agree on. The
+//! build scripts pull this file in with `#[path]` and inject
+//! [SHADER_DEFINES] into every shader, so it can only use std.
+
+/// How many lights fit in the [crate::LightList] buffer
+pub const MAX_LIGHTS: usize = 16;
+/// How many lights each object gets shaded with
+pub const LIGHTS_PER_OBJECT: usize = 4;
+/// Fragments get counted per square of this many pixels, see
+/// [crate::OverdrawCounter]
+pub const OVERDRAW_TILE_SIZE: u32 = 32;
+
+/// Every shader gets these as `#define`s, for anyone compiling shaders
+/// with a [crate::ShaderPreprocessor] at runtime too
+pub const SHADER_DEFINES: &[(&str, usize)] = &[
+    ("MAX_LIGHTS", MAX_LIGHTS),
+    ("LIGHTS_PER_OBJECT", LIGHTS_PER_OBJECT),
+    ("OVERDRAW_TILE_SIZE", OVERDRAW_TILE_SIZE as usize),
+];
@@ -0,0 +1,339 @@
+use anyhow::*;
+use cgmath::*;
+use crate::capture::read_pixels;
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/**
+ * A matrix the way GLSL's `mat4` wants it: four columns of four. This
+ * is what every matrix in a uniform or vertex buffer should be stored
+ * as, and [to_shader_bytes] is the only thing that should make them.
+ *
+ * cgmath is column major too, so nothing gets shuffled. The point is
+ * having one place to check matrices on the way up. Snippets ported
+ * from row major libraries (or from code that calls `transpose()` to
+ * make its shaders happy) tend to upload something that's almost
+ * right, which renders skewed rather than not at all.
+ */
+pub type ShaderMatrix = [[f32; 4]; 4];
+
+/// What a matrix is for, which decides what [check_matrix] expects of it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatrixKind {
+    /// World to camera. Only rotation and translation, no mirroring.
+    View,
+    /// Perspective or orthographic, as from [crate::Projection]
+    Projection,
+    /// Object to world, such as an instance. Anything affine, mirrors
+    /// included, as importers flip handedness with them. Also used for
+    /// color matrices.
+    Model,
+    /// Products and inverses of the others, like the view projection.
+    /// These only have to be invertible.
+    Combined,
+}
+
+/// What [check_matrix] found wrong
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatrixProblem {
+    NotFinite,
+    Singular,
+    /// Something other than 0, 0, 0, 1 along the bottom row. This is
+    /// where a transposed translation ends up.
+    NotAffine,
+    /// The 3x3 part of a view matrix scales or shears
+    NotOrthonormal,
+    /// A view matrix that flips handedness
+    Mirrored,
+    /// A bottom row that's neither 0, 0, -1, 0 (perspective) nor
+    /// 0, 0, 0, 1 (orthographic)
+    NotProjection,
+}
+
+impl std::fmt::Display for MatrixProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            MatrixProblem::NotFinite => "has NaNs or infinities in it",
+            MatrixProblem::Singular => "can't be inverted",
+            MatrixProblem::NotAffine => "has a bottom row other than 0, 0, 0, 1, so it may be transposed",
+            MatrixProblem::NotOrthonormal => "scales or shears",
+            MatrixProblem::Mirrored => "flips handedness",
+            MatrixProblem::NotProjection => "isn't a perspective or orthographic projection, so it may be transposed",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// How far off floats can be and still count
+const EPSILON: f32 = 1e-3;
+
+fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() <= EPSILON * a.abs().max(b.abs()).max(1.0)
+}
+
+/// The bottom row, which cgmath keeps as the w of each column
+fn bottom_row(m: &Matrix4<f32>) -> Vector4<f32> {
+    m.row(3)
+}
+
+fn is_row(row: Vector4<f32>, expected: [f32; 4]) -> bool {
+    (0..4).all(|i| close(row[i], expected[i]))
+}
+
+/// Checks `m` looks like a `kind` of matrix. [to_shader_bytes] does
+/// this in debug builds.
+pub fn check_matrix(m: &Matrix4<f32>, kind: MatrixKind) -> Result<(), MatrixProblem> {
+    let m_array: &[f32; 16] = m.as_ref();
+    if m_array.iter().any(|v| !v.is_finite()) {
+        return Err(MatrixProblem::NotFinite);
+    }
+    // Not cgmath's invert(), which gives up on the tiny determinants a
+    // big orthographic view has. Model matrices can squash things flat
+    // to hide them.
+    if kind != MatrixKind::Model && m.determinant() == 0.0 {
+        return Err(MatrixProblem::Singular);
+    }
+    match kind {
+        MatrixKind::View => {
+            if !is_row(bottom_row(m), [0.0, 0.0, 0.0, 1.0]) {
+                return Err(MatrixProblem::NotAffine);
+            }
+            let axes = [m.x.truncate(), m.y.truncate(), m.z.truncate()];
+            for (i, a) in axes.iter().enumerate() {
+                if !close(a.magnitude2(), 1.0) {
+                    return Err(MatrixProblem::NotOrthonormal);
+                }
+                for b in &axes[i + 1..] {
+                    if a.dot(*b).abs() > EPSILON {
+                        return Err(MatrixProblem::NotOrthonormal);
+                    }
+                }
+            }
+            if Matrix3::from_cols(axes[0], axes[1], axes[2]).determinant() < 0.0 {
+                return Err(MatrixProblem::Mirrored);
+            }
+        }
+        MatrixKind::Projection => {
+            // [3][3] tells the two apart: 0 for perspective as w comes
+            // from -z, 1 for orthographic
+            let row = bottom_row(m);
+            if !is_row(row, [0.0, 0.0, -1.0, 0.0]) && !is_row(row, [0.0, 0.0, 0.0, 1.0]) {
+                return Err(MatrixProblem::NotProjection);
+            }
+        }
+        MatrixKind::Model => {
+            if !is_row(bottom_row(m), [0.0, 0.0, 0.0, 1.0]) {
+                return Err(MatrixProblem::NotAffine);
+            }
+        }
+        MatrixKind::Combined => {}
+    }
+    Ok(())
+}
+
+/// Panics in debug builds when `m` isn't a `kind` of matrix. For
+/// matrices that only get used to make others, like the view and
+/// projection that go into the view projection.
+pub fn debug_check_matrix(m: &Matrix4<f32>, kind: MatrixKind) {
+    if cfg!(debug_assertions) {
+        if let Err(problem) = check_matrix(m, kind) {
+            panic!("This {:?} matrix {}: {:?}", kind, problem, m);
+        }
+    }
+}
+
+/// Every matrix headed for a shader goes through here. See
+/// [ShaderMatrix].
+pub fn to_shader_bytes(m: &Matrix4<f32>, kind: MatrixKind) -> ShaderMatrix {
+    debug_check_matrix(m, kind);
+    (*m).into()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quadrant {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// The size of the reference image
+const REFERENCE_SIZE: u32 = 32;
+
+/**
+ * Which quadrant the lit pixels of an RGBA8 image are in, with the
+ * first row at the top. None when nothing's lit, or when it's spread
+ * over more than one quadrant.
+ */
+pub fn lit_quadrant(pixels: &[u8], width: u32, height: u32) -> Option<Quadrant> {
+    let mut found = None;
+    for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+        if pixel[0] < 128 {
+            continue;
+        }
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let quadrant = match (x < width / 2, y < height / 2) {
+            (true, true) => Quadrant::TopLeft,
+            (false, true) => Quadrant::TopRight,
+            (true, false) => Quadrant::BottomLeft,
+            (false, false) => Quadrant::BottomRight,
+        };
+        match found {
+            None => found = Some(quadrant),
+            Some(q) if q != quadrant => return None,
+            Some(_) => {}
+        }
+    }
+    found
+}
+
+/// Turning the reference triangle a quarter turn anticlockwise takes
+/// it from the top right quadrant to the top left. Transposed, it
+/// turns the other way and ends up bottom right.
+fn reference_matrix() -> Matrix4<f32> {
+    Matrix4::from_angle_z(Deg(90.0))
+}
+
+/**
+ * Draws a lopsided triangle offscreen with [reference_matrix] and
+ * checks it landed where it should. Catches matrices getting
+ * transposed on the way to the shader, and backends that flip y,
+ * before anyone sees a garbled frame. [crate::run] calls this at
+ * startup.
+ */
+pub fn check_matrix_conventions(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<()> {
+    let target = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+        label: Some("check_matrix_conventions::target"),
+        size: wgpu::Extent3d { width: REFERENCE_SIZE, height: REFERENCE_SIZE, depth: 1 },
+        array_layer_count: 1,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+    });
+    let matrix = to_shader_bytes(&reference_matrix(), MatrixKind::Model);
+    let buffer = device.create_buffer_with_data(
+        bytemuck::cast_slice(&[matrix]),
+        wgpu::BufferUsage::UNIFORM,
+    );
+    let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        bindings: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX,
+                ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+            },
+        ],
+        label: Some("check_matrix_conventions::layout"),
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &layout,
+        bindings: &[
+            wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &buffer,
+                    range: 0..std::mem::size_of::<ShaderMatrix>() as wgpu::BufferAddress,
+                },
+            },
+        ],
+        label: Some("check_matrix_conventions::bind_group"),
+    });
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        bind_group_layouts: &[&layout],
+    });
+    let pipeline = RenderPipelineBuilder::new()
+        .layout(&pipeline_layout)
+        .color_solid(wgpu::TextureFormat::Rgba8Unorm)
+        .vertex_shader(include_bytes!("shaders/reference_triangle.vert.spv"))
+        .fragment_shader(include_bytes!("shaders/reference_triangle.frag.spv"))
+        .build(device)?;
+
+    let mut encoder = device.create_command_encoder(
+        &wgpu::CommandEncoderDescriptor { label: Some("check_matrix_conventions::encoder") }
+    );
+    {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &target.view,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Clear,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+    queue.submit(&[encoder.finish()]);
+
+    let pixels = read_pixels(device, queue, &target.texture, REFERENCE_SIZE, REFERENCE_SIZE, 4)?;
+    let landed = lit_quadrant(&pixels, REFERENCE_SIZE, REFERENCE_SIZE);
+    let why = match landed {
+        Some(Quadrant::TopLeft) => return Ok(()),
+        Some(Quadrant::BottomRight) => "matrices are being transposed on the way to the shader",
+        Some(Quadrant::BottomLeft) => "this backend flips y",
+        Some(Quadrant::TopRight) => "the matrix didn't get applied",
+        None => "the triangle got lost",
+    };
+    bail!(
+        "Matrix convention self test failed: the reference triangle landed in {:?} rather than {:?}, \
+        so {}",
+        landed,
+        Quadrant::TopLeft,
+        why,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::{OrbitCamera, Projection};
+
+    #[test]
+    fn transposed_matrices_get_caught() {
+        let camera = OrbitCamera::new((1.0, 2.0, 3.0), 5.0, Deg(30.0), Deg(20.0));
+        let view = camera.calc_matrix();
+        let model = Matrix4::from_translation(vec3(4.0, 5.0, 6.0)) * Matrix4::from_angle_y(Deg(40.0));
+        let perspective = Projection::new(800, 600, Deg(45.0), 0.1, 100.0).calc_matrix();
+
+        assert_eq!(check_matrix(&view, MatrixKind::View), Ok(()));
+        assert_eq!(check_matrix(&model, MatrixKind::Model), Ok(()));
+        assert_eq!(check_matrix(&perspective, MatrixKind::Projection), Ok(()));
+        assert_eq!(check_matrix(&(perspective * view), MatrixKind::Combined), Ok(()));
+
+        assert_eq!(check_matrix(&view.transpose(), MatrixKind::View), Err(MatrixProblem::NotAffine));
+        assert_eq!(check_matrix(&model.transpose(), MatrixKind::Model), Err(MatrixProblem::NotAffine));
+        assert_eq!(check_matrix(&perspective.transpose(), MatrixKind::Projection), Err(MatrixProblem::NotProjection));
+        assert_eq!(check_matrix(&(view * Matrix4::from_scale(2.0)), MatrixKind::View), Err(MatrixProblem::NotOrthonormal));
+        let mirror = Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0);
+        assert_eq!(check_matrix(&(mirror * view), MatrixKind::View), Err(MatrixProblem::Mirrored));
+        assert_eq!(check_matrix(&Matrix4::from_scale(0.0), MatrixKind::Combined), Err(MatrixProblem::Singular));
+    }
+
+    #[test]
+    fn reference_triangle_lands_top_left() {
+        // Where the shader puts the corners, before the matrix
+        let corners = [vec2(0.4, 0.1), vec2(0.8, 0.1), vec2(0.4, 0.3)];
+        let in_top_left = |m: Matrix4<f32>| corners.iter().all(|c| {
+            let p = m * c.extend(0.0).extend(1.0);
+            p.x < 0.0 && p.y > 0.0
+        });
+        assert!(in_top_left(reference_matrix()));
+        assert!(!in_top_left(reference_matrix().transpose()));
+
+        let mut pixels = vec![0u8; 4 * 4 * 4];
+        assert_eq!(lit_quadrant(&pixels, 4, 4), None);
+        // Second row, first column
+        pixels[4 * 4] = 255;
+        assert_eq!(lit_quadrant(&pixels, 4, 4), Some(Quadrant::TopLeft));
+        // Last pixel
+        pixels[4 * 15] = 255;
+        assert_eq!(lit_quadrant(&pixels, 4, 4), None);
+    }
+}
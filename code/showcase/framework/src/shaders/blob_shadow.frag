@@ -0,0 +1,13 @@
+#version 450
+
+layout(location=0) in vec2 v_offset;
+layout(location=1) in float v_alpha;
+
+layout(location=0) out vec4 f_color;
+
+void main() {
+    // Dark in the middle, fading out to nothing at the edge
+    float d = length(v_offset);
+    float falloff = 1.0 - smoothstep(0.0, 1.0, d);
+    f_color = vec4(0.0, 0.0, 0.0, v_alpha * falloff * falloff);
+}
@@ -0,0 +1,34 @@
+#version 450
+
+// Per blob
+layout(location=0) in vec4 a_center_radius;
+layout(location=1) in float a_alpha;
+
+layout(location=0) out vec2 v_offset;
+layout(location=1) out float v_alpha;
+
+layout(set=0, binding=0) 
+uniform Uniforms {
+    vec4 u_view_position; 
+    mat4 u_view_proj;
+};
+
+// Two triangles making a quad on the xz plane
+const vec2 CORNERS[6] = vec2[6](
+    vec2(-1.0, -1.0),
+    vec2( 1.0, -1.0),
+    vec2( 1.0,  1.0),
+    vec2(-1.0, -1.0),
+    vec2( 1.0,  1.0),
+    vec2(-1.0,  1.0)
+);
+
+void main() {
+    v_offset = CORNERS[gl_VertexIndex];
+    v_alpha = a_alpha;
+
+    vec3 center = a_center_radius.xyz;
+    float radius = a_center_radius.w;
+    vec3 position = center + vec3(v_offset.x, 0.0, v_offset.y) * radius;
+    gl_Position = u_view_proj * vec4(position, 1.0);
+}
@@ -0,0 +1,32 @@
+#version 450
+
+// Has to match MAX_BLUR_RADIUS in blur.rs
+#define MAX_RADIUS 16
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_src;
+layout(set=0, binding=1) uniform sampler s_src;
+layout(set=0, binding=2)
+uniform Blur {
+    // xy is the step between taps, z is the radius
+    ivec4 u_direction;
+    // Center first, then one side. Only x is used.
+    vec4 u_weights[MAX_RADIUS + 1];
+};
+
+vec4 fetch(ivec2 pixel, ivec2 last) {
+    return texelFetch(sampler2D(t_src, s_src), clamp(pixel, ivec2(0), last), 0);
+}
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    ivec2 last = textureSize(sampler2D(t_src, s_src), 0) - 1;
+    // This has to match blur_cpu() in blur.rs
+    vec4 sum = fetch(pixel, last) * u_weights[0].x;
+    for (int i = 1; i <= u_direction.z; i++) {
+        ivec2 step = u_direction.xy * i;
+        sum += (fetch(pixel + step, last) + fetch(pixel - step, last)) * u_weights[i].x;
+    }
+    f_color = sum;
+}
@@ -0,0 +1,68 @@
+// Screen space contact shadows, see ContactShadows in
+// contact_shadow.rs. Include with #include "common/contact_shadow.glsl"
+// after common/noise.glsl, and after declaring t_contact_depth,
+// s_contact_depth and this block, which matches ContactData:
+//
+// uniform ContactShadow {
+//     mat4 u_contact_view_proj;
+//     mat4 u_contact_inv_view_proj;
+//     vec4 u_contact_eye;
+//     vec4 u_contact_params;
+// };
+
+// How much of the light at `light_position` reaches `position`, as far
+// as the prepass depth can tell. 1 is all of it. `pixel` and `frame`
+// jitter where the steps land, which turns the banding between them
+// into grain.
+float contact_shadow(vec3 position, vec3 light_position, vec2 pixel, float frame) {
+    if (u_contact_eye.w == 0.0) {
+        return 1.0;
+    }
+    int steps = int(u_contact_params.x);
+    float thickness = u_contact_params.z;
+    float edge_fade = u_contact_params.w;
+
+    vec3 to_light = light_position - position;
+    float light_distance = length(to_light);
+    vec3 dir = to_light / max(light_distance, 1e-4);
+    float ray_length = min(u_contact_params.y, light_distance);
+    float step_length = ray_length / float(steps);
+    float jitter = interleaved_gradient_noise(pixel, frame);
+
+    for (int i = 0; i < steps; i++) {
+        float t = (float(i) + jitter) * step_length;
+        vec3 sample_position = position + dir * t;
+        vec4 clip = u_contact_view_proj * vec4(sample_position, 1.0);
+        // Behind the camera, so nothing further along is on screen
+        if (clip.w <= 0.0) {
+            break;
+        }
+        vec3 ndc = clip.xyz / clip.w;
+        vec2 uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        // Off the edge of the depth buffer, there's nothing to compare
+        // against from here on
+        if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || ndc.z > 1.0) {
+            break;
+        }
+        float depth = textureLod(sampler2D(t_contact_depth, s_contact_depth), uv, 0.0).r;
+        if (depth >= 1.0) {
+            continue;
+        }
+
+        // Compared as distances from the eye rather than depths, so the
+        // thickness is in world units however far away this is
+        vec4 occluder = u_contact_inv_view_proj * vec4(ndc.xy, depth, 1.0);
+        occluder.xyz /= occluder.w;
+        float behind = distance(u_contact_eye.xyz, sample_position) - distance(u_contact_eye.xyz, occluder.xyz);
+        if (behind > 1e-3 && behind < thickness) {
+            // Fade out towards the edges of the screen, where whatever is
+            // casting the shadow could be just out of view, and towards
+            // the end of the ray, so the shadow stays a contact line
+            vec2 edge = min(uv, 1.0 - uv);
+            float fade = smoothstep(0.0, edge_fade, min(edge.x, edge.y));
+            fade *= 1.0 - t / ray_length;
+            return 1.0 - fade;
+        }
+    }
+    return 1.0;
+}
@@ -0,0 +1,20 @@
+// Shading and weighting for TransparencyPass. Include with
+// #include "common/glass.glsl".
+
+// Glass doesn't get the scene's lights, just a light from above and
+// more opacity towards the edges, which is enough to read the shape
+vec4 glass_shade(vec3 normal, vec3 position, vec3 view_position, vec4 tint) {
+    vec3 view_dir = normalize(view_position - position);
+    vec3 light_dir = normalize(vec3(0.3, 1.0, 0.2));
+    float diffuse = 0.4 + 0.6 * max(dot(normal, light_dir), 0.0);
+    float fresnel = pow(1.0 - max(dot(normal, view_dir), 0.0), 5.0);
+    float alpha = mix(tint.a, 1.0, fresnel * 0.6);
+    return vec4(tint.rgb * diffuse, alpha);
+}
+
+// Matches wboit_weight in transparency.rs. McGuire and Bavoil (2013),
+// equation 7, so nearer fragments count for more.
+float wboit_weight(float distance, float alpha) {
+    float d = distance;
+    return alpha * clamp(10.0 / (1e-5 + pow(d / 5.0, 2.0) + pow(d / 200.0, 6.0)), 1e-2, 3e3);
+}
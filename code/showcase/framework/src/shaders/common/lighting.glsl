@@ -0,0 +1,58 @@
+// Lighting shared between shaders. Include with
+// #include "common/lighting.glsl". MAX_LIGHTS and LIGHTS_PER_OBJECT
+// come from shader_defines.rs.
+
+// Matches PointLightRaw in light_list.rs
+struct PointLight {
+    // w is the intensity
+    vec4 position;
+    vec4 color;
+};
+
+// Matches AmbientGradient::at in frame.rs. Straight up gets sky, the
+// sides get equator and straight down gets ground.
+vec3 gradient_ambient(vec3 normal, vec3 sky, vec3 equator, vec3 ground) {
+    float y = normal.y;
+    return y >= 0.0 ? mix(equator, sky, y) : mix(equator, ground, -y);
+}
+
+// Blinn-Phong diffuse in x and specular in y for one light, without
+// any attenuation
+vec2 blinn_phong_terms(vec3 normal, vec3 view_dir, vec3 light_dir, float shininess) {
+    vec3 half_dir = normalize(view_dir + light_dir);
+    float diffuse = max(dot(normal, light_dir), 0.0);
+    float specular = pow(max(dot(normal, half_dir), 0.0), shininess);
+    return vec2(diffuse, specular);
+}
+
+// The main light's highlights take on the object's color
+vec3 blinn_phong_with(vec3 normal, vec3 view_dir, vec3 light_dir, vec3 light_color, vec3 object_color, float shininess) {
+    vec2 terms = blinn_phong_terms(normal, view_dir, light_dir, shininess);
+    return (terms.x + terms.y) * light_color * object_color;
+}
+
+vec3 blinn_phong(vec3 normal, vec3 view_dir, vec3 light_dir, vec3 light_color, vec3 object_color) {
+    return blinn_phong_with(normal, view_dir, light_dir, light_color, object_color, 32.0);
+}
+
+// Light coming through a thin surface from behind it. `normal` faces
+// the camera, so this is strongest with the light straight behind. It
+// wraps a little past edge on as well, which makes silhouettes glow.
+vec3 translucency_with(vec3 normal, vec3 light_dir, vec3 light_color, vec3 object_color, float amount) {
+    const float wrap = 0.5;
+    float through = max((dot(-normal, light_dir) + wrap) / (1.0 + wrap), 0.0);
+    return through * amount * object_color * light_color;
+}
+
+// Point lights fall off with distance squared, and their highlights
+// are the light's own color
+vec3 point_light_with(PointLight light, vec3 position, vec3 normal, vec3 view_dir, vec3 object_color, float shininess) {
+    vec3 to_light = light.position.xyz - position;
+    float attenuation = light.position.w / max(dot(to_light, to_light), 0.01);
+    vec2 terms = blinn_phong_terms(normal, view_dir, normalize(to_light), shininess);
+    return (terms.x * object_color + terms.y) * light.color.rgb * attenuation;
+}
+
+vec3 point_light(PointLight light, vec3 position, vec3 normal, vec3 view_dir, vec3 object_color) {
+    return point_light_with(light, position, normal, view_dir, object_color, 32.0);
+}
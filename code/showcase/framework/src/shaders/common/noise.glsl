@@ -0,0 +1,22 @@
+// Per pixel noise shared between shaders. Include with
+// #include "common/noise.glsl".
+
+// Jimenez's interleaved gradient noise, between 0 and 1. Neighboring
+// pixels get very different values, so patterns it rotates turn into
+// fine grain instead of bands. `frame` moves it along 5.588238 pixels a
+// frame, which is what makes it average out over a few frames. Pass
+// the per-frame counter (u_time.y), or the accumulation index when
+// frames are being accumulated.
+float interleaved_gradient_noise(vec2 pixel, float frame) {
+    pixel += 5.588238 * mod(frame, 64.0);
+    return fract(52.9829189 * fract(0.06711056 * pixel.x + 0.00583715 * pixel.y));
+}
+
+// A rotation by a whole turn times `noise`, for turning a sampling
+// kernel (like Poisson disk PCF taps) differently for every pixel
+mat2 noise_rotation(float noise) {
+    float angle = noise * 6.28318530718;
+    float s = sin(angle);
+    float c = cos(angle);
+    return mat2(c, s, -s, c);
+}
@@ -0,0 +1,6 @@
+#version 450
+
+// Passes that only want depth, like the prepass in contact_shadow.rs.
+// wgpu wants a fragment shader even when there's nothing to write.
+void main() {
+}
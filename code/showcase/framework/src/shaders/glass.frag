@@ -0,0 +1,24 @@
+#version 450
+
+// Sorted transparency, blended straight over the scene
+layout(location=0) in vec3 v_position;
+layout(location=1) in vec3 v_normal;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+layout(set=2, binding=0)
+uniform Tint {
+    vec4 u_tint;
+};
+
+#include "common/glass.glsl"
+
+void main() {
+    f_color = glass_shade(normalize(v_normal), v_position, u_view_position.xyz, u_tint);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+// Tinted see-through models, see TransparencyPass in transparency.rs
+layout(location=0) in vec3 a_position;
+layout(location=2) in vec3 a_normal;
+layout(location=5) in mat4 a_model;
+
+layout(location=0) out vec3 v_position;
+layout(location=1) out vec3 v_normal;
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+void main() {
+    mat3 normal_matrix = mat3(transpose(inverse(a_model)));
+    v_normal = normalize(normal_matrix * a_normal);
+    vec4 world_position = a_model * vec4(a_position, 1.0);
+    v_position = world_position.xyz;
+    gl_Position = u_view_proj * world_position;
+}
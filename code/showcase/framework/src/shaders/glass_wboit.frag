@@ -0,0 +1,31 @@
+#version 450
+
+// Weighted blended transparency. Both targets get added up, whatever
+// order the fragments come in, and wboit_composite.frag resolves them.
+layout(location=0) in vec3 v_position;
+layout(location=1) in vec3 v_normal;
+
+layout(location=0) out vec4 f_accum;
+// Blended with (0, 1 - src), so this ends up as the product of
+// (1 - alpha) over every fragment
+layout(location=1) out float f_revealage;
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+layout(set=2, binding=0)
+uniform Tint {
+    vec4 u_tint;
+};
+
+#include "common/glass.glsl"
+
+void main() {
+    vec4 color = glass_shade(normalize(v_normal), v_position, u_view_position.xyz, u_tint);
+    float weight = wboit_weight(distance(v_position, u_view_position.xyz), color.a);
+    f_accum = vec4(color.rgb * color.a, color.a) * weight;
+    f_revealage = color.a;
+}
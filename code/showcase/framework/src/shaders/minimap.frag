@@ -0,0 +1,70 @@
+#version 450
+
+layout(location=0) in vec2 v_uv;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_map;
+layout(set=0, binding=1) uniform sampler s_map;
+layout(set=0, binding=2)
+uniform Minimap {
+    vec4 u_rect;
+    // xy is the camera on the map, zw the way it's facing
+    vec4 u_marker;
+    // rgb is the color, w the width in map units
+    vec4 u_border;
+    // rgb is the color of the arrow, w is 1 when the output isn't sRGB
+    // so we have to encode
+    vec4 u_marker_color;
+};
+// The map blurred, see Minimap::end_scene_pass
+layout(set=0, binding=3) uniform texture2D t_glow;
+
+// The same encoding as the tonemap pass
+vec3 linear_to_srgb(vec3 c) {
+    vec3 low = c * 12.92;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(c, vec3(0.0031308)));
+}
+
+// Which side of the line through a and b that p is on
+float side(vec2 p, vec2 a, vec2 b) {
+    return (p.x - b.x) * (a.y - b.y) - (a.x - b.x) * (p.y - b.y);
+}
+
+bool in_triangle(vec2 p, vec2 a, vec2 b, vec2 c) {
+    float d0 = side(p, a, b);
+    float d1 = side(p, b, c);
+    float d2 = side(p, c, a);
+    bool has_negative = d0 < 0.0 || d1 < 0.0 || d2 < 0.0;
+    bool has_positive = d0 > 0.0 || d1 > 0.0 || d2 > 0.0;
+    return !(has_negative && has_positive);
+}
+
+void main() {
+    vec3 hdr = texture(sampler2D(t_map, s_map), v_uv).rgb;
+    // The same Reinhard as the tonemap pass
+    vec3 color = hdr / (1.0 + hdr);
+
+    // An arrow pointing the way the camera faces. Looking straight
+    // down leaves no direction, so it points up the map.
+    vec2 forward = length(u_marker.zw) > 1e-4 ? normalize(u_marker.zw) : vec2(0.0, -1.0);
+    vec2 across = vec2(-forward.y, forward.x);
+    float size = 0.03;
+    vec2 tip = u_marker.xy + forward * size * 1.5;
+    vec2 left = u_marker.xy - forward * size + across * size;
+    vec2 right = u_marker.xy - forward * size - across * size;
+    if (in_triangle(v_uv, tip, left, right)) {
+        color = u_marker_color.rgb;
+    }
+
+    vec2 edge = min(v_uv, 1.0 - v_uv);
+    if (min(edge.x, edge.y) < u_border.w) {
+        vec3 glow = texture(sampler2D(t_glow, s_map), v_uv).rgb;
+        color = mix(u_border.rgb, glow / (1.0 + glow), 0.5);
+    }
+    if (u_marker_color.w != 0.0) {
+        color = linear_to_srgb(color);
+    }
+    f_color = vec4(color, 1.0);
+}
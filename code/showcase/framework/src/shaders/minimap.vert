@@ -0,0 +1,23 @@
+#version 450
+
+layout(location=0) out vec2 v_uv;
+
+layout(set=0, binding=2)
+uniform Minimap {
+    // Min and max corners in NDC
+    vec4 u_rect;
+    vec4 u_marker;
+    vec4 u_border;
+};
+
+const vec2 CORNERS[6] = vec2[6](
+    vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0),
+    vec2(0.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    // NDC has y going up, but the map's v goes down like a texture
+    v_uv = vec2(corner.x, 1.0 - corner.y);
+    gl_Position = vec4(mix(u_rect.xy, u_rect.zw, corner), 0.0, 1.0);
+}
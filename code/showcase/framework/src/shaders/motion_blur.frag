@@ -0,0 +1,68 @@
+#version 450
+
+// See MotionBlurPass in motion_blur.rs
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_color;
+layout(set = 0, binding = 1) uniform texture2D t_velocity;
+layout(set = 0, binding = 2) uniform texture2D t_depth;
+layout(set = 0, binding = 3) uniform sampler s_linear;
+layout(set = 0, binding = 4) uniform sampler s_nearest;
+layout(set = 0, binding = 5) uniform MotionBlur {
+    mat4 u_inv_view_proj;
+    mat4 u_prev_view_proj;
+    // Mode, shutter, samples and max blur in pixels
+    vec4 u_params;
+    // Width and height in pixels, then one over each
+    vec4 u_viewport;
+};
+
+// Matches MotionBlurMode::index
+const float MODE_PER_OBJECT = 2.0;
+
+// How far the point at `depth` under `uv` moved on screen because of
+// the camera, in texture coordinates
+vec2 camera_velocity(vec2 uv, float depth) {
+    vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    vec4 world = u_inv_view_proj * vec4(ndc, depth, 1.0);
+    vec4 prev = u_prev_view_proj * vec4(world.xyz / world.w, 1.0);
+    // Was behind the camera, so there's no telling where it came from
+    if (prev.w <= 0.0) {
+        return vec2(0.0);
+    }
+    vec2 prev_ndc = prev.xy / prev.w;
+    return uv - vec2(prev_ndc.x * 0.5 + 0.5, 0.5 - prev_ndc.y * 0.5);
+}
+
+void main() {
+    vec2 uv = gl_FragCoord.xy * u_viewport.zw;
+    vec4 center = textureLod(sampler2D(t_color, s_linear), uv, 0.0);
+    float depth = textureLod(sampler2D(t_depth, s_nearest), uv, 0.0).r;
+
+    // The sky never writes a velocity, so it always gets the camera's
+    vec2 velocity = u_params.x == MODE_PER_OBJECT && depth < 1.0
+        ? textureLod(sampler2D(t_velocity, s_nearest), uv, 0.0).rg
+        : camera_velocity(uv, depth);
+
+    vec2 blur = velocity * u_params.y;
+    float pixels = length(blur * u_viewport.xy);
+    // Not worth the taps
+    if (pixels < 0.5) {
+        f_color = center;
+        return;
+    }
+    if (pixels > u_params.w) {
+        blur *= u_params.w / pixels;
+    }
+
+    // Centered on the pixel, so the blur smears both ways like a real
+    // shutter instead of trailing behind
+    int samples = int(u_params.z);
+    vec4 sum = vec4(0.0);
+    for (int i = 0; i < samples; i++) {
+        float t = (float(i) + 0.5) / float(samples) - 0.5;
+        sum += textureLod(sampler2D(t_color, s_linear), uv + blur * t, 0.0);
+    }
+    f_color = sum / float(samples);
+}
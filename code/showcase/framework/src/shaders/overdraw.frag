@@ -0,0 +1,16 @@
+#version 450
+
+// One counter per OVERDRAW_TILE_SIZE square of the screen, which the
+// build script defines
+layout(set=2, binding=0) buffer Tiles {
+    uint counts[];
+};
+layout(set=2, binding=1) uniform Grid {
+    // x is how many tiles there are across
+    uvec4 u_grid;
+};
+
+void main() {
+    uvec2 tile = uvec2(gl_FragCoord.xy) / uint(OVERDRAW_TILE_SIZE);
+    atomicAdd(counts[tile.y * u_grid.x + tile.x], 1u);
+}
@@ -0,0 +1,17 @@
+#version 450
+
+// Only what's needed to put the fragments in the right place, see
+// OverdrawCounter in overdraw.rs. The contact shadow prepass uses it
+// too.
+layout(location=0) in vec3 a_position;
+layout(location=5) in mat4 a_model;
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+void main() {
+    gl_Position = u_view_proj * a_model * vec4(a_position, 1.0);
+}
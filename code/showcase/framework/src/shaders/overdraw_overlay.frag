@@ -0,0 +1,13 @@
+#version 450
+
+layout(location=0) out vec4 f_color;
+
+// One texel per tile. It's already in whatever encoding the output
+// wants, see OverdrawCounter::new.
+layout(set=0, binding=0) uniform texture2D t_heat;
+layout(set=0, binding=1) uniform sampler s_heat;
+
+void main() {
+    ivec2 tile = ivec2(gl_FragCoord.xy) / OVERDRAW_TILE_SIZE;
+    f_color = texelFetch(sampler2D(t_heat, s_heat), tile, 0);
+}
@@ -0,0 +1,17 @@
+#version 450
+
+// See check_matrix_conventions in shader_matrix.rs
+layout(set=0, binding=0)
+uniform Reference {
+    mat4 u_matrix;
+};
+
+// In the top right quadrant, with the right angle at the first corner
+// so it can't be mistaken for a mirror of itself
+const vec2 CORNERS[3] = vec2[3](
+    vec2(0.4, 0.1), vec2(0.8, 0.1), vec2(0.4, 0.3)
+);
+
+void main() {
+    gl_Position = u_matrix * vec4(CORNERS[gl_VertexIndex], 0.0, 1.0);
+}
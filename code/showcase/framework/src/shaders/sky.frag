@@ -0,0 +1,52 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) 
+uniform Uniforms {
+    vec4 u_view_position; 
+    mat4 u_view_proj;
+    mat4 u_inv_view_proj;
+};
+
+layout(set=1, binding=0)
+uniform Sky {
+    vec4 u_horizon_color;
+    vec4 u_zenith_color;
+    vec4 u_ground_color;
+    // xyz is the direction towards the sun
+    vec4 u_sun_direction;
+    // rgb is the color, a is the angular radius in radians
+    vec4 u_sun_color_size;
+    // x is how wide the glow is, y is how bright
+    vec4 u_glow;
+};
+
+void main() {
+    vec4 far = u_inv_view_proj * vec4(v_ndc, 1.0, 1.0);
+    vec3 dir = normalize(far.xyz / far.w - u_view_position.xyz);
+
+    // Vertical gradient, with a darker color below the horizon
+    float height = dir.y;
+    vec3 color = height >= 0.0
+        ? mix(u_horizon_color.rgb, u_zenith_color.rgb, pow(height, 0.5))
+        : mix(u_horizon_color.rgb, u_ground_color.rgb, pow(-height, 0.3));
+
+    vec3 sun_dir = normalize(u_sun_direction.xyz);
+    float cos_angle = dot(dir, sun_dir);
+
+    // Soft glow around the sun
+    float glow = pow(max(cos_angle, 0.0), 1.0 / max(u_glow.x, 0.0001)) * u_glow.y;
+    color += u_sun_color_size.rgb * glow;
+
+    // The sun disc. Using fwidth for the edge gives it about one
+    // pixel of antialiasing no matter how big it is on screen.
+    float cos_size = cos(u_sun_color_size.a);
+    float edge = fwidth(cos_angle);
+    float disc = smoothstep(cos_size - edge, cos_size + edge, cos_angle);
+    color = mix(color, u_sun_color_size.rgb, disc);
+
+    f_color = vec4(color, 1.0);
+}
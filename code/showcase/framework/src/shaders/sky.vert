@@ -0,0 +1,15 @@
+#version 450
+
+layout(location=0) out vec2 v_ndc;
+
+void main() {
+    // One big triangle that covers the screen
+    vec2 ndc = vec2(
+        float((gl_VertexIndex << 1) & 2) * 2.0 - 1.0,
+        float(gl_VertexIndex & 2) * 2.0 - 1.0
+    );
+    v_ndc = ndc;
+    // z = 1 puts the sky on the far plane, so depth testing against
+    // the opaque geometry only leaves the empty pixels
+    gl_Position = vec4(ndc, 1.0, 1.0);
+}
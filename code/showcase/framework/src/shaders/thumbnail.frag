@@ -0,0 +1,27 @@
+#version 450
+
+layout(location=0) in vec3 v_normal;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0)
+uniform Thumbnail {
+    mat4 u_view_proj;
+    // Towards each light: key, fill and rim
+    vec4 u_light_directions[3];
+    vec4 u_light_colors[3];
+};
+
+// Thumbnails are about the shape, so everything gets the same clay
+// color instead of its materials
+const vec3 CLAY = vec3(0.75, 0.72, 0.68);
+
+void main() {
+    vec3 normal = normalize(v_normal);
+    vec3 result = CLAY * 0.08;
+    for (int i = 0; i < 3; i++) {
+        float diffuse = max(dot(normal, u_light_directions[i].xyz), 0.0);
+        result += CLAY * u_light_colors[i].rgb * diffuse;
+    }
+    f_color = vec4(result, 1.0);
+}
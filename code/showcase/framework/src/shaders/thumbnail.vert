@@ -0,0 +1,19 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=2) in vec3 a_normal;
+
+layout(location=0) out vec3 v_normal;
+
+layout(set=0, binding=0)
+uniform Thumbnail {
+    mat4 u_view_proj;
+    // The rest is for the fragment shader
+    vec4 u_light_directions[3];
+    vec4 u_light_colors[3];
+};
+
+void main() {
+    v_normal = a_normal;
+    gl_Position = u_view_proj * vec4(a_position, 1.0);
+}
@@ -0,0 +1,12 @@
+#version 450
+
+layout(location=0) in float v_height;
+
+layout(location=0) out vec4 f_color;
+
+void main() {
+    // A neutral grey that gets lighter towards the top
+    vec3 bottom = vec3(0.18);
+    vec3 top = vec3(0.45);
+    f_color = vec4(mix(bottom, top, v_height), 1.0);
+}
@@ -0,0 +1,13 @@
+#version 450
+
+layout(location=0) out float v_height;
+
+void main() {
+    // One big triangle that covers the screen
+    vec2 ndc = vec2(
+        float((gl_VertexIndex << 1) & 2) * 2.0 - 1.0,
+        float(gl_VertexIndex & 2) * 2.0 - 1.0
+    );
+    v_height = ndc.y * 0.5 + 0.5;
+    gl_Position = vec4(ndc, 1.0, 1.0);
+}
@@ -0,0 +1,36 @@
+#version 450
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_hdr;
+layout(set=0, binding=1) uniform sampler s_hdr;
+layout(set=0, binding=2)
+uniform Tonemap {
+    // xy gets added to the pixel we read. This lets the pixel probe
+    // run the exact same shader into a 1x1 target. z is 1 when the
+    // output format isn't sRGB, so we have to encode.
+    ivec4 u_offset;
+    // Simulates color blindness, see ColorBlindness in palette.rs.
+    // Otherwise the identity.
+    mat4 u_color_matrix;
+};
+
+// This has to match linear_to_srgb() in tonemap.rs
+vec3 linear_to_srgb(vec3 c) {
+    vec3 low = c * 12.92;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(c, vec3(0.0031308)));
+}
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy) + u_offset.xy;
+    vec3 hdr = texelFetch(sampler2D(t_hdr, s_hdr), pixel, 0).rgb;
+    // Reinhard. This has to match tonemap() in tonemap.rs. The sRGB
+    // encoding is left to the output format when it can do it.
+    vec3 ldr = hdr / (1.0 + hdr);
+    vec3 color = clamp(mat3(u_color_matrix) * ldr, 0.0, 1.0);
+    if (u_offset.z != 0) {
+        color = linear_to_srgb(color);
+    }
+    f_color = vec4(color, 1.0);
+}
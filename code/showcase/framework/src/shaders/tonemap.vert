@@ -0,0 +1,10 @@
+#version 450
+
+void main() {
+    // One big triangle that covers the screen
+    vec2 ndc = vec2(
+        float((gl_VertexIndex << 1) & 2) * 2.0 - 1.0,
+        float(gl_VertexIndex & 2) * 2.0 - 1.0
+    );
+    gl_Position = vec4(ndc, 0.0, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 450
+
+// Resolves glass_wboit.frag's targets over the opaque scene, blended
+// with (src alpha, 1 - src alpha)
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_accum;
+layout(set=0, binding=1) uniform texture2D t_revealage;
+layout(set=0, binding=2) uniform sampler s_targets;
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float revealage = texelFetch(sampler2D(t_revealage, s_targets), pixel, 0).r;
+    if (revealage >= 1.0) {
+        // Nothing transparent here
+        discard;
+    }
+    vec4 accum = texelFetch(sampler2D(t_accum, s_targets), pixel, 0);
+    vec3 average = accum.rgb / max(accum.a, 1e-5);
+    f_color = vec4(average, 1.0 - revealage);
+}
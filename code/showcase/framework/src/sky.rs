@@ -0,0 +1,157 @@
+use anyhow::*;
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::pipeline::RenderPipelineBuilder;
+
+/**
+ * What the procedural sky looks like. Colors are linear RGB.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SkyDesc {
+    pub horizon_color: [f32; 3],
+    pub zenith_color: [f32; 3],
+    /// What you see looking below the horizon
+    pub ground_color: [f32; 3],
+    /// Direction towards the sun. This should match the directional
+    /// light so the lighting lines up with the backdrop.
+    pub sun_direction: [f32; 3],
+    pub sun_color: [f32; 3],
+    pub sun_size_degrees: f32,
+    /// Bigger numbers spread the glow further from the sun
+    pub glow_width: f32,
+    pub glow_strength: f32,
+}
+
+impl Default for SkyDesc {
+    fn default() -> Self {
+        Self {
+            horizon_color: [0.6, 0.7, 0.8],
+            zenith_color: [0.1, 0.25, 0.55],
+            ground_color: [0.2, 0.18, 0.15],
+            sun_direction: [0.3, 0.5, 0.6],
+            sun_color: [1.0, 0.95, 0.85],
+            sun_size_degrees: 1.0,
+            glow_width: 0.05,
+            glow_strength: 0.4,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SkyUniform {
+    horizon_color: Vector4<f32>,
+    zenith_color: Vector4<f32>,
+    ground_color: Vector4<f32>,
+    sun_direction: Vector4<f32>,
+    sun_color_size: Vector4<f32>,
+    glow: Vector4<f32>,
+}
+
+unsafe impl bytemuck::Pod for SkyUniform {}
+unsafe impl bytemuck::Zeroable for SkyUniform {}
+
+impl From<&SkyDesc> for SkyUniform {
+    fn from(desc: &SkyDesc) -> Self {
+        let v = |c: [f32; 3]| Vector3::from(c).extend(1.0);
+        Self {
+            horizon_color: v(desc.horizon_color),
+            zenith_color: v(desc.zenith_color),
+            ground_color: v(desc.ground_color),
+            sun_direction: Vector3::from(desc.sun_direction).normalize().extend(0.0),
+            sun_color_size: Vector3::from(desc.sun_color)
+                .extend(Rad::from(Deg(desc.sun_size_degrees)).0),
+            glow: Vector4::new(desc.glow_width, desc.glow_strength, 0.0, 0.0),
+        }
+    }
+}
+
+/**
+ * A cheap analytic sky. Draw it in the main pass after the opaque
+ * geometry: it sits on the far plane, so the depth test makes it only
+ * show up where nothing else was drawn.
+ */
+pub struct SkyPass {
+    pipeline: wgpu::RenderPipeline,
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl SkyPass {
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        desc: &SkyDesc,
+    ) -> Result<Self> {
+        let buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[SkyUniform::from(desc)]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                ],
+                label: Some("SkyPass::layout"),
+            }
+        );
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &buffer,
+                            range: 0..std::mem::size_of::<SkyUniform>() as wgpu::BufferAddress,
+                        },
+                    },
+                ],
+                label: Some("SkyPass::bind_group"),
+            }
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[uniform_layout, &layout],
+            }
+        );
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .color_solid(color_format)
+            .depth_no_stencil(depth_format, false, wgpu::CompareFunction::LessEqual)
+            .vertex_shader(include_bytes!("shaders/sky.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/sky.frag.spv"))
+            .build(device)?;
+
+        Ok(Self { pipeline, buffer, bind_group })
+    }
+
+    pub fn update(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, desc: &SkyDesc) {
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[SkyUniform::from(desc)]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.buffer,
+            0,
+            std::mem::size_of::<SkyUniform>() as _,
+        );
+    }
+
+    pub fn render<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>, uniforms: &'a wgpu::BindGroup) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, uniforms, &[]);
+        pass.set_bind_group(1, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
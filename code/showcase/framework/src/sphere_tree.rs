@@ -0,0 +1,178 @@
+use cgmath::*;
+use std::ops::Range;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Sphere {
+    /// The smallest sphere around both
+    pub fn merge(&self, other: &Sphere) -> Sphere {
+        let offset = other.center - self.center;
+        let distance = offset.magnitude();
+        if distance + other.radius <= self.radius {
+            return *self;
+        }
+        if distance + self.radius <= other.radius {
+            return *other;
+        }
+        let radius = (distance + self.radius + other.radius) * 0.5;
+        let center = self.center + offset * ((radius - self.radius) / distance);
+        Sphere { center, radius }
+    }
+}
+
+/**
+ * The six planes of a view projection matrix, pointing inwards. This
+ * expects wgpu's 0 to 1 depth range, which is what
+ * [crate::OPENGL_TO_WGPU_MATRIX] gives.
+ */
+#[derive(Debug, Copy, Clone)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub fn from_matrix(view_proj: Matrix4<f32>) -> Self {
+        let m = view_proj.transpose();
+        let planes = [m.w + m.x, m.w - m.x, m.w + m.y, m.w - m.y, m.z, m.w - m.z];
+        let normalize = |p: Vector4<f32>| p / p.truncate().magnitude();
+        Self {
+            planes: [
+                normalize(planes[0]),
+                normalize(planes[1]),
+                normalize(planes[2]),
+                normalize(planes[3]),
+                normalize(planes[4]),
+                normalize(planes[5]),
+            ],
+        }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        let center = sphere.center.to_vec().extend(1.0);
+        self.planes.iter().all(|plane| plane.dot(center) >= -sphere.radius)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    sphere: Sphere,
+    /// For leaves, the instances. Otherwise the child nodes.
+    children: Range<u32>,
+    leaf: bool,
+}
+
+/**
+ * Bounding spheres around runs of instances, then around runs of
+ * those, up to a single root. Culling walks down from the root, so a
+ * whole forest off screen costs one sphere test instead of one per
+ * tree.
+ *
+ * The leaves are consecutive instances, so this only helps when
+ * instances that are close together in the buffer are close together
+ * in the world as well. [crate::InstanceGenerator] writes them that way.
+ */
+#[derive(Debug, Clone)]
+pub struct SphereTree {
+    /// Leaves first, then each level above them, ending with the root
+    nodes: Vec<Node>,
+}
+
+impl SphereTree {
+    /// How many nodes get grouped under each parent
+    pub const BRANCHING: usize = 8;
+
+    /**
+     * `leaves` are the spheres around each run of instances, with the
+     * instances they hold. The ranges should be in order and not
+     * overlap.
+     */
+    pub fn new(leaves: Vec<(Sphere, Range<u32>)>) -> Self {
+        let mut nodes = leaves.into_iter()
+            .map(|(sphere, children)| Node { sphere, children, leaf: true })
+            .collect::<Vec<_>>();
+        let mut level = 0..nodes.len();
+        while level.len() > 1 {
+            let start = nodes.len();
+            for group_start in level.clone().step_by(Self::BRANCHING) {
+                let group = group_start..(group_start + Self::BRANCHING).min(level.end);
+                let sphere = nodes[group.clone()].iter()
+                    .skip(1)
+                    .fold(nodes[group.start].sphere, |sphere, node| sphere.merge(&node.sphere));
+                nodes.push(Node {
+                    sphere,
+                    children: group.start as u32..group.end as u32,
+                    leaf: false,
+                });
+            }
+            level = start..nodes.len();
+        }
+        Self { nodes }
+    }
+
+    pub fn root(&self) -> Option<&Sphere> {
+        self.nodes.last().map(|node| &node.sphere)
+    }
+
+    /// The instances in leaves that might be visible, with neighboring
+    /// ranges joined so there are as few draws as possible
+    pub fn visible_ranges(&self, frustum: &Frustum) -> Vec<Range<u32>> {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        if let Some(root) = self.nodes.len().checked_sub(1) {
+            self.collect(root, frustum, &mut ranges);
+        }
+        ranges
+    }
+
+    fn collect(&self, index: usize, frustum: &Frustum, ranges: &mut Vec<Range<u32>>) {
+        let node = &self.nodes[index];
+        if !frustum.intersects_sphere(&node.sphere) {
+            return;
+        }
+        if !node.leaf {
+            for child in node.children.clone() {
+                self.collect(child as usize, frustum, ranges);
+            }
+            return;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == node.children.start => last.end = node.children.end,
+            _ => ranges.push(node.children.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::OPENGL_TO_WGPU_MATRIX;
+
+    #[test]
+    fn only_visible_leaves_get_drawn() {
+        // A row of leaves along x, 100 instances each
+        let leaves = (0..20)
+            .map(|i| {
+                let sphere = Sphere { center: Point3::new(i as f32 * 10.0, 0.0, 0.0), radius: 1.0 };
+                (sphere, i * 100..(i + 1) * 100)
+            })
+            .collect();
+        let tree = SphereTree::new(leaves);
+        let root = tree.root().unwrap();
+        assert!(root.radius >= 96.0, "{:?}", root);
+
+        // Looking down -z at the second and third leaves. The view is
+        // about 25 wide there, so the first and fourth are just out.
+        let view = Matrix4::look_at(Point3::new(15.0, 0.0, 30.0), Point3::new(15.0, 0.0, 0.0), Vector3::unit_y());
+        let proj = OPENGL_TO_WGPU_MATRIX * perspective(Deg(45.0), 1.0, 0.1, 100.0);
+        let frustum = Frustum::from_matrix(proj * view);
+        let ranges = tree.visible_ranges(&frustum);
+        assert_eq!(ranges, vec![100..300]);
+
+        // Looking away sees nothing
+        let away = Matrix4::look_at(Point3::new(0.0, 0.0, 30.0), Point3::new(0.0, 0.0, 60.0), Vector3::unit_y());
+        assert!(tree.visible_ranges(&Frustum::from_matrix(proj * away)).is_empty());
+    }
+}
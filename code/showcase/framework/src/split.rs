@@ -0,0 +1,176 @@
+use cgmath::*;
+use std::collections::HashMap;
+use crate::bounds::Aabb;
+use crate::model::{MeshData, ModelVertex};
+
+/**
+ * Whether both of the mesh's buffers fit in `max_buffer_size` bytes
+ * as they are.
+ */
+pub fn mesh_fits(mesh: &MeshData, max_buffer_size: u64) -> bool {
+    // Including the levels of detail, which come out of the same buffers
+    let vertex_bytes = (mesh.vertices.len() * std::mem::size_of::<ModelVertex>()) as u64;
+    let index_bytes = (mesh.indices.len() * std::mem::size_of::<u32>()) as u64;
+    vertex_bytes <= max_buffer_size && index_bytes <= max_buffer_size
+}
+
+/**
+ * Cuts a mesh whose vertex or index buffer would be bigger than
+ * `max_buffer_size` into parts that aren't. Triangles are kept whole
+ * and in order, and each part only gets the vertices its triangles
+ * use, renumbered from 0.
+ *
+ * Every part keeps the mesh's name, material and placements, so
+ * anything that finds meshes by name still finds all of them. Each
+ * gets its own AABB, which together cover the original one.
+ *
+ * Meshes that fit already come back as the only part. A limit too
+ * small for even one triangle gets one triangle per part anyway, as
+ * there's nothing smaller to split into.
+ */
+pub fn split_mesh(mesh: &MeshData, max_buffer_size: u64) -> Vec<MeshData> {
+    if mesh_fits(mesh, max_buffer_size) {
+        return vec![mesh.clone()];
+    }
+    let max_vertices = (max_buffer_size / std::mem::size_of::<ModelVertex>() as u64).max(3) as usize;
+    let max_indices = ((max_buffer_size / std::mem::size_of::<u32>() as u64) as usize / 3 * 3).max(3);
+
+    let mut parts = Vec::new();
+    let mut remap: HashMap<u32, u32> = HashMap::new();
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    // Levels of detail are dropped, as they'd need splitting the
+    // same way as the full mesh
+    for triangle in mesh.lod0_indices().chunks_exact(3) {
+        let new_vertices = (0..3)
+            .filter(|&i| !remap.contains_key(&triangle[i]) && !triangle[..i].contains(&triangle[i]))
+            .count();
+        let full = indices.len() + 3 > max_indices || vertices.len() + new_vertices > max_vertices;
+        if full && !indices.is_empty() {
+            parts.push(part(mesh, std::mem::take(&mut vertices), std::mem::take(&mut indices)));
+            remap.clear();
+        }
+        for &v in triangle {
+            let local = *remap.entry(v).or_insert_with(|| {
+                vertices.push(mesh.vertices[v as usize]);
+                vertices.len() as u32 - 1
+            });
+            indices.push(local);
+        }
+    }
+    if !indices.is_empty() {
+        parts.push(part(mesh, vertices, indices));
+    }
+
+    log::info!(
+        "{}: {} vertices and {} indices don't fit in {} byte buffers, split into {} meshes",
+        mesh.name,
+        mesh.vertices.len(),
+        mesh.indices.len(),
+        max_buffer_size,
+        parts.len(),
+    );
+    parts
+}
+
+fn part(mesh: &MeshData, vertices: Vec<ModelVertex>, indices: Vec<u32>) -> MeshData {
+    let aabb = Aabb::from_points(vertices.iter().map(|v| Point3::from_vec(v.position)));
+    MeshData {
+        name: mesh.name.clone(),
+        vertices,
+        indices,
+        material: mesh.material,
+        has_tex_coords: mesh.has_tex_coords,
+        normals: mesh.normals,
+        tangents: mesh.tangents,
+        aabb,
+        placements: mesh.placements.clone(),
+        lods: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::capabilities::DeviceCapabilities;
+    use crate::inspect::AttributeSource;
+
+    /// A strip of quads along x, two triangles each
+    fn strip(quads: u32) -> MeshData {
+        let mut vertices = Vec::new();
+        for i in 0..=quads {
+            for y in 0..2 {
+                vertices.push(ModelVertex {
+                    position: Vector3::new(i as f32, y as f32, 0.0),
+                    tex_coords: Vector2::zero(),
+                    normal: Vector3::unit_z(),
+                    tangent: Vector4::unit_x(),
+                });
+            }
+        }
+        let mut indices = Vec::new();
+        for i in 0..quads {
+            let v = i * 2;
+            indices.extend_from_slice(&[v, v + 2, v + 1, v + 1, v + 2, v + 3]);
+        }
+        MeshData {
+            name: "strip".to_string(),
+            aabb: Aabb::from_points(vertices.iter().map(|v| Point3::from_vec(v.position))),
+            vertices,
+            indices,
+            material: 3,
+            has_tex_coords: false,
+            normals: AttributeSource::File,
+            tangents: AttributeSource::Generated,
+            placements: vec![Matrix4::from_scale(2.0)],
+            lods: Vec::new(),
+        }
+    }
+
+    fn corners(vertices: &[ModelVertex], triangle: &[u32]) -> [Vector3<f32>; 3] {
+        let p = |i: usize| vertices[triangle[i] as usize].position;
+        [p(0), p(1), p(2)]
+    }
+
+    #[test]
+    fn oversized_meshes_get_split() {
+        let mesh = strip(1000);
+        let mut caps = DeviceCapabilities::for_backend(
+            wgpu::Backend::Vulkan,
+            "test".to_string(),
+            "Other".to_string(),
+            &Default::default(),
+        );
+        assert_eq!(split_mesh(&mesh, caps.max_buffer_size).len(), 1);
+
+        // Only room for 21 vertices, so those run out well before the
+        // 256 indices do
+        caps.max_buffer_size = 1024;
+        assert!(!mesh_fits(&mesh, caps.max_buffer_size));
+        let parts = split_mesh(&mesh, caps.max_buffer_size);
+        assert!(parts.len() > 1);
+
+        let mut triangles = Vec::new();
+        let mut aabb = Aabb::empty();
+        for part in &parts {
+            assert!(mesh_fits(part, caps.max_buffer_size), "{} {}", part.vertices.len(), part.indices.len());
+            assert_eq!(part.indices.len() % 3, 0);
+            assert_eq!((part.name.as_str(), part.material), ("strip", 3));
+            assert_eq!(part.placements, mesh.placements);
+            // Every vertex in a part is used by it
+            let used = part.indices.iter().copied().max().unwrap() as usize + 1;
+            assert_eq!(used, part.vertices.len());
+            for t in part.indices.chunks_exact(3) {
+                triangles.push(corners(&part.vertices, t));
+            }
+            aabb = aabb.union(&part.aabb);
+        }
+
+        // The same triangles in the same order
+        let original = mesh.indices.chunks_exact(3)
+            .map(|t| corners(&mesh.vertices, t))
+            .collect::<Vec<_>>();
+        assert_eq!(triangles, original);
+        assert_eq!(aabb, mesh.aabb);
+    }
+}
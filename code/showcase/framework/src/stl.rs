@@ -0,0 +1,119 @@
+use anyhow::*;
+use std::convert::TryInto;
+
+/**
+ * One facet of an STL file.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Triangle {
+    pub normal: [f32; 3],
+    pub vertices: [[f32; 3]; 3],
+}
+
+/**
+ * Parses either flavor of STL. Binary files are 80 byte header, a u32
+ * triangle count, and then 50 bytes per triangle. Ascii files start with
+ * `solid`, but so do some binary files, so we check the size first.
+ */
+pub fn parse(bytes: &[u8]) -> Result<Vec<Triangle>> {
+    if bytes.len() >= 84 {
+        let count = u32::from_le_bytes(bytes[80..84].try_into().unwrap()) as usize;
+        if bytes.len() == 84 + count * 50 {
+            return parse_binary(bytes, count);
+        }
+    }
+    parse_ascii(std::str::from_utf8(bytes).context("STL is neither binary nor ascii")?)
+}
+
+fn parse_binary(bytes: &[u8], count: usize) -> Result<Vec<Triangle>> {
+    let read_vec3 = |offset: usize| {
+        let mut v = [0.0; 3];
+        for (i, c) in v.iter_mut().enumerate() {
+            let start = offset + i * 4;
+            *c = f32::from_le_bytes(bytes[start..start + 4].try_into().unwrap());
+        }
+        v
+    };
+
+    let mut triangles = Vec::with_capacity(count);
+    for i in 0..count {
+        // Each record is the normal, three vertices, and a u16 we ignore
+        let offset = 84 + i * 50;
+        triangles.push(Triangle {
+            normal: read_vec3(offset),
+            vertices: [
+                read_vec3(offset + 12),
+                read_vec3(offset + 24),
+                read_vec3(offset + 36),
+            ],
+        });
+    }
+    Ok(triangles)
+}
+
+fn parse_ascii(src: &str) -> Result<Vec<Triangle>> {
+    let parse_vec3 = |words: &mut std::str::SplitWhitespace| -> Result<[f32; 3]> {
+        let mut v = [0.0; 3];
+        for c in v.iter_mut() {
+            *c = words.next().context("Expected 3 components")?.parse()?;
+        }
+        Ok(v)
+    };
+
+    let mut triangles = Vec::new();
+    let mut normal = [0.0; 3];
+    let mut vertices = Vec::with_capacity(3);
+    for line in src.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("facet") => {
+                // facet normal nx ny nz
+                words.next();
+                normal = parse_vec3(&mut words)?;
+                vertices.clear();
+            }
+            Some("vertex") => vertices.push(parse_vec3(&mut words)?),
+            Some("endfacet") => {
+                if vertices.len() != 3 {
+                    bail!("Facet has {} vertices, expected 3", vertices.len());
+                }
+                triangles.push(Triangle {
+                    normal,
+                    vertices: [vertices[0], vertices[1], vertices[2]],
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ascii_and_binary_match() {
+        let ascii = "solid test
+            facet normal 0 0 1
+                outer loop
+                    vertex 0 0 0
+                    vertex 1 0 0
+                    vertex 0 1 0
+                endloop
+            endfacet
+        endsolid test";
+
+        let mut binary = vec![0u8; 80];
+        binary.extend(&1u32.to_le_bytes());
+        for f in &[0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            binary.extend(&f.to_le_bytes());
+        }
+        binary.extend(&[0, 0]);
+
+        let a = parse(ascii.as_bytes()).unwrap();
+        let b = parse(&binary).unwrap();
+        assert_eq!(a.len(), 1);
+        assert_eq!(a, b);
+    }
+}
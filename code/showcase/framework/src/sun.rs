@@ -0,0 +1,222 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::sky::SkyDesc;
+
+/// The tilt of the earth's axis, which is as far as the sun gets from
+/// the equator
+const AXIAL_TILT_DEGREES: f32 = 23.44;
+
+/**
+ * Sun light color by elevation in degrees. Colors in between get
+ * blended, which is close enough to real scattering for a demo: deep
+ * orange at the horizon, white once it's well up.
+ */
+const SUN_COLORS: [(f32, [f32; 3]); 5] = [
+    (0.0, [1.0, 0.35, 0.1]),
+    (5.0, [1.0, 0.55, 0.25]),
+    (15.0, [1.0, 0.8, 0.6]),
+    (40.0, [1.0, 0.95, 0.88]),
+    (90.0, [1.0, 1.0, 1.0]),
+];
+
+/// How far below the horizon the sun can go before its light is gone
+/// completely. Twilight ends around here.
+const TWILIGHT_DEGREES: f32 = 4.0;
+
+/**
+ * Where the sun is, worked out from the date, time and place rather
+ * than set by hand. Put one in [crate::SceneDesc::sun] and the main
+ * light and the sky follow it. Angles are in degrees.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SunDesc {
+    /// Local solar time in hours, so 12 is when the sun is highest
+    pub time_of_day: f32,
+    /// 1 is the 1st of January
+    pub day_of_year: u32,
+    /// Positive is north
+    pub latitude: f32,
+    /// How fast [SunDesc::time_of_day] moves on its own, in hours per
+    /// second. 0 keeps it still.
+    pub hours_per_second: f32,
+    /// The lighting that's left at night, so the scene never goes fully
+    /// black unless this is zero. Linear RGB.
+    pub night_ambient: [f32; 3],
+    /// The light is a point light, so it gets put this far away in the
+    /// sun's direction
+    pub distance: f32,
+}
+
+impl Default for SunDesc {
+    fn default() -> Self {
+        Self {
+            time_of_day: 10.0,
+            // Around the spring equinox
+            day_of_year: 80,
+            latitude: 45.0,
+            hours_per_second: 0.0,
+            night_ambient: [0.02, 0.025, 0.04],
+            distance: 50.0,
+        }
+    }
+}
+
+impl SunDesc {
+    /// Moves the time of day along by `dt` seconds, wrapping at
+    /// midnight
+    pub fn advance(&mut self, dt: f32) {
+        self.set_time_of_day(self.time_of_day + self.hours_per_second * dt);
+    }
+
+    pub fn set_time_of_day(&mut self, hours: f32) {
+        self.time_of_day = hours.rem_euclid(24.0);
+    }
+
+    /// Where the sun is for the current settings
+    pub fn state(&self) -> SunState {
+        SunState::new(sun_direction(self.time_of_day, self.day_of_year, self.latitude), self.night_ambient)
+    }
+}
+
+/**
+ * The direction towards the sun, with Y up, -Z north and X east. This
+ * uses the usual textbook approximation for the declination, which is
+ * good to about a degree.
+ */
+pub fn sun_direction(time_of_day: f32, day_of_year: u32, latitude: f32) -> Vector3<f32> {
+    let year = 2.0 * std::f32::consts::PI / 365.0;
+    let declination = Rad::from(Deg(-AXIAL_TILT_DEGREES)).0 * (year * (day_of_year as f32 + 10.0)).cos();
+    let hour_angle = Rad::from(Deg(15.0 * (time_of_day - 12.0))).0;
+    let latitude = Rad::from(Deg(latitude)).0;
+
+    let east = -declination.cos() * hour_angle.sin();
+    let north = latitude.cos() * declination.sin()
+        - latitude.sin() * declination.cos() * hour_angle.cos();
+    let up = latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos();
+    Vector3::new(east, up, -north).normalize()
+}
+
+/// Looks up [SUN_COLORS]. Below the horizon this is the horizon color,
+/// see [SunState::intensity] for how it fades out.
+pub fn sun_color(elevation_degrees: f32) -> Vector3<f32> {
+    let (first_elevation, first) = SUN_COLORS[0];
+    if elevation_degrees <= first_elevation {
+        return first.into();
+    }
+    for pair in SUN_COLORS.windows(2) {
+        let ((e0, c0), (e1, c1)) = (pair[0], pair[1]);
+        if elevation_degrees < e1 {
+            let t = (elevation_degrees - e0) / (e1 - e0);
+            return Vector3::from(c0).lerp(c1.into(), t);
+        }
+    }
+    SUN_COLORS[SUN_COLORS.len() - 1].1.into()
+}
+
+/// What [SunDesc] works out to at one moment
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SunState {
+    pub direction: Vector3<f32>,
+    pub elevation_degrees: f32,
+    /// 1 with the sun up, fading to 0 over twilight
+    pub intensity: f32,
+    /// What the main light gets set to, already scaled by
+    /// [SunState::intensity]
+    pub light_color: Vector3<f32>,
+    pub night_ambient: Vector3<f32>,
+}
+
+impl SunState {
+    fn new(direction: Vector3<f32>, night_ambient: [f32; 3]) -> Self {
+        let elevation_degrees = Deg::from(Rad(direction.y.max(-1.0).min(1.0).asin())).0;
+        let t = ((elevation_degrees + TWILIGHT_DEGREES) / (TWILIGHT_DEGREES + 2.0)).max(0.0).min(1.0);
+        // Smoothstep, so the light doesn't switch off with a jump
+        let intensity = t * t * (3.0 - 2.0 * t);
+        Self {
+            direction,
+            elevation_degrees,
+            intensity,
+            light_color: sun_color(elevation_degrees) * intensity,
+            night_ambient: night_ambient.into(),
+        }
+    }
+
+    /// Where to put the main light so it shines from the sun's
+    /// direction on things around `target`
+    pub fn light_position(&self, target: Point3<f32>, distance: f32) -> Vector3<f32> {
+        target.to_vec() + self.direction * distance
+    }
+
+    /**
+     * `base` with the sun moved and the sky tinted to match. Both the
+     * zenith and horizon darken towards `night_ambient` as the sun
+     * sets, and the horizon picks up some of the sun's color when
+     * it's low.
+     */
+    pub fn sky(&self, base: &SkyDesc) -> SkyDesc {
+        let sun = sun_color(self.elevation_degrees);
+        let night = self.night_ambient;
+        // How much sunset color the horizon gets. Strongest with the
+        // sun right on it.
+        let low = (1.0 - self.elevation_degrees.abs() / 20.0).max(0.0) * self.intensity.max(0.2);
+        let tint = |color: [f32; 3], warmth: f32| -> [f32; 3] {
+            let day = Vector3::from(color).lerp(Vector3::from(color).mul_element_wise(sun), warmth);
+            night.lerp(day, self.intensity).into()
+        };
+        SkyDesc {
+            horizon_color: tint(base.horizon_color, low),
+            zenith_color: tint(base.zenith_color, low * 0.3),
+            ground_color: tint(base.ground_color, 0.0),
+            sun_direction: self.direction.into(),
+            sun_color: (sun * self.intensity).into(),
+            ..base.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sun_follows_the_day() {
+        // On the equator at the equinox the sun goes straight overhead
+        let noon = SunDesc { time_of_day: 12.0, latitude: 0.0, ..Default::default() }.state();
+        assert!(noon.elevation_degrees > 85.0, "{}", noon.elevation_degrees);
+        assert_eq!(noon.intensity, 1.0);
+
+        let sunrise = SunDesc { time_of_day: 6.0, latitude: 0.0, ..Default::default() }.state();
+        assert!(sunrise.elevation_degrees.abs() < 2.0, "{}", sunrise.elevation_degrees);
+        // Rising in the east
+        assert!(sunrise.direction.x > 0.9);
+
+        let midnight = SunDesc { time_of_day: 0.0, ..Default::default() }.state();
+        assert!(midnight.elevation_degrees < -30.0);
+        assert_eq!(midnight.intensity, 0.0);
+        assert_eq!(midnight.light_color, Vector3::zero());
+        // Still lit by the night ambient
+        let sky = midnight.sky(&SkyDesc::default());
+        assert_eq!(sky.zenith_color, SunDesc::default().night_ambient);
+
+        // Northern summer noons are higher than winter ones
+        let summer = SunDesc { time_of_day: 12.0, day_of_year: 172, ..Default::default() }.state();
+        let winter = SunDesc { time_of_day: 12.0, day_of_year: 355, ..Default::default() }.state();
+        assert!((summer.elevation_degrees - winter.elevation_degrees - AXIAL_TILT_DEGREES * 2.0).abs() < 1.0);
+        // Which is in the south
+        assert!(winter.direction.z > 0.0);
+    }
+
+    #[test]
+    fn low_sun_is_warm() {
+        let low = sun_color(2.0);
+        assert!(low.x > low.z * 2.0);
+        assert_eq!(sun_color(90.0), Vector3::new(1.0, 1.0, 1.0));
+        assert_eq!(sun_color(-10.0), sun_color(0.0));
+
+        let mut desc = SunDesc { hours_per_second: 2.0, time_of_day: 23.0, ..Default::default() };
+        desc.advance(1.0);
+        assert_eq!(desc.time_of_day, 1.0);
+    }
+}
@@ -0,0 +1,213 @@
+use cgmath::*;
+use std::collections::HashMap;
+use crate::model::ModelVertex;
+
+/// Triangles with less UV area than this don't say anything useful
+/// about which way the tangent should point
+const MIN_UV_AREA: f32 = 1e-12;
+
+/**
+ * Fills in `ModelVertex::tangent` for an indexed triangle list.
+ *
+ * Mirrored UVs (very common on symmetric models) mean two triangles
+ * sharing a vertex can disagree about which way the bitangent points.
+ * Averaging those would give a garbage basis right on the seam, so any
+ * vertex used with both handednesses gets split in two. That's why this
+ * takes `vertices` as a `Vec` and rewrites `indices`.
+ *
+ * The handedness ends up in `tangent.w`, and shaders rebuild the
+ * bitangent with `cross(normal, tangent.xyz) * tangent.w`.
+ */
+pub fn generate_tangents(vertices: &mut Vec<ModelVertex>, indices: &mut [u32]) {
+    let mut tangents = vec![Vector3::zero(); vertices.len()];
+    // The copy of a vertex that was made for the opposite handedness
+    let mut split: HashMap<(u32, bool), u32> = HashMap::new();
+    let mut handedness: Vec<Option<bool>> = vec![None; vertices.len()];
+
+    for c in indices.chunks_mut(3) {
+        if c.len() < 3 {
+            break;
+        }
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        // Calculate the edges of the triangle
+        let delta_pos1 = v1.position - v0.position;
+        let delta_pos2 = v2.position - v0.position;
+
+        // This will give us a direction to calculate the
+        // tangent and bitangent
+        let delta_uv1 = v1.tex_coords - v0.tex_coords;
+        let delta_uv2 = v2.tex_coords - v0.tex_coords;
+
+        // Solving the following system of equations will
+        // give us the tangent and bitangent.
+        //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        let det = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        if det.abs() < MIN_UV_AREA {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
+
+        // Which way the UVs wind relative to the normal. Mirrored
+        // triangles come out negative.
+        let normal = v0.normal + v1.normal + v2.normal;
+        let right_handed = normal.cross(tangent).dot(bitangent) >= 0.0;
+
+        for index in c.iter_mut() {
+            let i = *index as usize;
+            let current = handedness[i];
+            let target = match current {
+                None => {
+                    handedness[i] = Some(right_handed);
+                    *index
+                }
+                Some(h) if h == right_handed => *index,
+                Some(_) => *split.entry((*index, right_handed)).or_insert_with(|| {
+                    vertices.push(vertices[i]);
+                    tangents.push(Vector3::zero());
+                    handedness.push(Some(right_handed));
+                    (vertices.len() - 1) as u32
+                }),
+            };
+            *index = target;
+            // Bigger triangles get more say in the average, which
+            // is what we want
+            tangents[target as usize] += tangent;
+        }
+    }
+
+    for (i, v) in vertices.iter_mut().enumerate() {
+        let normal = v.normal.normalize();
+        // Gram-Schmidt: remove the part of the tangent that points
+        // along the normal
+        let t = tangents[i];
+        let mut tangent = t - normal * normal.dot(t);
+        if tangent.magnitude2() < 1e-12 {
+            tangent = any_perpendicular(normal);
+        }
+        let w = if handedness[i].unwrap_or(true) { 1.0 } else { -1.0 };
+        v.tangent = tangent.normalize().extend(w);
+    }
+}
+
+/// Some unit vector at right angles to `n`, for vertices that don't
+/// have a usable tangent
+fn any_perpendicular(n: Vector3<f32>) -> Vector3<f32> {
+    let other = if n.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+    let t = n.cross(other);
+    if t.magnitude2() < 1e-12 {
+        Vector3::unit_x()
+    } else {
+        t.normalize()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vertex(position: [f32; 3], tex_coords: [f32; 2]) -> ModelVertex {
+        ModelVertex {
+            position: position.into(),
+            tex_coords: tex_coords.into(),
+            normal: [0.0, 0.0, 1.0].into(),
+            tangent: [0.0; 4].into(),
+        }
+    }
+
+    #[test]
+    fn mirrored_seam_gets_split() {
+        // Two triangles sharing the edge x = 0. The right one has its
+        // UVs mirrored, like a symmetric character.
+        let mut vertices = vec![
+            vertex([0.0, 0.0, 0.0], [1.0, 0.0]),
+            vertex([0.0, 1.0, 0.0], [1.0, 1.0]),
+            vertex([-1.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0], [0.0, 0.0]),
+        ];
+        let mut indices = vec![0, 2, 1, 0, 1, 3];
+        generate_tangents(&mut vertices, &mut indices);
+
+        // The shared edge's two vertices were duplicated
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(&indices[..3], &[0, 2, 1]);
+        assert_eq!(indices[3], 4);
+        assert_eq!(indices[4], 5);
+        assert_eq!(indices[5], 3);
+
+        for &i in &indices[..3] {
+            assert_eq!(vertices[i as usize].tangent.w, 1.0);
+        }
+        for &i in &indices[3..] {
+            assert_eq!(vertices[i as usize].tangent.w, -1.0);
+        }
+
+        // Both sides' tangents are perpendicular to the normal and unit
+        // length
+        for v in &vertices {
+            let t = v.tangent.truncate();
+            assert!((t.magnitude() - 1.0).abs() < 1e-5);
+            assert!(t.dot(v.normal).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn mirrored_quad_has_no_seam() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../viewer/res/mirrored-quad.obj");
+        let data = crate::model::ModelData::load(path, &Default::default()).unwrap();
+        let mesh = &data.meshes[0];
+        let indices = mesh.lod0_indices();
+        // What the shaders rebuild from a vertex
+        let basis = |v: &ModelVertex| {
+            let t = v.tangent.truncate();
+            (t, v.normal.cross(t) * v.tangent.w)
+        };
+
+        // Every vertex's tangent points along +u and its bitangent
+        // along +v, mirrored or not. Averaging across the seam would
+        // leave the middle column pointing nowhere in particular.
+        for c in indices.chunks_exact(3) {
+            let (v0, v1, v2) = (mesh.vertices[c[0] as usize], mesh.vertices[c[1] as usize], mesh.vertices[c[2] as usize]);
+            let (dp1, dp2) = (v1.position - v0.position, v2.position - v0.position);
+            let (duv1, duv2) = (v1.tex_coords - v0.tex_coords, v2.tex_coords - v0.tex_coords);
+            let r = 1.0 / (duv1.x * duv2.y - duv1.y * duv2.x);
+            let along_u = ((dp1 * duv2.y - dp2 * duv1.y) * r).normalize();
+            let along_v = ((dp2 * duv1.x - dp1 * duv2.x) * r).normalize();
+            for v in &[v0, v1, v2] {
+                let (t, b) = basis(v);
+                assert!(t.dot(along_u) > 0.99, "{:?} has tangent {:?}, not {:?}", v.position, t, along_u);
+                assert!(b.dot(along_v) > 0.99, "{:?} has bitangent {:?}, not {:?}", v.position, b, along_v);
+            }
+        }
+
+        // The two copies of each seam vertex sample the same texel, so
+        // they have to turn it into mirror images: same bitangent and
+        // normal, opposite tangent. A texel on the mirror line itself
+        // then lights the same from both sides.
+        let mut used = indices.to_vec();
+        used.sort();
+        used.dedup();
+        let mut seam = 0;
+        for (i, &a) in used.iter().enumerate() {
+            for &b in &used[i + 1..] {
+                let (a, b) = (&mesh.vertices[a as usize], &mesh.vertices[b as usize]);
+                if (a.position - b.position).magnitude() > 1e-5 {
+                    continue;
+                }
+                seam += 1;
+                let ((ta, ba), (tb, bb)) = (basis(a), basis(b));
+                assert_eq!(a.tangent.w, -b.tangent.w);
+                assert!((ta + tb).magnitude() < 1e-4 && (ba - bb).magnitude() < 1e-4);
+                let texel = Vector3::new(0.0, 0.6, 0.8);
+                let lit = |t: Vector3<f32>, b: Vector3<f32>, n: Vector3<f32>| t * texel.x + b * texel.y + n * texel.z;
+                assert!((lit(ta, ba, a.normal) - lit(tb, bb, b.normal)).magnitude() < 1e-4);
+            }
+        }
+        assert_eq!(seam, 2);
+    }
+}
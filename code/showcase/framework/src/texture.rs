@@ -1,9 +1,14 @@
 use image::GenericImageView;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::mem;
+use std::rc::Rc;
 use anyhow::*;
 
 use crate::buffer;
+use crate::manifest::EmbeddedAssets;
+use crate::model::TextureSource;
+use crate::texture_budget::{reduced_size, Residency, TextureBudget, TextureMemoryReport};
 
 
 pub struct Texture<'a> {
@@ -15,6 +20,10 @@ pub struct Texture<'a> {
 
 impl<'a> Texture<'a> {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    /// What the scene gets rendered to before [crate::TonemapPass]
+    pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    /// Screen space motion, see [crate::MotionBlurPass]
+    pub const VELOCITY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
 
     pub fn load<P: AsRef<Path>>(
         device: &wgpu::Device,
@@ -141,7 +150,33 @@ impl<'a> Texture<'a> {
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            // Sampled so passes like the Hi-Z pyramid can read it, and
+            // copyable for the pixel probe
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC,
+        };
+        Self::from_descriptor(device, desc)
+    }
+
+    /// A window sized target for the scene, in linear color that can
+    /// go above 1
+    pub fn create_hdr_texture(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) -> Self {
+        let desc = wgpu::TextureDescriptor {
+            label: Some("hdr_texture"),
+            size: wgpu::Extent3d {
+                width: sc_desc.width,
+                height: sc_desc.height,
+                depth: 1,
+            },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::HDR_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC,
         };
         Self::from_descriptor(device, desc)
     }
@@ -164,4 +199,238 @@ impl<'a> Texture<'a> {
 
         raw_buffer
     }
-}
\ No newline at end of file
+}
+/// One of the textures in a [TextureCache]
+struct CachedTexture<'a> {
+    texture: Rc<Texture<'a>>,
+    residency: Residency,
+}
+
+/**
+ * A texture that [TextureCache::enforce_budget] uploaded again at a
+ * different size. Anything holding `old` should switch to `new`, like
+ * with [crate::Model::replace_texture].
+ */
+pub struct TextureSwap<'a> {
+    pub old: Rc<Texture<'a>>,
+    pub new: Rc<Texture<'a>>,
+}
+
+/**
+ * Textures loaded from files, so that materials using the same file
+ * share one copy. Normal maps are kept apart from color textures as
+ * they end up in a different format. See [crate::Model::from_data].
+ *
+ * With a [TextureCache::budget] the cache keeps its textures under it,
+ * dropping top mips from the ones drawn least recently. Textures have
+ * no mip chain on the GPU, so a reduced one is the file decoded again
+ * and scaled down. Tell the cache what got drawn with
+ * [TextureCache::mark_drawn].
+ */
+#[derive(Default)]
+pub struct TextureCache<'a> {
+    textures: HashMap<(PathBuf, bool), CachedTexture<'a>>,
+    pub budget: Option<TextureBudget>,
+    /// Textures found in here are read out of the binary rather than
+    /// from files. See [crate::Model::load_from_memory].
+    pub embedded: Option<EmbeddedAssets>,
+    /// Counts calls to [TextureCache::enforce_budget]
+    frame: u64,
+}
+
+impl<'a> TextureCache<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Anything that needs submitting before the texture is used gets
+    /// pushed onto `cmds`
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        device: &wgpu::Device,
+        path: P,
+        is_normal_map: bool,
+        cmds: &mut Vec<wgpu::CommandBuffer>,
+    ) -> Result<Rc<Texture<'a>>> {
+        let key = (path.as_ref().to_path_buf(), is_normal_map);
+        if let Some(cached) = self.textures.get(&key) {
+            return Ok(cached.texture.clone());
+        }
+        let img = self.open(path.as_ref())
+            .with_context(|| format!("Unable to load {}", path.as_ref().display()))?;
+        self.insert_image(device, path, is_normal_map, &img, cmds)
+    }
+
+    /// [TextureCache::load] for one of a material's textures. Packed
+    /// textures nobody's saved yet get packed in memory.
+    pub fn load_source(
+        &mut self,
+        device: &wgpu::Device,
+        source: &TextureSource,
+        cmds: &mut Vec<wgpu::CommandBuffer>,
+    ) -> Result<Rc<Texture<'a>>> {
+        let key = (source.path.clone(), source.is_normal_map);
+        let unsaved = if self.textures.contains_key(&key) { None } else { source.pack_unsaved() };
+        match unsaved {
+            Some(packed) => self.insert_image(device, &source.path, source.is_normal_map, &packed?, cmds),
+            None => self.load(device, &source.path, source.is_normal_map, cmds),
+        }
+    }
+
+    pub(crate) fn open(&self, path: &Path) -> Result<image::DynamicImage> {
+        let embedded = self.embedded.and_then(|assets| assets.get(&path.to_string_lossy()));
+        Ok(match embedded {
+            Some(bytes) => image::load_from_memory(bytes)?,
+            None => image::open(path)?,
+        })
+    }
+
+    /// Adds a texture that's already been decoded, like on a loader
+    /// thread. Later loads of `path` get this one.
+    pub fn insert_image<P: AsRef<Path>>(
+        &mut self,
+        device: &wgpu::Device,
+        path: P,
+        is_normal_map: bool,
+        image: &image::DynamicImage,
+        cmds: &mut Vec<wgpu::CommandBuffer>,
+    ) -> Result<Rc<Texture<'a>>> {
+        let (width, height) = image.dimensions();
+        let dropped_mips = match &self.budget {
+            Some(budget) => budget.dropped_mips_for(width, height, self.report().resident_bytes),
+            None => 0,
+        };
+        let (texture, cmd) = Texture::from_image(device, &reduce(image, dropped_mips), is_normal_map)?;
+        cmds.push(cmd);
+        let texture = Rc::new(texture);
+        let residency = Residency {
+            width,
+            height,
+            dropped_mips,
+            last_drawn: self.frame,
+            last_changed: if dropped_mips > 0 { Some(self.frame) } else { None },
+            pinned: false,
+        };
+        self.textures.insert(
+            (path.as_ref().to_path_buf(), is_normal_map),
+            CachedTexture { texture: texture.clone(), residency },
+        );
+        Ok(texture)
+    }
+
+    /// A 1x1 texture of [crate::NEUTRAL_PACKED], for materials without
+    /// a packed texture. There's only ever one per cache.
+    pub fn neutral_packed(
+        &mut self,
+        device: &wgpu::Device,
+        cmds: &mut Vec<wgpu::CommandBuffer>,
+    ) -> Result<Rc<Texture<'a>>> {
+        // Can't clash with a real file, as paths can't contain nul
+        let key = (PathBuf::from("\0neutral_packed"), true);
+        if let Some(cached) = self.textures.get(&key) {
+            return Ok(cached.texture.clone());
+        }
+        let image = image::DynamicImage::ImageRgba8(
+            image::RgbaImage::from_pixel(1, 1, image::Rgba(crate::packing::NEUTRAL_PACKED)),
+        );
+        self.insert_image(device, &key.0, key.1, &image, cmds)
+    }
+
+    /// Counts every texture `model` uses as drawn this frame
+    pub fn mark_drawn(&mut self, model: &crate::Model<'a>) {
+        for material in &model.materials {
+            for texture in &[&material.diffuse_texture, &material.normal_texture, &material.packed_texture] {
+                if let Some(cached) = self.textures.values_mut().find(|c| Rc::ptr_eq(&c.texture, *texture)) {
+                    cached.residency.last_drawn = self.frame;
+                }
+            }
+        }
+    }
+
+    /**
+     * Uploads textures again at whatever size [TextureCache::budget]
+     * says, and ends the frame. Call this once a frame, after
+     * [TextureCache::mark_drawn], and hand the swaps to whatever uses
+     * the textures. A texture whose file can't be read any more keeps
+     * its size from then on, rather than being tried again every frame.
+     */
+    pub fn enforce_budget(
+        &mut self,
+        device: &wgpu::Device,
+        cmds: &mut Vec<wgpu::CommandBuffer>,
+    ) -> Vec<TextureSwap<'a>> {
+        let frame = self.frame;
+        self.frame += 1;
+        let budget = match &self.budget {
+            Some(budget) => *budget,
+            None => return Vec::new(),
+        };
+        let keys = self.textures.keys().cloned().collect::<Vec<_>>();
+        let residency = keys.iter().map(|key| self.textures[key].residency).collect::<Vec<_>>();
+
+        let mut swaps = Vec::new();
+        for (i, dropped_mips) in budget.plan(&residency, frame) {
+            let (path, is_normal_map) = &keys[i];
+            let texture = self.open(path)
+                .and_then(|img| Texture::from_image(device, &reduce(&img, dropped_mips), *is_normal_map));
+            let (texture, cmd) = match texture {
+                Ok(texture) => texture,
+                Err(e) => {
+                    log::warn!("Unable to resize {}, keeping it as it is: {}", path.display(), e);
+                    self.textures.get_mut(&keys[i]).unwrap().residency.pinned = true;
+                    continue;
+                }
+            };
+            cmds.push(cmd);
+            let cached = self.textures.get_mut(&keys[i]).unwrap();
+            log::info!(
+                "{}: {} to {} dropped mips",
+                path.display(),
+                cached.residency.dropped_mips,
+                dropped_mips,
+            );
+            cached.residency.dropped_mips = dropped_mips;
+            cached.residency.last_changed = Some(frame);
+            let new = Rc::new(texture);
+            let old = std::mem::replace(&mut cached.texture, new.clone());
+            swaps.push(TextureSwap { old, new });
+        }
+        swaps
+    }
+
+    /// Forgets textures nothing else holds on to any more, like those
+    /// of a model that's been replaced. Returns how many there were.
+    pub fn evict_unused(&mut self) -> usize {
+        let before = self.textures.len();
+        self.textures.retain(|_, cached| Rc::strong_count(&cached.texture) > 1);
+        before - self.textures.len()
+    }
+
+    pub fn report(&self) -> TextureMemoryReport {
+        let mut report = TextureMemoryReport {
+            budget_bytes: self.budget.map(|budget| budget.bytes),
+            ..Default::default()
+        };
+        for cached in self.textures.values() {
+            report.add(&cached.residency);
+        }
+        report
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+}
+
+/// `image` without its top `dropped_mips` levels
+fn reduce(image: &image::DynamicImage, dropped_mips: u32) -> std::borrow::Cow<image::DynamicImage> {
+    if dropped_mips == 0 {
+        return std::borrow::Cow::Borrowed(image);
+    }
+    let (width, height) = reduced_size(image.width(), image.height(), dropped_mips);
+    std::borrow::Cow::Owned(image.resize_exact(width, height, image::imageops::FilterType::Triangle))
+}
@@ -0,0 +1,268 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+/// Textures are never reduced below this many pixels on their longest
+/// side, as there's little left to save by then
+pub const MIN_REDUCED_SIZE: u32 = 64;
+
+/// Bytes a `width` by `height` RGBA8 texture takes with its top
+/// `dropped_mips` levels gone
+pub fn texture_bytes(width: u32, height: u32, dropped_mips: u32) -> u64 {
+    let (width, height) = reduced_size(width, height, dropped_mips);
+    width as u64 * height as u64 * 4
+}
+
+/// The size a texture gets uploaded at with `dropped_mips` levels gone
+pub fn reduced_size(width: u32, height: u32, dropped_mips: u32) -> (u32, u32) {
+    ((width >> dropped_mips).max(1), (height >> dropped_mips).max(1))
+}
+
+/**
+ * What [TextureBudget::plan] needs to know about one texture. Frames
+ * are whatever counter the caller uses, as long as it only goes up.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Residency {
+    /// The size of the file, before anything was dropped
+    pub width: u32,
+    pub height: u32,
+    pub dropped_mips: u32,
+    pub last_drawn: u64,
+    /// When `dropped_mips` last changed, if it ever has
+    pub last_changed: Option<u64>,
+    /// Stays at `dropped_mips` whatever the budget, like when its file
+    /// couldn't be read again to resize it
+    pub pinned: bool,
+}
+
+impl Residency {
+    pub fn full_bytes(&self) -> u64 {
+        texture_bytes(self.width, self.height, 0)
+    }
+
+    pub fn resident_bytes(&self) -> u64 {
+        texture_bytes(self.width, self.height, self.dropped_mips)
+    }
+
+    fn can_drop(&self, dropped_mips: u32) -> bool {
+        (self.width.max(self.height) >> (dropped_mips + 1)) >= MIN_REDUCED_SIZE
+    }
+}
+
+/**
+ * How much texture memory to use, and how to get back under it. When
+ * textures don't fit, the least recently drawn ones lose their top mip
+ * levels until they do. They get them back once there's room again.
+ *
+ * Two things stop textures flipping between sizes every frame. They're
+ * only restored while everything stays under `headroom` of the budget,
+ * so the one that was just reduced doesn't fit back straight away.
+ * And one that changed size stays that size for `cooldown_frames`
+ * before it can grow again.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextureBudget {
+    pub bytes: u64,
+    /// Fraction of `bytes` that restoring stops at
+    pub headroom: f32,
+    pub cooldown_frames: u64,
+}
+
+impl TextureBudget {
+    pub fn new(bytes: u64) -> Self {
+        Self { bytes, headroom: 0.8, cooldown_frames: 120 }
+    }
+
+    /**
+     * How many mips each texture should drop to fit, as `(index, dropped_mips)`
+     * for the ones that change. Reducing happens straight away however
+     * recently a texture changed, as going over the budget is what
+     * fails on small cards.
+     */
+    pub fn plan(&self, textures: &[Residency], frame: u64) -> Vec<(usize, u32)> {
+        let mut levels = textures.iter().map(|t| t.dropped_mips).collect::<Vec<_>>();
+        let size = |i: usize, dropped: u32| texture_bytes(textures[i].width, textures[i].height, dropped);
+        let mut resident = textures.iter().map(Residency::resident_bytes).sum::<u64>();
+        let mut order = (0..textures.len()).collect::<Vec<_>>();
+
+        if resident > self.bytes {
+            // Least recently drawn first, and the biggest of those, so
+            // as few textures as possible get blurry
+            order.sort_by_key(|&i| (textures[i].last_drawn, Reverse(size(i, levels[i]))));
+            for &i in &order {
+                while resident > self.bytes && !textures[i].pinned && textures[i].can_drop(levels[i]) {
+                    resident -= size(i, levels[i]) - size(i, levels[i] + 1);
+                    levels[i] += 1;
+                }
+            }
+        } else {
+            let limit = (self.bytes as f64 * self.headroom as f64) as u64;
+            let cooled = |t: &Residency| t.last_changed.map_or(true, |c| frame >= c + self.cooldown_frames);
+            order.sort_by_key(|&i| Reverse(textures[i].last_drawn));
+            for &i in &order {
+                if textures[i].pinned || !cooled(&textures[i]) {
+                    continue;
+                }
+                while levels[i] > 0 {
+                    let grow = size(i, levels[i] - 1) - size(i, levels[i]);
+                    if resident + grow > limit {
+                        break;
+                    }
+                    resident += grow;
+                    levels[i] -= 1;
+                }
+            }
+        }
+
+        levels.into_iter()
+            .enumerate()
+            .filter(|&(i, dropped)| dropped != textures[i].dropped_mips)
+            .collect()
+    }
+
+    /**
+     * How many mips a new `width` by `height` texture should drop so
+     * it fits next to `resident` bytes of others. Over budget anyway
+     * gives as many as it can, and [TextureBudget::plan] takes it from
+     * there.
+     */
+    pub fn dropped_mips_for(&self, width: u32, height: u32, resident: u64) -> u32 {
+        let texture = Residency { width, height, dropped_mips: 0, last_drawn: 0, last_changed: None, pinned: false };
+        let mut dropped = 0;
+        while resident + texture_bytes(width, height, dropped) > self.bytes && texture.can_drop(dropped) {
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+/// How much memory a [crate::TextureCache] is using, from [crate::TextureCache::report]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TextureMemoryReport {
+    pub textures: usize,
+    /// Textures with some mips dropped
+    pub reduced: usize,
+    pub resident_bytes: u64,
+    /// What they'd all take at full size
+    pub full_bytes: u64,
+    pub budget_bytes: Option<u64>,
+}
+
+impl TextureMemoryReport {
+    pub fn add(&mut self, texture: &Residency) {
+        self.textures += 1;
+        if texture.dropped_mips > 0 {
+            self.reduced += 1;
+        }
+        self.resident_bytes += texture.resident_bytes();
+        self.full_bytes += texture.full_bytes();
+    }
+}
+
+impl fmt::Display for TextureMemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mb = |bytes: u64| bytes as f64 / (1 << 20) as f64;
+        write!(
+            f,
+            "{} textures ({} reduced): {:.1} MB resident of {:.1} MB at full size",
+            self.textures,
+            self.reduced,
+            mb(self.resident_bytes),
+            mb(self.full_bytes),
+        )?;
+        if let Some(budget) = self.budget_bytes {
+            write!(f, ", budget {:.1} MB", mb(budget))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn textures(count: usize, size: u32) -> Vec<Residency> {
+        (0..count)
+            .map(|_| Residency {
+                width: size,
+                height: size,
+                dropped_mips: 0,
+                last_drawn: 0,
+                last_changed: None,
+                pinned: false,
+            })
+            .collect()
+    }
+
+    fn apply(textures: &mut [Residency], changes: &[(usize, u32)], frame: u64) {
+        for &(i, dropped) in changes {
+            textures[i].dropped_mips = dropped;
+            textures[i].last_changed = Some(frame);
+        }
+    }
+
+    #[test]
+    fn tiny_budgets_dont_thrash() {
+        // Four 1 MB textures and a budget a bit short of all of them
+        let mut textures = textures(4, 512);
+        let budget = TextureBudget::new(3800 << 10);
+
+        // Everything got drawn on frame 0, and from then on one of them
+        // only every tenth frame, so it's the one that loses out
+        let mut changes = 0;
+        for frame in 1..600 {
+            for (i, texture) in textures.iter_mut().enumerate() {
+                if i != 2 || frame % 10 == 0 {
+                    texture.last_drawn = frame;
+                }
+            }
+            let plan = budget.plan(&textures, frame);
+            changes += plan.len();
+            apply(&mut textures, &plan, frame);
+            let resident: u64 = textures.iter().map(Residency::resident_bytes).sum();
+            assert!(resident <= budget.bytes, "over budget at frame {}", frame);
+        }
+        // One reduction, and nothing flipping back and forth after it
+        assert_eq!(changes, 1);
+        assert_eq!(textures.iter().map(|t| t.dropped_mips).collect::<Vec<_>>(), vec![0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn textures_come_back_when_theres_room() {
+        let mut textures = textures(4, 512);
+        let mut budget = TextureBudget::new(1 << 20);
+        textures[0].last_drawn = 5;
+        let plan = budget.plan(&textures, 10);
+        apply(&mut textures, &plan, 10);
+        let resident: u64 = textures.iter().map(Residency::resident_bytes).sum();
+        assert!(resident <= budget.bytes);
+        // The one drawn most recently loses the least
+        assert!(textures[0].dropped_mips < textures[1].dropped_mips);
+        // Never below the smallest size
+        assert!(textures.iter().all(|t| (512 >> t.dropped_mips) >= MIN_REDUCED_SIZE));
+
+        // Plenty of room, but they only just changed
+        budget.bytes = 64 << 20;
+        assert!(budget.plan(&textures, 11).is_empty());
+        let plan = budget.plan(&textures, 10 + budget.cooldown_frames);
+        apply(&mut textures, &plan, 10 + budget.cooldown_frames);
+        assert!(textures.iter().all(|t| t.dropped_mips == 0));
+
+        // New textures start out small enough to fit
+        let budget = TextureBudget::new(1 << 20);
+        assert_eq!(budget.dropped_mips_for(512, 512, 0), 0);
+        assert_eq!(budget.dropped_mips_for(1024, 1024, 0), 1);
+        assert_eq!(budget.dropped_mips_for(256, 256, 2 << 20), 2);
+    }
+
+    #[test]
+    fn pinned_textures_keep_their_size() {
+        // One that can't be resized counts against the budget all the
+        // same, so the other shrinks as far as it goes
+        let mut textures = textures(2, 512);
+        textures[0].pinned = true;
+        textures[1].last_drawn = 5;
+        let budget = TextureBudget::new(1 << 20);
+        assert_eq!(budget.plan(&textures, 10), vec![(1, 3)]);
+    }
+}
@@ -0,0 +1,365 @@
+use anyhow::*;
+use cgmath::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use crate::assets::AssetManager;
+use crate::capture::read_pixels;
+use crate::lod::{projected_size, LodPolicy};
+use crate::material_layout::MaterialLayout;
+use crate::model::{Model, ModelLoadOptions, ModelVertex};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::shader_matrix::*;
+use crate::texture::Texture;
+
+/// What thumbnails get saved and read back as
+const THUMBNAIL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// A three quarter view from slightly above, which shows off most things
+const THUMBNAIL_YAW: Deg<f32> = Deg(45.0);
+const THUMBNAIL_PITCH: Deg<f32> = Deg(25.0);
+const THUMBNAIL_FOVY: Deg<f32> = Deg(45.0);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ThumbnailUniform {
+    view_proj: ShaderMatrix,
+    /// Pointing towards each light, in world space
+    light_directions: [Vector4<f32>; 3],
+    light_colors: [Vector4<f32>; 3],
+}
+
+unsafe impl bytemuck::Pod for ThumbnailUniform {}
+unsafe impl bytemuck::Zeroable for ThumbnailUniform {}
+
+impl ThumbnailUniform {
+    /**
+     * The usual key, fill and rim rig. The lights are placed relative
+     * to the camera so every model gets lit the same way no matter
+     * which way it faces.
+     */
+    fn new(camera: &crate::OrbitCamera, projection: &crate::Projection) -> Self {
+        let to_eye = camera.direction();
+        let right = Vector3::unit_y().cross(to_eye).normalize();
+        let up = to_eye.cross(right);
+        let key = (to_eye + up - right * 0.7).normalize();
+        let fill = (to_eye + right - up * 0.2).normalize();
+        let rim = (-to_eye + up * 0.5).normalize();
+        let (view, proj) = (camera.calc_matrix(), projection.calc_matrix());
+        debug_check_matrix(&view, MatrixKind::View);
+        debug_check_matrix(&proj, MatrixKind::Projection);
+        Self {
+            view_proj: to_shader_bytes(&(proj * view), MatrixKind::Combined),
+            light_directions: [key.extend(0.0), fill.extend(0.0), rim.extend(0.0)],
+            light_colors: [
+                Vector4::new(1.0, 0.96, 0.9, 1.0),
+                Vector4::new(0.35, 0.38, 0.45, 1.0),
+                Vector4::new(0.6, 0.6, 0.6, 1.0),
+            ],
+        }
+    }
+}
+
+/**
+ * Draws small previews of models offscreen. Every model gets the same
+ * plain clay material, three point lighting and grey gradient
+ * background, and is framed with [crate::OrbitCamera::framing], so
+ * thumbnails sit well next to each other in a list.
+ *
+ * This waits for the GPU, so don't call it every frame. See
+ * [ThumbnailCache] for keeping the results around.
+ */
+pub struct ThumbnailRenderer {
+    /// Models still need one to load, even though their materials
+    /// don't get used
+    material_layout: MaterialLayout,
+    uniform_layout: wgpu::BindGroupLayout,
+    background_pipeline: wgpu::RenderPipeline,
+    model_pipeline: wgpu::RenderPipeline,
+}
+
+impl ThumbnailRenderer {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let material_layout = MaterialLayout::new(device, Default::default());
+        let uniform_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                ],
+                label: Some("ThumbnailRenderer::uniform_layout"),
+            }
+        );
+
+        let background_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[],
+            }
+        );
+        let background_pipeline = RenderPipelineBuilder::new()
+            .layout(&background_layout)
+            .color_solid(THUMBNAIL_FORMAT)
+            .depth_no_stencil(Texture::DEPTH_FORMAT, false, wgpu::CompareFunction::Always)
+            .vertex_shader(include_bytes!("shaders/thumbnail_background.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/thumbnail_background.frag.spv"))
+            .build(device)?;
+
+        let model_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&uniform_layout],
+            }
+        );
+        let model_pipeline = RenderPipelineBuilder::new()
+            .layout(&model_layout)
+            .color_solid(THUMBNAIL_FORMAT)
+            .depth_format(Texture::DEPTH_FORMAT)
+            .vertex_buffer::<ModelVertex>()
+            .vertex_shader(include_bytes!("shaders/thumbnail.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/thumbnail.frag.spv"))
+            .build(device)?;
+
+        Ok(Self { material_layout, uniform_layout, background_pipeline, model_pipeline })
+    }
+
+    /// Loads the model at `path` just for its thumbnail
+    pub fn render_path(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        assets: &AssetManager,
+        path: &str,
+        size: u32,
+    ) -> Result<image::RgbaImage> {
+        let (model, cmds) = assets.load_model(device, &self.material_layout, path, &ModelLoadOptions::default())
+            .with_context(|| format!("Unable to load {} for its thumbnail", path))?;
+        queue.submit(&cmds);
+        self.render(device, queue, &model, size)
+    }
+
+    /// A `size` by `size` picture of `model`
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        model: &Model,
+        size: u32,
+    ) -> Result<image::RgbaImage> {
+        ensure!(size > 0, "Thumbnails need to be at least one pixel");
+        let aabb = model.aabb();
+        ensure!(!aabb.is_empty(), "The model has nothing in it to draw");
+
+        let camera = crate::OrbitCamera::framing(&aabb, THUMBNAIL_FOVY.into(), THUMBNAIL_YAW, THUMBNAIL_PITCH);
+        let radius = aabb.radius().max(0.01);
+        let projection = crate::Projection::new(
+            size,
+            size,
+            THUMBNAIL_FOVY,
+            (camera.distance - radius).max(camera.distance * 0.01),
+            camera.distance + radius,
+        );
+        let uniform = ThumbnailUniform::new(&camera, &projection);
+        let uniform_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[uniform]),
+            wgpu::BufferUsage::UNIFORM,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.uniform_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &uniform_buffer,
+                        range: 0..std::mem::size_of::<ThumbnailUniform>() as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("ThumbnailRenderer::bind_group"),
+        });
+
+        let extent = wgpu::Extent3d { width: size, height: size, depth: 1 };
+        let color = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("ThumbnailRenderer::color"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: THUMBNAIL_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let depth = Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some("ThumbnailRenderer::depth"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        });
+
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("ThumbnailRenderer::render") }
+        );
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &color.view,
+                        resolve_target: None,
+                        // The background covers everything
+                        load_op: wgpu::LoadOp::Load,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::BLACK,
+                    }
+                ],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: &depth.view,
+                    depth_load_op: wgpu::LoadOp::Clear,
+                    depth_store_op: wgpu::StoreOp::Store,
+                    clear_depth: 1.0,
+                    stencil_load_op: wgpu::LoadOp::Clear,
+                    stencil_store_op: wgpu::StoreOp::Store,
+                    clear_stencil: 0,
+                }),
+            });
+            pass.set_pipeline(&self.background_pipeline);
+            pass.draw(0..3, 0..1);
+
+            pass.set_pipeline(&self.model_pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            // Thumbnails are small, so models with levels of detail
+            // rarely need their full mesh
+            let lods = LodPolicy::default();
+            for mesh in &model.meshes {
+                let mesh_size = projected_size(mesh.aabb.radius(), camera.distance, THUMBNAIL_FOVY.into(), size);
+                let level = match lods.select(mesh.lods.len(), mesh_size) {
+                    Some(level) => level,
+                    None => continue,
+                };
+                pass.set_vertex_buffer(0, &mesh.vertex_buffer, 0, 0);
+                pass.set_index_buffer(&mesh.index_buffer, 0, 0);
+                pass.draw_indexed(mesh.lod_indices(level), 0, 0..1);
+            }
+        }
+        queue.submit(&[encoder.finish()]);
+
+        let pixels = read_pixels(device, queue, &color.texture, size, size, 4)?;
+        image::RgbaImage::from_raw(size, size, pixels).context("Thumbnail is the wrong size")
+    }
+}
+
+/**
+ * Keeps thumbnails on disk so a folder of models only gets rendered
+ * once. They're keyed by a hash of the model file and the size, so
+ * editing a model makes a new thumbnail. Editing only its textures
+ * doesn't, but thumbnails don't show textures anyway.
+ */
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `thumbnails` in [crate::settings_dir]
+    pub fn in_settings_dir() -> Self {
+        Self::new(crate::settings_dir().join("thumbnails"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the thumbnail for `model_path` at `size` goes
+    pub fn path_for<P: AsRef<Path>>(&self, model_path: P, size: u32) -> Result<PathBuf> {
+        let model_path = model_path.as_ref();
+        let bytes = std::fs::read(model_path)
+            .with_context(|| format!("Unable to read {}", model_path.display()))?;
+        Ok(self.dir.join(format!("{:016x}-{}.png", file_hash(&bytes), size)))
+    }
+
+    /// Reads the thumbnail from disk, or renders and saves it if there
+    /// isn't one
+    pub fn get_or_render(
+        &self,
+        renderer: &ThumbnailRenderer,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        assets: &AssetManager,
+        path: &str,
+        size: u32,
+    ) -> Result<image::RgbaImage> {
+        let cached = self.path_for(assets.resolve(path)?, size)?;
+        if cached.exists() {
+            match image::open(&cached) {
+                Ok(image) => return Ok(image.to_rgba()),
+                Err(e) => log::warn!("Ignoring unreadable thumbnail {}: {}", cached.display(), e),
+            }
+        }
+        let image = renderer.render_path(device, queue, assets, path, size)?;
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Unable to create {}", self.dir.display()))?;
+        image.save(&cached)
+            .with_context(|| format!("Unable to save {}", cached.display()))?;
+        Ok(image)
+    }
+}
+
+fn file_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// A device for rendering without a window, like for batch thumbnails
+pub async fn headless_device() -> Result<(wgpu::Device, wgpu::Queue)> {
+    let adapter = wgpu::Adapter::request(
+        &wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::Default,
+            compatible_surface: None,
+        },
+        wgpu::BackendBit::PRIMARY,
+    ).await.context("Unable to find valid device!")?;
+    Ok(adapter.request_device(&Default::default()).await)
+}
+
+/// [headless_device] for tests, which skip themselves when there's no
+/// GPU to run on
+#[cfg(test)]
+pub(crate) fn test_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    match futures::executor::block_on(headless_device()) {
+        Ok(device) => Some(device),
+        Err(e) => {
+            eprintln!("Skipping, as there's no GPU: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn thumbnails_follow_the_model_file() {
+        let model = std::env::temp_dir().join(format!("learn-wgpu-thumbnail-{}.obj", std::process::id()));
+        let cache = ThumbnailCache::new("thumbnails");
+        std::fs::write(&model, "v 0 0 0\n").unwrap();
+        let first = cache.path_for(&model, 64).unwrap();
+        assert!(first.starts_with("thumbnails"));
+        assert_eq!(cache.path_for(&model, 64).unwrap(), first);
+        assert_ne!(cache.path_for(&model, 128).unwrap(), first);
+
+        // Same name, different model
+        std::fs::write(&model, "v 1 0 0\n").unwrap();
+        assert_ne!(cache.path_for(&model, 64).unwrap(), first);
+        std::fs::remove_file(&model).unwrap();
+        assert!(cache.path_for(&model, 64).is_err());
+    }
+}
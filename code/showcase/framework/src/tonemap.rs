@@ -0,0 +1,246 @@
+use anyhow::*;
+use cgmath::*;
+use crate::palette::ColorBlindness;
+use crate::capabilities::is_srgb_format;
+use crate::pipeline::RenderPipelineBuilder;
+use crate::settings::RenderSettings;
+use crate::shader_matrix::*;
+use crate::texture::Texture;
+
+/// What the tonemap shader does, for checking its output on the CPU
+pub fn tonemap(linear: Vector3<f32>) -> Vector3<f32> {
+    linear.map(|c| c / (1.0 + c))
+}
+
+/// The encoding an sRGB target applies when it's written to
+pub fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.max(0.0).min(1.0);
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct TonemapUniform {
+    /// xy gets added to the pixel that's read. z is 1 when the shader
+    /// has to do the sRGB encoding itself.
+    pub offset: [i32; 4],
+    /// Applied after tonemapping. The identity unless some
+    /// [ColorBlindness] is being simulated.
+    pub color_matrix: ShaderMatrix,
+}
+
+impl TonemapUniform {
+    pub fn new(pixel: [i32; 2], encode_srgb: bool, color_blindness: Option<ColorBlindness>) -> Self {
+        let matrix = color_blindness
+            .map(|kind| Matrix4::from(kind.matrix()))
+            .unwrap_or_else(Matrix4::identity);
+        Self {
+            offset: [pixel[0], pixel[1], encode_srgb as i32, 0],
+            color_matrix: to_shader_bytes(&matrix, MatrixKind::Model),
+        }
+    }
+}
+
+unsafe impl bytemuck::Pod for TonemapUniform {}
+unsafe impl bytemuck::Zeroable for TonemapUniform {}
+
+/**
+ * Turns the HDR scene (see [Texture::create_hdr_texture]) into
+ * something the swap chain can show, in
+ * [RenderSettings::surface_format]. When that isn't sRGB the shader
+ * does the encoding, so the output looks the same either way.
+ */
+pub struct TonemapPass {
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    /// The offset is always zero, since the full screen pass reads the
+    /// pixel it's writing
+    offset_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    color_blindness: Option<ColorBlindness>,
+    output_format: wgpu::TextureFormat,
+}
+
+impl TonemapPass {
+    pub fn new(device: &wgpu::Device, hdr: &Texture, settings: &RenderSettings) -> Result<Self> {
+        let output_format = settings.surface_format;
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                ],
+                label: Some("TonemapPass::layout"),
+            }
+        );
+        // The texture's own sampler is a comparison sampler, which
+        // doesn't fit the layout
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let offset_buffer = Self::create_offset_buffer(device, output_format);
+
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&layout],
+            }
+        );
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .color_output(settings)
+            .vertex_shader(include_bytes!("shaders/tonemap.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/tonemap.frag.spv"))
+            .build(device)?;
+
+        let bind_group = Self::create_bind_group_with(device, &layout, &sampler, hdr, &offset_buffer);
+        Ok(Self { pipeline, layout, sampler, offset_buffer, bind_group, color_blindness: None, output_format })
+    }
+
+    /// Call this after recreating `hdr`, like when the window resizes
+    pub fn resize(&mut self, device: &wgpu::Device, hdr: &Texture) {
+        self.bind_group = self.create_bind_group(device, hdr, &self.offset_buffer);
+    }
+
+    /// What the pipeline was made for
+    pub fn output_format(&self) -> wgpu::TextureFormat {
+        self.output_format
+    }
+
+    /// Whether the shader has to sRGB encode, as the output doesn't
+    pub(crate) fn encodes_srgb(&self) -> bool {
+        !is_srgb_format(self.output_format)
+    }
+
+    pub fn color_blindness(&self) -> Option<ColorBlindness> {
+        self.color_blindness
+    }
+
+    /// Simulates `kind` on everything this draws from now on. `None`
+    /// turns it off.
+    pub fn set_color_blindness(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        kind: Option<ColorBlindness>,
+    ) {
+        if kind == self.color_blindness {
+            return;
+        }
+        self.color_blindness = kind;
+        let staging_buffer = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[TonemapUniform::new([0; 2], self.encodes_srgb(), kind)]),
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        encoder.copy_buffer_to_buffer(
+            &staging_buffer,
+            0,
+            &self.offset_buffer,
+            0,
+            std::mem::size_of::<TonemapUniform>() as _,
+        );
+    }
+
+    pub(crate) fn create_offset_buffer(device: &wgpu::Device, output_format: wgpu::TextureFormat) -> wgpu::Buffer {
+        device.create_buffer_with_data(
+            bytemuck::cast_slice(&[TonemapUniform::new([0; 2], !is_srgb_format(output_format), None)]),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        )
+    }
+
+    pub(crate) fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        hdr: &Texture,
+        offset_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        Self::create_bind_group_with(device, &self.layout, &self.sampler, hdr, offset_buffer)
+    }
+
+    fn create_bind_group_with(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        hdr: &Texture,
+        offset_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&hdr.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: offset_buffer,
+                        range: 0..std::mem::size_of::<TonemapUniform>() as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("TonemapPass::bind_group"),
+        })
+    }
+
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        self.draw(encoder, output, &self.bind_group);
+    }
+
+    pub(crate) fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        bind_group: &wgpu::BindGroup,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    // Every pixel gets written
+                    load_op: wgpu::LoadOp::Load,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,442 @@
+use anyhow::*;
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use crate::instance::InstanceRaw;
+use crate::material_layout::MaterialLayout;
+use crate::model::{DrawModel, ModelPass, ModelVertex};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/**
+ * How see-through things get blended, from
+ * [crate::RenderSettings::transparency].
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransparencyMode {
+    /// Whole objects drawn back to front, see [back_to_front]. Exact
+    /// as long as objects don't overlap themselves or each other in
+    /// depth, and wrong wherever they do.
+    Sorted,
+    /// Weighted blended order independent transparency (McGuire and
+    /// Bavoil, 2013). Order never matters, but the result is an
+    /// approximation that leans towards whatever is nearest.
+    WeightedBlended,
+}
+
+impl Default for TransparencyMode {
+    fn default() -> Self {
+        TransparencyMode::Sorted
+    }
+}
+
+/// The order to draw objects centered at `centers` in for
+/// [TransparencyMode::Sorted], furthest from `eye` first
+pub fn back_to_front(eye: Point3<f32>, centers: &[Point3<f32>]) -> Vec<usize> {
+    let mut order = (0..centers.len()).collect::<Vec<_>>();
+    let distance = |i: usize| (centers[i] - eye).magnitude2();
+    order.sort_by(|&a, &b| distance(b).partial_cmp(&distance(a)).unwrap_or(std::cmp::Ordering::Equal));
+    order
+}
+
+/// What glass.glsl weights a fragment `distance` from the eye with
+pub fn wboit_weight(distance: f32, alpha: f32) -> f32 {
+    let d = distance;
+    alpha * (10.0 / (1e-5 + (d / 5.0).powi(2) + (d / 200.0).powi(6))).max(1e-2).min(3e3)
+}
+
+/// One transparent fragment landing on a pixel
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TransparentFragment {
+    pub color: Vector3<f32>,
+    pub alpha: f32,
+    /// From the eye, for [wboit_weight]
+    pub distance: f32,
+}
+
+/// What [TransparencyMode::Sorted] makes of `fragments`, blended over
+/// `background` in the order given. Only right when that's back to
+/// front.
+pub fn blend_in_order(fragments: &[TransparentFragment], background: Vector3<f32>) -> Vector3<f32> {
+    fragments.iter().fold(background, |dst, f| f.color * f.alpha + dst * (1.0 - f.alpha))
+}
+
+/// What [TransparencyMode::WeightedBlended] makes of `fragments`, for
+/// checking on the CPU. The order doesn't matter.
+pub fn blend_weighted(fragments: &[TransparentFragment], background: Vector3<f32>) -> Vector3<f32> {
+    let mut accum = Vector4::zero();
+    let mut revealage = 1.0;
+    for f in fragments {
+        let weight = wboit_weight(f.distance, f.alpha);
+        accum += (f.color * f.alpha).extend(f.alpha) * weight;
+        revealage *= 1.0 - f.alpha;
+    }
+    if revealage >= 1.0 {
+        return background;
+    }
+    let average = accum.truncate() / accum.w.max(1e-5);
+    average * (1.0 - revealage) + background * revealage
+}
+
+fn blend(src_factor: wgpu::BlendFactor, dst_factor: wgpu::BlendFactor) -> wgpu::BlendDescriptor {
+    wgpu::BlendDescriptor { src_factor, dst_factor, operation: wgpu::BlendOperation::Add }
+}
+
+fn attachment(
+    view: &wgpu::TextureView,
+    load_op: wgpu::LoadOp,
+    clear_color: wgpu::Color,
+) -> wgpu::RenderPassColorAttachmentDescriptor {
+    wgpu::RenderPassColorAttachmentDescriptor {
+        attachment: view,
+        resolve_target: None,
+        load_op,
+        store_op: wgpu::StoreOp::Store,
+        clear_color,
+    }
+}
+
+struct Targets {
+    accum: Texture<'static>,
+    revealage: Texture<'static>,
+    composite_bind_group: wgpu::BindGroup,
+}
+
+/// The color of one transparent draw, from [TransparencyPass::tint]
+pub struct GlassTint {
+    pub bind_group: wgpu::BindGroup,
+}
+
+/**
+ * Draws tinted glass over the opaque scene, either sorted or with
+ * weighted blended OIT. Both test against the scene's depth without
+ * writing it, so glass has to go after everything opaque.
+ *
+ * For WBOIT the glass goes into two targets the size of the screen,
+ * which [TransparencyPass::resolve] then blends over the scene. They
+ * always have one sample. If the scene ever gets MSAA, resolve it
+ * before compositing and keep these single sampled, as the weights
+ * don't survive being averaged per sample.
+ */
+pub struct TransparencyPass {
+    tint_layout: wgpu::BindGroupLayout,
+    composite_layout: wgpu::BindGroupLayout,
+    sorted_pipeline: wgpu::RenderPipeline,
+    accum_pipeline: wgpu::RenderPipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+    sampler: wgpu::Sampler,
+    targets: Targets,
+}
+
+impl TransparencyPass {
+    pub const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+    /**
+     * `view_layout` is what the scene's view bind group uses, like
+     * [crate::UniformBinding::layout]. Glass gets drawn with the usual
+     * [DrawModel] calls, with a [GlassTint] in bind group 2.
+     */
+    pub fn new(
+        device: &wgpu::Device,
+        material_layout: &MaterialLayout,
+        view_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let tint_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+            ],
+            label: Some("TransparencyPass::tint_layout"),
+        });
+        let target_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                component_type: wgpu::TextureComponentType::Float,
+                dimension: wgpu::TextureViewDimension::D2,
+            },
+        };
+        let composite_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                target_entry(0),
+                target_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                },
+            ],
+            label: Some("TransparencyPass::composite_layout"),
+        });
+
+        let glass_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&material_layout.layout, view_layout, &tint_layout],
+        });
+        let over = || blend(wgpu::BlendFactor::SrcAlpha, wgpu::BlendFactor::OneMinusSrcAlpha);
+        let sorted_pipeline = RenderPipelineBuilder::new()
+            .layout(&glass_layout)
+            .cull_mode(wgpu::CullMode::Back)
+            .color_state(wgpu::ColorStateDescriptor {
+                format: Texture::HDR_FORMAT,
+                color_blend: over(),
+                alpha_blend: over(),
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            .depth_no_stencil(Texture::DEPTH_FORMAT, false, wgpu::CompareFunction::Less)
+            .vertex_buffer::<ModelVertex>()
+            .vertex_buffer::<InstanceRaw>()
+            .vertex_shader(include_bytes!("shaders/glass.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/glass.frag.spv"))
+            .build(device)?;
+
+        let add = || blend(wgpu::BlendFactor::One, wgpu::BlendFactor::One);
+        let reveal = || blend(wgpu::BlendFactor::Zero, wgpu::BlendFactor::OneMinusSrcColor);
+        let accum_pipeline = RenderPipelineBuilder::new()
+            .layout(&glass_layout)
+            .cull_mode(wgpu::CullMode::Back)
+            .color_state(wgpu::ColorStateDescriptor {
+                format: Self::ACCUM_FORMAT,
+                color_blend: add(),
+                alpha_blend: add(),
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            .color_state(wgpu::ColorStateDescriptor {
+                format: Self::REVEALAGE_FORMAT,
+                color_blend: reveal(),
+                alpha_blend: reveal(),
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            .depth_no_stencil(Texture::DEPTH_FORMAT, false, wgpu::CompareFunction::Less)
+            .vertex_buffer::<ModelVertex>()
+            .vertex_buffer::<InstanceRaw>()
+            .vertex_shader(include_bytes!("shaders/glass.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/glass_wboit.frag.spv"))
+            .build(device)?;
+
+        let composite_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&composite_layout],
+        });
+        let composite_pipeline = RenderPipelineBuilder::new()
+            .layout(&composite_pipeline_layout)
+            .color_state(wgpu::ColorStateDescriptor {
+                format: Texture::HDR_FORMAT,
+                color_blend: over(),
+                alpha_blend: over(),
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            // The same full screen triangle
+            .vertex_shader(include_bytes!("shaders/tonemap.vert.spv"))
+            .fragment_shader(include_bytes!("shaders/wboit_composite.frag.spv"))
+            .build(device)?;
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let targets = Self::create_targets(device, &composite_layout, &sampler, width, height);
+        Ok(Self {
+            tint_layout,
+            composite_layout,
+            sorted_pipeline,
+            accum_pipeline,
+            composite_pipeline,
+            sampler,
+            targets,
+        })
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        width: u32,
+        height: u32,
+    ) -> Targets {
+        let target = |label, format| Texture::from_descriptor(device, wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d { width, height, depth: 1 },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            // See the note on TransparencyPass about MSAA
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let accum = target("TransparencyPass::accum", Self::ACCUM_FORMAT);
+        let revealage = target("TransparencyPass::revealage", Self::REVEALAGE_FORMAT);
+        let composite_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("TransparencyPass::composite_bind_group"),
+        });
+        Targets { accum, revealage, composite_bind_group }
+    }
+
+    /// Call this when the window resizes, as the WBOIT targets have to
+    /// match the screen
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.targets = Self::create_targets(device, &self.composite_layout, &self.sampler, width, height);
+    }
+
+    /// `color` is linear, with how opaque the glass is in `w`
+    pub fn tint(&self, device: &wgpu::Device, color: Vector4<f32>) -> GlassTint {
+        let color: [f32; 4] = color.into();
+        let buffer = device.create_buffer_with_data(bytemuck::cast_slice(&color), wgpu::BufferUsage::UNIFORM);
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.tint_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &buffer,
+                        range: 0..std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    },
+                },
+            ],
+            label: Some("TransparencyPass::tint"),
+        });
+        GlassTint { bind_group }
+    }
+
+    /**
+     * Starts drawing glass. With [TransparencyMode::Sorted] it goes
+     * straight onto `output`, so draw it back to front. Either way,
+     * call [TransparencyPass::resolve] once the pass has ended.
+     */
+    pub fn begin<'b>(
+        &'b self,
+        mode: TransparencyMode,
+        encoder: &'b mut wgpu::CommandEncoder,
+        output: &'b wgpu::TextureView,
+        depth: &'b Texture,
+        material_layout: &MaterialLayout,
+    ) -> ModelPass<'b> {
+        let depth_stencil_attachment = Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+            attachment: &depth.view,
+            depth_load_op: wgpu::LoadOp::Load,
+            depth_store_op: wgpu::StoreOp::Store,
+            clear_depth: 1.0,
+            stencil_load_op: wgpu::LoadOp::Load,
+            stencil_store_op: wgpu::StoreOp::Store,
+            clear_stencil: 0,
+        });
+        let (pass, pipeline) = match mode {
+            TransparencyMode::Sorted => (
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    color_attachments: &[attachment(output, wgpu::LoadOp::Load, wgpu::Color::BLACK)],
+                    depth_stencil_attachment,
+                }),
+                &self.sorted_pipeline,
+            ),
+            TransparencyMode::WeightedBlended => (
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    color_attachments: &[
+                        attachment(&self.targets.accum.view, wgpu::LoadOp::Clear, wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }),
+                        // Everything starts out fully revealed
+                        attachment(&self.targets.revealage.view, wgpu::LoadOp::Clear, wgpu::Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 }),
+                    ],
+                    depth_stencil_attachment,
+                }),
+                &self.accum_pipeline,
+            ),
+        };
+        let mut pass = ModelPass::from(pass);
+        pass.set_model_pipeline(pipeline, material_layout);
+        pass
+    }
+
+    /// Blends the WBOIT targets over `output`. Sorted glass is already
+    /// there, so this does nothing for it.
+    pub fn resolve(&self, mode: TransparencyMode, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        if mode != TransparencyMode::WeightedBlended {
+            return;
+        }
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Load,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.composite_pipeline);
+        pass.set_bind_group(0, &self.targets.composite_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(color: (f32, f32, f32), alpha: f32, distance: f32) -> TransparentFragment {
+        TransparentFragment { color: color.into(), alpha, distance }
+    }
+
+    #[test]
+    fn intersecting_glass_breaks_sorting_but_not_wboit() {
+        // Two glass cubes poking through each other. The red one's
+        // center is further away, but at this pixel its face is in
+        // front of the blue one's.
+        let eye = Point3::new(0.0, 0.0, 0.0);
+        let centers = [Point3::new(0.0, 0.0, -4.5), Point3::new(0.3, 0.0, -4.0)];
+        let red = fragment((1.0, 0.0, 0.0), 0.5, 3.0);
+        let blue = fragment((0.0, 0.0, 1.0), 0.5, 3.5);
+        let background = Vector3::zero();
+
+        // Sorting by center draws red first, then puts blue over it
+        assert_eq!(back_to_front(eye, &centers), vec![0, 1]);
+        let sorted = blend_in_order(&[red, blue], background);
+        let correct = blend_in_order(&[blue, red], background);
+        assert!((sorted - correct).magnitude() > 0.3);
+
+        let weighted = blend_weighted(&[red, blue], background);
+        assert!((weighted - blend_weighted(&[blue, red], background)).magnitude() < 1e-6);
+        assert!((weighted - correct).magnitude() < 0.15, "{:?} vs {:?}", weighted, correct);
+        // Red is nearer, so it should come out on top
+        assert!(weighted.x > weighted.z);
+    }
+
+    #[test]
+    fn one_layer_is_exact() {
+        let background = Vector3::new(0.2, 0.4, 0.6);
+        let glass = fragment((0.9, 0.8, 0.1), 0.3, 12.0);
+        let expected = blend_in_order(&[glass], background);
+        assert!((blend_weighted(&[glass], background) - expected).magnitude() < 1e-5);
+        assert_eq!(blend_weighted(&[], background), background);
+        // Nearer counts for more, but never for nothing
+        assert!(wboit_weight(1.0, 0.5) > wboit_weight(50.0, 0.5));
+        assert!(wboit_weight(1e4, 1.0) >= 1e-2);
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use crate::model::Model;
+
+/**
+ * Shell style matching for mesh names. `*` matches any run of
+ * characters, including none, and `?` matches exactly one. Everything
+ * else has to match as is, case included.
+ */
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it has eaten
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the star have one more character and try again
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VisibilityKey {
+    /// Seconds from the start of the [Timeline]
+    pub time: f32,
+    pub visible: bool,
+}
+
+/**
+ * Shows and hides every mesh whose name matches `pattern` (see
+ * [glob_match]) at set times, like
+ * `(pattern: "engine_*", keys: [(time: 0.0, visible: false), (time: 2.5, visible: true)])`.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VisibilityTrack {
+    pub pattern: String,
+    /// In any order
+    pub keys: Vec<VisibilityKey>,
+}
+
+impl VisibilityTrack {
+    pub fn matches(&self, mesh: &str) -> bool {
+        glob_match(&self.pattern, mesh)
+    }
+
+    /**
+     * What the last key at or before `time` says. Only the time
+     * matters, not how it got there, so scrubbing backwards gives the
+     * same answer as playing up to it. `None` before the first key.
+     */
+    pub fn visible_at(&self, time: f32) -> Option<bool> {
+        self.keys.iter()
+            .filter(|key| key.time <= time)
+            // Later keys win ties, so a track can flip at the same time
+            .fold(None, |last: Option<&VisibilityKey>, key| match last {
+                Some(last) if last.time > key.time => Some(last),
+                _ => Some(key),
+            })
+            .map(|key| key.visible)
+    }
+}
+
+/**
+ * The clock [VisibilityTrack]s play against. Stepping pauses, and moves
+ * straight to the next or previous key, which is what presenting one
+ * step at a time wants.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timeline {
+    pub time: f32,
+    pub paused: bool,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self { time: 0.0, paused: false }
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        if !self.paused {
+            self.time += dt;
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn seek(&mut self, time: f32) {
+        self.time = time.max(0.0);
+    }
+
+    /// Pauses on the first key after now. Returns whether there was one.
+    pub fn step_forward(&mut self, tracks: &[VisibilityTrack]) -> bool {
+        self.paused = true;
+        let next = key_times(tracks)
+            .filter(|&t| t > self.time)
+            .fold(None, |next: Option<f32>, t| Some(next.map_or(t, |n| n.min(t))));
+        match next {
+            Some(t) => {
+                self.seek(t);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pauses on the last key before now, or the start if there isn't
+    /// one
+    pub fn step_back(&mut self, tracks: &[VisibilityTrack]) {
+        self.paused = true;
+        let previous = key_times(tracks)
+            .filter(|&t| t < self.time)
+            .fold(None, |previous: Option<f32>, t| Some(previous.map_or(t, |p| p.max(t))));
+        self.seek(previous.unwrap_or(0.0));
+    }
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn key_times(tracks: &[VisibilityTrack]) -> impl Iterator<Item = f32> + '_ {
+    tracks.iter().flat_map(|track| track.keys.iter().map(|key| key.time))
+}
+
+/**
+ * Which meshes of a model get drawn. Meshes shown or hidden by hand
+ * stay that way whatever the tracks say, until
+ * [MeshVisibility::reset]. Anything neither has touched is visible.
+ *
+ * Meshes are kept by name, so the parts of a split mesh (see
+ * [crate::split_mesh]) always go together.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MeshVisibility {
+    manual: HashMap<String, bool>,
+}
+
+impl MeshVisibility {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, mesh: &str, visible: bool) {
+        self.manual.insert(mesh.to_string(), visible);
+    }
+
+    pub fn is_manual(&self, mesh: &str) -> bool {
+        self.manual.contains_key(mesh)
+    }
+
+    /// Hands every mesh back to the tracks
+    pub fn reset(&mut self) {
+        self.manual.clear();
+    }
+
+    /// Later tracks win over earlier ones that match the same mesh
+    pub fn evaluate(&self, mesh: &str, tracks: &[VisibilityTrack], time: f32) -> bool {
+        if let Some(&visible) = self.manual.get(mesh) {
+            return visible;
+        }
+        tracks.iter()
+            .filter(|track| track.matches(mesh))
+            .filter_map(|track| track.visible_at(time))
+            .last()
+            .unwrap_or(true)
+    }
+
+    /// Sets [crate::Mesh::visible] on each of `model`'s meshes
+    pub fn apply(&self, model: &mut Model, tracks: &[VisibilityTrack], time: f32) {
+        for mesh in &mut model.meshes {
+            mesh.visible = self.evaluate(&mesh.name, tracks, time);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(pattern: &str, keys: &[(f32, bool)]) -> VisibilityTrack {
+        VisibilityTrack {
+            pattern: pattern.to_string(),
+            keys: keys.iter().map(|&(time, visible)| VisibilityKey { time, visible }).collect(),
+        }
+    }
+
+    #[test]
+    fn globs_match_mesh_names() {
+        assert!(glob_match("engine_*", "engine_block"));
+        assert!(glob_match("engine_*", "engine_"));
+        assert!(!glob_match("engine_*", "engine"));
+        assert!(!glob_match("engine_*", "Engine_block"));
+        assert!(glob_match("*_cover", "engine_top_cover"));
+        assert!(glob_match("wheel_?", "wheel_3"));
+        assert!(!glob_match("wheel_?", "wheel_12"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxbxxa"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("exact", "exact"));
+    }
+
+    #[test]
+    fn tracks_scrub_both_ways_and_manual_wins() {
+        let tracks = vec![
+            track("engine_*", &[(2.0, true), (0.0, false), (5.0, false)]),
+            // Later tracks win
+            track("engine_cover", &[(3.0, false)]),
+        ];
+        let visibility = MeshVisibility::new();
+        let at = |time: f32| visibility.evaluate("engine_cover", &tracks, time);
+        let forwards = (0..70).map(|i| at(i as f32 * 0.1)).collect::<Vec<_>>();
+        let backwards = (0..70).rev().map(|i| at(i as f32 * 0.1)).collect::<Vec<_>>();
+        assert_eq!(forwards, backwards.into_iter().rev().collect::<Vec<_>>());
+        assert!(!at(1.0));
+        assert!(at(2.5));
+        assert!(!at(3.0));
+        // Untouched meshes stay visible
+        assert!(visibility.evaluate("chassis", &tracks, 1.0));
+        assert!(visibility.evaluate("engine_block", &tracks, -1.0));
+
+        let mut visibility = MeshVisibility::new();
+        visibility.set("engine_block", true);
+        assert!(visibility.evaluate("engine_block", &tracks, 1.0));
+        assert!(visibility.evaluate("engine_block", &tracks, 6.0));
+        visibility.reset();
+        assert!(!visibility.evaluate("engine_block", &tracks, 1.0));
+
+        let mut timeline = Timeline::new();
+        timeline.seek(2.5);
+        assert!(timeline.step_forward(&tracks));
+        assert_eq!((timeline.time, timeline.paused), (3.0, true));
+        timeline.step_back(&tracks);
+        timeline.step_back(&tracks);
+        assert_eq!(timeline.time, 0.0);
+        timeline.seek(5.0);
+        assert!(!timeline.step_forward(&tracks));
+        assert_eq!(timeline.time, 5.0);
+    }
+}
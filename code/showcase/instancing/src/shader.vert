@@ -3,8 +3,7 @@
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec2 a_tex_coords;
 layout(location=2) in vec3 a_normal;
-layout(location=3) in vec3 a_tangent;
-layout(location=4) in vec3 a_bitangent;
+layout(location=3) in vec4 a_tangent;
 
 layout(location=0) out vec2 v_tex_coords;
 layout(location=1) out vec3 v_position;
@@ -35,8 +34,8 @@ void main() {
 
     mat3 normal_matrix = mat3(transpose(inverse(model_matrix)));
     vec3 normal = normalize(normal_matrix * a_normal);
-    vec3 tangent = normalize(normal_matrix * a_tangent);
-    vec3 bitangent = normalize(normal_matrix * a_bitangent);
+    vec3 tangent = normalize(normal_matrix * a_tangent.xyz);
+    vec3 bitangent = cross(normal, tangent) * a_tangent.w;
 
     // UDPATED!
     mat3 tangent_matrix = transpose(mat3(
@@ -15,6 +15,25 @@ struct InstanceData {
 unsafe impl bytemuck::Pod for InstanceData {}
 unsafe impl bytemuck::Zeroable for InstanceData {}
 
+const NUM_INSTANCES: u32 = 100;
+const RADIUS: f32 = 50.0;
+
+/// Scatters the cubes randomly, but the same way every time for a
+/// given seed
+fn generate_instances(seed: framework::Seed) -> Vec<InstanceData> {
+    let mut rng = seed.derive("instances").rng();
+    (0..NUM_INSTANCES).map(|_| {
+        let position = Vector3::new(
+            rng.gen_range(-RADIUS, RADIUS),
+            rng.gen_range(-RADIUS, RADIUS),
+            rng.gen_range(-RADIUS, RADIUS),
+        );
+        let model_matrix = Matrix4::from_translation(position);
+
+        InstanceData { model_matrix }
+    }).collect::<Vec<_>>()
+}
+
 struct StorageBuffersDemo<'a> {
     depth_texture: framework::Texture<'a>,
     cube_model: framework::Model<'a>,
@@ -31,41 +50,14 @@ struct StorageBuffersDemo<'a> {
 
 impl framework::Demo for StorageBuffersDemo<'static> {
     fn init(display: &framework::Display) -> Result<Self> {
-        let texture_layout = display.device.create_bind_group_layout(
-            &wgpu::BindGroupLayoutDescriptor {
-                bindings: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::SampledTexture {
-                            multisampled: false,
-                            component_type: wgpu::TextureComponentType::Float,
-                            dimension: wgpu::TextureViewDimension::D2,
-                        }
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler { comparison: false },
-                    },
-                    // normal map
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::SampledTexture {
-                            multisampled: false,
-                            component_type: wgpu::TextureComponentType::Float,
-                            dimension: wgpu::TextureViewDimension::D2,
-                        },
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler { comparison: false },
-                    },
-                ],
-                label: Some("texture_layout")
-            }
+        // There's no point falling back to a vertex buffer, since
+        // showing off storage buffers is what this demo is for
+        if let Some(reason) = display.caps.instance_storage().reason {
+            anyhow::bail!("This demo needs storage buffers in the vertex shader: {}", reason);
+        }
+        let texture_layout = framework::MaterialLayout::new(
+            &display.device,
+            Default::default(),
         );
 
         let depth_texture = framework::Texture::create_depth_texture(&display.device, &display.sc_desc);
@@ -98,19 +90,13 @@ impl framework::Demo for StorageBuffersDemo<'static> {
         uniforms.update_buffer(&display.device, &mut encoder);
         let uniform_binding = framework::UniformBinding::new(&display.device, &uniforms);
 
-        const NUM_INSTANCES: u32 = 100;
-        const RADIUS: f32 = 50.0;
-        let instances = (0..NUM_INSTANCES).map(|_| {
-            let mut rng = rand::thread_rng();
-            let position = Vector3::new(
-                rng.gen_range(-RADIUS, RADIUS),
-                rng.gen_range(-RADIUS, RADIUS),
-                rng.gen_range(-RADIUS, RADIUS),
-            );
-            let model_matrix = Matrix4::from_translation(position);
-
-            InstanceData { model_matrix }
-        }).collect::<Vec<_>>();
+        let seed = std::env::args()
+            .skip_while(|a| a != "--seed")
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .map(framework::Seed)
+            .unwrap_or_default();
+        let instances = generate_instances(seed);
         let instance_buffer = display.device.create_buffer_with_data(
             bytemuck::cast_slice(&instances),
             wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::STORAGE_READ,
@@ -150,7 +136,7 @@ impl framework::Demo for StorageBuffersDemo<'static> {
         let model_layout = display.device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
                 bind_group_layouts: &[
-                    &texture_layout,
+                    &texture_layout.layout,
                     &uniform_binding.layout, 
                     &instance_layout,
                 ],
@@ -160,7 +146,7 @@ impl framework::Demo for StorageBuffersDemo<'static> {
         let model_pipeline = framework::RenderPipelineBuilder::new()
             .layout(&model_layout)
             .depth_format(framework::Texture::DEPTH_FORMAT)
-            .color_solid(display.sc_desc.format)
+            .color_output(&framework::RenderSettings::for_display(display))
             .vertex_buffer::<framework::ModelVertex>()
             .vertex_shader(include_bytes!("shader.vert.spv"))
             .fragment_shader(include_bytes!("shader.frag.spv"))
@@ -258,5 +244,24 @@ impl framework::Demo for StorageBuffersDemo<'static> {
 
 
 fn main() -> Result<()> {
+    env_logger::init();
     futures::executor::block_on(framework::run::<StorageBuffersDemo>())
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instances_are_deterministic() {
+        let a = generate_instances(framework::Seed(7));
+        let b = generate_instances(framework::Seed(7));
+        let c = generate_instances(framework::Seed(8));
+        let a_bytes: &[u8] = bytemuck::cast_slice(&a);
+        let b_bytes: &[u8] = bytemuck::cast_slice(&b);
+        let c_bytes: &[u8] = bytemuck::cast_slice(&c);
+        assert_eq!(a_bytes, b_bytes);
+        assert_ne!(a_bytes, c_bytes);
+    }
+}
@@ -46,15 +46,25 @@ impl Render {
             wgpu::BackendBit::PRIMARY,
         ).await.unwrap();
 
-        let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
+        let device_desc = wgpu::DeviceDescriptor {
             extensions: Default::default(),
             limits: Default::default(),
-        }).await;
+        };
+        let (device, queue) = adapter.request_device(&device_desc).await;
+
+        // The same format the showcase demos pick, see
+        // framework::SURFACE_FORMAT_PREFERENCE
+        let caps = framework::DeviceCapabilities::new(&adapter, &device_desc);
+        let settings = framework::RenderSettings {
+            surface_format: caps.surface_format().get("surface format"),
+            ..Default::default()
+        };
+        println!("Surface format: {:?}", settings.surface_format);
 
         let size = video_mode.size();
         let sc_desc = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
-            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            format: settings.surface_format,
             width: size.width,
             height: size.height,
             present_mode: wgpu::PresentMode::Fifo,
@@ -67,7 +77,7 @@ impl Render {
         let pipeline = create_render_pipeline(
             &device, 
             &pipeline_layout, 
-            sc_desc.format, 
+            &settings, 
             &[Vertex::DESC], 
             include_str!("../../res/shaders/textured.vert"), 
             include_str!("../../res/shaders/textured.frag"),
@@ -87,7 +97,7 @@ impl Render {
 
         let font = wgpu_glyph::Font::from_bytes(FONT_BYTES).unwrap();
         let glyph_brush = wgpu_glyph::GlyphBrushBuilder::using_font(font)
-            .build(&device, sc_desc.format);
+            .build(&device, settings.surface_format);
 
         Self {
             surface,
@@ -215,7 +225,7 @@ fn draw_text(
 fn create_render_pipeline(
     device: &wgpu::Device, 
     layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
+    settings: &framework::RenderSettings,
     vertex_descs: &[wgpu::VertexBufferDescriptor], 
     vs_src: &str, 
     fs_src: &str,
@@ -247,7 +257,7 @@ fn create_render_pipeline(
         }),
         primitive_topology: wgpu::PrimitiveTopology::TriangleList,
         color_states: &[wgpu::ColorStateDescriptor {
-            format: color_format,
+            format: settings.surface_format,
             color_blend: wgpu::BlendDescriptor::REPLACE,
             alpha_blend: wgpu::BlendDescriptor::REPLACE,
             write_mask: wgpu::ColorWrite::ALL,
@@ -0,0 +1,138 @@
+use framework::{InputMap, KeyBinding};
+use winit::event::VirtualKeyCode;
+
+/**
+ * Everything the viewer can do from the keyboard. Keys are mapped
+ * to these through an [InputMap] so they can be rebound.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    ViewFront,
+    ViewBack,
+    ViewRight,
+    ViewLeft,
+    ViewTop,
+    ViewBottom,
+    ToggleProjection,
+    ResetView,
+    CaptureTrace,
+    DumpFrame,
+    SaveSnapshot(usize),
+    RestoreSnapshot(usize),
+    DeleteSelection,
+    ToggleOcclusionCulling,
+    ToggleVsync,
+    CycleFrameLimit,
+    PrintCullStats,
+    /// How much of the texture budget is in use, how much got reduced
+    /// to fit, and how big the models' buffers are
+    PrintTextureMemory,
+    ToggleWave,
+    ListRecent,
+    InspectModel,
+    OpenRecent(usize),
+    ToggleMinimap,
+    /// Moves the sun an hour, when the scene has one
+    SunEarlier,
+    SunLater,
+    ToggleDayCycle,
+    /// Swaps the debug colors between the classic and color blind safe
+    /// palettes
+    TogglePalette,
+    /// Shows the frame as each kind of color blindness would see it,
+    /// then normally again
+    CycleColorBlindness,
+    /// Swaps the scene's ambient gradient for the flat default and
+    /// back, for comparing the two
+    ToggleAmbientGradient,
+    /// Stops opening a model, keeping the one that's already open
+    CancelLoad,
+    /// Hides the mesh under the cursor, whatever the scene's
+    /// visibility tracks say
+    HideMeshUnderCursor,
+    /// Hands every mesh hidden by hand back to the visibility tracks
+    ResetMeshVisibility,
+    ToggleTimeline,
+    /// Pause and jump to the previous or next visibility key
+    TimelineBack,
+    TimelineForward,
+    /// Turns two sided lighting on the quad off and on again
+    ToggleTwoSidedLighting,
+    /// Heatmap of how many fragments get shaded per pixel
+    ToggleOverdraw,
+    /// Between sorted and weighted blended glass
+    ToggleTransparencyMode,
+    ToggleContactShadows,
+    /// Off, camera only, then per object
+    CycleMotionBlur,
+    /// The order the render graph runs its passes in at the moment
+    PrintRenderGraph,
+}
+
+/// Blender style numpad bindings. The number row works too for
+/// keyboards without a numpad, so snapshots only get the numbers the
+/// view presets leave alone, and Shift for the rest.
+pub fn default_bindings() -> InputMap<Action> {
+    use VirtualKeyCode::*;
+    let mut map = InputMap::new();
+    for &(numpad, row, action, opposite) in &[
+        (Numpad1, Key1, Action::ViewFront, Action::ViewBack),
+        (Numpad3, Key3, Action::ViewRight, Action::ViewLeft),
+        (Numpad7, Key7, Action::ViewTop, Action::ViewBottom),
+    ] {
+        for &key in &[numpad, row] {
+            map.bind(KeyBinding::key(key), action);
+            map.bind(KeyBinding::ctrl(key), opposite);
+        }
+    }
+    map.bind(KeyBinding::key(Numpad5), Action::ToggleProjection);
+    map.bind(KeyBinding::key(Key5), Action::ToggleProjection);
+    map.bind(KeyBinding::key(Home), Action::ResetView);
+    map.bind(KeyBinding::key(F9), Action::CaptureTrace);
+    map.bind(KeyBinding::key(F10), Action::DumpFrame);
+    map.bind(KeyBinding::key(Delete), Action::DeleteSelection);
+    map.bind(KeyBinding::key(F2), Action::ListRecent);
+    map.bind(KeyBinding::key(F3), Action::ToggleVsync);
+    map.bind(KeyBinding::key(F4), Action::CycleFrameLimit);
+    map.bind(KeyBinding::key(F5), Action::InspectModel);
+    map.bind(KeyBinding::key(F6), Action::ToggleWave);
+    map.bind(KeyBinding::key(F7), Action::ToggleOcclusionCulling);
+    map.bind(KeyBinding::key(F8), Action::PrintCullStats);
+    map.bind(KeyBinding::key(F12), Action::PrintTextureMemory);
+    map.bind(KeyBinding::key(Escape), Action::CancelLoad);
+    map.bind(KeyBinding::key(M), Action::ToggleMinimap);
+    map.bind(KeyBinding::key(LBracket), Action::SunEarlier);
+    map.bind(KeyBinding::key(RBracket), Action::SunLater);
+    map.bind(KeyBinding::key(T), Action::ToggleDayCycle);
+    map.bind(KeyBinding::key(P), Action::TogglePalette);
+    map.bind(KeyBinding::key(F11), Action::CycleColorBlindness);
+    map.bind(KeyBinding::key(G), Action::ToggleAmbientGradient);
+    map.bind(KeyBinding::key(H), Action::HideMeshUnderCursor);
+    map.bind(KeyBinding::ctrl(H), Action::ResetMeshVisibility);
+    map.bind(KeyBinding::key(K), Action::ToggleTimeline);
+    map.bind(KeyBinding::key(J), Action::TimelineBack);
+    map.bind(KeyBinding::key(L), Action::TimelineForward);
+    map.bind(KeyBinding::key(B), Action::ToggleTwoSidedLighting);
+    map.bind(KeyBinding::key(O), Action::ToggleOverdraw);
+    map.bind(KeyBinding::key(U), Action::ToggleTransparencyMode);
+    map.bind(KeyBinding::key(C), Action::ToggleContactShadows);
+    map.bind(KeyBinding::key(V), Action::CycleMotionBlur);
+    map.bind(KeyBinding::key(F1), Action::PrintRenderGraph);
+
+    // Alt+number opens the recent files listed by F2
+    for (index, &key) in [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8].iter().enumerate() {
+        map.bind(KeyBinding::alt(key), Action::OpenRecent(index));
+    }
+
+    for (slot, &key) in [Key1, Key2, Key3, Key4].iter().enumerate() {
+        // Only take keys nobody else wants
+        let (restore, save) = if map.is_bound(&KeyBinding::key(key)) || map.is_bound(&KeyBinding::ctrl(key)) {
+            (KeyBinding::shift(key), KeyBinding { ctrl: true, ..KeyBinding::shift(key) })
+        } else {
+            (KeyBinding::key(key), KeyBinding::ctrl(key))
+        };
+        map.bind(restore, Action::RestoreSnapshot(slot));
+        map.bind(save, Action::SaveSnapshot(slot));
+    }
+    map
+}
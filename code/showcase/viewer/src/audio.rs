@@ -0,0 +1,65 @@
+//! Only built with the `audio` feature. Shows off the event bus by
+//! clicking whenever something gets picked, panned towards where it is.
+
+use cgmath::*;
+use rodio::Source;
+
+const SAMPLE_RATE: u32 = 44100;
+/// How long a click rings for
+const CLICK_SECONDS: f32 = 0.04;
+/// Picks further than this from the camera come out at half volume
+const HALF_VOLUME_DISTANCE: f32 = 10.0;
+
+pub struct PickSounds {
+    device: rodio::Device,
+    // One click, in mono. Panning only scales it, so it's made once
+    // and every play shares the samples.
+    click: rodio::source::Buffered<rodio::buffer::SamplesBuffer<f32>>,
+}
+
+impl PickSounds {
+    /// None when there's nothing to play sound through
+    pub fn new() -> Option<Self> {
+        let device = rodio::default_output_device()?;
+        let len = (SAMPLE_RATE as f32 * CLICK_SECONDS) as usize;
+        let samples = (0..len)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                // A short blip that dies off quickly
+                (t * 2000.0 * std::f32::consts::PI * 2.0).sin() * (-t * 120.0).exp() * 0.5
+            })
+            .collect::<Vec<_>>();
+        let click = rodio::buffer::SamplesBuffer::new(1, SAMPLE_RATE, samples).buffered();
+        Some(Self { device, click })
+    }
+
+    pub fn on_event(&self, event: &framework::DemoEvent, camera: &framework::OrbitCamera) {
+        if let framework::DemoEvent::ObjectPicked { world_pos, .. } = event {
+            let (left, right) = pan(camera, *world_pos);
+            let click = rodio::source::ChannelVolume::new(self.click.clone(), vec![left, right]);
+            rodio::play_raw(&self.device, click);
+        }
+    }
+}
+
+/**
+ * Left and right volumes for a sound at `position`. Constant power
+ * panning, so it's as loud in the middle as off to one side, from how
+ * far `position` is to the camera's right. Nothing fancier than that:
+ * things behind the camera sound the same as things in front.
+ */
+fn pan(camera: &framework::OrbitCamera, position: Point3<f32>) -> (f32, f32) {
+    let eye = camera.eye();
+    let offset = position - eye;
+    let distance = offset.magnitude();
+    if distance < 1e-4 {
+        return (0.5f32.sqrt(), 0.5f32.sqrt());
+    }
+    let forward = (camera.target - eye).normalize();
+    let right = forward.cross(Vector3::unit_y()).normalize();
+    // -1 is all the way left, 1 all the way right
+    let side = (offset / distance).dot(right).max(-1.0).min(1.0);
+    let angle = (side + 1.0) * std::f32::consts::FRAC_PI_4;
+    let volume = HALF_VOLUME_DISTANCE / (HALF_VOLUME_DISTANCE + distance);
+    (angle.cos() * volume, angle.sin() * volume)
+}
@@ -0,0 +1,49 @@
+use cgmath::*;
+use std::time::Duration;
+
+/**
+ * A `--bench-startup` run in progress. The viewer starts up with the
+ * bench's scene and settings, then draws [framework::BenchConfig::frames]
+ * frames at a fixed dt while the profiler records all of it, from the
+ * first load stage on.
+ */
+pub struct BenchRun {
+    pub config: framework::BenchConfig,
+    peak_gpu_bytes: u64,
+}
+
+impl BenchRun {
+    pub fn new(config: framework::BenchConfig) -> Self {
+        Self { config, peak_gpu_bytes: 0 }
+    }
+
+    pub fn dt(&self) -> Duration {
+        Duration::from_secs_f32(self.config.dt.max(0.0))
+    }
+
+    /// How far to orbit the camera each frame when there's no camera
+    /// path, which goes round the scene once over the run
+    pub fn orbit_step(&self) -> Option<Rad<f32>> {
+        match self.config.camera_path {
+            Some(_) => None,
+            None => Some(Rad::full_turn() / self.config.frames.max(1) as f32),
+        }
+    }
+
+    pub fn track_memory(&mut self, bytes: u64) {
+        self.peak_gpu_bytes = self.peak_gpu_bytes.max(bytes);
+    }
+
+    /// Prints the summary and saves the report, returning what to exit
+    /// with
+    pub fn finish(&self, trace: &framework::ChromeTrace) -> i32 {
+        let report = framework::BenchReport::new(&self.config, trace, self.peak_gpu_bytes);
+        println!("{}", report);
+        if let Err(e) = report.save(&self.config.report) {
+            eprintln!("{:?}", e);
+            return 2;
+        }
+        println!("Saved the report to {}", self.config.report.display());
+        if report.passed() { 0 } else { 1 }
+    }
+}
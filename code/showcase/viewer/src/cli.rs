@@ -0,0 +1,132 @@
+use anyhow::*;
+use std::path::PathBuf;
+
+/**
+ * Command line flags for the viewer. There are few enough that
+ * pulling in an argument parsing crate isn't worth it.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct Args {
+    pub seed: Option<framework::Seed>,
+    /// Save the first frame's textures, like pressing F10
+    pub dump_frame: bool,
+    /// Render a thumbnail of every model in this folder and exit
+    pub thumbnails: Option<PathBuf>,
+    /// `--fps 90` turns vsync off and limits to 90, `--fps off` turns
+    /// vsync off and runs uncapped
+    pub fps: Option<framework::FrameLimit>,
+    /// Print what the loader makes of this model as JSON and exit
+    pub inspect: Option<String>,
+    /// Print what the GPU can do and exit, for bug reports
+    pub print_caps: bool,
+    /// Pack the occlusion, roughness and metalness maps of every
+    /// material in this MTL file, print the scene overrides that use
+    /// them and exit
+    pub pack_textures: Option<PathBuf>,
+    /// Write this many randomly scattered instances to a file, for
+    /// [framework::SceneDesc::instance_files], and exit
+    pub generate_instances: Option<(PathBuf, u64)>,
+    /// Write everything we do with the mouse and keyboard to this file
+    pub record_input: Option<PathBuf>,
+    /// Act on what's in this file instead of the mouse and keyboard.
+    /// Pass the same `--seed` as the recording for the same session.
+    pub replay_input: Option<PathBuf>,
+    /// Replay as fast as frames can be drawn, instead of at the speed
+    /// it was recorded at
+    pub replay_fast: bool,
+    /// Texture memory to stay under in MB, instead of guessing from
+    /// the device. Tiny values are handy for testing reduced textures.
+    pub texture_budget: Option<u64>,
+    /// Load the scene, draw a scripted run of frames, print how long
+    /// everything took and exit, nonzero if anything was too slow
+    pub bench_startup: bool,
+    /// What the bench runs, instead of res/bench.ron. Implies
+    /// `--bench-startup`.
+    pub bench_config: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut result = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--seed" => {
+                    let value = args.next().context("--seed needs a value")?;
+                    result.seed = Some(framework::Seed(value.parse()
+                        .with_context(|| format!("Invalid seed: {}", value))?));
+                }
+                "--dump-frame" => result.dump_frame = true,
+                "--replay-fast" => result.replay_fast = true,
+                "--record-input" => {
+                    let value = args.next().context("--record-input needs a file")?;
+                    result.record_input = Some(PathBuf::from(value));
+                }
+                "--replay-input" => {
+                    let value = args.next().context("--replay-input needs a file")?;
+                    result.replay_input = Some(PathBuf::from(value));
+                }
+                "--print-caps" => result.print_caps = true,
+                "--bench-startup" => result.bench_startup = true,
+                "--bench-config" => {
+                    let value = args.next().context("--bench-config needs a file")?;
+                    result.bench_config = Some(PathBuf::from(value));
+                    result.bench_startup = true;
+                }
+                "--texture-budget" => {
+                    let value = args.next().context("--texture-budget needs a size in MB")?;
+                    result.texture_budget = Some(value.parse()
+                        .with_context(|| format!("Invalid texture budget: {}", value))?);
+                }
+                "--thumbnails" => {
+                    let value = args.next().context("--thumbnails needs a folder")?;
+                    result.thumbnails = Some(PathBuf::from(value));
+                }
+                "--pack-textures" => {
+                    let value = args.next().context("--pack-textures needs an MTL file")?;
+                    result.pack_textures = Some(PathBuf::from(value));
+                }
+                "--generate-instances" => {
+                    let path = args.next().context("--generate-instances needs a file")?;
+                    let count = args.next().context("--generate-instances needs a count")?;
+                    let count = count.parse()
+                        .with_context(|| format!("Invalid instance count: {}", count))?;
+                    result.generate_instances = Some((PathBuf::from(path), count));
+                }
+                "--inspect" => {
+                    result.inspect = Some(args.next().context("--inspect needs a model")?);
+                }
+                "--fps" => {
+                    let value = args.next().context("--fps needs a value")?;
+                    result.fps = Some(match value.as_str() {
+                        "off" => framework::FrameLimit::Off,
+                        _ => framework::FrameLimit::Custom(value.parse()
+                            .with_context(|| format!("Invalid frame rate: {}", value))?),
+                    });
+                }
+                _ => bail!("Unknown argument: {}", arg),
+            }
+        }
+        ensure!(
+            result.record_input.is_none() || result.replay_input.is_none(),
+            "--record-input and --replay-input can't be used together",
+        );
+        ensure!(
+            !result.bench_startup || (result.record_input.is_none() && result.replay_input.is_none()),
+            "--bench-startup replays the camera path from its config, so it can't be used with \
+             --record-input or --replay-input",
+        );
+        Ok(result)
+    }
+
+    pub fn input_session(&self) -> Option<framework::InputSession> {
+        if let Some(path) = &self.replay_input {
+            return Some(framework::InputSession::Replay { path: path.clone(), fast: self.replay_fast });
+        }
+        self.record_input.clone().map(framework::InputSession::Record)
+    }
+}
@@ -0,0 +1,2164 @@
+mod actions;
+#[cfg(feature = "audio")]
+mod audio;
+mod bench;
+mod cli;
+mod session;
+mod snapshot;
+mod thumbnails;
+mod wave;
+
+use actions::Action;
+use anyhow::Result;
+use cgmath::*;
+use std::path::Path;
+use std::time::Duration;
+use winit::event::*;
+use framework::prelude::*;
+
+type InstanceBuffer = framework::Buffer<framework::Instance, framework::InstanceRaw>;
+
+struct Viewer<'a> {
+    depth_texture: framework::Texture<'a>,
+    // The scene gets drawn here, then tonemapped onto the swap chain
+    hdr_texture: framework::Texture<'a>,
+    tonemap: framework::TonemapPass,
+    // Holding Alt prints what's under the cursor
+    probe: framework::PixelProbe,
+    // Saves this frame's textures once it's been drawn
+    dump_requested: bool,
+    texture_layout: framework::MaterialLayout,
+    // Every model's textures, kept under the texture budget
+    textures: framework::TextureCache<'a>,
+    assets: framework::AssetManager,
+    cube_model: framework::Model<'a>,
+    // The same brick material as the cube, but with triplanar
+    // projection forced on so we can put it on models that don't
+    // have materials of their own.
+    triplanar_cube: framework::Model<'a>,
+    // Another cube with the same textures, but with the scene's
+    // material instances swapped in. Only here to show them off, so it
+    // isn't culled or pickable.
+    shiny_cube: framework::Model<'a>,
+    shiny_instances: InstanceBuffer,
+    // One quad that the light shines through, for two sided lighting.
+    // Not culled or pickable either.
+    quad: framework::Model<'a>,
+    quad_instances: InstanceBuffer,
+    // Two glass cubes poking through each other, which sorting gets
+    // wrong and WBOIT doesn't
+    transparency: framework::TransparencyPass,
+    glass_instances: InstanceBuffer,
+    glass_tints: Vec<framework::GlassTint>,
+    // Whatever was opened last. This starts out as the bundled torus.
+    opened_model: framework::Model<'a>,
+    // Where `opened_model` came from, which is also its name in
+    // `resources`
+    opened_path: String,
+    model_pipeline: wgpu::RenderPipeline,
+    // The same shaders, for views without a velocity buffer
+    minimap_pipeline: wgpu::RenderPipeline,
+    cube_instances: InstanceBuffer,
+    opened_instances: InstanceBuffer,
+    // Cubes from the scene's instance files. There can be millions, so
+    // they're only drawn by chunk and never picked or edited.
+    streamed_cubes: Vec<framework::StreamedInstances>,
+    uniforms: framework::Uniforms,
+    uniform_binding: framework::UniformBinding,
+    light: framework::Light,
+    frame_uniforms: framework::FrameUniforms,
+    frame_binding: framework::FrameBinding,
+    point_lights: framework::LightList,
+    cube_lights: framework::ObjectLights,
+    opened_lights: framework::ObjectLights,
+    camera: framework::OrbitCamera,
+    // Where Home takes us back to
+    home_camera: framework::OrbitCamera,
+    transition: Option<framework::OrbitTransition>,
+    projection: framework::Projection,
+    input_map: framework::InputMap<Action>,
+    mouse_pressed: bool,
+    cursor_position: (f32, f32),
+    // Where the left button went down, to tell clicks from drags
+    press_position: (f32, f32),
+    // Shift-dragging draws a selection box instead of orbiting
+    box_selecting: bool,
+    settings: framework::RenderSettings,
+    scene: framework::SceneDesc,
+    blob_shadows: framework::BlobShadows,
+    sky: framework::SkyPass,
+    // Moves the main light and the sky's sun when the scene has one
+    sun: Option<framework::SunDesc>,
+    // Stops the day going by on its own. [ and ] still work.
+    sun_paused: bool,
+    // What the scene's visibility tracks play against
+    timeline: framework::Timeline,
+    // Meshes hidden by hand, for the cubes and the opened model in the
+    // same order as the pick targets
+    mesh_visibility: [framework::MeshVisibility; 2],
+    hiz: framework::HiZPyramid,
+    culler: framework::HiZCuller,
+    cube_cull: framework::CullBatch,
+    opened_cull: framework::CullBatch,
+    // Set when the opened model had copies folded into placements.
+    // These aren't culled, as the batch only knows about one box per
+    // mesh.
+    opened_placements: Option<framework::PlacementBuffers>,
+    // A top down view in the corner. Clicking it moves the camera.
+    minimap: framework::Minimap<'a>,
+    show_minimap: bool,
+    // Counts fragments per screen tile every so often, drawn as a
+    // heatmap over the scene
+    overdraw: framework::OverdrawCounter,
+    show_overdraw: bool,
+    // Depth prepass for the short shadows where things touch
+    contact_shadows: framework::ContactShadows,
+    // Owns the velocity buffer the main pass writes
+    motion_blur: framework::MotionBlurPass,
+    // A model being opened in the background
+    loading: Option<framework::LoadHandle>,
+    // How far it's got, for the window title
+    load_progress: Option<framework::LoadProgress>,
+    // Opened meshes bigger than this get split, from the device's caps
+    max_buffer_size: u64,
+    // Wobbles the opened model with a compute shader
+    wave: wave::WavePass,
+    // Every pass of a frame, see Viewer::render_graph
+    graph: framework::RenderGraph<Viewer<'a>>,
+    profiler: framework::Profiler,
+    // Set for --bench-startup, which exits once it's done
+    bench: Option<bench::BenchRun>,
+    // Buffers of every model, by name. Textures are in `textures`.
+    resources: framework::ResourceTracker,
+    selection: framework::Selection,
+    // Picks, selection changes, loads and light moves, handed out at
+    // the end of every update
+    events: framework::EventBus,
+    // Clicks when something's picked, if there's a sound device
+    #[cfg(feature = "audio")]
+    pick_sounds: Option<audio::PickSounds>,
+    snapshots: snapshot::Snapshots,
+    session: session::Session,
+    // Winit only tells us when the window moves, so this is the last
+    // position we heard about
+    window_position: Option<(i32, i32)>,
+}
+
+/// How many frames F9 records
+const TRACE_FRAMES: usize = 120;
+
+/// How far in pixels the mouse can move before a click becomes a drag
+const CLICK_SLOP: f32 = 4.0;
+
+/// What build.rs found in res/
+const ASSET_MANIFEST: &str = include_str!(concat!(env!("OUT_DIR"), "/asset_manifest.txt"));
+
+/// Everything the viewer opens by name. Instance files are left out,
+/// as the scene can do without them.
+const REQUIRED_ASSETS: &[&str] = &[
+    "scene.ron",
+    "cube.obj",
+    "cube.mtl",
+    "cube-diffuse.jpg",
+    "cube-normal.png",
+    "quad.obj",
+    "quad.mtl",
+    "torus.stl",
+];
+
+#[cfg(feature = "fully_embedded")]
+fn embedded_assets() -> Option<framework::EmbeddedAssets> {
+    static FILES: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+    Some(framework::EmbeddedAssets::new(FILES))
+}
+
+#[cfg(not(feature = "fully_embedded"))]
+fn embedded_assets() -> Option<framework::EmbeddedAssets> {
+    None
+}
+
+/// Checks every required asset up front, so a broken res/ gets one
+/// error listing everything wrong with it rather than failing on
+/// whichever file happens to be opened first
+fn check_assets(res_dir: &Path) -> Result<Option<framework::EmbeddedAssets>> {
+    let manifest = framework::AssetManifest::parse(ASSET_MANIFEST)?;
+    let embedded = embedded_assets();
+    let problems = match &embedded {
+        Some(embedded) => embedded.check(&manifest, REQUIRED_ASSETS),
+        None => manifest.check(res_dir, REQUIRED_ASSETS),
+    };
+    problems.into_result()?;
+    Ok(embedded)
+}
+
+fn load_scene(
+    res_dir: &Path,
+    embedded: Option<framework::EmbeddedAssets>,
+    name: &str,
+) -> Result<framework::SceneDesc> {
+    match embedded.and_then(|assets| assets.get(name)) {
+        Some(bytes) => framework::SceneDesc::parse(std::str::from_utf8(bytes)?),
+        None => framework::SceneDesc::load(res_dir.join(name)),
+    }
+}
+
+/// What `--bench-startup` runs, from `--bench-config` or res/bench.ron
+fn bench_config(args: &cli::Args) -> Result<Option<framework::BenchConfig>> {
+    if !args.bench_startup {
+        return Ok(None);
+    }
+    let config = match &args.bench_config {
+        Some(path) => framework::BenchConfig::load(path)?,
+        None => match embedded_assets().and_then(|assets| assets.get("bench.ron")) {
+            Some(bytes) => framework::BenchConfig::parse(std::str::from_utf8(bytes)?)?,
+            None => framework::BenchConfig::load(Path::new(env!("OUT_DIR")).join("res").join("bench.ron"))?,
+        },
+    };
+    Ok(Some(config))
+}
+
+/// The wave example needs to write to the opened model's vertices
+fn opened_model_options() -> framework::ModelLoadOptions {
+    framework::ModelLoadOptions {
+        storage_buffers: true,
+        auto_instance: true,
+        ..Default::default()
+    }
+}
+
+/// Bounds of every instance of every model in world space
+fn scene_aabb(models: &[(&framework::Model, &InstanceBuffer)]) -> framework::Aabb {
+    let mut aabb = framework::Aabb::empty();
+    for (model, instances) in models {
+        let model_aabb = model.aabb();
+        for instance in &instances.data {
+            aabb = aabb.union(&model_aabb.transform(&instance.calc_matrix()));
+        }
+    }
+    aabb
+}
+
+impl<'a> Viewer<'a> {
+    /// What [framework::InstanceHandle::model] refers to
+    fn instances_mut(&mut self, model: usize) -> &mut InstanceBuffer {
+        match model {
+            0 => &mut self.cube_instances,
+            _ => &mut self.opened_instances,
+        }
+    }
+
+    fn pick_targets(&self) -> [framework::PickTarget; 2] {
+        [
+            framework::PickTarget {
+                aabb: self.cube_model.aabb(),
+                instances: &self.cube_instances.data,
+            },
+            framework::PickTarget {
+                aabb: self.opened_model.aabb(),
+                instances: &self.opened_instances.data,
+            },
+        ]
+    }
+
+    /// Where the cursor hits the closest instance's box. None over
+    /// empty space, or when the camera is inside the box.
+    fn point_under_cursor(&self, display: &framework::Display) -> Option<Point3<f32>> {
+        let ray = framework::Ray::from_screen(
+            self.cursor_position.0,
+            self.cursor_position.1,
+            display.sc_desc.width as f32,
+            display.sc_desc.height as f32,
+            &self.uniforms.inv_view_proj(),
+        );
+        match framework::pick_instance_hit(&ray, &self.pick_targets()) {
+            Some((_, t)) if t > 0.0 => Some(ray.at(t)),
+            _ => None,
+        }
+    }
+
+    /// Click to select one instance, Ctrl-click to add or remove it.
+    /// Clicking on nothing clears the selection unless Ctrl is held.
+    fn click(&mut self, display: &framework::Display) {
+        let ray = framework::Ray::from_screen(
+            self.cursor_position.0,
+            self.cursor_position.1,
+            display.sc_desc.width as f32,
+            display.sc_desc.height as f32,
+            &self.uniforms.inv_view_proj(),
+        );
+        let hit = framework::pick_instance_hit(&ray, &self.pick_targets());
+        if let Some((instance, t)) = hit {
+            let (model, instances) = match instance.model {
+                0 => (&self.cube_model, &self.cube_instances),
+                _ => (&self.opened_model, &self.opened_instances),
+            };
+            let mesh = framework::pick_mesh(&ray, model, &instances.data[instance.instance]);
+            self.events.emit(framework::DemoEvent::ObjectPicked { instance, mesh, world_pos: ray.at(t) });
+        }
+        let hit = hit.map(|(handle, _)| handle);
+        let ctrl = self.input_map.modifiers().ctrl();
+        match (hit, ctrl) {
+            (Some(handle), true) => self.selection.toggle(handle),
+            (Some(handle), false) => self.selection.replace(handle),
+            (None, true) => {}
+            (None, false) => self.selection.clear(),
+        }
+        self.sync_selection(display);
+    }
+
+    /// Hides the closest visible mesh under the cursor, on any instance
+    fn hide_mesh_under_cursor(&mut self, display: &framework::Display) {
+        let ray = framework::Ray::from_screen(
+            self.cursor_position.0,
+            self.cursor_position.1,
+            display.sc_desc.width as f32,
+            display.sc_desc.height as f32,
+            &self.uniforms.inv_view_proj(),
+        );
+        let handle = match framework::pick_instance(&ray, &self.pick_targets()) {
+            Some(handle) => handle,
+            None => return,
+        };
+        let (model, instances) = match handle.model {
+            0 => (&self.cube_model, &self.cube_instances),
+            _ => (&self.opened_model, &self.opened_instances),
+        };
+        if let Some(mesh) = framework::pick_mesh(&ray, model, &instances.data[handle.instance]) {
+            let name = model.meshes[mesh].name.clone();
+            self.mesh_visibility[handle.model].set(&name, false);
+            println!("Hid {}, Ctrl+H shows it again", name);
+        }
+    }
+
+    /// Selects everything in the box the mouse was dragged over. Ctrl
+    /// adds to the current selection.
+    fn box_select(&mut self, display: &framework::Display) {
+        let handles = framework::instances_in_rect(
+            self.press_position,
+            self.cursor_position,
+            display.sc_desc.width as f32,
+            display.sc_desc.height as f32,
+            &self.uniforms.view_proj(),
+            &self.pick_targets(),
+        );
+        if !self.input_map.modifiers().ctrl() {
+            self.selection.clear();
+        }
+        self.selection.extend(handles);
+        self.sync_selection(display);
+    }
+
+    /// Copies the selection into the instances' selected bits, and
+    /// uploads only the part of each buffer that changed
+    fn sync_selection(&mut self, display: &framework::Display) {
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("sync_selection::encoder") }
+        );
+        let selection = &self.selection;
+        let mut changed = false;
+        let mut buffers = [&mut self.cube_instances, &mut self.opened_instances];
+        for (model, instances) in buffers.iter_mut().enumerate() {
+            let mut dirty: Option<std::ops::Range<usize>> = None;
+            for (i, instance) in instances.data.iter_mut().enumerate() {
+                let selected = selection.contains(&framework::InstanceHandle { model, instance: i });
+                if instance.selected != selected {
+                    instance.selected = selected;
+                    dirty = Some(match dirty {
+                        Some(range) => range.start..i + 1,
+                        None => i..i + 1,
+                    });
+                }
+            }
+            if let Some(range) = dirty {
+                instances.update_range(&display.device, &mut encoder, range);
+                changed = true;
+            }
+        }
+        display.queue.submit(&[encoder.finish()]);
+        if changed {
+            self.events.emit(framework::DemoEvent::SelectionChanged { selected: self.selection.len() });
+        }
+    }
+
+    /// Removes the selected instances. They're removed in reverse
+    /// order so the indices of the ones we haven't got to yet stay
+    /// put.
+    fn delete_selection(&mut self, display: &framework::Display) {
+        if self.selection.is_empty() {
+            return;
+        }
+        let selection = std::mem::take(&mut self.selection);
+        for handle in selection.iter().rev() {
+            self.instances_mut(handle.model).data.remove(handle.instance);
+        }
+        self.events.emit(framework::DemoEvent::SelectionChanged { selected: 0 });
+        self.cube_instances.recreate(&display.device);
+        self.opened_instances.recreate(&display.device);
+        self.bind_culling(&display.device);
+
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("delete_selection::encoder") }
+        );
+        self.update_blob_shadows(&display.device, &mut encoder);
+        display.queue.submit(&[encoder.finish()]);
+    }
+
+    /// The cull batches hold on to the instance buffers and the
+    /// pyramid, so this needs calling whenever either gets recreated
+    fn bind_culling(&mut self, device: &wgpu::Device) {
+        // However many are left, not how many the buffer was made for
+        let cubes = &self.cube_instances.raw_buffer;
+        self.cube_cull.bind(device, &self.culler, &self.hiz, &cubes.buffer, cubes.buffer_size());
+        let opened = &self.opened_instances.raw_buffer;
+        self.opened_cull.bind(device, &self.culler, &self.hiz, &opened.buffer, opened.buffer_size());
+    }
+
+    /// Starts loading `path` in the background. Anything that was
+    /// already loading gets cancelled. The current model stays until
+    /// the new one is ready, see [Viewer::poll_loading].
+    fn open_model(&mut self, path: &Path) -> Result<()> {
+        let resolved = self.assets.resolve(&path.to_string_lossy())?;
+        let mut options = self.scene.model_options(&path.to_string_lossy(), opened_model_options());
+        options.max_buffer_size = Some(self.max_buffer_size);
+        println!("Loading {} (Escape cancels)", path.display());
+        self.loading = Some(framework::ModelLoader::spawn(
+            resolved,
+            options,
+            self.assets.resolver().clone(),
+            self.profiler.clone(),
+        ));
+        self.load_progress = None;
+        Ok(())
+    }
+
+    /// Keeps track of how the background load is going, for the window
+    /// title, and swaps the model in once it's done
+    fn poll_loading(&mut self, display: &framework::Display) {
+        let finished = match &self.loading {
+            Some(handle) => {
+                self.load_progress = Some(handle.progress());
+                handle.try_finish()
+            }
+            None => return,
+        };
+        let result = match finished {
+            Some(result) => result,
+            None => return,
+        };
+        let path = self.loading.take().unwrap().path().to_path_buf();
+        let opened = result.and_then(|decoded| {
+            let mut uploaded = None;
+            let profiler = self.profiler.clone();
+            let scope = profiler.load_scope(framework::LoadStage::Upload.scope_name());
+            let (model, cmds) = decoded.upload_in_steps(
+                &display.device,
+                &self.texture_layout,
+                &mut self.textures,
+                &mut |p| uploaded = Some(p),
+            )?;
+            drop(scope);
+            save_packed_textures(&decoded.data);
+            self.load_progress = uploaded;
+            self.swap_opened_model(display, &path, model, cmds)
+        });
+        match opened {
+            Ok(()) => {}
+            Err(e) if e.is::<framework::Cancelled>() => {
+                println!("Stopped loading {}", path.display());
+            }
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+
+    /// How many instances the last frame's occlusion culling threw away
+    fn culling_stats(&mut self, display: &framework::Display) -> Option<String> {
+        if !self.settings.occlusion_culling {
+            return None;
+        }
+        // Reading the counts back stalls, but only once a second
+        let (mut culled, mut total) = (0, 0);
+        for (batch, count) in &[
+            (&self.cube_cull, self.cube_instances.data.len()),
+            (&self.opened_cull, self.opened_instances.data.len()),
+        ] {
+            let count = (*count).min(batch.bound_instances());
+            let visible = batch.read_visible(&display.device, &display.queue).ok()? as usize;
+            culled += count - visible.min(count);
+            total += count;
+        }
+        Some(format!("{} of {} instances culled", culled, total))
+    }
+
+    /**
+     * Unloads `old`, which was loaded from `path` and was model `handle`
+     * to the selection. That frees its buffers, and its textures unless
+     * another model uses them too. Anything selected in it gets
+     * deselected, even though the instances stay where they are.
+     */
+    fn remove_model(&mut self, display: &framework::Display, handle: usize, path: &str, old: framework::Model<'a>) {
+        self.assets.unload(path, old, &mut self.resources, &mut self.textures);
+        let mut counts = [self.cube_instances.data.len(), self.opened_instances.data.len()];
+        counts[handle] = 0;
+        self.selection.retain_valid(&counts);
+        self.sync_selection(display);
+    }
+
+    /// Replaces the opened model with `model`. The instances stay where
+    /// they are.
+    fn swap_opened_model(
+        &mut self,
+        display: &framework::Display,
+        path: &Path,
+        model: framework::Model<'a>,
+        mut cmds: Vec<wgpu::CommandBuffer>,
+    ) -> Result<()> {
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("open_model::encoder") }
+        );
+        self.wave = wave::WavePass::new(&display.device, &mut encoder, &model)?;
+        self.opened_cull = framework::CullBatch::new(&display.device, &model, self.opened_instances.data.len());
+        let old = std::mem::replace(&mut self.opened_model, model);
+        self.opened_placements = None;
+        let old_path = std::mem::replace(&mut self.opened_path, path.to_string_lossy().into_owned());
+        self.remove_model(display, 1, &old_path, old);
+        self.resources.track_model(&self.opened_path, &self.opened_model);
+        // Whatever was hidden by hand was a mesh of the old model
+        self.mesh_visibility[1].reset();
+        self.bind_culling(&display.device);
+        self.hiz.invalidate();
+        self.update_blob_shadows(&display.device, &mut encoder);
+        cmds.push(encoder.finish());
+        display.queue.submit(&cmds);
+
+        let aabb = self.scene_aabb();
+        self.minimap.fit(&aabb);
+        self.home_camera = framework::OrbitCamera::framing(&aabb, self.projection.fovy(), Deg(70.0), Deg(25.0));
+        let home = self.home_camera;
+        self.move_camera_to(home);
+
+        println!("Opened {}", path.display());
+        self.events.emit(framework::DemoEvent::ModelLoaded { path: path.to_path_buf() });
+        self.session.model = Some(path.to_path_buf());
+        self.session.add_recent(path);
+        if let Err(e) = self.session.save() {
+            eprintln!("{:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Remembers the camera and window for next time
+    fn save_session(&mut self, display: &framework::Display) {
+        self.session.camera = Some(self.transition.as_ref()
+            .map(|t| t.target())
+            .unwrap_or(&self.camera)
+            .into());
+        self.session.window = Some(framework::WindowGeometry {
+            width: display.sc_desc.width,
+            height: display.sc_desc.height,
+            position: self.window_position,
+        });
+        if let Err(e) = self.session.save() {
+            eprintln!("{:?}", e);
+        }
+    }
+
+    fn open_recent(&mut self, index: usize) {
+        let path = match self.session.recent.get(index) {
+            Some(path) => path.clone(),
+            None => {
+                println!("There's no recent file {}", index + 1);
+                return;
+            }
+        };
+        if let Err(e) = self.open_model(&path) {
+            eprintln!("{:?}", e);
+        }
+    }
+
+    fn scene_aabb(&self) -> framework::Aabb {
+        scene_aabb(&[
+            (&self.cube_model, &self.cube_instances),
+            (&self.opened_model, &self.opened_instances),
+        ])
+    }
+
+    /// Puts a blob under every instance. This only needs to run when
+    /// something moves.
+    fn update_blob_shadows(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        self.blob_shadows.clear();
+        for (model, instances) in &[
+            (&self.cube_model, &self.cube_instances),
+            (&self.opened_model, &self.opened_instances),
+        ] {
+            let model_aabb = model.aabb();
+            for instance in &instances.data {
+                let aabb = model_aabb.transform(&instance.calc_matrix());
+                self.blob_shadows.add(&aabb, self.scene.ground_height);
+            }
+        }
+        self.blob_shadows.upload(device, encoder);
+    }
+
+    /// Saves the textures the last frame was drawn with. The swap
+    /// chain can't be read, so the final image is tonemapped again
+    /// into a texture that can.
+    fn dump_frame(&self, display: &framework::Display) {
+        let final_texture = framework::Texture::from_descriptor(&display.device, wgpu::TextureDescriptor {
+            label: Some("final_texture"),
+            size: self.hdr_texture.desc.size,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.settings.surface_format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("dump_frame::encoder") }
+        );
+        self.tonemap.render(&mut encoder, &final_texture.view);
+        display.queue.submit(&[encoder.finish()]);
+
+        let mut capture = framework::FrameCapture::new();
+        capture
+            .add("depth", &self.depth_texture, framework::CaptureKind::Depth {
+                znear: self.projection.znear(),
+                zfar: self.projection.zfar(),
+                perspective: self.projection.mode == framework::ProjectionMode::Perspective,
+            })
+            .add("hdr", &self.hdr_texture, framework::CaptureKind::Hdr)
+            .add("final", &final_texture, framework::CaptureKind::Color);
+
+        let dir = framework::FrameCapture::timestamped_dir(".");
+        match capture.save(&display.device, &display.queue, &dir) {
+            Ok(files) => {
+                println!("Saved frame to {}", dir.display());
+                for file in files {
+                    println!("  {}", file.display());
+                }
+            }
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+
+    fn print_probe(&self, display: &framework::Display, sample: &framework::ProbeSample) {
+        let (width, height) = (display.sc_desc.width, display.sc_desc.height);
+        let position = self.uniforms.view_proj()
+            .invert()
+            .and_then(|inv| sample.world_position(width, height, &inv));
+        match position {
+            Some(p) => println!("{}, {:.3} from the camera", sample, (p - self.camera.eye()).magnitude()),
+            None => println!("{}, sky", sample),
+        }
+    }
+
+    fn move_camera_to(&mut self, target: framework::OrbitCamera) {
+        self.transition = Some(framework::OrbitTransition::new(
+            self.camera,
+            target,
+            framework::OrbitTransition::DEFAULT_DURATION,
+        ));
+    }
+
+    /// Everything opaque that writes velocity, culled when the
+    /// culler is on
+    fn draw_main_scene(&self, encoder: &mut wgpu::CommandEncoder) {
+        let culling = self.settings.occlusion_culling;
+        let mut pass = framework::ModelPass::from(encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &self.hdr_texture.view,
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Clear,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color {
+                            r: 0.1,
+                            g: 0.2,
+                            b: 0.3,
+                            a: 1.0,
+                        },
+                    },
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: self.motion_blur.velocity(),
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Clear,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::TRANSPARENT,
+                    },
+                ],
+                depth_stencil_attachment: Some(
+                    wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                        attachment: &self.depth_texture.view,
+                        depth_load_op: wgpu::LoadOp::Clear,
+                        depth_store_op: wgpu::StoreOp::Store,
+                        clear_depth: 1.0,
+                        stencil_load_op: wgpu::LoadOp::Clear,
+                        stencil_store_op: wgpu::StoreOp::Store,
+                        clear_stencil: 0,
+                    }
+                )
+            }
+        ));
+
+        pass.set_model_pipeline(&self.model_pipeline, &self.texture_layout);
+        // The per-frame group is shared by every model
+        pass.set_bind_group(2, &self.frame_binding.bind_group, &[]);
+
+        // Group 3 says which point lights each model is lit by.
+        // DrawModel doesn't touch it, so it stays set between draws.
+        pass.set_bind_group(3, &self.cube_lights.bind_group, &[]);
+        if culling {
+            pass.set_vertex_buffer(1, &self.cube_cull.instance_buffer, 0, 0);
+            pass.draw_model_indirect(
+                &self.cube_model,
+                &self.cube_cull.indirect_buffer,
+                &self.uniform_binding.bind_group,
+            );
+        } else {
+            pass.set_vertex_buffer(1, &self.cube_instances.raw_buffer.buffer, 0, 0);
+            pass.draw_model_instanced(
+                &self.cube_model,
+                0..self.cube_instances.data.len() as u32,
+                &self.uniform_binding.bind_group,
+            );
+        }
+
+        // Only the chunks that might be on screen, with neighbors
+        // joined into one draw
+        let view_proj = self.projection.calc_matrix() * self.camera.calc_matrix();
+        for streamed in &self.streamed_cubes {
+            pass.set_vertex_buffer(1, &streamed.buffer, 0, 0);
+            for range in streamed.visible_ranges(view_proj) {
+                pass.draw_model_instanced(&self.cube_model, range, &self.uniform_binding.bind_group);
+            }
+        }
+
+        pass.set_vertex_buffer(1, &self.shiny_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(
+            &self.shiny_cube,
+            0..self.shiny_instances.data.len() as u32,
+            &self.uniform_binding.bind_group,
+        );
+        pass.set_vertex_buffer(1, &self.quad_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(
+            &self.quad,
+            0..self.quad_instances.data.len() as u32,
+            &self.uniform_binding.bind_group,
+        );
+
+        pass.set_bind_group(3, &self.opened_lights.bind_group, &[]);
+        let borrowed_material = self.opened_material();
+        if self.opened_placements.is_some() {
+            // Placed copies don't go through the culler
+            self.draw_opened_unculled(&mut pass, &self.uniform_binding.bind_group);
+        } else if culling {
+            pass.set_vertex_buffer(1, &self.opened_cull.instance_buffer, 0, 0);
+            match borrowed_material {
+                Some(material) => pass.draw_model_indirect_with_material(
+                    &self.opened_model,
+                    material,
+                    &self.opened_cull.indirect_buffer,
+                    &self.uniform_binding.bind_group,
+                ),
+                None => pass.draw_model_indirect(
+                    &self.opened_model,
+                    &self.opened_cull.indirect_buffer,
+                    &self.uniform_binding.bind_group,
+                ),
+            }
+        } else {
+            pass.set_vertex_buffer(1, &self.opened_instances.raw_buffer.buffer, 0, 0);
+            let instances = 0..self.opened_instances.data.len() as u32;
+            match borrowed_material {
+                Some(material) => pass.draw_model_instanced_with_material(
+                    &self.opened_model,
+                    material,
+                    instances,
+                    &self.uniform_binding.bind_group,
+                ),
+                None => pass.draw_model_instanced(
+                    &self.opened_model,
+                    instances,
+                    &self.uniform_binding.bind_group,
+                ),
+            }
+        }
+    }
+
+    /// In a pass of their own, as they don't write velocity. The sky
+    /// gets the camera's motion, and blobs whatever they're lying on.
+    fn draw_sky_and_blobs(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &self.hdr_texture.view,
+                        resolve_target: None,
+                        load_op: wgpu::LoadOp::Load,
+                        store_op: wgpu::StoreOp::Store,
+                        clear_color: wgpu::Color::BLACK,
+                    },
+                ],
+                depth_stencil_attachment: Some(
+                    wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                        attachment: &self.depth_texture.view,
+                        depth_load_op: wgpu::LoadOp::Load,
+                        depth_store_op: wgpu::StoreOp::Store,
+                        clear_depth: 1.0,
+                        stencil_load_op: wgpu::LoadOp::Load,
+                        stencil_store_op: wgpu::StoreOp::Store,
+                        clear_stencil: 0,
+                    }
+                )
+            }
+        );
+
+        // The sky goes after the opaque geometry so that the depth
+        // test can skip every pixel that's already covered
+        self.sky.render(&mut pass, &self.uniform_binding.bind_group);
+
+        if self.settings.use_blob_shadows() {
+            self.blob_shadows.render(&mut pass, &self.uniform_binding.bind_group);
+        }
+    }
+
+    /// After everything opaque, as glass only tests against the depth
+    fn draw_glass(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mode = self.settings.transparency;
+        let order = match mode {
+            framework::TransparencyMode::Sorted => {
+                let centers = self.glass_instances.data.iter()
+                    .map(|instance| Point3::from_vec(instance.position))
+                    .collect::<Vec<_>>();
+                framework::back_to_front(self.camera.eye(), &centers)
+            }
+            framework::TransparencyMode::WeightedBlended => (0..self.glass_instances.data.len()).collect(),
+        };
+        {
+            let view = &self.uniform_binding.bind_group;
+            let mut pass = self.transparency.begin(
+                mode,
+                encoder,
+                &self.hdr_texture.view,
+                &self.depth_texture,
+                &self.texture_layout,
+            );
+            pass.set_vertex_buffer(1, &self.glass_instances.raw_buffer.buffer, 0, 0);
+            for i in order {
+                pass.set_bind_group(2, &self.glass_tints[i].bind_group, &[]);
+                pass.draw_model_instanced(&self.cube_model, i as u32..i as u32 + 1, view);
+            }
+        }
+        self.transparency.resolve(mode, encoder, &self.hdr_texture.view);
+    }
+
+    /// The same models as the main pass, but none of them culled, for
+    /// passes that have set their own pipeline
+    fn draw_unculled<'b>(&'b self, pass: &mut framework::ModelPass<'b>) {
+        let view = &self.uniform_binding.bind_group;
+        pass.set_vertex_buffer(1, &self.cube_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(&self.cube_model, 0..self.cube_instances.data.len() as u32, view);
+        pass.set_vertex_buffer(1, &self.shiny_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(&self.shiny_cube, 0..self.shiny_instances.data.len() as u32, view);
+        pass.set_vertex_buffer(1, &self.quad_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(&self.quad, 0..self.quad_instances.data.len() as u32, view);
+
+        self.draw_opened_unculled(pass, view);
+    }
+
+    /// STL files and OBJs without an MTL don't come with materials, so
+    /// they borrow the triplanar version of the brick material
+    fn opened_material(&self) -> Option<&framework::Material> {
+        if self.opened_model.materials.is_empty() {
+            Some(&self.triplanar_cube.materials[0])
+        } else {
+            None
+        }
+    }
+
+    fn draw_opened_unculled<'b>(&'b self, pass: &mut framework::ModelPass<'b>, view: &'b wgpu::BindGroup) {
+        let material = self.opened_material();
+        if let Some(placements) = &self.opened_placements {
+            match material {
+                Some(material) => pass.draw_model_placed_with_material(&self.opened_model, material, placements, view),
+                None => pass.draw_model_placed(&self.opened_model, placements, view),
+            }
+            return;
+        }
+        pass.set_vertex_buffer(1, &self.opened_instances.raw_buffer.buffer, 0, 0);
+        let instances = 0..self.opened_instances.data.len() as u32;
+        match material {
+            Some(material) => pass.draw_model_instanced_with_material(&self.opened_model, material, instances, view),
+            None => pass.draw_model_instanced(&self.opened_model, instances, view),
+        }
+    }
+
+    /// Everything gets counted, culled or not, so the count shows
+    /// everything that gets rasterized
+    fn draw_overdraw_scene(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        {
+            let mut pass = self.overdraw.begin_count_pass(device, encoder, &self.depth_texture, &self.texture_layout);
+            self.draw_unculled(&mut pass);
+        }
+        self.overdraw.end_count(encoder);
+    }
+
+    /// Unculled too, as something the culler hid can still cast a
+    /// contact shadow onto something it didn't
+    fn draw_contact_prepass(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = self.contact_shadows.begin_prepass(encoder, &self.texture_layout);
+        self.draw_unculled(&mut pass);
+    }
+
+    /// Draws the models from above for the minimap. The sky and blob
+    /// shadows are left out, and nothing's culled, as the cull batches
+    /// are for the main camera.
+    fn draw_minimap_scene(&self, encoder: &mut wgpu::CommandEncoder) {
+        let view = self.minimap.view_bind_group();
+        let mut pass = framework::ModelPass::from(self.minimap.begin_scene_pass(
+            encoder,
+            wgpu::Color { r: 0.05, g: 0.08, b: 0.1, a: 1.0 },
+        ));
+        pass.set_model_pipeline(&self.minimap_pipeline, &self.texture_layout);
+        pass.set_bind_group(2, &self.frame_binding.bind_group, &[]);
+
+        pass.set_bind_group(3, &self.cube_lights.bind_group, &[]);
+        pass.set_vertex_buffer(1, &self.cube_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(&self.cube_model, 0..self.cube_instances.data.len() as u32, view);
+        pass.set_vertex_buffer(1, &self.shiny_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(&self.shiny_cube, 0..self.shiny_instances.data.len() as u32, view);
+        pass.set_vertex_buffer(1, &self.quad_instances.raw_buffer.buffer, 0, 0);
+        pass.draw_model_instanced(&self.quad, 0..self.quad_instances.data.len() as u32, view);
+
+        pass.set_bind_group(3, &self.opened_lights.bind_group, &[]);
+        self.draw_opened_unculled(&mut pass, view);
+    }
+
+    /**
+     * Every pass of a frame, by what it reads and writes. The graph
+     * works out the order from those, so a new pass is one more node
+     * here. A pass sees a resource the way the passes added before it
+     * left it, so they're added in about the order they run.
+     */
+    fn render_graph(profiler: &framework::Profiler, width: u32, height: u32) -> framework::RenderGraph<Self> {
+        use framework::PassNode;
+
+        let mut graph = framework::RenderGraph::new(width, height).with_profiler(profiler.clone());
+        let owned = ["contact_depth", "minimap", "hiz", "culled", "hdr", "velocity", "depth", "overdraw", "output"];
+        for key in &owned {
+            graph.import(key);
+        }
+
+        graph.add_pass(
+            PassNode::<Self>::new("contact_prepass", |v, ctx| v.draw_contact_prepass(ctx.encoder))
+                .writes(&["contact_depth"])
+                .enabled_when(|v| v.settings.contact_shadows.enabled),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("minimap_scene", |v, ctx| {
+                if v.minimap.should_redraw() {
+                    v.minimap.update_view(ctx.device, ctx.encoder);
+                    v.draw_minimap_scene(ctx.encoder);
+                    v.minimap.end_scene_pass(ctx.device, ctx.encoder);
+                }
+            })
+                // For contact shadows wherever the main camera can see
+                .reads(&["contact_depth"])
+                .writes(&["minimap"])
+                .enabled_when(|v| v.show_minimap),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("cull", |v, ctx| {
+                let (cubes, opened) = (v.cube_instances.data.len(), v.opened_instances.data.len());
+                let view_proj = v.uniforms.view_proj();
+                v.culler.cull(ctx.device, ctx.encoder, &v.cube_cull, &v.hiz, view_proj, cubes);
+                v.culler.cull(ctx.device, ctx.encoder, &v.opened_cull, &v.hiz, view_proj, opened);
+            })
+                .reads_history(&["hiz"])
+                .writes(&["culled"])
+                .enabled_when(|v| v.settings.occlusion_culling),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("main", |v, ctx| v.draw_main_scene(ctx.encoder))
+                .reads(&["contact_depth", "culled"])
+                .writes(&["hdr", "velocity", "depth"]),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("sky_and_blobs", |v, ctx| v.draw_sky_and_blobs(ctx.encoder))
+                .modifies(&["hdr"])
+                .reads(&["depth"]),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("glass", |v, ctx| v.draw_glass(ctx.encoder))
+                .modifies(&["hdr"])
+                .reads(&["depth"]),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("motion_blur", |v, ctx| v.motion_blur.render(ctx.encoder, &v.hdr_texture))
+                .modifies(&["hdr"])
+                .reads(&["velocity", "depth"])
+                // Dumps are for seeing what got drawn, so they skip the
+                // blur unless asked not to
+                .enabled_when(|v| {
+                    let blur = v.settings.motion_blur;
+                    blur.mode != framework::MotionBlurMode::Off && (!v.dump_requested || blur.in_captures)
+                }),
+        );
+        graph.add_pass(
+            // Next frame gets culled against this frame's depth
+            PassNode::<Self>::new("hiz_build", |v, ctx| v.hiz.build(ctx.encoder, v.uniforms.view_proj()))
+                .reads(&["depth"])
+                .writes(&["hiz"])
+                .enabled_when(|v| v.settings.occlusion_culling),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("overdraw_count", |v, ctx| {
+                if v.overdraw.should_count() {
+                    v.draw_overdraw_scene(ctx.device, ctx.encoder);
+                }
+            })
+                .reads(&["depth"])
+                .writes(&["overdraw"])
+                .enabled_when(|v| v.show_overdraw),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("tonemap", |v, ctx| v.tonemap.render(ctx.encoder, ctx.output))
+                .reads(&["hdr"])
+                .writes(&["output"]),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("overdraw_overlay", |v, ctx| v.overdraw.draw_overlay(ctx.encoder, ctx.output))
+                .modifies(&["output"])
+                .reads(&["overdraw"])
+                .enabled_when(|v| v.show_overdraw),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("minimap_composite", |v, ctx| {
+                v.minimap.composite(ctx.device, ctx.encoder, ctx.output, ctx.width, ctx.height, &v.camera);
+            })
+                .modifies(&["output"])
+                .reads(&["minimap"])
+                .enabled_when(|v| v.show_minimap),
+        );
+        graph.add_pass(
+            PassNode::<Self>::new("probe", |v, ctx| {
+                let (x, y) = v.cursor_position;
+                v.probe.capture(
+                    ctx.device,
+                    ctx.encoder,
+                    &v.tonemap,
+                    &v.hdr_texture,
+                    &v.depth_texture,
+                    x as u32,
+                    y as u32,
+                );
+            })
+                .reads(&["hdr", "depth"])
+                .enabled_when(|v| {
+                    let (x, y) = v.cursor_position;
+                    v.input_map.modifiers().alt() && x >= 0.0 && y >= 0.0
+                }),
+        );
+        graph
+    }
+
+    /// Snaps to look at the scene from the direction given by `yaw`
+    /// and `pitch`, keeping the current zoom level
+    fn view_preset(&mut self, yaw: Deg<f32>, pitch: Deg<f32>) {
+        let aabb = self.scene_aabb();
+        let mut target = framework::OrbitCamera::framing(&aabb, self.projection.fovy(), yaw, pitch);
+        target.distance = self.camera.distance;
+        self.move_camera_to(target);
+    }
+
+    fn take_snapshot(&self) -> snapshot::Snapshot {
+        snapshot::Snapshot {
+            // If we're mid transition, save where we're going
+            camera: self.transition.as_ref()
+                .map(|t| t.target())
+                .unwrap_or(&self.camera)
+                .into(),
+            projection: self.projection.mode,
+            selection: self.selection.clone(),
+            settings: self.settings.clone(),
+            light_position: self.light.position().into(),
+        }
+    }
+
+    fn restore_snapshot(&mut self, display: &framework::Display, snapshot: &snapshot::Snapshot) {
+        self.move_camera_to((&snapshot.camera).into());
+        self.projection.mode = snapshot.projection;
+        // Pacing and the surface are about this machine, not the view
+        let pacing = self.settings.pacing;
+        let surface_format = self.settings.surface_format;
+        let texture_budget = self.settings.texture_budget;
+        self.settings = snapshot.settings.clone();
+        self.settings.pacing = pacing;
+        self.settings.surface_format = surface_format;
+        self.settings.texture_budget = texture_budget;
+        self.hiz.invalidate();
+
+        // The snapshot could be from before the scene changed
+        self.selection = snapshot.selection.clone();
+        self.selection.retain_valid(&[
+            self.cube_instances.data.len(),
+            self.opened_instances.data.len(),
+        ]);
+        self.sync_selection(display);
+
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("restore_snapshot::encoder") }
+        );
+        self.light.set_position(&display.device, &mut encoder, snapshot.light_position.into());
+        display.queue.submit(&[encoder.finish()]);
+        self.events.emit(framework::DemoEvent::LightMoved { position: snapshot.light_position.into() });
+    }
+
+    fn process_action(&mut self, display: &framework::Display, action: Action) {
+        match action {
+            // Yaw 90 puts the eye on +z looking towards -z
+            Action::ViewFront => self.view_preset(Deg(90.0), Deg(0.0)),
+            Action::ViewBack => self.view_preset(Deg(-90.0), Deg(0.0)),
+            Action::ViewRight => self.view_preset(Deg(0.0), Deg(0.0)),
+            Action::ViewLeft => self.view_preset(Deg(180.0), Deg(0.0)),
+            Action::ViewTop => self.view_preset(Deg(90.0), Deg(90.0)),
+            Action::ViewBottom => self.view_preset(Deg(90.0), Deg(-90.0)),
+            Action::ToggleProjection => self.projection.toggle_mode(),
+            Action::ResetView => {
+                let home = self.home_camera;
+                self.move_camera_to(home);
+            }
+            Action::CaptureTrace => if !self.profiler.is_capturing() {
+                println!("Capturing {} frames...", TRACE_FRAMES);
+                self.profiler.start_capture(TRACE_FRAMES);
+            }
+            Action::DumpFrame => self.dump_requested = true,
+            Action::SaveSnapshot(slot) => {
+                let snapshot = self.take_snapshot();
+                self.snapshots.set(slot, snapshot);
+                match self.snapshots.save() {
+                    Ok(()) => println!("Saved snapshot {}", slot + 1),
+                    Err(e) => eprintln!("{:?}", e),
+                }
+            }
+            Action::RestoreSnapshot(slot) => match self.snapshots.get(slot).cloned() {
+                Some(snapshot) => self.restore_snapshot(display, &snapshot),
+                None => println!("Snapshot {} is empty", slot + 1),
+            }
+            Action::DeleteSelection => self.delete_selection(display),
+            Action::ToggleOcclusionCulling => {
+                self.settings.occlusion_culling = !self.settings.occlusion_culling;
+                // The pyramid stops getting built while culling is off,
+                // so whatever's in it is out of date
+                self.hiz.invalidate();
+                println!("Occlusion culling: {}", self.settings.occlusion_culling);
+            }
+            Action::ToggleVsync => {
+                self.settings.pacing.vsync = !self.settings.pacing.vsync;
+                println!("Vsync: {}", self.settings.pacing.vsync);
+            }
+            Action::CycleFrameLimit => {
+                let pacing = &mut self.settings.pacing;
+                pacing.limit = pacing.limit.next();
+                if pacing.vsync {
+                    println!("Frame limit: {:?} (ignored until vsync is off, F3)", pacing.limit);
+                } else {
+                    println!("Frame limit: {:?}", pacing.limit);
+                }
+            }
+            Action::ToggleWave => {
+                let mut encoder = display.device.create_command_encoder(
+                    &wgpu::CommandEncoderDescriptor { label: Some("toggle_wave::encoder") }
+                );
+                let enabled = !self.wave.is_enabled();
+                self.wave.set_enabled(&mut encoder, &self.opened_model, enabled);
+                display.queue.submit(&[encoder.finish()]);
+                println!("Wave: {}", enabled);
+            }
+            Action::ListRecent => self.session.print_recent(),
+            Action::InspectModel => match self.opened_model.describe().to_json() {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("{:?}", e),
+            }
+            Action::OpenRecent(index) => self.open_recent(index),
+            Action::ToggleMinimap => self.show_minimap = !self.show_minimap,
+            Action::ToggleTransparencyMode => {
+                self.settings.transparency = match self.settings.transparency {
+                    framework::TransparencyMode::Sorted => framework::TransparencyMode::WeightedBlended,
+                    framework::TransparencyMode::WeightedBlended => framework::TransparencyMode::Sorted,
+                };
+                println!("Transparency: {:?}", self.settings.transparency);
+            }
+            Action::ToggleOverdraw => {
+                self.show_overdraw = !self.show_overdraw;
+                println!("Overdraw: {}", if self.show_overdraw { "on" } else { "off" });
+            }
+            Action::CycleMotionBlur => {
+                let blur = &mut self.settings.motion_blur;
+                blur.mode = blur.mode.cycle();
+                println!("Motion blur: {:?}", blur.mode);
+            }
+            Action::PrintRenderGraph => print!("{}", self.graph.describe(self)),
+            Action::ToggleContactShadows => {
+                let contact = &mut self.settings.contact_shadows;
+                contact.enabled = !contact.enabled;
+                println!("Contact shadows: {}", if contact.enabled { "on" } else { "off" });
+            }
+            Action::SunEarlier | Action::SunLater => if let Some(sun) = &mut self.sun {
+                let step = if action == Action::SunLater { 1.0 } else { -1.0 };
+                sun.set_time_of_day(sun.time_of_day + step);
+                println!("{:05.2} hours", sun.time_of_day);
+            }
+            Action::ToggleDayCycle => if self.sun.is_some() {
+                self.sun_paused = !self.sun_paused;
+                println!("Day cycle {}", if self.sun_paused { "paused" } else { "running" });
+            }
+            Action::TogglePalette => {
+                self.settings.palette = match self.settings.palette {
+                    framework::PaletteScheme::Classic => framework::PaletteScheme::ColorBlindSafe,
+                    framework::PaletteScheme::ColorBlindSafe => framework::PaletteScheme::Classic,
+                };
+                println!("Palette: {:?}", self.settings.palette);
+            }
+            Action::ToggleAmbientGradient => {
+                let flat = framework::AmbientGradient::default();
+                let gradient = &mut self.frame_uniforms.ambient_gradient;
+                *gradient = if *gradient == flat { self.scene.ambient } else { flat };
+                println!("Ambient: {:?}", gradient);
+            }
+            Action::CycleColorBlindness => {
+                self.settings.simulate_color_blindness =
+                    framework::ColorBlindness::cycle(self.settings.simulate_color_blindness);
+                match self.settings.simulate_color_blindness {
+                    Some(kind) => println!("Simulating {:?}", kind),
+                    None => println!("Color blindness simulation off"),
+                }
+            }
+            Action::CancelLoad => if let Some(handle) = &self.loading {
+                handle.cancel();
+            }
+            Action::PrintTextureMemory => {
+                println!("{}", self.textures.report());
+                println!("Models: {}", self.resources.totals());
+            }
+            Action::ToggleTwoSidedLighting => {
+                let mut encoder = display.device.create_command_encoder(
+                    &wgpu::CommandEncoderDescriptor { label: Some("two_sided::encoder") }
+                );
+                for material in &mut self.quad.materials {
+                    let mut params = material.params;
+                    params.two_sided_lighting ^= 1;
+                    material.update_params(&display.device, &mut encoder, params);
+                }
+                display.queue.submit(&[encoder.finish()]);
+                let on = self.quad.materials.iter().any(|m| m.params.two_sided_lighting != 0);
+                println!("Two sided lighting {}", if on { "on" } else { "off" });
+            }
+            Action::HideMeshUnderCursor => self.hide_mesh_under_cursor(display),
+            Action::ResetMeshVisibility => {
+                for visibility in &mut self.mesh_visibility {
+                    visibility.reset();
+                }
+            }
+            Action::ToggleTimeline => {
+                self.timeline.toggle_pause();
+                println!("Timeline {} at {:.2}s", if self.timeline.paused { "paused" } else { "running" }, self.timeline.time);
+            }
+            Action::TimelineBack => {
+                self.timeline.step_back(&self.scene.visibility_tracks);
+                println!("Timeline at {:.2}s", self.timeline.time);
+            }
+            Action::TimelineForward => if self.timeline.step_forward(&self.scene.visibility_tracks) {
+                println!("Timeline at {:.2}s", self.timeline.time);
+            } else {
+                println!("No visibility keys after {:.2}s", self.timeline.time);
+            }
+            Action::PrintCullStats => if self.settings.occlusion_culling {
+                let device = &display.device;
+                let queue = &display.queue;
+                for (name, batch, total) in &[
+                    ("cube", &self.cube_cull, self.cube_instances.data.len()),
+                    ("opened", &self.opened_cull, self.opened_instances.data.len()),
+                ] {
+                    match batch.read_visible(device, queue) {
+                        Ok(visible) => println!("{}: {} of {} visible", name, visible, total),
+                        Err(e) => eprintln!("{:?}", e),
+                    }
+                }
+            } else {
+                println!("Occlusion culling is off (F7)");
+            }
+        }
+    }
+}
+
+impl framework::Demo for Viewer<'static> {
+    fn window_geometry() -> Option<framework::WindowGeometry> {
+        let args = cli::Args::from_env().ok();
+        match args.as_ref().map(bench_config) {
+            Some(Ok(Some(bench))) => Some(bench.window),
+            _ => session::Session::load().window,
+        }
+    }
+
+    fn input_session() -> Option<framework::InputSession> {
+        // Bad arguments get reported by init
+        let args = cli::Args::from_env().ok()?;
+        if let Ok(Some(bench)) = bench_config(&args) {
+            // Fast, as the bench has a dt of its own
+            return bench.camera_path.map(|path| framework::InputSession::Replay { path, fast: true });
+        }
+        args.input_session()
+    }
+
+    fn hide_window() -> bool {
+        cli::Args::from_env().map(|args| args.bench_startup).unwrap_or(false)
+    }
+
+    fn init(display: &framework::Display) -> Result<Self> {
+        // Material, view, frame and object lights
+        display.caps.require_bind_groups(4)?;
+        let args = cli::Args::from_env()?;
+        let bench = bench_config(&args)?.map(bench::BenchRun::new);
+        // A bench records everything from here on
+        let profiler = framework::Profiler::new();
+        if let Some(bench) = &bench {
+            profiler.start_capture(bench.config.frames);
+        }
+        let stage = profiler.load_scope("targets");
+        let texture_layout = framework::MaterialLayout::new(
+            &display.device,
+            Default::default(),
+        );
+
+        let depth_texture = framework::Texture::create_depth_texture(&display.device, &display.sc_desc);
+        let hdr_texture = framework::Texture::create_hdr_texture(&display.device, &display.sc_desc);
+        let mut settings = match &bench {
+            Some(bench) => framework::RenderSettings {
+                surface_format: display.sc_desc.format,
+                ..bench.config.settings.clone()
+            },
+            None => framework::RenderSettings::for_display(display),
+        };
+        let tonemap = framework::TonemapPass::new(&display.device, &hdr_texture, &settings)?;
+        let probe = framework::PixelProbe::new(&display.device, &tonemap, &hdr_texture);
+        drop(stage);
+
+        let stage = profiler.load_scope("scene");
+        let mut res_cmds = Vec::new();
+        let res_dir = Path::new(env!("OUT_DIR")).join("res");
+        let embedded = check_assets(&res_dir)?;
+        let mut assets = framework::AssetManager::new(&res_dir);
+        assets.embedded = embedded;
+        let scene_name = bench.as_ref().map_or("scene.ron", |bench| bench.config.scene.as_str());
+        let scene = load_scene(&res_dir, embedded, scene_name)?;
+        if let Some(mode) = scene.transparency {
+            settings.transparency = mode;
+        }
+        drop(stage);
+
+        let stage = profiler.load_scope("models");
+        let cube_data = assets.load_model_data(
+            "cube.obj",
+            &scene.model_options("cube.obj", Default::default()),
+        )?;
+        save_packed_textures(&cube_data);
+        if let Some(mb) = args.texture_budget {
+            settings.texture_budget = Some(mb << 20);
+        }
+        let mut textures = framework::TextureCache::new();
+        textures.embedded = embedded;
+        textures.budget = Some(settings.texture_budget(&display.caps));
+        let (cube_model, cmds) = framework::Model::from_data(
+            &display.device,
+            &texture_layout,
+            &cube_data,
+            &mut textures,
+        )?;
+        res_cmds.extend(cmds);
+        // Comes out of the same cache, so nothing gets uploaded twice
+        let (mut shiny_cube, cmds) = framework::Model::from_data(
+            &display.device,
+            &texture_layout,
+            &cube_data,
+            &mut textures,
+        )?;
+        res_cmds.extend(cmds);
+        for (name, instance) in scene.material_instances_for("cube.obj") {
+            instance.apply(&display.device, &texture_layout, name, &mut shiny_cube)?;
+        }
+        let quad_data = assets.load_model_data("quad.obj", &scene.model_options("quad.obj", Default::default()))?;
+        save_packed_textures(&quad_data);
+        let (mut quad, cmds) = framework::Model::from_data(
+            &display.device,
+            &texture_layout,
+            &quad_data,
+            &mut textures,
+        )?;
+        res_cmds.extend(cmds);
+        for (name, instance) in scene.material_instances_for("quad.obj") {
+            instance.apply(&display.device, &texture_layout, name, &mut quad)?;
+        }
+        let cube_aabb = cube_model.aabb();
+        let cube_radius = cube_aabb.center().to_vec().magnitude() + cube_aabb.radius();
+        let mut streamed_cubes = Vec::new();
+        for desc in &scene.instance_files {
+            if desc.model != "cube.obj" {
+                eprintln!("Instance file {} wants {}, but only cube.obj can be streamed", desc.path, desc.model);
+                continue;
+            }
+            let streamed = framework::StreamedInstances::load(&display.device, res_dir.join(&desc.path), cube_radius)?;
+            println!("Streamed {} instances from {}", streamed.count, desc.path);
+            streamed_cubes.push(streamed);
+        }
+        let triplanar_data = assets.load_model_data(
+            "cube.obj",
+            &framework::ModelLoadOptions {
+                triplanar: Some(true),
+                triplanar_scale: 0.5,
+                ..Default::default()
+            },
+        )?;
+        let (triplanar_cube, cmds) = framework::Model::from_data(
+            &display.device,
+            &texture_layout,
+            &triplanar_data,
+            &mut textures,
+        )?;
+        res_cmds.extend(cmds);
+
+        // Put back whatever was open last time. If that's gone we fall
+        // back to the cube rather than refusing to start. Benches start
+        // from the same place every time.
+        let mut session = if bench.is_some() {
+            session::Session::default()
+        } else {
+            session::Session::load()
+        };
+        let mut load_opened = |path: &str| -> Result<_> {
+            let data = assets.load_model_data(path, &framework::ModelLoadOptions {
+                max_buffer_size: Some(display.caps.max_buffer_size),
+                ..scene.model_options(path, opened_model_options())
+            })?;
+            save_packed_textures(&data);
+            framework::Model::from_data(&display.device, &texture_layout, &data, &mut textures)
+        };
+        let opened_path = match &session.model {
+            Some(path) if path.is_file() => path.to_string_lossy().into_owned(),
+            Some(path) => {
+                eprintln!("{} is missing, using the cube instead", path.display());
+                session.model = None;
+                "cube.obj".to_string()
+            }
+            None => "torus.stl".to_string(),
+        };
+        let (opened_path, (opened_model, cmds)) = match load_opened(&opened_path) {
+            Ok(loaded) => (opened_path, loaded),
+            Err(e) if session.model.is_some() => {
+                eprintln!("Unable to open {}, using the cube instead: {:?}", opened_path, e);
+                session.model = None;
+                ("cube.obj".to_string(), load_opened("cube.obj")?)
+            }
+            Err(e) => return Err(e),
+        };
+        res_cmds.extend(cmds);
+        drop(stage);
+
+        let stage = profiler.load_scope("passes");
+        // Logged so a bug report can include it
+        let seed = args.seed.or(scene.seed).unwrap_or_default();
+        println!("Seed: {}", seed.0);
+        // The city's towers get culled like the other cubes, and its
+        // wall is drawn shiny and never culled, so it's always there to
+        // hide them
+        let city = scene.cube_city.map(|desc| desc.build(seed));
+        let mut cubes = vec![framework::Instance::new((-2.0, 0.0, 0.0))];
+        let mut shiny = vec![framework::Instance::new((-2.0, 0.0, -3.0))];
+        if let Some(city) = city {
+            cubes.extend(city.towers);
+            shiny.extend(city.wall);
+        }
+        let cube_instances = InstanceBuffer::with_usage(
+            &display.device,
+            cubes,
+            wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        );
+        let shiny_instances = InstanceBuffer::with_usage(
+            &display.device,
+            shiny,
+            wgpu::BufferUsage::VERTEX,
+        );
+        // Between the light and the cubes behind it
+        let quad_instances = InstanceBuffer::with_usage(
+            &display.device,
+            vec![framework::Instance::new((1.0, 0.5, -3.0))],
+            wgpu::BufferUsage::VERTEX,
+        );
+        let glass_instances = InstanceBuffer::with_usage(
+            &display.device,
+            [(-3.2, 1.0, -3.0), (-2.5, 1.2, -2.6)]
+                .iter()
+                .map(|&position| framework::Instance { scale: 0.6, ..framework::Instance::new(position) })
+                .collect(),
+            wgpu::BufferUsage::VERTEX,
+        );
+        let opened_instances = InstanceBuffer::with_usage(
+            &display.device,
+            vec![framework::Instance::new((2.0, 0.0, 0.0))],
+            wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        );
+
+        let mut resources = framework::ResourceTracker::new();
+        for &(name, model) in &[
+            ("cube", &cube_model),
+            ("shiny_cube", &shiny_cube),
+            ("quad", &quad),
+            ("triplanar_cube", &triplanar_cube),
+            (&opened_path[..], &opened_model),
+        ] {
+            resources.track_model(name, model);
+        }
+
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("init::encoder")
+            }
+        );
+
+        let projection = framework::Projection::new(
+            display.sc_desc.width,
+            display.sc_desc.height,
+            Deg(45.0),
+            0.1,
+            100.0,
+        );
+
+        let home_camera = framework::OrbitCamera::framing(
+            &scene_aabb(&[(&cube_model, &cube_instances), (&opened_model, &opened_instances)]),
+            projection.fovy(),
+            Deg(70.0),
+            Deg(25.0),
+        );
+        let camera = session.camera.as_ref()
+            .map(framework::OrbitCamera::from)
+            .unwrap_or(home_camera);
+
+        let mut uniforms = framework::Uniforms::new(&display.device);
+        uniforms.update_matrices(camera.eye(), camera.calc_matrix(), projection.calc_matrix());
+        uniforms.update_viewport(display.sc_desc.width, display.sc_desc.height);
+        uniforms.update_buffer(&display.device, &mut encoder);
+        let uniform_binding = framework::UniformBinding::new(&display.device, &uniforms);
+
+        let light = framework::Light::new(
+            &display.device,
+            (2.0, 4.0, 4.0).into(),
+            (1.0, 1.0, 1.0).into(),
+        );
+        let frame_uniforms = framework::FrameUniforms::new(&display.device, scene.fog, scene.ambient);
+        let contact_shadows = framework::ContactShadows::new(
+            &display.device,
+            &texture_layout,
+            &uniform_binding.layout,
+            display.sc_desc.width,
+            display.sc_desc.height,
+        )?;
+        let frame_binding = framework::FrameBinding::with_contact_shadows(
+            &display.device,
+            &light,
+            &frame_uniforms,
+            &contact_shadows,
+        );
+
+        let object_lights_layout = framework::LightList::create_object_layout(&display.device);
+        let point_lights = framework::LightList::new(&display.device, scene.lights.clone());
+        let cube_lights = framework::ObjectLights::new(&display.device, &object_lights_layout, &point_lights);
+        let opened_lights = framework::ObjectLights::new(&display.device, &object_lights_layout, &point_lights);
+
+        let model_layout = display.device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[
+                    &texture_layout.layout,
+                    &uniform_binding.layout,
+                    &frame_binding.layout,
+                    &object_lights_layout,
+                ],
+            }
+        );
+
+        let model_pipeline = framework::RenderPipelineBuilder::new()
+            .layout(&model_layout)
+            .depth_format(framework::Texture::DEPTH_FORMAT)
+            .color_solid(framework::Texture::HDR_FORMAT)
+            .color_solid(framework::Texture::VELOCITY_FORMAT)
+            .vertex_buffer::<framework::ModelVertex>()
+            .vertex_buffer::<framework::InstanceRaw>()
+            .vertex_shader(include_bytes!("shader.vert.spv"))
+            .fragment_shader(include_bytes!("shader.frag.spv"))
+            .build(&display.device)?;
+        // The velocity output just goes nowhere
+        let minimap_pipeline = framework::RenderPipelineBuilder::new()
+            .layout(&model_layout)
+            .depth_format(framework::Texture::DEPTH_FORMAT)
+            .color_solid(framework::Texture::HDR_FORMAT)
+            .vertex_buffer::<framework::ModelVertex>()
+            .vertex_buffer::<framework::InstanceRaw>()
+            .vertex_shader(include_bytes!("shader.vert.spv"))
+            .fragment_shader(include_bytes!("shader.frag.spv"))
+            .build(&display.device)?;
+
+        if let Some(limit) = args.fps {
+            settings.pacing.vsync = false;
+            settings.pacing.limit = limit;
+        }
+        let blob_shadows = framework::BlobShadows::new(
+            &display.device,
+            &uniform_binding.layout,
+            framework::Texture::HDR_FORMAT,
+            framework::Texture::DEPTH_FORMAT,
+        )?;
+
+        let mut minimap = framework::Minimap::new(&display.device, &uniform_binding, &settings)?;
+        let transparency = framework::TransparencyPass::new(
+            &display.device,
+            &texture_layout,
+            &uniform_binding.layout,
+            display.sc_desc.width,
+            display.sc_desc.height,
+        )?;
+        let glass_tints = vec![
+            transparency.tint(&display.device, Vector4::new(0.9, 0.15, 0.1, 0.45)),
+            transparency.tint(&display.device, Vector4::new(0.1, 0.3, 0.9, 0.45)),
+        ];
+        let overdraw = framework::OverdrawCounter::new(
+            &display.device,
+            &texture_layout,
+            &uniform_binding.layout,
+            &settings,
+            display.sc_desc.width,
+            display.sc_desc.height,
+        )?;
+        minimap.fit(&scene_aabb(&[(&cube_model, &cube_instances), (&opened_model, &opened_instances)]));
+
+        let sky = framework::SkyPass::new(
+            &display.device,
+            &uniform_binding.layout,
+            framework::Texture::HDR_FORMAT,
+            framework::Texture::DEPTH_FORMAT,
+            &scene.sky,
+        )?;
+
+        let hiz = framework::HiZPyramid::new(&display.device, &depth_texture, false)?;
+        let motion_blur = framework::MotionBlurPass::new(
+            &display.device,
+            &depth_texture,
+            display.sc_desc.width,
+            display.sc_desc.height,
+        )?;
+        let culler = framework::HiZCuller::new(&display.device)?;
+        // Instances only ever get deleted, so the starting count is
+        // as big as the batches need to be
+        let cube_cull = framework::CullBatch::new(&display.device, &cube_model, cube_instances.data.len());
+        let opened_cull = framework::CullBatch::new(&display.device, &opened_model, opened_instances.data.len());
+        let wave = wave::WavePass::new(&display.device, &mut encoder, &opened_model)?;
+        let graph = Self::render_graph(&profiler, display.sc_desc.width, display.sc_desc.height);
+        drop(stage);
+
+        let window_position = session.window.and_then(|w| w.position);
+        let mut viewer = Self {
+            depth_texture,
+            hdr_texture,
+            tonemap,
+            probe,
+            dump_requested: args.dump_frame,
+            texture_layout,
+            textures,
+            assets,
+            cube_model,
+            triplanar_cube,
+            shiny_cube,
+            shiny_instances,
+            quad,
+            quad_instances,
+            opened_model,
+            opened_path,
+            model_pipeline,
+            minimap_pipeline,
+            cube_instances,
+            opened_instances,
+            streamed_cubes,
+            uniforms,
+            uniform_binding,
+            light,
+            frame_uniforms,
+            frame_binding,
+            point_lights,
+            cube_lights,
+            opened_lights,
+            camera,
+            home_camera,
+            transition: None,
+            projection,
+            input_map: actions::default_bindings(),
+            mouse_pressed: false,
+            cursor_position: (0.0, 0.0),
+            press_position: (0.0, 0.0),
+            box_selecting: false,
+            settings,
+            sun: scene.sun,
+            sun_paused: false,
+            timeline: framework::Timeline::new(),
+            mesh_visibility: Default::default(),
+            scene,
+            blob_shadows,
+            sky,
+            hiz,
+            culler,
+            cube_cull,
+            opened_cull,
+            opened_placements: None,
+            minimap,
+            show_minimap: true,
+            overdraw,
+            show_overdraw: false,
+            contact_shadows,
+            motion_blur,
+            transparency,
+            glass_instances,
+            glass_tints,
+            loading: None,
+            load_progress: None,
+            max_buffer_size: display.caps.max_buffer_size,
+            wave,
+            graph,
+            profiler: profiler.clone(),
+            bench,
+            resources,
+            selection: framework::Selection::new(),
+            events: framework::EventBus::new(),
+            #[cfg(feature = "audio")]
+            pick_sounds: audio::PickSounds::new(),
+            snapshots: snapshot::Snapshots::load(),
+            session,
+            window_position,
+        };
+        let stage = profiler.load_scope("upload");
+        viewer.update_blob_shadows(&display.device, &mut encoder);
+        viewer.bind_culling(&display.device);
+
+        res_cmds.push(encoder.finish());
+        display.queue.submit(&res_cmds);
+        drop(stage);
+
+        Ok(viewer)
+    }
+
+    fn process_mouse(&mut self, dx: f64, dy: f64) {
+        if self.mouse_pressed && !self.box_selecting {
+            // Dragging cancels any preset we were animating towards
+            self.transition = None;
+            self.camera.rotate(Rad(dx as f32 * 0.005), Rad(dy as f32 * 0.005));
+        }
+    }
+
+    fn input(&mut self, display: &framework::Display, event: &WindowEvent) -> bool {
+        if let Some(action) = self.input_map.process_event(event) {
+            self.process_action(display, action);
+            return true;
+        }
+        match event {
+            WindowEvent::DroppedFile(path) => {
+                if let Err(e) = self.open_model(path) {
+                    eprintln!("{:?}", e);
+                }
+                true
+            }
+            WindowEvent::Moved(position) => {
+                self.window_position = Some((position.x, position.y));
+                // The framework doesn't care, but let it see this anyway
+                false
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = (position.x as f32, position.y as f32);
+                true
+            }
+            WindowEvent::MouseInput {
+                button: MouseButton::Left,
+                state,
+                ..
+            } => {
+                let pressed = *state == ElementState::Pressed;
+                let (x, y) = self.cursor_position;
+                let minimap_hit = framework::Minimap::hit(x, y, display.sc_desc.width, display.sc_desc.height)
+                    .filter(|_| self.show_minimap);
+                // Clicks on the minimap never select anything
+                if let Some(uv) = minimap_hit {
+                    if pressed {
+                        let target = self.minimap.teleport(&self.camera, uv);
+                        self.move_camera_to(target);
+                    } else {
+                        self.mouse_pressed = false;
+                        self.box_selecting = false;
+                    }
+                    return true;
+                }
+                self.mouse_pressed = pressed;
+                if self.mouse_pressed {
+                    self.press_position = self.cursor_position;
+                    self.box_selecting = self.input_map.modifiers().shift();
+                } else {
+                    let dx = self.cursor_position.0 - self.press_position.0;
+                    let dy = self.cursor_position.1 - self.press_position.1;
+                    if self.box_selecting {
+                        self.box_select(display);
+                    } else if dx * dx + dy * dy <= CLICK_SLOP * CLICK_SLOP {
+                        self.click(display);
+                    }
+                    self.box_selecting = false;
+                }
+                true
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let scroll = match delta {
+                    MouseScrollDelta::LineDelta(_, scroll) => *scroll,
+                    // I'm assuming a line is about 100 pixels
+                    MouseScrollDelta::PixelDelta(p) => p.y as f32 / 100.0,
+                };
+                self.transition = None;
+                match self.point_under_cursor(display) {
+                    Some(point) => {
+                        let min_distance = self.projection.znear() * 2.0;
+                        self.camera.zoom_towards(point, scroll * 0.1, min_distance);
+                    }
+                    // Nothing to zoom towards, so dolly towards the target
+                    None => {
+                        let amount = scroll * self.camera.distance * 0.1;
+                        self.camera.zoom(amount);
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn resize(&mut self, display: &framework::Display) {
+        self.depth_texture = framework::Texture::create_depth_texture(&display.device, &display.sc_desc);
+        self.hdr_texture = framework::Texture::create_hdr_texture(&display.device, &display.sc_desc);
+        self.tonemap.resize(&display.device, &self.hdr_texture);
+        self.probe.resize(&display.device, &self.tonemap, &self.hdr_texture);
+        self.overdraw.resize(&display.device, display.sc_desc.width, display.sc_desc.height);
+        self.contact_shadows.resize(&display.device, display.sc_desc.width, display.sc_desc.height);
+        self.motion_blur.resize(&display.device, &self.depth_texture, display.sc_desc.width, display.sc_desc.height);
+        self.frame_binding.rebind(&display.device, &self.light, &self.frame_uniforms, Some(&self.contact_shadows));
+        self.transparency.resize(&display.device, display.sc_desc.width, display.sc_desc.height);
+        self.graph.resize(display.sc_desc.width, display.sc_desc.height);
+        self.projection.resize(display.sc_desc.width, display.sc_desc.height);
+        self.uniforms.update_viewport(display.sc_desc.width, display.sc_desc.height);
+        match framework::HiZPyramid::new(&display.device, &self.depth_texture, false) {
+            Ok(hiz) => {
+                self.hiz = hiz;
+                self.bind_culling(&display.device);
+            }
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+
+    fn update(&mut self, display: &framework::Display, dt: Duration) {
+        let profiler = self.profiler.clone();
+        let _scope = profiler.scope("update");
+        // Every bench run sees the same frames, however long they take
+        let dt = self.bench.as_ref().map_or(dt, |bench| bench.dt());
+        self.poll_loading(display);
+        if let Some(transition) = &mut self.transition {
+            self.camera = transition.update(dt);
+            if transition.is_finished() {
+                self.transition = None;
+            }
+        }
+        if let Some(bench) = &mut self.bench {
+            if let Some(step) = bench.orbit_step() {
+                self.camera.rotate(step, Rad(0.0));
+            }
+            bench.track_memory(self.resources.totals().buffer_bytes + self.textures.report().resident_bytes);
+        }
+        // Keep the orthographic view roughly the same size as the
+        // perspective one so toggling doesn't jump
+        self.projection.match_ortho_to_distance(self.camera.distance);
+        self.uniforms.update_matrices(
+            self.camera.eye(),
+            self.camera.calc_matrix(),
+            self.projection.calc_matrix(),
+        );
+
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("update::encoder")
+            }
+        );
+        self.uniforms.update_buffer(&display.device, &mut encoder);
+        self.contact_shadows.update(&display.device, &mut encoder, &self.uniforms, &self.settings.contact_shadows);
+        self.motion_blur.update(&display.device, &mut encoder, &self.uniforms, &self.settings.motion_blur);
+        // Before anything gets moved this frame
+        for instances in &mut [
+            &mut self.cube_instances,
+            &mut self.shiny_instances,
+            &mut self.quad_instances,
+            &mut self.glass_instances,
+            &mut self.opened_instances,
+        ] {
+            instances.start_motion_frame(&display.device, &mut encoder);
+        }
+        self.frame_uniforms.time += dt.as_secs_f32();
+        self.frame_uniforms.frame = self.frame_uniforms.frame.wrapping_add(1);
+        // Worked out first, as the sun is borrowed below
+        let sun_target = self.sun.as_ref().map(|_| self.scene_aabb().center());
+        if let (Some(sun), Some(target)) = (&mut self.sun, sun_target) {
+            if !self.sun_paused {
+                sun.advance(dt.as_secs_f32());
+            }
+            let state = sun.state();
+            let position = state.light_position(target, sun.distance);
+            if position != self.light.position() {
+                self.events.emit(framework::DemoEvent::LightMoved { position: Point3::from_vec(position) });
+            }
+            self.light.set_position(&display.device, &mut encoder, position);
+            self.light.set_color(&display.device, &mut encoder, state.light_color);
+            self.sky.update(&display.device, &mut encoder, &state.sky(&self.scene.sky));
+            self.frame_uniforms.ambient = state.night_ambient.into();
+        }
+        // Everything that draws a debug color takes it from here
+        let palette = self.settings.palette();
+        self.frame_uniforms.selection_color = palette.color(framework::DebugColor::Selection).into();
+        self.minimap.palette = palette;
+        self.frame_uniforms.update_buffer(&display.device, &mut encoder);
+        self.tonemap.set_color_blindness(&display.device, &mut encoder, self.settings.simulate_color_blindness);
+
+        self.timeline.advance(dt.as_secs_f32());
+        let tracks = &self.scene.visibility_tracks;
+        let time = self.timeline.time;
+        for model in &mut [&mut self.cube_model, &mut self.triplanar_cube, &mut self.shiny_cube, &mut self.quad] {
+            self.mesh_visibility[0].apply(model, tracks, time);
+        }
+        self.mesh_visibility[1].apply(&mut self.opened_model, tracks, time);
+
+        // Everything's drawn every frame, so this only gets picky once
+        // the budget's too small for all of it
+        for model in &[&self.cube_model, &self.triplanar_cube, &self.shiny_cube, &self.quad, &self.opened_model] {
+            self.textures.mark_drawn(model);
+        }
+        let mut texture_cmds = Vec::new();
+        let swaps = self.textures.enforce_budget(&display.device, &mut texture_cmds);
+        if !swaps.is_empty() {
+            for model in &mut [
+                &mut self.cube_model,
+                &mut self.triplanar_cube,
+                &mut self.shiny_cube,
+                &mut self.quad,
+                &mut self.opened_model,
+            ] {
+                model.replace_textures(&display.device, &self.texture_layout, &swaps);
+            }
+        }
+
+        // Each group of instances gets the lights that matter most to
+        // it as a whole
+        let cube_aabb = scene_aabb(&[(&self.cube_model, &self.cube_instances)]);
+        let opened_aabb = scene_aabb(&[(&self.opened_model, &self.opened_instances)]);
+        self.cube_lights.update(&display.device, &mut encoder, &self.point_lights, &cube_aabb);
+        self.opened_lights.update(&display.device, &mut encoder, &self.point_lights, &opened_aabb);
+        self.wave.update(&display.device, &mut encoder, dt.as_secs_f32());
+
+        // Checked every frame so moving or deleting instances doesn't
+        // need to know about it, but only rebuilt when they changed, as
+        // it's a buffer per mesh
+        if !self.opened_model.has_placements() {
+            self.opened_placements = None;
+        } else if let Some(placements) = &mut self.opened_placements {
+            placements.update(&display.device, &self.opened_model, &self.opened_instances.data);
+        } else {
+            self.opened_placements = Some(framework::PlacementBuffers::new(
+                &display.device,
+                &self.opened_model,
+                &self.opened_instances.data,
+            ));
+        }
+
+        texture_cmds.push(encoder.finish());
+        display.queue.submit(&texture_cmds);
+
+        // Once a frame, after everything that could have emitted
+        self.events.dispatch();
+        #[cfg(feature = "audio")]
+        {
+            if let Some(sounds) = &self.pick_sounds {
+                for event in self.events.delivered() {
+                    sounds.on_event(event, &self.camera);
+                }
+            }
+        }
+    }
+
+    fn render(&mut self, display: &mut framework::Display) {
+        let profiler = self.profiler.clone();
+        let encode_scope = profiler.scope("encode");
+        let mut encoder = display.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor { label: Some("render::encoder")}
+        );
+        let frame = display.swap_chain.get_next_texture().expect("Timeout");
+
+        if let Some(sample) = self.probe.poll(&display.device) {
+            self.print_probe(display, &sample);
+        }
+        if self.show_overdraw {
+            let palette = self.settings.palette();
+            if let Some(counts) = self.overdraw.poll(&display.device, &mut encoder, &palette) {
+                println!("{}", counts);
+            }
+        }
+
+        // The passes and their order are in Viewer::render_graph
+        let mut graph = std::mem::take(&mut self.graph);
+        if let Err(e) = graph.execute(self, &display.device, &display.queue, &mut encoder, &frame.view) {
+            eprintln!("{:?}", e);
+        }
+        self.graph = graph;
+        drop(encode_scope);
+
+
+        {
+            let _scope = profiler.scope("submit");
+            display.queue.submit(&[encoder.finish()]);
+        }
+        self.probe.after_submit();
+        self.overdraw.after_submit();
+        if self.dump_requested {
+            self.dump_requested = false;
+            self.dump_frame(display);
+        }
+        {
+            // The frame gets presented when it's dropped
+            let _scope = profiler.scope("present");
+            drop(frame);
+        }
+
+        profiler.end_frame();
+        if let Some(trace) = profiler.take_trace() {
+            // A bench's capture is the whole run, and it's done
+            if let Some(bench) = &self.bench {
+                std::process::exit(bench.finish(&trace));
+            }
+            let secs = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let path = format!("trace-{}.json", secs);
+            match trace.save(&path) {
+                Ok(()) => println!("Saved trace to {}", path),
+                Err(e) => eprintln!("{:?}", e),
+            }
+        }
+    }
+
+    fn frame_pacing(&self) -> framework::FramePacing {
+        self.settings.pacing
+    }
+
+    fn exit(&mut self, display: &framework::Display) {
+        self.save_session(display);
+    }
+
+    fn overlay_stats(&mut self, display: &framework::Display) -> Option<String> {
+        // A finished load still shows its upload once
+        let loading = if self.loading.is_some() {
+            self.load_progress.map(|p| p.bar(10))
+        } else {
+            self.load_progress.take().map(|p| p.bar(10))
+        };
+        let culling = self.culling_stats(display);
+        match (loading, culling) {
+            (Some(loading), Some(culling)) => Some(format!("{} | {}", loading, culling)),
+            (loading, culling) => loading.or(culling),
+        }
+    }
+}
+
+/// Loading only packs textures in memory, so the viewer saves them for
+/// next time. Not being able to isn't worth stopping for.
+fn save_packed_textures(data: &framework::ModelData) {
+    if let Err(e) = framework::PackedTextureCache::in_settings_dir().save_packed(data) {
+        eprintln!("{:?}", e);
+    }
+}
+
+/// `--inspect`, which loads with the same options the viewer would but
+/// stops before anything needs a GPU
+fn inspect(path: &str) -> Result<()> {
+    let res_dir = Path::new(env!("OUT_DIR")).join("res");
+    let assets = framework::AssetManager::new(&res_dir);
+    let scene = framework::SceneDesc::load(res_dir.join("scene.ron"))?;
+    let options = scene.model_options(path, opened_model_options());
+    let report = framework::ModelReport::from_file(assets.resolve(path)?, &options, assets.resolver())?;
+    println!("{}", report.to_json()?);
+    Ok(())
+}
+
+/// `--pack-textures`. The snippet guesses the model has the same name
+/// as the MTL file, which is what most exporters do.
+fn pack_textures(mtl_path: &Path) -> Result<()> {
+    let packing = framework::pack_mtl_textures(mtl_path)?;
+    if packing.is_empty() {
+        println!("Nothing to pack in {}", mtl_path.display());
+        return Ok(());
+    }
+    for name in packing.keys() {
+        println!("Packed {}", name);
+    }
+    let overrides = framework::ModelOverrides { packing, ..Default::default() };
+    let model = mtl_path.with_extension("obj");
+    let model = model.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    println!("\nAdd this to the models in scene.ron:\n");
+    println!("{:?}: {},", model, ron::ser::to_string_pretty(&overrides, Default::default())?);
+    Ok(())
+}
+
+/// `--generate-instances`, seeded like everything else so the same
+/// seed gives the same file
+fn generate_instances(path: &Path, count: u64, seed: Option<framework::Seed>) -> Result<()> {
+    let generator = framework::InstanceGenerator {
+        seed: seed.unwrap_or_default(),
+        ..framework::InstanceGenerator::new(count)
+    };
+    generator.write(path)?;
+    println!("Wrote {} instances to {}", count, path.display());
+    println!("\nAdd this to scene.ron to draw them as cubes:\n");
+    println!("instance_files: [(path: {:?}, model: \"cube.obj\")],", path.display().to_string());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args = cli::Args::from_env()?;
+    if let Some(path) = args.inspect {
+        return inspect(&path);
+    }
+    if let Some(path) = args.pack_textures {
+        return pack_textures(&path);
+    }
+    if let Some((path, count)) = &args.generate_instances {
+        return generate_instances(path, *count, args.seed);
+    }
+    if args.print_caps {
+        let caps = futures::executor::block_on(framework::DeviceCapabilities::request_headless())?;
+        println!("{}", caps.to_json()?);
+        return Ok(());
+    }
+    // Batch thumbnails don't need a window, so they skip the usual
+    // setup entirely
+    if let Some(dir) = args.thumbnails {
+        let saved = futures::executor::block_on(thumbnails::render_folder(&dir))?;
+        println!("Saved {} thumbnails to {}", saved.len(), dir.join("thumbnails").display());
+        return Ok(());
+    }
+    futures::executor::block_on(framework::run::<Viewer>())
+}
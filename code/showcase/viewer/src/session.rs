@@ -0,0 +1,69 @@
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::snapshot::CameraState;
+
+/// How many files the recent list remembers
+pub const MAX_RECENT: usize = 8;
+
+/**
+ * What the viewer was looking at when it was closed. This gets put
+ * back on the next launch.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    /// The last model that was opened. None means the bundled one.
+    pub model: Option<PathBuf>,
+    pub camera: Option<CameraState>,
+    pub window: Option<framework::WindowGeometry>,
+    /// Most recent first
+    pub recent: Vec<PathBuf>,
+}
+
+impl Session {
+    fn path() -> PathBuf {
+        framework::settings_dir().join("session.ron")
+    }
+
+    /// Like [crate::snapshot::Snapshots::load], a missing or broken
+    /// file just means starting fresh
+    pub fn load() -> Self {
+        std::fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|src| ron::de::from_str::<Session>(&src).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let src = ron::ser::to_string_pretty(self, Default::default())?;
+        std::fs::write(&path, src)
+            .with_context(|| format!("Unable to save session to {}", path.display()))
+    }
+
+    /// Moves `path` to the front of the recent list, dropping whatever
+    /// falls off the end
+    pub fn add_recent(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.recent.retain(|p| p != &path);
+        self.recent.insert(0, path);
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    pub fn print_recent(&self) {
+        if self.recent.is_empty() {
+            println!("No recent files. Drop a model on the window to open it.");
+            return;
+        }
+        println!("Recent files (Alt+number to open):");
+        for (i, path) in self.recent.iter().enumerate() {
+            let missing = if path.is_file() { "" } else { " (missing)" };
+            println!("  {}: {}{}", i + 1, path.display(), missing);
+        }
+    }
+}
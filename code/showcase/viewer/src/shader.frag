@@ -0,0 +1,232 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 v_position;
+layout(location=2) in vec3 v_normal;
+layout(location=3) in vec3 v_tangent;
+layout(location=4) in vec3 v_bitangent;
+layout(location=5) flat in uint v_flags;
+layout(location=6) in vec4 v_clip;
+layout(location=7) in vec4 v_prev_clip;
+
+layout(location=0) out vec4 f_color;
+// How far this moved on screen since last frame, in texture
+// coordinates. See MotionBlurPass in motion_blur.rs.
+layout(location=1) out vec2 f_velocity;
+
+layout(set = 0, binding = 0) uniform texture2D t_diffuse;
+layout(set = 0, binding = 1) uniform sampler s_diffuse;
+layout(set = 0, binding = 2) uniform texture2D t_normal;
+layout(set = 0, binding = 3) uniform sampler s_normal;
+layout(set = 0, binding = 4) uniform MaterialParams {
+    uint u_triplanar;
+    float u_triplanar_scale;
+    float u_shininess;
+    float u_rim;
+    vec4 u_tint;
+    uint u_two_sided_lighting;
+    float u_translucency;
+};
+// Occlusion, roughness and metalness. Materials without one get a
+// texture that leaves everything as it was.
+layout(set = 0, binding = 5) uniform texture2D t_packed;
+layout(set = 0, binding = 6) uniform sampler s_packed;
+
+// Per view
+layout(set=1, binding=0) 
+uniform Uniforms {
+    vec4 u_view_position; 
+    mat4 u_view_proj;
+};
+
+// Per frame
+layout(set = 2, binding = 0) uniform Light {
+    vec4 light_position;
+    vec4 light_color;
+};
+layout(set = 2, binding = 1) uniform Frame {
+    // w is the density
+    vec4 u_fog;
+    // x is the time in seconds, y the frame counter
+    vec4 u_time;
+    // Light that's there even when the main light isn't, like at night
+    vec4 u_ambient;
+    // rgb is the palette's selection color, w how strongly it tints
+    vec4 u_selection;
+    // AmbientGradient in frame.rs, as fractions of the light's color
+    vec4 u_ambient_sky;
+    vec4 u_ambient_equator;
+    vec4 u_ambient_ground;
+};
+// Depth from the contact shadow prepass, see ContactShadows in
+// contact_shadow.rs
+layout(set = 2, binding = 2) uniform texture2D t_contact_depth;
+layout(set = 2, binding = 3) uniform sampler s_contact_depth;
+layout(set = 2, binding = 4) uniform ContactShadow {
+    // The main camera's, even when drawing another view
+    mat4 u_contact_view_proj;
+    mat4 u_contact_inv_view_proj;
+    // w is 1 when they're on
+    vec4 u_contact_eye;
+    // Steps, max distance, thickness and edge fade
+    vec4 u_contact_params;
+};
+
+#include "common/lighting.glsl"
+#include "common/noise.glsl"
+#include "common/contact_shadow.glsl"
+
+layout(set = 3, binding = 0) uniform LightList {
+    PointLight u_lights[MAX_LIGHTS];
+    uvec4 u_light_count;
+};
+layout(set = 3, binding = 1) uniform ObjectLights {
+    uvec4 u_light_indices;
+    vec4 u_light_weights;
+};
+
+// Reoriented normal mapping. Rotates the tangent space normal `n2` so
+// that the tangent space "up" matches `n1`.
+vec3 blend_rnm(vec3 n1, vec3 n2) {
+    n1.z += 1.0;
+    n2.xy = -n2.xy;
+    return n1 * dot(n1, n2) / n1.z - n2;
+}
+
+vec3 unpack_normal(vec4 sampled) {
+    return sampled.xyz * 2.0 - 1.0;
+}
+
+// Samples along each world axis and blends the results using the
+// normal, so meshes without UVs still get textured.
+//
+// The normal map is treated as if each projection's UVs lined up with
+// the world axes. That isn't quite true (we don't have a real tangent
+// basis), but with the reoriented blend the error is only noticeable
+// where two projections meet at a steep angle.
+void triplanar(vec3 position, vec3 normal, out vec4 color, out vec3 world_normal) {
+    vec3 p = position * u_triplanar_scale;
+    vec3 blend = pow(abs(normal), vec3(4.0));
+    blend /= dot(blend, vec3(1.0));
+
+    vec2 uv_x = p.zy;
+    vec2 uv_y = p.xz;
+    vec2 uv_z = p.xy;
+
+    color = texture(sampler2D(t_diffuse, s_diffuse), uv_x) * blend.x
+        + texture(sampler2D(t_diffuse, s_diffuse), uv_y) * blend.y
+        + texture(sampler2D(t_diffuse, s_diffuse), uv_z) * blend.z;
+
+    vec3 tn_x = unpack_normal(texture(sampler2D(t_normal, s_normal), uv_x));
+    vec3 tn_y = unpack_normal(texture(sampler2D(t_normal, s_normal), uv_y));
+    vec3 tn_z = unpack_normal(texture(sampler2D(t_normal, s_normal), uv_z));
+
+    vec3 abs_normal = abs(normal);
+    tn_x = blend_rnm(vec3(normal.zy, abs_normal.x), tn_x);
+    tn_y = blend_rnm(vec3(normal.xz, abs_normal.y), tn_y);
+    tn_z = blend_rnm(vec3(normal.xy, abs_normal.z), tn_z);
+
+    // The blend loses which side of the axis we're on
+    vec3 axis_sign = sign(normal);
+    tn_x.z *= axis_sign.x;
+    tn_y.z *= axis_sign.y;
+    tn_z.z *= axis_sign.z;
+
+    world_normal = normalize(
+        tn_x.zyx * blend.x
+        + tn_y.xzy * blend.y
+        + tn_z.xyz * blend.z
+    );
+}
+
+// The same projection as triplanar(), for the packed texture
+vec3 triplanar_packed(vec3 position, vec3 normal) {
+    vec3 p = position * u_triplanar_scale;
+    vec3 blend = pow(abs(normal), vec3(4.0));
+    blend /= dot(blend, vec3(1.0));
+    return texture(sampler2D(t_packed, s_packed), p.zy).rgb * blend.x
+        + texture(sampler2D(t_packed, s_packed), p.xz).rgb * blend.y
+        + texture(sampler2D(t_packed, s_packed), p.xy).rgb * blend.z;
+}
+
+void main() {
+    vec3 normal = normalize(v_normal);
+
+    vec4 object_color;
+    vec3 packed;
+    if (u_triplanar != 0) {
+        // Before the normal gets replaced by the normal mapped one
+        packed = triplanar_packed(v_position, normal);
+        triplanar(v_position, normal, object_color, normal);
+    } else {
+        packed = texture(sampler2D(t_packed, s_packed), v_tex_coords).rgb;
+        object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+        vec3 tangent_normal = unpack_normal(texture(sampler2D(t_normal, s_normal), v_tex_coords));
+        mat3 tbn = mat3(normalize(v_tangent), normalize(v_bitangent), normal);
+        normal = normalize(tbn * tangent_normal);
+    }
+    object_color.rgb *= u_tint.rgb;
+
+    // Back faces of thin surfaces get lit like the front. Only the
+    // final normal gets flipped, as the normal map was drawn for the
+    // front.
+    if (u_two_sided_lighting != 0u && !gl_FrontFacing) {
+        normal = -normal;
+    }
+
+    float occlusion = packed.r;
+    // Rough surfaces get a wider, dimmer highlight. Metals tint their
+    // highlights and have no diffuse of their own, which multiplying
+    // the direct light by the color again gets close enough to.
+    float shininess = mix(u_shininess, 2.0, packed.g);
+    float metallic = packed.b;
+
+    vec3 gradient = gradient_ambient(normal, u_ambient_sky.rgb, u_ambient_equator.rgb, u_ambient_ground.rgb);
+    vec3 ambient_color = (light_color.rgb * gradient + u_ambient.rgb) * occlusion;
+
+    vec3 light_dir = normalize(light_position.xyz - v_position);
+    vec3 view_dir = normalize(u_view_position.xyz - v_position);
+
+    vec3 direct = blinn_phong_with(normal, view_dir, light_dir, light_color.rgb, object_color.xyz, shininess);
+    if (u_two_sided_lighting != 0u) {
+        direct += translucency_with(normal, light_dir, light_color.rgb, object_color.xyz, u_translucency);
+    }
+    // Only the main light, the point lights are too dim to bother
+    direct *= contact_shadow(v_position, light_position.xyz, gl_FragCoord.xy, u_time.y);
+
+    // Brightens edges facing away from the camera
+    float rim = pow(1.0 - max(dot(normal, view_dir), 0.0), 4.0);
+    vec3 result = ambient_color * object_color.xyz + rim * u_rim * occlusion * light_color.rgb;
+
+    // Only the lights picked for this object. The last one can have a
+    // weight below 1 so it fades out before it gets swapped.
+    for (int i = 0; i < LIGHTS_PER_OBJECT; i++) {
+        float weight = u_light_weights[i];
+        if (weight <= 0.0) {
+            continue;
+        }
+        PointLight light = u_lights[u_light_indices[i]];
+        direct += point_light_with(light, v_position, normal, view_dir, object_color.xyz, shininess) * weight;
+        if (u_two_sided_lighting != 0u) {
+            vec3 to_light = light.position.xyz - v_position;
+            float attenuation = light.position.w / max(dot(to_light, to_light), 0.01);
+            direct += translucency_with(normal, normalize(to_light), light.color.rgb, object_color.xyz, u_translucency)
+                * attenuation * weight;
+        }
+    }
+    result += mix(direct, direct * object_color.rgb, metallic);
+
+    float fog = 1.0 - exp(-u_fog.w * length(u_view_position.xyz - v_position));
+    result = mix(result, u_fog.rgb, fog);
+
+    // Selected instances get tinted. This matches InstanceRaw::SELECTED.
+    if ((v_flags & 1u) != 0u) {
+        result = mix(result, u_selection.rgb, u_selection.w);
+    }
+    f_color = vec4(result, object_color.a);
+
+    vec2 ndc = v_clip.xy / v_clip.w;
+    vec2 prev_ndc = v_prev_clip.xy / v_prev_clip.w;
+    // y flips, as texture coordinates go down
+    f_velocity = (ndc - prev_ndc) * vec2(0.5, -0.5);
+}
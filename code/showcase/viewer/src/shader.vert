@@ -0,0 +1,48 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec2 a_tex_coords;
+layout(location=2) in vec3 a_normal;
+layout(location=3) in vec4 a_tangent;
+layout(location=5) in mat4 a_model;
+layout(location=9) in uint a_flags;
+layout(location=10) in mat4 a_prev_model;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec3 v_position;
+layout(location=2) out vec3 v_normal;
+layout(location=3) out vec3 v_tangent;
+layout(location=4) out vec3 v_bitangent;
+layout(location=5) flat out uint v_flags;
+// Where the vertex is now and was last frame, for the velocity buffer
+layout(location=6) out vec4 v_clip;
+layout(location=7) out vec4 v_prev_clip;
+
+layout(set=1, binding=0) 
+uniform Uniforms {
+    vec4 u_view_position; 
+    mat4 u_view_proj;
+    mat4 u_inv_view_proj;
+    vec4 u_viewport;
+    mat4 u_prev_view_proj;
+};
+
+void main() {
+    v_tex_coords = a_tex_coords;
+    v_flags = a_flags;
+
+    // Lighting happens in world space in the viewer, as triplanar
+    // projection needs the world position and normal anyway.
+    mat3 normal_matrix = mat3(transpose(inverse(a_model)));
+    v_normal = normalize(normal_matrix * a_normal);
+    v_tangent = normal_matrix * a_tangent.xyz;
+    // The handedness flips the bitangent on mirrored UVs
+    v_bitangent = cross(v_normal, v_tangent) * a_tangent.w;
+
+    vec4 world_position = a_model * vec4(a_position, 1.0);
+    v_position = world_position.xyz;
+
+    gl_Position = u_view_proj * world_position;
+    v_clip = gl_Position;
+    v_prev_clip = u_prev_view_proj * a_prev_model * vec4(a_position, 1.0);
+}
@@ -0,0 +1,96 @@
+use anyhow::*;
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+pub const NUM_SLOTS: usize = 4;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct CameraState {
+    pub target: [f32; 3],
+    pub distance: f32,
+    pub yaw_degrees: f32,
+    pub pitch_degrees: f32,
+}
+
+impl From<&framework::OrbitCamera> for CameraState {
+    fn from(camera: &framework::OrbitCamera) -> Self {
+        Self {
+            target: camera.target.into(),
+            distance: camera.distance,
+            yaw_degrees: Deg::from(camera.yaw).0,
+            pitch_degrees: Deg::from(camera.pitch).0,
+        }
+    }
+}
+
+impl From<&CameraState> for framework::OrbitCamera {
+    fn from(state: &CameraState) -> Self {
+        framework::OrbitCamera::new(
+            state.target,
+            state.distance,
+            Deg(state.yaw_degrees),
+            Deg(state.pitch_degrees),
+        )
+    }
+}
+
+/**
+ * Everything needed to get the viewer back to how it looked when the
+ * snapshot was taken.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub camera: CameraState,
+    pub projection: framework::ProjectionMode,
+    #[serde(default)]
+    pub selection: framework::Selection,
+    pub settings: framework::RenderSettings,
+    pub light_position: [f32; 3],
+}
+
+/**
+ * The numbered snapshot slots. These get written to disk every time a
+ * slot is saved so they survive restarts.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshots {
+    slots: Vec<Option<Snapshot>>,
+}
+
+impl Snapshots {
+    fn path() -> PathBuf {
+        framework::settings_dir().join("snapshots.ron")
+    }
+
+    /// Missing or unreadable files just mean there aren't any
+    /// snapshots yet
+    pub fn load() -> Self {
+        let mut snapshots = std::fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|src| ron::de::from_str::<Snapshots>(&src).ok())
+            .unwrap_or_default();
+        snapshots.slots.resize(NUM_SLOTS, None);
+        snapshots
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let src = ron::ser::to_string_pretty(self, Default::default())?;
+        std::fs::write(&path, src)
+            .with_context(|| format!("Unable to save snapshots to {}", path.display()))
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&Snapshot> {
+        self.slots.get(slot).and_then(|s| s.as_ref())
+    }
+
+    pub fn set(&mut self, slot: usize, snapshot: Snapshot) {
+        if slot < self.slots.len() {
+            self.slots[slot] = Some(snapshot);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::*;
+use std::path::{Path, PathBuf};
+
+/// How big the batch thumbnails are
+const SIZE: u32 = 128;
+
+/**
+ * Renders every OBJ and STL file in `dir` into `dir/thumbnails`, for
+ * `--thumbnails`. Models that fail to load get logged and skipped so
+ * one broken file doesn't stop the rest.
+ */
+pub async fn render_folder(dir: &Path) -> Result<Vec<PathBuf>> {
+    let (device, queue) = framework::headless_device().await?;
+    let renderer = framework::ThumbnailRenderer::new(&device)?;
+    let cache = framework::ThumbnailCache::in_settings_dir();
+    let assets = framework::AssetManager::new(dir);
+
+    let mut models = std::fs::read_dir(dir)
+        .with_context(|| format!("Unable to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+            ext.as_ref().map(|e| e == "obj" || e == "stl").unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    models.sort();
+
+    let out_dir = dir.join("thumbnails");
+    std::fs::create_dir_all(&out_dir)
+        .with_context(|| format!("Unable to create {}", out_dir.display()))?;
+    let mut saved = Vec::new();
+    for model in models {
+        let name = model.to_string_lossy();
+        let image = match cache.get_or_render(&renderer, &device, &queue, &assets, &name, SIZE) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("Skipping {}: {:?}", name, e);
+                continue;
+            }
+        };
+        let stem = model.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let path = out_dir.join(format!("{}.png", stem));
+        image.save(&path).with_context(|| format!("Unable to save {}", path.display()))?;
+        saved.push(path);
+    }
+    Ok(saved)
+}
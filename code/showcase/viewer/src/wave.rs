@@ -0,0 +1,219 @@
+use anyhow::Result;
+use std::mem;
+
+/// Uniform buffer bindings have to start on a 256 byte boundary
+const PARAMS_STRIDE: wgpu::BufferAddress = 256;
+
+/**
+ * An example of working on a mesh with a compute shader. Every vertex
+ * gets pushed along its normal by a sine wave.
+ *
+ * Only the positions get written. That means:
+ *
+ * - The normals and tangents are still the ones from the rest pose, so
+ *   the lighting looks like the model is flat where it isn't. Getting
+ *   them right means recomputing them from the displaced triangles
+ *   (using the index buffer in [framework::Mesh::storage_layout]), or
+ *   working out the derivative of the wave, and then rebuilding the
+ *   tangents to match the new normals.
+ * - The model's [framework::Aabb] doesn't know about the displacement
+ *   either. Picking, blob shadows and occlusion culling all use that
+ *   box, so large amplitudes can get clipped by the culling.
+ *
+ * The mesh needs loading with `storage_buffers` turned on.
+ */
+pub struct WavePass {
+    pipeline: wgpu::ComputePipeline,
+    params_buffer: wgpu::Buffer,
+    meshes: Vec<WaveMesh>,
+    time: f32,
+    enabled: bool,
+    pub amplitude: f32,
+    pub frequency: f32,
+    /// In radians per second
+    pub speed: f32,
+}
+
+struct WaveMesh {
+    /// A copy of the vertices from before the first dispatch
+    rest_buffer: wgpu::Buffer,
+    vertex_buffer_size: wgpu::BufferAddress,
+    num_vertices: u32,
+    params_bind_group: wgpu::BindGroup,
+    mesh_bind_group: wgpu::BindGroup,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct WaveParams {
+    time: f32,
+    amplitude: f32,
+    frequency: f32,
+    vertex_count: u32,
+}
+
+unsafe impl bytemuck::Pod for WaveParams {}
+unsafe impl bytemuck::Zeroable for WaveParams {}
+
+impl WavePass {
+    /// The rest pose gets copied with `encoder`, so submit it before
+    /// the first [WavePass::update]
+    pub fn new(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        model: &framework::Model,
+    ) -> Result<Self> {
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            bindings: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::StorageBuffer { dynamic: false, readonly: true },
+                },
+            ],
+            label: Some("WavePass::params_layout"),
+        });
+        let mesh_layout = framework::Mesh::storage_layout(device);
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&params_layout, &mesh_layout],
+        });
+        let module = device.create_shader_module(
+            &wgpu::read_spirv(std::io::Cursor::new(&include_bytes!("wave.comp.spv")[..]))?
+        );
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            layout: &pipeline_layout,
+            compute_stage: wgpu::ProgrammableStageDescriptor {
+                module: &module,
+                entry_point: "main",
+            },
+        });
+
+        // Every mesh has its own vertex count, so it gets its own
+        // slice of the params buffer
+        let params_size = mem::size_of::<WaveParams>() as wgpu::BufferAddress;
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("WavePass::params_buffer"),
+            size: PARAMS_STRIDE * model.meshes.len().max(1) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let mut meshes = Vec::new();
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            let mesh_bind_group = mesh.storage_bind_group(device, &mesh_layout)?;
+            let vertex_buffer_size = mesh.vertex_buffer_size();
+            let rest_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("WavePass::rest_buffer"),
+                size: vertex_buffer_size,
+                usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_SRC | wgpu::BufferUsage::COPY_DST,
+            });
+            encoder.copy_buffer_to_buffer(&mesh.vertex_buffer, 0, &rest_buffer, 0, vertex_buffer_size);
+
+            let offset = PARAMS_STRIDE * i as wgpu::BufferAddress;
+            let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &params_layout,
+                bindings: &[
+                    wgpu::Binding {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &params_buffer,
+                            range: offset..offset + params_size,
+                        },
+                    },
+                    wgpu::Binding {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Buffer {
+                            buffer: &rest_buffer,
+                            range: 0..vertex_buffer_size,
+                        },
+                    },
+                ],
+                label: Some("WavePass::params_bind_group"),
+            });
+            meshes.push(WaveMesh {
+                rest_buffer,
+                vertex_buffer_size,
+                num_vertices: mesh.num_vertices,
+                params_bind_group,
+                mesh_bind_group,
+            });
+        }
+
+        Ok(Self {
+            pipeline,
+            params_buffer,
+            meshes,
+            time: 0.0,
+            enabled: false,
+            amplitude: 0.05,
+            frequency: 8.0,
+            speed: 4.0,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Turning the waves off puts `model` back in its rest pose. This
+    /// has to be the model the pass was made with.
+    pub fn set_enabled(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        model: &framework::Model,
+        enabled: bool,
+    ) {
+        if self.enabled && !enabled {
+            for (wave_mesh, mesh) in self.meshes.iter().zip(&model.meshes) {
+                encoder.copy_buffer_to_buffer(
+                    &wave_mesh.rest_buffer,
+                    0,
+                    &mesh.vertex_buffer,
+                    0,
+                    wave_mesh.vertex_buffer_size,
+                );
+            }
+        }
+        self.enabled = enabled;
+    }
+
+    /// Moves the waves along by `dt` seconds. Does nothing while the
+    /// pass is turned off.
+    pub fn update(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, dt: f32) {
+        if !self.enabled || self.meshes.is_empty() {
+            return;
+        }
+        // Keeps the sine's argument small so it doesn't lose precision
+        // if the viewer is left running
+        self.time = (self.time + dt * self.speed) % (std::f32::consts::PI * 2.0);
+
+        let stride = PARAMS_STRIDE as usize;
+        let mut params = vec![0u8; stride * self.meshes.len()];
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            let data = WaveParams {
+                time: self.time,
+                amplitude: self.amplitude,
+                frequency: self.frequency,
+                vertex_count: mesh.num_vertices,
+            };
+            let start = i * stride;
+            params[start..start + mem::size_of::<WaveParams>()]
+                .copy_from_slice(bytemuck::bytes_of(&data));
+        }
+        let staging_buffer = device.create_buffer_with_data(&params, wgpu::BufferUsage::COPY_SRC);
+        encoder.copy_buffer_to_buffer(&staging_buffer, 0, &self.params_buffer, 0, params.len() as _);
+
+        let mut pass = encoder.begin_compute_pass();
+        pass.set_pipeline(&self.pipeline);
+        for mesh in &self.meshes {
+            pass.set_bind_group(0, &mesh.params_bind_group, &[]);
+            pass.set_bind_group(1, &mesh.mesh_bind_group, &[]);
+            pass.dispatch((mesh.num_vertices + 63) / 64, 1, 1);
+        }
+    }
+}
+